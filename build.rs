@@ -0,0 +1,33 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // Allow CI to inject the SHA explicitly (e.g. shallow clones without .git)
+    let git_sha = std::env::var("GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|sha| sha.trim().to_string())
+    });
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    println!(
+        "cargo:rustc-env=LANGPROXY_GIT_SHA={}",
+        git_sha.as_deref().unwrap_or("unknown")
+    );
+    println!("cargo:rustc-env=LANGPROXY_BUILD_TIMESTAMP={build_timestamp}");
+
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
@@ -0,0 +1,68 @@
+-- Copyright (c) 2025 PROS Inc.
+-- All rights reserved.
+
+-- Usage events written by `UsageAnalytics::save` when the `USAGE_DB` D1 binding is configured.
+-- One column per `UsageAnalytics` field; `extra` and `tool_names` hold JSON, flags are 0 or 1.
+CREATE TABLE IF NOT EXISTS usage_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    app_id TEXT NOT NULL,
+    tenant_id TEXT,
+    module_id TEXT,
+    session_id TEXT,
+    request_id TEXT,
+    env_id TEXT,
+    ip_address TEXT,
+    country TEXT,
+    cf_ray TEXT,
+    domain TEXT,
+    deployment TEXT,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    total_tokens INTEGER NOT NULL,
+    timestamp REAL NOT NULL,
+    build TEXT NOT NULL,
+    audio_seconds REAL NOT NULL,
+    image_count INTEGER NOT NULL,
+    image_width INTEGER NOT NULL,
+    image_height INTEGER NOT NULL,
+    image_quality TEXT,
+    moderation_flagged INTEGER NOT NULL,
+    moderation_top_category TEXT,
+    batch_total INTEGER NOT NULL,
+    batch_completed INTEGER NOT NULL,
+    batch_failed INTEGER NOT NULL,
+    http_method TEXT NOT NULL,
+    rejected_upstream TEXT,
+    target TEXT,
+    api_version TEXT,
+    user_id TEXT,
+    extra TEXT NOT NULL,
+    usage_captured INTEGER NOT NULL,
+    error TEXT,
+    model_alias TEXT,
+    max_tokens_capped INTEGER,
+    fields_stripped INTEGER NOT NULL,
+    system_prompt_chars INTEGER,
+    proxy_key TEXT,
+    status_code INTEGER NOT NULL,
+    client_disconnected INTEGER NOT NULL,
+    ttft_ms INTEGER,
+    duration_ms INTEGER NOT NULL,
+    chunk_count INTEGER NOT NULL,
+    response_bytes INTEGER NOT NULL,
+    completion_chars INTEGER NOT NULL,
+    finish_reason TEXT,
+    tool_call_count INTEGER NOT NULL,
+    tool_names TEXT NOT NULL,
+    reasoning_tokens INTEGER NOT NULL,
+    provider TEXT,
+    cached_tokens INTEGER NOT NULL,
+    invocation_latency_ms INTEGER,
+    first_byte_latency_ms INTEGER,
+    system_fingerprint TEXT,
+    service_tier TEXT,
+    upstream_request_id TEXT
+);
+
+CREATE INDEX IF NOT EXISTS usage_events_tenant_timestamp ON usage_events (tenant_id, timestamp);
@@ -0,0 +1,243 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::admin;
+
+/// KV namespace holding account records
+pub const ACCOUNTS_BINDING: &str = "ACCOUNTS";
+/// Page size used when `GET /accounts` doesn't pass `limit`
+const DEFAULT_PAGE_SIZE: u64 = 50;
+/// Upper bound for `limit`; every listed key costs one KV read
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// Account record stored in KV under its id
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Account {
+    pub id: u64,
+    /// Empty in records stored before accounts had a name; `PUT` requires one
+    #[serde(default)]
+    pub name: String,
+    /// Empty in records stored before accounts had a tenant; `PUT` requires one
+    #[serde(default)]
+    pub tenant_id: String,
+    /// Creation time in milliseconds since the Unix epoch; set by the worker when omitted
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+impl Account {
+    /// Checks an account submitted for `PUT /account/:id`
+    fn validate(&self, id: &str) -> std::result::Result<(), String> {
+        if self.id.to_string() != id {
+            return Err(format!("`id` must match the path id {id}"));
+        }
+        if self.name.trim().is_empty() {
+            return Err("`name` must not be empty".to_string());
+        }
+        if self.tenant_id.trim().is_empty() {
+            return Err("`tenant_id` must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListQuery {
+    cursor: Option<String>,
+    limit: Option<u64>,
+}
+
+fn bad_request(message: &str) -> Result<Response> {
+    Ok(Response::from_json(&json!({
+        "error": true,
+        "type": "Bad Request",
+        "message": message,
+    }))?
+    .with_status(400))
+}
+
+pub async fn get_account(_req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if let Some(id) = ctx.param("id") {
+        let accounts = ctx.kv(ACCOUNTS_BINDING)?;
+        return match accounts.get(id).json::<Account>().await? {
+            Some(account) => Response::from_json(&account),
+            None => Response::error("Not found", 404),
+        };
+    }
+
+    Response::error("Bad Request", 400)
+}
+
+/// Creates or replaces an account; `created_at` of an existing record is kept
+pub async fn put_account(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if let Some(rejection) = admin::reject_non_admin(&req, &ctx.env) {
+        return rejection;
+    }
+
+    let id = match ctx.param("id") {
+        Some(id) => id.to_string(),
+        None => return Response::error("Bad Request", 400),
+    };
+
+    let mut account = match req.json::<Account>().await {
+        Ok(account) => account,
+        Err(e) => return bad_request(&format!("Invalid account JSON: {e}")),
+    };
+    if let Err(message) = account.validate(&id) {
+        return bad_request(&message);
+    }
+
+    let accounts = ctx.kv(ACCOUNTS_BINDING)?;
+    let existing = accounts.get(&id).json::<Account>().await?;
+    let created = existing.is_none();
+
+    account.created_at = match existing {
+        Some(existing) => existing.created_at,
+        None if account.created_at == 0 => Date::now().as_millis(),
+        None => account.created_at,
+    };
+
+    accounts
+        .put(&id, serde_json::to_string(&account)?)?
+        .execute()
+        .await?;
+
+    Ok(Response::from_json(&account)?.with_status(if created { 201 } else { 200 }))
+}
+
+pub async fn delete_account(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if let Some(rejection) = admin::reject_non_admin(&req, &ctx.env) {
+        return rejection;
+    }
+
+    let id = match ctx.param("id") {
+        Some(id) => id.to_string(),
+        None => return Response::error("Bad Request", 400),
+    };
+
+    let accounts = ctx.kv(ACCOUNTS_BINDING)?;
+    if accounts.get(&id).text().await?.is_none() {
+        return Response::error("Not found", 404);
+    }
+    accounts.delete(&id).await?;
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+/// Lists accounts a page at a time; the KV cursor is handed to the client as-is
+pub async fn list_accounts(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if let Some(rejection) = admin::reject_non_admin(&req, &ctx.env) {
+        return rejection;
+    }
+
+    let query = match req.query::<ListQuery>() {
+        Ok(query) => query,
+        Err(e) => return bad_request(&e.to_string()),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let accounts = ctx.kv(ACCOUNTS_BINDING)?;
+    let mut list = accounts.list().limit(limit);
+    if let Some(cursor) = query.cursor.filter(|cursor| !cursor.is_empty()) {
+        list = list.cursor(cursor);
+    }
+    let page = list.execute().await?;
+
+    let records = join_all(
+        page.keys
+            .iter()
+            .map(|key| accounts.get(&key.name).json::<Account>()),
+    )
+    .await;
+
+    let mut items = Vec::with_capacity(records.len());
+    for (key, record) in page.keys.iter().zip(records) {
+        match record {
+            Ok(Some(account)) => items.push(account),
+            // A key deleted between list and get simply drops out of the page
+            Ok(None) => {}
+            // So does a record that can't be read, rather than failing the whole page
+            Err(e) => console_warn!("Skipping unreadable account {}: {}", key.name, e),
+        }
+    }
+
+    Response::from_json(&json!({
+        "accounts": items,
+        "cursor": page.cursor,
+        "list_complete": page.list_complete,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_round_trip() {
+        let account = Account {
+            id: 42,
+            name: "Acme Airlines".to_string(),
+            tenant_id: "acme".to_string(),
+            created_at: 1640995200000,
+        };
+
+        let json = serde_json::to_string(&account).unwrap();
+        assert_eq!(
+            json,
+            r#"{"id":42,"name":"Acme Airlines","tenant_id":"acme","created_at":1640995200000}"#
+        );
+        assert_eq!(serde_json::from_str::<Account>(&json).unwrap(), account);
+    }
+
+    #[test]
+    fn test_account_created_at_optional() {
+        let account: Account =
+            serde_json::from_str(r#"{"id": 7, "name": "Beta", "tenant_id": "beta"}"#).unwrap();
+        assert_eq!(account.created_at, 0);
+        assert!(serde_json::from_str::<Account>(r#"{"name": "Beta"}"#).is_err());
+    }
+
+    #[test]
+    fn test_account_legacy_record() {
+        // Records stored before accounts had a name and tenant
+        let account: Account = serde_json::from_str(r#"{"id": 7}"#).unwrap();
+        assert_eq!(account.id, 7);
+        assert_eq!(account.name, "");
+        assert_eq!(account.tenant_id, "");
+        assert!(account.validate("7").is_err());
+    }
+
+    #[test]
+    fn test_account_validation() {
+        let account = Account {
+            id: 7,
+            name: "Beta".to_string(),
+            tenant_id: "beta".to_string(),
+            created_at: 0,
+        };
+        assert!(account.validate("7").is_ok());
+        assert!(account.validate("8").is_err());
+
+        let unnamed = Account {
+            name: " ".to_string(),
+            ..account.clone()
+        };
+        assert!(unnamed.validate("7").is_err());
+
+        let no_tenant = Account {
+            tenant_id: String::new(),
+            ..account
+        };
+        assert!(no_tenant.validate("7").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde_json::json;
+use worker::*;
+
+/// Worker secret holding the bearer token for admin routes
+pub const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+/// Compares two byte strings without short-circuiting on the first difference
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Extracts the token of an `Authorization: Bearer <token>` header value
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+fn error_response(status: u16, message: &str) -> Result<Response> {
+    Ok(Response::from_json(&json!({
+        "error": true,
+        "type": "Unauthorized",
+        "message": message,
+    }))?
+    .with_status(status))
+}
+
+/// Returns the response to send when the request doesn't carry the admin bearer token
+pub fn reject_non_admin(req: &Request, env: &Env) -> Option<Result<Response>> {
+    let expected = match env.secret(ADMIN_TOKEN_SECRET) {
+        Ok(secret) => secret.to_string(),
+        Err(_) => {
+            console_error!("{} secret is not configured", ADMIN_TOKEN_SECRET);
+            return Some(error_response(403, "Admin API is not configured"));
+        }
+    };
+
+    let authorization = req.headers().get("authorization").ok().flatten();
+    match authorization.as_deref().and_then(bearer_token) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => None,
+        _ => Some(error_response(401, "Missing or invalid admin token")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc123"), Some("abc123"));
+        assert_eq!(bearer_token("bearer  abc123 "), Some("abc123"));
+        assert_eq!(bearer_token("Basic abc123"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("abc123"), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
@@ -1,8 +1,27 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
 use serde::{Deserialize, Serialize};
 use worker::*;
 
+use crate::analytics_engine::{DataPoint, DataPointWriter};
+use crate::analytics_sink::Sinks;
+use crate::build_info::BUILD_ID;
+use crate::tenant_usage;
+use crate::usage::{self, DailyUsage};
+
+/// Name of the Analytics Engine dataset binding configured in wrangler.toml
+pub const ANALYTICS_BINDING: &str = "OPENAI_PROXY_USAGE_ANALYTICS";
+
+/// Seconds a daily aggregate is kept after its last write
+const AGGREGATE_TTL_SECS: u64 = 100 * 86_400;
+/// Least milliseconds between two writes of an aggregate by one isolate; KV takes at most one
+/// write per second to a key
+const AGGREGATE_FLUSH_MS: f64 = 10_000.0;
+
 /// Analytics data structure for tracking OpenAI proxy usage
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsageAnalytics {
     /// Application identifier from request parameters
     pub app_id: String,
@@ -36,10 +55,147 @@ pub struct UsageAnalytics {
     pub total_tokens: u32,
     /// Timestamp of the usage event
     pub timestamp: f64,
+    /// Build identifier (version + git SHA) of the binary that produced the event, as sent in
+    /// the `X-LangProxy-Version` response header
+    #[serde(default)]
+    pub build: String,
+    /// Seconds of audio processed (transcription requests only)
+    #[serde(default)]
+    pub audio_seconds: f64,
+    /// Number of images generated (image generation requests only)
+    #[serde(default)]
+    pub image_count: u32,
+    /// Requested image width in pixels
+    #[serde(default)]
+    pub image_width: u32,
+    /// Requested image height in pixels
+    #[serde(default)]
+    pub image_height: u32,
+    /// Requested image quality (e.g. standard, hd)
+    #[serde(default)]
+    pub image_quality: Option<String>,
+    /// Number of inputs flagged by a moderation request
+    #[serde(default)]
+    pub moderation_flagged: u32,
+    /// Highest-scoring flagged moderation category
+    #[serde(default)]
+    pub moderation_top_category: Option<String>,
+    /// Request counts of a completed batch job
+    #[serde(default)]
+    pub batch_total: u32,
+    #[serde(default)]
+    pub batch_completed: u32,
+    #[serde(default)]
+    pub batch_failed: u32,
+    /// HTTP method of the upstream call
+    #[serde(default = "default_http_method")]
+    pub http_method: String,
+    /// Upstream host (or scheme) refused by the allowlist; set on rejection events only
+    #[serde(default)]
+    pub rejected_upstream: Option<String>,
+    /// Named upstream target the request was routed to
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Azure OpenAI api-version the upstream was called with
+    #[serde(default)]
+    pub api_version: Option<String>,
+    /// End-user identifier supplied by the calling app (`usrId`)
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Free-form dimensions from `meta.*` query parameters
+    #[serde(default)]
+    pub extra: BTreeMap<String, String>,
+    /// False when the client opted out of usage capture (`noUsage=1`), so tokens are unknown
+    #[serde(default = "default_usage_captured")]
+    pub usage_captured: bool,
+    /// Why the proxy refused the request (e.g. `body_too_large`); set on rejection events only
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Client-facing model name the request used, when `MODEL_MAP` rewrote it to `model`
+    #[serde(default)]
+    pub model_alias: Option<String>,
+    /// Completion cap the proxy applied to the request (`TENANT_LIMITS`/`MAX_TOKENS_CAP`)
+    #[serde(default)]
+    pub max_tokens_capped: Option<u32>,
+    /// Denied request fields removed before forwarding (`STRIP_FIELDS`/`FIELD_DENYLIST`)
+    #[serde(default)]
+    pub fields_stripped: u32,
+    /// Length in characters of the `SYSTEM_PROMPTS` prompt prepended to the conversation
+    #[serde(default)]
+    pub system_prompt_chars: Option<u32>,
+    /// Hash of the proxy key the request was made with (`REQUIRE_PROXY_KEY`); never the key
+    #[serde(default)]
+    pub proxy_key: Option<String>,
+    /// Upstream HTTP status the response was relayed with; 0 when not known
+    #[serde(default)]
+    pub status_code: u16,
+    /// Whether the client went away mid-stream; its tokens are those relayed until then
+    #[serde(default)]
+    pub client_disconnected: bool,
+    /// Milliseconds from dispatching the upstream request to the first streamed choice, or to
+    /// the end of the response when it isn't streamed
+    #[serde(default)]
+    pub ttft_ms: Option<u32>,
+    /// Milliseconds from the first to the last chunk relayed to the client
+    #[serde(default)]
+    pub duration_ms: u32,
+    /// Network chunks relayed to the client
+    #[serde(default)]
+    pub chunk_count: u32,
+    /// Bytes of response body relayed to the client
+    #[serde(default)]
+    pub response_bytes: u64,
+    /// Characters of completion content streamed, recorded alongside any usage
+    #[serde(default)]
+    pub completion_chars: u32,
+    /// Why the completion ended (`stop`, `length`, `content_filter`, `tool_calls`...)
+    #[serde(default)]
+    pub finish_reason: Option<String>,
+    /// Tool calls the completion made
+    #[serde(default)]
+    pub tool_call_count: u32,
+    /// Function names of the tool calls, in order and up to a bound
+    #[serde(default)]
+    pub tool_names: Vec<String>,
+    /// Tokens spent reasoning (`reasoning_tokens`, Gemini's `thoughtsTokenCount`), when reported
+    #[serde(default)]
+    pub reasoning_tokens: u32,
+    /// The upstream's provider (`openai`, `azure`, `anthropic`...), from its host or `provider=`
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Prompt tokens served from the provider's prompt cache (`cached_tokens`), when reported
+    #[serde(default)]
+    pub cached_tokens: u32,
+    /// Milliseconds the upstream reports spending on the invocation (Bedrock's
+    /// `invocationLatency`)
+    #[serde(default)]
+    pub invocation_latency_ms: Option<u32>,
+    /// Milliseconds the upstream reports until its first byte (Bedrock's `firstByteLatency`)
+    #[serde(default)]
+    pub first_byte_latency_ms: Option<u32>,
+    /// The backend configuration that served the completion (`system_fingerprint`), when reported
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+    /// The processing tier that served the completion (`service_tier`), when reported
+    #[serde(default)]
+    pub service_tier: Option<String>,
+    /// The upstream's own id of the request (`x-request-id` or `apim-request-id`), linking
+    /// `request_id` to the provider's logs
+    #[serde(default)]
+    pub upstream_request_id: Option<String>,
+}
+
+fn default_http_method() -> String {
+    "POST".to_string()
+}
+
+fn default_usage_captured() -> bool {
+    true
 }
 
 impl UsageAnalytics {
     /// Creates a new UsageAnalytics instance
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         app_id: String,
         tenant_id: Option<String>,
@@ -74,12 +230,54 @@ impl UsageAnalytics {
             completion_tokens,
             total_tokens,
             timestamp: Self::current_timestamp(),
+            build: BUILD_ID.to_string(),
+            audio_seconds: 0.0,
+            image_count: 0,
+            image_width: 0,
+            image_height: 0,
+            image_quality: None,
+            moderation_flagged: 0,
+            moderation_top_category: None,
+            batch_total: 0,
+            batch_completed: 0,
+            batch_failed: 0,
+            http_method: default_http_method(),
+            rejected_upstream: None,
+            target: None,
+            api_version: None,
+            user_id: None,
+            extra: BTreeMap::new(),
+            usage_captured: default_usage_captured(),
+            error: None,
+            model_alias: None,
+            max_tokens_capped: None,
+            fields_stripped: 0,
+            system_prompt_chars: None,
+            proxy_key: None,
+            status_code: 0,
+            client_disconnected: false,
+            ttft_ms: None,
+            duration_ms: 0,
+            chunk_count: 0,
+            response_bytes: 0,
+            completion_chars: 0,
+            finish_reason: None,
+            tool_call_count: 0,
+            tool_names: Vec::new(),
+            reasoning_tokens: 0,
+            cached_tokens: 0,
+            provider: None,
+            invocation_latency_ms: None,
+            first_byte_latency_ms: None,
+            system_fingerprint: None,
+            service_tier: None,
+            upstream_request_id: None,
         }
     }
 
     /// Creates a timestamp for the current time
     /// In WASM context uses Date::now(), for testing uses a fixed value
-    fn current_timestamp() -> f64 {
+    pub fn current_timestamp() -> f64 {
         #[cfg(target_arch = "wasm32")]
         {
             Date::now().as_millis() as f64
@@ -92,6 +290,7 @@ impl UsageAnalytics {
 
     #[cfg(test)]
     /// Creates a UsageAnalytics instance with a specific timestamp for testing
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_timestamp(
         app_id: String,
         tenant_id: Option<String>,
@@ -111,34 +310,48 @@ impl UsageAnalytics {
         timestamp: f64,
     ) -> Self {
         Self {
-            app_id,
-            tenant_id,
-            module_id,
-            session_id,
-            request_id,
-            env_id,
-            ip_address,
-            country,
-            cf_ray,
-            domain,
-            deployment,
-            model,
-            prompt_tokens,
-            completion_tokens,
-            total_tokens,
             timestamp,
+            ..Self::new(
+                app_id,
+                tenant_id,
+                module_id,
+                session_id,
+                request_id,
+                env_id,
+                ip_address,
+                country,
+                cf_ray,
+                domain,
+                deployment,
+                model,
+                prompt_tokens,
+                completion_tokens,
+                total_tokens,
+            )
         }
     }
 
+    /// Whether the event is usage the upstream served and reported: not a rejection or a failed
+    /// stream, nor one the client left, whose tokens are estimated
+    pub fn is_served_usage(&self) -> bool {
+        self.usage_captured
+            && !self.client_disconnected
+            && self.error.is_none()
+            && self.rejected_upstream.is_none()
+    }
+
     /// Saves the analytics data to CloudFlare Analytics Engine
     ///
     /// This method writes usage data to the OPENAI_PROXY_USAGE_ANALYTICS dataset
-    /// configured in wrangler.toml. If the write fails, it logs an error but
-    /// does not propagate the error to avoid failing the main request.
-    pub async fn save(&self, env: &Env) {
+    /// configured in wrangler.toml, to the usage queue when `USAGE_QUEUE` names one, and to
+    /// the `USAGE_DB` database when bound, and counts served usage in the tenant's `TenantUsage`
+    /// object when that is bound. If a write fails, it logs an error but does not propagate the error
+    /// to avoid failing the main request. Writes that aren't waited for are handed to `ctx`, the
+    /// fetch event's context, so they finish after the response is sent.
+    pub async fn save(&self, env: &Env, ctx: &Context) {
         // Log the analytics data for monitoring
         console_log!(
-            "Analytics Event: app={}, tenant={:?}, module={:?}, session={:?}, request={:?}, env={:?}, ip={:?}, country={:?}, cf_ray={:?}, domain={:?}, deployment={:?}, model={}, prompt_tokens={}, completion_tokens={}, total_tokens={}", 
+            "Analytics Event: app={}, tenant={:?}, module={:?}, session={:?}, request={:?}, env={:?}, ip={:?}, country={:?}, cf_ray={:?}, domain={:?}, deployment={:?}, model={}, prompt_tokens={}, completion_tokens={}, total_tokens={}, build={}, audio_seconds={}, image_count={}, image_size={}x{}, image_quality={:?}, moderation_flagged={}, moderation_top_category={:?}, batch_total={}, batch_completed={}, batch_failed={}, method={}, rejected_upstream={:?}, target={:?}, api_version={:?}, user={:?}, extra={:?}, usage_captured={}, error={:?}, model_alias={:?}, max_tokens_capped={:?}, fields_stripped={}, system_prompt_chars={:?}, proxy_key={:?}, status_code={}, client_disconnected={}, ttft_ms={:?}, duration_ms={}, chunk_count={}, response_bytes={}, completion_chars={}, finish_reason={:?}, tool_call_count={}, tool_names={:?}, reasoning_tokens={}, invocation_latency_ms={:?}, first_byte_latency_ms={:?}, cached_tokens={}, provider={:?}, system_fingerprint={:?}, service_tier={:?}, upstream_request_id={:?}", 
             self.app_id,
             self.tenant_id,
             self.module_id,
@@ -153,13 +366,96 @@ impl UsageAnalytics {
             self.model,
             self.prompt_tokens,
             self.completion_tokens,
-            self.total_tokens
+            self.total_tokens,
+            self.build,
+            self.audio_seconds,
+            self.image_count,
+            self.image_width,
+            self.image_height,
+            self.image_quality,
+            self.moderation_flagged,
+            self.moderation_top_category,
+            self.batch_total,
+            self.batch_completed,
+            self.batch_failed,
+            self.http_method,
+            self.rejected_upstream,
+            self.target,
+            self.api_version,
+            self.user_id,
+            self.extra,
+            self.usage_captured,
+            self.error,
+            self.model_alias,
+            self.max_tokens_capped,
+            self.fields_stripped,
+            self.system_prompt_chars,
+            self.proxy_key,
+            self.status_code,
+            self.client_disconnected,
+            self.ttft_ms,
+            self.duration_ms,
+            self.chunk_count,
+            self.response_bytes,
+            self.completion_chars,
+            self.finish_reason,
+            self.tool_call_count,
+            self.tool_names,
+            self.reasoning_tokens,
+            self.invocation_latency_ms,
+            self.first_byte_latency_ms,
+            self.cached_tokens,
+            self.provider,
+            self.system_fingerprint,
+            self.service_tier,
+            self.upstream_request_id
+        );
+
+        console_debug!("Analytics data point structure: {}", self.data_point());
+
+        Sinks::from_env(env).write(std::slice::from_ref(self), ctx).await;
+
+        // The daily totals are of served requests; rejections would count as requests
+        if self.is_served_usage() {
+            self.aggregate_to_kv(env).await;
+        }
+
+        // Counted in the tenant's `TenantUsage` object without holding up the caller, when it's
+        // usage the tenant is billed for
+        if self.is_served_usage() {
+            let (event, env) = (self.clone(), env.clone());
+            ctx.wait_until(async move {
+                if let Err(e) = tenant_usage::add(&env, &event).await {
+                    console_error!("Failed to count usage of tenant {:?}: {}", event.tenant_id, e);
+                }
+            });
+        }
+
+        console_debug!(
+            "Analytics processing completed for request: {:?}",
+            self.request_id
         );
+    }
 
+    /// Saves the event without holding up the caller; `ctx` keeps the request alive until it's
+    /// written
+    pub fn save_in_background(self, env: &Env, ctx: &Rc<Context>) {
+        let (env, event_ctx) = (env.clone(), ctx.clone());
+        ctx.wait_until(async move {
+            self.save(&env, &event_ctx).await;
+        });
+    }
+
+    /// The Analytics Engine data point of this event.
+    ///
+    /// Analytics Engine takes at most `MAX_BLOBS` blobs and `MAX_DOUBLES` doubles. Columns keep
+    /// their positions as fields are added, so the fields past those limits go in the `meta`
+    /// blob alongside the request's `meta.*` parameters.
+    fn data_point(&self) -> serde_json::Value {
         // Prepare data for Analytics Engine
         // CloudFlare Analytics Engine expects structured data with blobs, doubles, and indexes
         // Following the original JavaScript implementation order
-        let data_point = serde_json::json!({
+        serde_json::json!({
             "blobs": [
                 self.ip_address.as_deref().unwrap_or("unknown"),       // ipAddr
                 self.country.as_deref().unwrap_or("unknown"),          // country
@@ -172,48 +468,225 @@ impl UsageAnalytics {
                 self.request_id.as_deref().unwrap_or("unknown"),       // reqId
                 self.env_id.as_deref().unwrap_or("unknown"),           // envId
                 &self.model,                                           // model
+                &self.build,                                           // build
+                self.image_quality.as_deref().unwrap_or("unknown"),    // imageQuality
+                self.moderation_top_category.as_deref().unwrap_or("unknown"), // moderationTopCategory
+                &self.http_method,                                     // method
+                self.rejected_upstream.as_deref().unwrap_or("none"),   // rejectedUpstream
+                self.target.as_deref().unwrap_or("none"),              // target
+                self.api_version.as_deref().unwrap_or("none"),         // apiVersion
+                self.user_id.as_deref().unwrap_or("unknown"),          // usrId
+                self.meta_blob(),                                      // meta (JSON object)
             ],
             "doubles": [
                 self.prompt_tokens as f64,     // prompt_tokens
                 self.completion_tokens as f64, // completion_tokens
                 self.total_tokens as f64,      // total_tokens
                 1.0,                          // stream (1.0 for streaming requests)
+                self.audio_seconds,            // audio_seconds
+                self.image_count as f64,       // image_count
+                self.image_width as f64,       // image_width
+                self.image_height as f64,      // image_height
+                self.moderation_flagged as f64, // moderation_flagged
+                self.batch_total as f64,        // batch_total
+                self.batch_completed as f64,    // batch_completed
+                self.batch_failed as f64,       // batch_failed
+                if self.usage_captured { 1.0 } else { 0.0 }, // usage_captured
+                self.max_tokens_capped.unwrap_or(0) as f64, // max_tokens_capped (0 when not capped)
+                self.fields_stripped as f64,    // fields_stripped
+                self.system_prompt_chars.unwrap_or(0) as f64, // system_prompt_chars (0 when none injected)
+                self.status_code as f64,        // status_code (0 when not known)
+                if self.client_disconnected { 1.0 } else { 0.0 }, // client_disconnected
+                self.ttft_ms.unwrap_or(0) as f64, // ttft_ms (0 when not measured)
+                self.duration_ms as f64,        // duration_ms
             ],
             "indexes": [
                 format!("{}:{}", self.tenant_id.as_deref().unwrap_or("unknown"), &self.app_id)
             ]
-        });
+        })
+    }
+
+    /// The `meta` blob: the request's `meta.*` parameters, then the fields past Analytics
+    /// Engine's blob and double limits (those not set are left out)
+    fn meta_blob(&self) -> String {
+        let mut meta = serde_json::Map::new();
+        for (key, value) in &self.extra {
+            meta.insert(key.clone(), value.clone().into());
+        }
 
-        // Try different ways to access Analytics Engine based on worker crate version
-        // Method 1: Try env.analytics_engine() if available in newer versions
+        let strings = [
+            ("error", &self.error),
+            ("modelAlias", &self.model_alias),
+            ("proxyKey", &self.proxy_key),
+            ("finishReason", &self.finish_reason),
+            ("provider", &self.provider),
+            ("systemFingerprint", &self.system_fingerprint),
+            ("serviceTier", &self.service_tier),
+            ("upstreamReqId", &self.upstream_request_id),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                meta.insert(key.to_string(), value.clone().into());
+            }
+        }
+        if !self.tool_names.is_empty() {
+            meta.insert("toolNames".to_string(), self.tool_names.clone().into());
+        }
 
-        // Method 2: Try direct binding access (this may work in some versions)
-        if let Ok(binding) = env.var("OPENAI_PROXY_USAGE_ANALYTICS") {
-            console_debug!("Found analytics binding: {}", binding.to_string());
-            // TODO: When the correct Analytics Engine API is available, use:
-            // dataset.write_data_point(data_point).await
+        let counts: [(&str, Option<u64>); 8] = [
+            ("chunkCount", Some(self.chunk_count.into())),
+            ("responseBytes", Some(self.response_bytes)),
+            ("completionChars", Some(self.completion_chars.into())),
+            ("toolCallCount", Some(self.tool_call_count.into())),
+            ("reasoningTokens", Some(self.reasoning_tokens.into())),
+            ("invocationLatencyMs", self.invocation_latency_ms.map(u64::from)),
+            ("firstByteLatencyMs", self.first_byte_latency_ms.map(u64::from)),
+            ("cachedTokens", Some(self.cached_tokens.into())),
+        ];
+        for (key, value) in counts {
+            if let Some(value) = value {
+                meta.insert(key.to_string(), value.into());
+            }
         }
 
-        // Method 3: Log structured data for external processing/debugging
-        console_debug!("Analytics data point structure: {}", data_point.to_string());
+        serde_json::Value::Object(meta).to_string()
+    }
 
-        // Note: The actual Analytics Engine write call will be:
-        // if let Ok(dataset) = env.analytics_engine("OPENAI_PROXY_USAGE_ANALYTICS") {
-        //     if let Err(e) = dataset.write_data_point(data_point).await {
-        //         console_error!("Failed to write analytics data: {}", e);
-        //     }
-        // }
+    /// Writes this event's data point to `dataset`, its blobs and index truncated to fit
+    pub fn write_data_point(
+        &self,
+        dataset: &impl DataPointWriter,
+    ) -> std::result::Result<(), String> {
+        let data_point = serde_json::from_value::<DataPoint>(self.data_point())
+            .map_err(|e| e.to_string())?;
+        dataset.write_data_point(&data_point.truncated())
+    }
 
-        console_debug!(
-            "Analytics processing completed for request: {:?}",
-            self.request_id
-        );
+    /// Adds this event to its tenant's and app's daily aggregate, served by `GET /usage/:tenantId`
+    pub async fn aggregate_to_kv(&self, env: &Env) {
+        let now = Self::current_timestamp();
+        let due = AGGREGATOR.with(|aggregator| aggregator.borrow_mut().add(self, now));
+        if due.is_empty() {
+            return;
+        }
+
+        let kv = match env.kv(usage::USAGE_BINDING) {
+            Ok(kv) => kv,
+            Err(e) => {
+                console_error!("Failed to aggregate usage: {}", e);
+                return;
+            }
+        };
+        for (key, pending) in due {
+            if let Err(e) = flush_aggregate(&kv, &key, &pending).await {
+                console_error!("Failed to aggregate usage under {}: {}", key, e);
+                AGGREGATOR.with(|aggregator| aggregator.borrow_mut().restore(key, pending));
+            }
+        }
+    }
+}
+
+/// Adds `pending` to the aggregate stored under `key`, kept in the key's metadata as well so
+/// listing a tenant's keys reads their aggregates
+async fn flush_aggregate(kv: &kv::KvStore, key: &str, pending: &DailyUsage) -> Result<()> {
+    let stored = kv.get(key).json::<DailyUsage>().await?;
+    let usage = KvAggregator::merge(stored, pending);
+    kv.put(key, serde_json::to_string(&usage)?)?
+        .metadata(&usage)?
+        .expiration_ttl(AGGREGATE_TTL_SECS)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Accumulates usage events into daily per-tenant and per-app aggregates, flushed to KV under
+/// `agg:{tenant}:{app}:{date}`.
+///
+/// The aggregates are best-effort and may undercount, never overcount:
+/// - KV has no atomic increment, so a flush reads, merges and writes back; isolates flushing the
+///   same key at once overwrite one another's counts.
+/// - Events are accumulated per isolate and a key is flushed at most every
+///   `AGGREGATE_FLUSH_MS`, with the next event of any key; that keeps to KV's write rate and
+///   makes those races rare, but what an isolate holds when it's evicted is lost.
+#[derive(Debug, Default)]
+pub struct KvAggregator {
+    /// Events not yet written, by key
+    pending: BTreeMap<String, DailyUsage>,
+    /// When each key was last flushed, for keys flushed within `AGGREGATE_FLUSH_MS`
+    flushed_at: BTreeMap<String, f64>,
+}
+
+thread_local! {
+    // Per isolate, like the other in-memory state; workers run single-threaded
+    static AGGREGATOR: RefCell<KvAggregator> = RefCell::new(KvAggregator::default());
+}
+
+impl KvAggregator {
+    /// Adds `event` to its pending aggregate, taking every aggregate due for a flush at `now`;
+    /// events without a tenant aren't aggregated
+    fn add(&mut self, event: &UsageAnalytics, now: f64) -> Vec<(String, DailyUsage)> {
+        if let Some(tenant) = event.tenant_id.as_deref() {
+            let date = usage::format_date(usage::days_from_millis(event.timestamp));
+            let key = usage::aggregate_key(tenant, &event.app_id, &date);
+            let pending = self.pending.entry(key).or_insert_with(|| DailyUsage {
+                date,
+                ..DailyUsage::default()
+            });
+            pending.record(
+                event.prompt_tokens,
+                event.completion_tokens,
+                event.total_tokens,
+            );
+        }
+
+        self.flushed_at
+            .retain(|_, flushed_at| now - *flushed_at < AGGREGATE_FLUSH_MS);
+        let due = self
+            .pending
+            .keys()
+            .filter(|key| !self.flushed_at.contains_key(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        due.into_iter()
+            .filter_map(|key| {
+                let pending = self.pending.remove(&key)?;
+                self.flushed_at.insert(key.clone(), now);
+                Some((key, pending))
+            })
+            .collect()
+    }
+
+    /// Puts back an aggregate whose flush failed, for the next flush of its key
+    fn restore(&mut self, key: String, usage: DailyUsage) {
+        let pending = self.pending.remove(&key);
+        let usage = match pending {
+            Some(pending) => Self::merge(Some(usage), &pending),
+            None => usage,
+        };
+        self.pending.insert(key, usage);
+    }
+
+    /// The aggregate `stored` (none before a key's first flush) with `pending` added
+    pub fn merge(stored: Option<DailyUsage>, pending: &DailyUsage) -> DailyUsage {
+        let stored = stored.unwrap_or_default();
+        DailyUsage {
+            date: pending.date.clone(),
+            prompt_tokens: stored.prompt_tokens.saturating_add(pending.prompt_tokens),
+            completion_tokens: stored
+                .completion_tokens
+                .saturating_add(pending.completion_tokens),
+            total_tokens: stored.total_tokens.saturating_add(pending.total_tokens),
+            requests: stored.requests.saturating_add(pending.requests),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::analytics_engine::{
+        MockDataset, MAX_BLOBS, MAX_BLOBS_BYTES, MAX_DOUBLES, MAX_INDEX_BYTES,
+    };
 
     #[test]
     fn test_usage_analytics_creation() {
@@ -252,6 +725,7 @@ mod tests {
         assert_eq!(analytics.completion_tokens, 50);
         assert_eq!(analytics.total_tokens, 150);
         assert_eq!(analytics.timestamp, 1640995200000.0);
+        assert_eq!(analytics.build, BUILD_ID);
     }
 
     #[test]
@@ -394,6 +868,280 @@ mod tests {
         assert_eq!(analytics.completion_tokens, 50);
         assert_eq!(analytics.total_tokens, 150);
         assert_eq!(analytics.timestamp, 1640995200000.0);
+        // Records written before the build field existed still deserialize
+        assert_eq!(analytics.build, "");
+        assert!(analytics.usage_captured);
+        assert_eq!(analytics.status_code, 0);
+        assert!(!analytics.client_disconnected);
+        assert_eq!(analytics.ttft_ms, None);
+        assert_eq!(
+            (analytics.duration_ms, analytics.chunk_count, analytics.response_bytes),
+            (0, 0, 0)
+        );
+        assert_eq!(analytics.completion_chars, 0);
+        assert_eq!(analytics.finish_reason, None);
+        assert_eq!(analytics.tool_call_count, 0);
+        assert!(analytics.tool_names.is_empty());
+        assert_eq!(analytics.reasoning_tokens, 0);
+        assert_eq!(analytics.invocation_latency_ms, None);
+        assert_eq!(analytics.first_byte_latency_ms, None);
+        assert_eq!(analytics.cached_tokens, 0);
+        assert_eq!(analytics.provider, None);
+        assert_eq!(analytics.system_fingerprint, None);
+        assert_eq!(analytics.service_tier, None);
+        assert_eq!(analytics.upstream_request_id, None);
+    }
+
+    #[test]
+    fn test_served_usage() {
+        let served = UsageAnalytics::new_with_timestamp(
+            "app123".to_string(),
+            Some("tenant123".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "gpt-4o".to_string(),
+            10,
+            5,
+            15,
+            1640995200000.0,
+        );
+        assert!(served.is_served_usage());
+
+        let rejected = UsageAnalytics {
+            error: Some("ip_not_allowed".to_string()),
+            ..served.clone()
+        };
+        assert!(!rejected.is_served_usage());
+        let disallowed = UsageAnalytics {
+            rejected_upstream: Some("evil.example.com".to_string()),
+            ..served.clone()
+        };
+        assert!(!disallowed.is_served_usage());
+        // A stream the client left is counted from its chunks, not reported
+        let disconnected = UsageAnalytics {
+            client_disconnected: true,
+            ..served.clone()
+        };
+        assert!(!disconnected.is_served_usage());
+        let uncaptured = UsageAnalytics {
+            usage_captured: false,
+            ..served
+        };
+        assert!(!uncaptured.is_served_usage());
+    }
+
+    #[test]
+    fn test_data_point_positions() {
+        let mut analytics = UsageAnalytics::new_with_timestamp(
+            "app123".to_string(),
+            Some("tenant123".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "o3-mini".to_string(),
+            1200,
+            900,
+            2100,
+            1640995200000.0,
+        );
+        analytics.reasoning_tokens = 640;
+        analytics.cached_tokens = 1024;
+
+        let data_point = analytics.data_point();
+        let doubles = data_point["doubles"].as_array().unwrap();
+        assert_eq!(doubles.len(), MAX_DOUBLES);
+        // The original columns never move
+        assert_eq!(doubles[..4], [1200.0, 900.0, 2100.0, 1.0]);
+        assert_eq!(data_point["indexes"][0], "tenant123:app123");
+
+        let blobs = data_point["blobs"].as_array().unwrap();
+        assert_eq!(blobs.len(), MAX_BLOBS);
+        assert_eq!(blobs[10], "o3-mini");
+        assert_eq!(blobs[12..14], ["unknown", "unknown"]);
+        let meta = |data_point: &serde_json::Value| {
+            serde_json::from_str::<serde_json::Value>(data_point["blobs"][19].as_str().unwrap())
+                .unwrap()
+        };
+        assert_eq!(
+            meta(&data_point),
+            serde_json::json!({
+                "chunkCount": 0,
+                "responseBytes": 0,
+                "completionChars": 0,
+                "toolCallCount": 0,
+                "reasoningTokens": 640,
+                "cachedTokens": 1024,
+            })
+        );
+
+        analytics.image_quality = Some("hd".to_string());
+        analytics.moderation_top_category = Some("violence".to_string());
+        analytics.chunk_count = 42;
+        analytics.first_byte_latency_ms = Some(180);
+        analytics.tool_call_count = 2;
+        analytics.tool_names = vec!["search_fares".to_string(), "book".to_string()];
+        analytics.provider = Some("azure".to_string());
+        analytics.system_fingerprint = Some("fp_06737a9306".to_string());
+        analytics.service_tier = Some("default".to_string());
+        analytics.extra.insert("channel".to_string(), "web".to_string());
+        let data_point = analytics.data_point();
+        assert_eq!(data_point["blobs"][12], "hd");
+        assert_eq!(data_point["blobs"][13], "violence");
+        // Fields past the limits are in `meta`, with the request's own parameters
+        assert_eq!(
+            meta(&data_point),
+            serde_json::json!({
+                "channel": "web",
+                "provider": "azure",
+                "systemFingerprint": "fp_06737a9306",
+                "serviceTier": "default",
+                "toolNames": ["search_fares", "book"],
+                "chunkCount": 42,
+                "responseBytes": 0,
+                "completionChars": 0,
+                "toolCallCount": 2,
+                "reasoningTokens": 640,
+                "firstByteLatencyMs": 180,
+                "cachedTokens": 1024,
+            })
+        );
+    }
+
+    #[test]
+    fn test_aggregate_merge() {
+        let pending = DailyUsage {
+            date: "2022-01-01".to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 50,
+            total_tokens: 150,
+            requests: 2,
+        };
+        // The first flush of a key stores the pending aggregate as is
+        assert_eq!(KvAggregator::merge(None, &pending), pending);
+
+        let stored = DailyUsage {
+            date: "2022-01-01".to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            total_tokens: u64::MAX - 1,
+            requests: 1,
+        };
+        let merged = KvAggregator::merge(Some(stored), &pending);
+        assert_eq!(
+            (merged.prompt_tokens, merged.completion_tokens, merged.requests),
+            (110, 55, 3)
+        );
+        assert_eq!(merged.total_tokens, u64::MAX);
+        assert_eq!(merged.date, "2022-01-01");
+    }
+
+    #[test]
+    fn test_aggregator_flushes() {
+        let event = |app: &str, tenant: Option<&str>| {
+            UsageAnalytics::new_with_timestamp(
+                app.to_string(),
+                tenant.map(str::to_string),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                "gpt-4o".to_string(),
+                10,
+                5,
+                15,
+                1640995200000.0,
+            )
+        };
+        let mut aggregator = KvAggregator::default();
+        let now = 1_000_000.0;
+
+        // A key's first event is flushed at once
+        let due = aggregator.add(&event("fares", Some("acme")), now);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, "agg:acme:fares:2022-01-01");
+        assert_eq!((due[0].1.total_tokens, due[0].1.requests), (15, 1));
+        assert!(aggregator.add(&event("fares", None), now).is_empty());
+
+        // Then held until `AGGREGATE_FLUSH_MS` passed...
+        assert!(aggregator.add(&event("fares", Some("acme")), now + 1.0).is_empty());
+        assert!(aggregator.add(&event("fares", Some("acme")), now + 2.0).is_empty());
+        // ...while other keys go on their own schedule
+        let due = aggregator.add(&event("search", Some("acme")), now + 3.0);
+        assert_eq!(due[0].0, "agg:acme:search:2022-01-01");
+
+        // Any event flushes what's due, and a failed flush is put back for the next one
+        let due = aggregator.add(&event("search", None), now + AGGREGATE_FLUSH_MS);
+        assert_eq!(due.len(), 1);
+        let (key, usage) = due.into_iter().next().unwrap();
+        assert_eq!((usage.total_tokens, usage.requests), (30, 2));
+        aggregator.restore(key, usage);
+        aggregator.add(&event("fares", Some("acme")), now + AGGREGATE_FLUSH_MS + 1.0);
+        let due = aggregator.add(&event("fares", None), now + 2.0 * AGGREGATE_FLUSH_MS + 1.0);
+        assert_eq!(due.len(), 1);
+        assert_eq!((due[0].1.total_tokens, due[0].1.requests), (45, 3));
+    }
+
+    #[test]
+    fn test_write_data_point() {
+        let tenant = "t".repeat(120);
+        let mut analytics = UsageAnalytics::new_with_timestamp(
+            "app123".to_string(),
+            Some(tenant.clone()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "gpt-4o".to_string(),
+            10,
+            5,
+            15,
+            1640995200000.0,
+        );
+        analytics.error = Some("e".repeat(MAX_BLOBS_BYTES));
+
+        let dataset = MockDataset::default();
+        analytics.write_data_point(&dataset).unwrap();
+        let points = dataset.points.borrow();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].blobs.len(), MAX_BLOBS);
+        assert_eq!(points[0].blobs[10], "gpt-4o");
+        // The error, in `meta`, gets what's left of the budget after the other blobs
+        let blobs_bytes = points[0].blobs.iter().map(String::len).sum::<usize>();
+        assert_eq!(blobs_bytes, MAX_BLOBS_BYTES);
+        assert_eq!(points[0].blobs[17..19], ["none", "unknown"]);
+        assert!(points[0].blobs[19].starts_with(r#"{"error":"eee"#));
+        assert_eq!(points[0].doubles[..3], [10.0, 5.0, 15.0]);
+        assert_eq!(points[0].indexes, [&tenant[..MAX_INDEX_BYTES]]);
+
+        // Failed writes are handed back for `save` to log
+        let dataset = MockDataset {
+            fail: true,
+            ..MockDataset::default()
+        };
+        assert!(analytics.write_data_point(&dataset).is_err());
     }
 
     #[test]
@@ -437,6 +1185,7 @@ mod tests {
         assert_eq!(analytics.completion_tokens, deserialized.completion_tokens);
         assert_eq!(analytics.total_tokens, deserialized.total_tokens);
         assert_eq!(analytics.timestamp, deserialized.timestamp);
+        assert_eq!(analytics.build, deserialized.build);
     }
 
     #[test]
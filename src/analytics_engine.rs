@@ -0,0 +1,181 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde::Deserialize;
+use worker::wasm_bindgen::{self, prelude::*, JsCast};
+use worker::{js_sys, EnvBinding};
+
+/// Blobs Analytics Engine accepts in a data point
+pub const MAX_BLOBS: usize = 20;
+/// Doubles Analytics Engine accepts in a data point
+pub const MAX_DOUBLES: usize = 20;
+/// Bytes Analytics Engine accepts in the blobs of a data point together; more fail the whole
+/// write
+pub const MAX_BLOBS_BYTES: usize = 16 * 1024;
+/// Bytes Analytics Engine accepts in an index
+pub const MAX_INDEX_BYTES: usize = 96;
+
+/// A data point as `writeDataPoint` takes it
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DataPoint {
+    pub blobs: Vec<String>,
+    pub doubles: Vec<f64>,
+    pub indexes: Vec<String>,
+}
+
+impl DataPoint {
+    /// The point with its blobs, doubles and indexes cut to what Analytics Engine accepts
+    pub fn truncated(mut self) -> Self {
+        self.blobs.truncate(MAX_BLOBS);
+        self.doubles.truncate(MAX_DOUBLES);
+        fit_blobs(&mut self.blobs);
+        for index in &mut self.indexes {
+            truncate(index, MAX_INDEX_BYTES);
+        }
+        self
+    }
+
+    /// The point as the `{ blobs, doubles, indexes }` object the binding takes
+    fn to_js(&self) -> Result<JsValue, JsValue> {
+        let strings = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| JsValue::from_str(value))
+                .collect::<js_sys::Array>()
+        };
+        let doubles = self.doubles.iter().map(|&value| JsValue::from_f64(value));
+
+        let point = js_sys::Object::new();
+        js_sys::Reflect::set(&point, &"blobs".into(), &strings(&self.blobs))?;
+        js_sys::Reflect::set(
+            &point,
+            &"doubles".into(),
+            &doubles.collect::<js_sys::Array>(),
+        )?;
+        js_sys::Reflect::set(&point, &"indexes".into(), &strings(&self.indexes))?;
+        Ok(point.into())
+    }
+}
+
+/// Cuts `text` to at most `max_bytes`, at a character boundary
+fn truncate(text: &mut String, max_bytes: usize) {
+    if text.len() > max_bytes {
+        let end = (0..=max_bytes)
+            .rev()
+            .find(|&end| text.is_char_boundary(end));
+        text.truncate(end.unwrap_or(0));
+    }
+}
+
+/// Cuts the longest blobs until all of them fit in `MAX_BLOBS_BYTES`, so the short ones (ids,
+/// names) stay whole
+fn fit_blobs(blobs: &mut [String]) {
+    loop {
+        let total = blobs.iter().map(String::len).sum::<usize>();
+        if total <= MAX_BLOBS_BYTES {
+            return;
+        }
+        let Some(longest) = blobs.iter_mut().max_by_key(|blob| blob.len()) else {
+            return;
+        };
+        let keep = longest.len().saturating_sub(total - MAX_BLOBS_BYTES);
+        truncate(longest, keep);
+    }
+}
+
+/// Where data points are written: the dataset binding, or `MockDataset` in tests
+pub trait DataPointWriter {
+    fn write_data_point(&self, point: &DataPoint) -> Result<(), String>;
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// An Analytics Engine dataset binding; the worker crate has no wrapper for it
+    #[derive(Clone)]
+    pub type AnalyticsEngineDataset;
+
+    #[wasm_bindgen(method, catch, js_name = writeDataPoint)]
+    fn write_js_data_point(this: &AnalyticsEngineDataset, point: &JsValue) -> Result<(), JsValue>;
+}
+
+impl EnvBinding for AnalyticsEngineDataset {
+    const TYPE_NAME: &'static str = "AnalyticsEngineDataset";
+
+    // The runtime's class name for the binding isn't part of its API, so it isn't checked
+    fn get(val: JsValue) -> worker::Result<Self> {
+        Ok(val.unchecked_into())
+    }
+}
+
+impl DataPointWriter for AnalyticsEngineDataset {
+    fn write_data_point(&self, point: &DataPoint) -> Result<(), String> {
+        let point = point.to_js().map_err(|e| format!("{e:?}"))?;
+        self.write_js_data_point(&point)
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// Keeps the data points written to it, or fails every write; `analytics-mock` builds it for
+/// harnesses outside the unit tests
+#[cfg(any(test, feature = "analytics-mock"))]
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, Default)]
+pub struct MockDataset {
+    pub points: std::cell::RefCell<Vec<DataPoint>>,
+    pub fail: bool,
+}
+
+#[cfg(any(test, feature = "analytics-mock"))]
+impl DataPointWriter for MockDataset {
+    fn write_data_point(&self, point: &DataPoint) -> Result<(), String> {
+        if self.fail {
+            return Err("writeDataPoint failed".to_string());
+        }
+        self.points.borrow_mut().push(point.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncated() {
+        let point = DataPoint {
+            blobs: vec!["a".repeat(MAX_BLOBS_BYTES + 1), "ok".to_string()],
+            doubles: vec![1.0],
+            indexes: vec![format!("{}é", "t".repeat(MAX_INDEX_BYTES - 1))],
+        };
+        let point = point.truncated();
+        // The budget is shared: the long blob makes room for the short one
+        assert_eq!(
+            point.blobs,
+            ["a".repeat(MAX_BLOBS_BYTES - 2), "ok".to_string()]
+        );
+        // Cut before the character that would cross the limit
+        assert_eq!(point.indexes, ["t".repeat(MAX_INDEX_BYTES - 1)]);
+        assert_eq!(point.doubles, [1.0]);
+    }
+
+    #[test]
+    fn test_truncated_to_limits() {
+        let half = MAX_BLOBS_BYTES / 2;
+        let blobs = (0..MAX_BLOBS + 2).map(|i| match i {
+            0 | 1 => "x".repeat(half),
+            _ => "id".to_string(),
+        });
+        let point = DataPoint {
+            blobs: blobs.collect(),
+            doubles: vec![0.0; MAX_DOUBLES + 1],
+            indexes: Vec::new(),
+        };
+        let point = point.truncated();
+        assert_eq!(point.blobs.len(), MAX_BLOBS);
+        assert_eq!(point.doubles.len(), MAX_DOUBLES);
+        let total = point.blobs.iter().map(String::len).sum::<usize>();
+        assert_eq!(total, MAX_BLOBS_BYTES);
+        assert_eq!(point.blobs[0].len() + point.blobs[1].len(), 2 * half - 36);
+        assert!(point.blobs[2..].iter().all(|blob| blob == "id"));
+    }
+}
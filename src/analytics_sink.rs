@@ -0,0 +1,233 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use worker::*;
+
+use crate::analytics::{UsageAnalytics, ANALYTICS_BINDING};
+use crate::analytics_engine::AnalyticsEngineDataset;
+use crate::redact::LogBodies;
+use crate::usage_archive::R2Sink;
+use crate::usage_db::D1Sink;
+use crate::webhook::WebhookSink;
+
+/// Names the Queue binding usage events are sent to; unset sends them to none
+pub const USAGE_QUEUE_VAR: &str = "USAGE_QUEUE";
+/// `also` (the default) or `only`: whether events sent to the queue still go to Analytics Engine
+pub const USAGE_QUEUE_MODE_VAR: &str = "USAGE_QUEUE_MODE";
+
+/// A destination for usage events
+pub trait AnalyticsSink {
+    /// Names the sink in logs
+    fn name(&self) -> &'static str;
+
+    /// Writes the events of one request, at once where the sink can
+    async fn write(&self, events: &[UsageAnalytics]) -> std::result::Result<(), String>;
+}
+
+impl AnalyticsSink for AnalyticsEngineDataset {
+    fn name(&self) -> &'static str {
+        "Analytics Engine"
+    }
+
+    async fn write(&self, events: &[UsageAnalytics]) -> std::result::Result<(), String> {
+        events
+            .iter()
+            .try_for_each(|event| event.write_data_point(self))
+    }
+}
+
+/// Sends every event as a JSON message of its own, unsampled, to a queue feeding the billing
+/// pipeline
+pub struct QueueSink(Queue);
+
+impl AnalyticsSink for QueueSink {
+    fn name(&self) -> &'static str {
+        "the usage queue"
+    }
+
+    async fn write(&self, events: &[UsageAnalytics]) -> std::result::Result<(), String> {
+        let sent = match events {
+            [] => return Ok(()),
+            [event] => self.0.send(event).await,
+            events => self.0.send_batch(events.iter()).await,
+        };
+        sent.map_err(|e| e.to_string())
+    }
+}
+
+/// Whether events sent to the queue are written to Analytics Engine as well
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum QueueMode {
+    #[default]
+    Also,
+    Only,
+}
+
+impl QueueMode {
+    fn parse(value: Option<&str>) -> Self {
+        match value
+            .map(|value| value.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("only") => QueueMode::Only,
+            _ => QueueMode::Also,
+        }
+    }
+}
+
+/// The sinks `env` configures usage events to go to
+pub struct Sinks {
+    engine: Option<AnalyticsEngineDataset>,
+    queue: Option<QueueSink>,
+    database: Option<D1Sink>,
+    webhook: Option<WebhookSink>,
+    archive: Option<R2Sink>,
+}
+
+impl Sinks {
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str| env.var(name).ok().map(|var| var.to_string());
+        let queue = var(USAGE_QUEUE_VAR).and_then(|binding| match env.queue(&binding) {
+            Ok(queue) => Some(QueueSink(queue)),
+            Err(e) => {
+                console_error!("Usage queue `{}` unavailable: {}", binding, e);
+                None
+            }
+        });
+
+        let mode = QueueMode::parse(var(USAGE_QUEUE_MODE_VAR).as_deref());
+        let engine = if queue.is_some() && mode == QueueMode::Only {
+            None
+        } else {
+            match env.get_binding::<AnalyticsEngineDataset>(ANALYTICS_BINDING) {
+                Ok(dataset) => Some(dataset),
+                Err(e) => {
+                    console_error!("Failed to write analytics data: {}", e);
+                    None
+                }
+            }
+        };
+        Sinks {
+            engine,
+            queue,
+            database: D1Sink::from_env(env),
+            webhook: WebhookSink::from_env(env),
+            archive: R2Sink::from_env(env),
+        }
+    }
+
+    /// Writes the events of one request to every sink, logging what a sink failed to take.
+    ///
+    /// Writes that aren't waited for go to `ctx`, the fetch event's context, which keeps the
+    /// request alive until they're done.
+    pub async fn write(self, events: &[UsageAnalytics], ctx: &Context) {
+        if let Some(engine) = &self.engine {
+            write_logged(engine, events).await;
+        }
+        if let Some(queue) = &self.queue {
+            write_logged(queue, events).await;
+        }
+        if let Some(database) = self.database {
+            // Inserts aren't waited for, wherever the event is saved from
+            let events = events.to_vec();
+            ctx.wait_until(async move {
+                write_logged(&database, &events).await;
+            });
+        }
+        if let Some(webhook) = self.webhook {
+            // Nor are deliveries, so a slow collector never holds up the response
+            let events = events.to_vec();
+            ctx.wait_until(async move {
+                write_logged(&webhook, &events).await;
+            });
+        }
+        if let Some(archive) = self.archive {
+            // The request's events make one object per tenant and hour, written in the background
+            let events = events.to_vec();
+            ctx.wait_until(async move {
+                write_logged(&archive, &events).await;
+            });
+        }
+    }
+}
+
+async fn write_logged(sink: &impl AnalyticsSink, events: &[UsageAnalytics]) {
+    if let Err(error) = sink.write(events).await {
+        console_error!("{}", failure_message(sink.name(), events, &error));
+    }
+}
+
+/// The log line of a failed write, with the events it lost redacted like logged bodies
+fn failure_message(sink: &str, events: &[UsageAnalytics], error: &str) -> String {
+    let payload = events
+        .iter()
+        .map(|event| {
+            let json = serde_json::to_string(event).unwrap_or_default();
+            LogBodies::Redacted
+                .body(&json)
+                .unwrap_or_default()
+                .into_owned()
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "Failed to write {} usage event(s) to {sink}: {error}\n<!--\n{payload}\n-->",
+        events.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_mode() {
+        assert_eq!(QueueMode::parse(None), QueueMode::Also);
+        assert_eq!(QueueMode::parse(Some(" Only ")), QueueMode::Only);
+        assert_eq!(QueueMode::parse(Some("instead")), QueueMode::Also);
+    }
+
+    #[test]
+    fn test_failure_message() {
+        let mut event = UsageAnalytics::new_with_timestamp(
+            "app123".to_string(),
+            Some("tenant123".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "gpt-4o".to_string(),
+            10,
+            5,
+            15,
+            1640995200000.0,
+        );
+        event.error = Some("upstream timed out".to_string());
+        event
+            .extra
+            .insert("prompt".to_string(), "Fares from MIA".to_string());
+
+        let message = failure_message("the usage queue", &[event.clone(), event], "Queue full");
+        let (head, payload) = message.split_once("\n<!--\n").unwrap();
+        assert_eq!(
+            head,
+            "Failed to write 2 usage event(s) to the usage queue: Queue full"
+        );
+
+        // One JSON event per line, nothing lost but prompt text
+        let lines = payload.strip_suffix("\n-->").unwrap().lines();
+        let events = lines
+            .map(|line| serde_json::from_str::<UsageAnalytics>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].tenant_id.as_deref(), Some("tenant123"));
+        assert_eq!(events[0].total_tokens, 15);
+        assert_eq!(events[0].error.as_deref(), Some("upstream timed out"));
+        assert_eq!(events[0].extra["prompt"], "[14 chars]");
+    }
+}
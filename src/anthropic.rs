@@ -0,0 +1,424 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use futures_util::StreamExt;
+use serde::Deserialize;
+use worker::*;
+
+use crate::usage_extractor::{Extracted, ExtractedUsage, UsageExtractor};
+use crate::{
+    cors, forward_upstream, guard_request, keep_alive, provider::Provider, proxy_response_headers,
+    query_error_response, redact, reject_disallowed_upstream, sse, targets::AuthError, trim_body,
+    upstream, upstream_auth_headers, upstream_error_headers, AzureReqBodyStream, BodyError,
+    ProxyUrlParams, RequestMeta,
+};
+
+/// Header carrying the caller's Anthropic API key
+const API_KEY_HEADER: &str = "x-api-key";
+/// Header selecting the Anthropic API version
+const VERSION_HEADER: &str = "anthropic-version";
+/// Version sent upstream when the caller doesn't specify one
+const DEFAULT_VERSION: &str = "2023-06-01";
+
+#[derive(Debug, Default, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicMessage {
+    model: String,
+    #[serde(default)]
+    usage: AnthropicUsage,
+    /// `end_turn`, `max_tokens`, `stop_sequence`, `tool_use`...; `null` until the message ends
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AnthropicDelta {
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+/// The subset of Anthropic stream events that carry usage information
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicEvent {
+    MessageStart {
+        message: AnthropicMessage,
+    },
+    MessageDelta {
+        #[serde(default)]
+        delta: AnthropicDelta,
+        #[serde(default)]
+        usage: AnthropicUsage,
+    },
+    MessageStop,
+    #[serde(other)]
+    Other,
+}
+
+/// Token usage mapped onto the OpenAI-style columns used by `UsageAnalytics`
+#[derive(Debug, PartialEq)]
+pub struct MessageUsage {
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    /// The message's `stop_reason`, recorded as the `finish_reason`
+    pub finish_reason: Option<String>,
+}
+
+impl MessageUsage {
+    fn new(
+        model: String,
+        input_tokens: u32,
+        output_tokens: u32,
+        stop_reason: Option<String>,
+    ) -> Self {
+        Self {
+            model,
+            prompt_tokens: input_tokens,
+            completion_tokens: output_tokens,
+            total_tokens: input_tokens.saturating_add(output_tokens),
+            finish_reason: stop_reason,
+        }
+    }
+}
+
+/// Incrementally scans an Anthropic SSE stream for usage.
+///
+/// `message_start` carries the model and input tokens, `message_delta` the
+/// (cumulative) output tokens and the stop reason; the usage is reported once
+/// `message_stop` arrives.
+#[derive(Debug, Default)]
+pub struct AnthropicUsageScanner {
+    parser: sse::SseParser,
+    model: Option<String>,
+    input_tokens: u32,
+    output_tokens: u32,
+    stop_reason: Option<String>,
+    done: bool,
+}
+
+impl AnthropicUsageScanner {
+    /// Feeds a network chunk, returning the usage when the final event is seen
+    pub fn push(&mut self, chunk: &[u8]) -> Option<MessageUsage> {
+        let mut usage = None;
+        for event in self.parser.push(chunk) {
+            if let Some(found) = self.process_event(&event.data) {
+                usage = Some(found);
+            }
+        }
+        usage
+    }
+
+    /// Feeds the data of a whole event, returning the usage on the final one
+    pub fn process_event(&mut self, data: &str) -> Option<MessageUsage> {
+        match serde_json::from_str::<AnthropicEvent>(data) {
+            Ok(AnthropicEvent::MessageStart { message }) => {
+                self.model = Some(message.model);
+                self.input_tokens = message.usage.input_tokens;
+                self.output_tokens = message.usage.output_tokens;
+                None
+            }
+            Ok(AnthropicEvent::MessageDelta { delta, usage }) => {
+                self.output_tokens = usage.output_tokens;
+                if delta.stop_reason.is_some() {
+                    self.stop_reason = delta.stop_reason;
+                }
+                None
+            }
+            Ok(AnthropicEvent::MessageStop) if !self.done => {
+                self.done = true;
+                Some(MessageUsage::new(
+                    self.model.take().unwrap_or_default(),
+                    self.input_tokens,
+                    self.output_tokens,
+                    self.stop_reason.take(),
+                ))
+            }
+            Ok(_) => None,
+            Err(e) => {
+                console_debug!("Skipping unparseable Anthropic event: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl From<MessageUsage> for ExtractedUsage {
+    fn from(usage: MessageUsage) -> Self {
+        ExtractedUsage {
+            model: Some(usage.model),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            finish_reason: usage.finish_reason,
+            ..ExtractedUsage::default()
+        }
+    }
+}
+
+impl UsageExtractor for AnthropicUsageScanner {
+    fn feed_event(&mut self, event: &sse::SseEvent) -> Option<Extracted> {
+        self.process_event(&event.data)
+            .map(|usage| Ok(usage.into()))
+    }
+}
+
+/// Parses the usage of a non-streaming Messages API response
+fn parse_message_usage(body: &[u8]) -> serde_json::Result<MessageUsage> {
+    let message = serde_json::from_slice::<AnthropicMessage>(body)?;
+    Ok(MessageUsage::new(
+        message.model,
+        message.usage.input_tokens,
+        message.usage.output_tokens,
+        message.stop_reason,
+    ))
+}
+
+/// Proxies a request to the Anthropic Messages API and records its usage
+pub async fn anthropic_proxy(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let data = req.bytes().await?;
+    let env = ctx.env.clone();
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    // Extract metadata for analytics
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    // Anthropic streams usage natively, so the body is forwarded untouched
+    let is_stream = match serde_json::from_slice::<AzureReqBodyStream>(trim_body(&data)) {
+        Ok(stream_params) => stream_params.stream,
+        Err(e) => {
+            console_error!("JSON Error: {}", e.to_string());
+            return BodyError::InvalidJson(e.to_string()).to_response();
+        }
+    };
+
+    let provider = Provider::Anthropic;
+    let mut proxy_headers = match upstream_auth_headers(&req, &ctx.env, &meta, provider) {
+        Ok(headers) => headers,
+        Err(AuthError::MissingCredential) => {
+            console_error!("Request Error: Missing {API_KEY_HEADER} header");
+            return Response::error("Missing x-api-key header", 401);
+        }
+        Err(e) => return e.to_response(),
+    };
+    let version = req
+        .headers()
+        .get(VERSION_HEADER)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| DEFAULT_VERSION.to_string());
+
+    proxy_headers.set(VERSION_HEADER, &version)?;
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    if !response.status().is_success() {
+        console_error!("Error {}", response.status());
+        let status = response.status();
+        let error_headers = upstream_error_headers(&response, origin.as_deref())?;
+        let text = &response.text().await;
+        let error = Response::error(format!("{:?}", &text), status.into())?;
+        return Ok(error.with_headers(error_headers));
+    }
+
+    let build_analytics = move |usage: MessageUsage| {
+        let mut analytics = meta.usage_analytics(
+            usage.model,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens,
+        );
+        analytics.finish_reason = usage.finish_reason;
+        analytics
+    };
+
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+
+    if !is_stream {
+        let status = response.status().as_u16();
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                console_error!("Error reading upstream body: {}", e);
+                return Response::error("Internal Server Error!!!!!", 500);
+            }
+        };
+
+        match parse_message_usage(&body) {
+            Ok(usage) => build_analytics(usage).save(&env, &ctx.data).await,
+            Err(e) => console_error!("Failed to parse Anthropic usage: {e}"),
+        }
+
+        return Ok(Response::from_bytes(body.into())?
+            .with_status(status)
+            .with_headers(my_response_headers));
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let keep_alive = keep_alive::interval(&env, xparams.keep_alive.as_deref(), content_type);
+    let rx = forward_upstream(&env, response, xparams.sends_error_events());
+    let mut scanner = AnthropicUsageScanner::default();
+
+    let event_ctx = ctx.data.clone();
+    let stream = rx.map(move |result| {
+        if let Ok(bytes) = &result {
+            if let Some(usage) = scanner.push(bytes) {
+                console_log!("ANTHROPIC USAGE: {:?}", usage);
+
+                // Save analytics data asynchronously (fire-and-forget)
+                let analytics = build_analytics(usage);
+                analytics.save_in_background(&env, &event_ctx);
+            }
+        }
+        result
+    });
+    let stream = keep_alive::wrap(stream, keep_alive);
+
+    match Response::from_stream(stream) {
+        Ok(resp) => Ok(resp.with_headers(my_response_headers)),
+        Err(e) => {
+            console_error!("Error creating streaming response: {}", e);
+            Response::error("Internal Server Error!!!!!", 500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAUDE_STREAM: &str = concat!(
+        "event: message_start\n",
+        "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_01\",\"type\":\"message\",\"role\":\"assistant\",\"model\":\"claude-3-5-sonnet-20241022\",\"content\":[],\"stop_reason\":null,\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n",
+        "\n",
+        "event: content_block_start\n",
+        "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n",
+        "\n",
+        "event: ping\n",
+        "data: {\"type\": \"ping\"}\n",
+        "\n",
+        "event: content_block_delta\n",
+        "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello!\"}}\n",
+        "\n",
+        "event: content_block_stop\n",
+        "data: {\"type\":\"content_block_stop\",\"index\":0}\n",
+        "\n",
+        "event: message_delta\n",
+        "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\",\"stop_sequence\":null},\"usage\":{\"output_tokens\":15}}\n",
+        "\n",
+        "event: message_stop\n",
+        "data: {\"type\":\"message_stop\"}\n",
+        "\n",
+    );
+
+    fn expected_usage() -> MessageUsage {
+        MessageUsage {
+            model: "claude-3-5-sonnet-20241022".to_string(),
+            prompt_tokens: 25,
+            completion_tokens: 15,
+            total_tokens: 40,
+            finish_reason: Some("end_turn".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_scanner_whole_stream() {
+        let mut scanner = AnthropicUsageScanner::default();
+        assert_eq!(
+            scanner.push(CLAUDE_STREAM.as_bytes()),
+            Some(expected_usage())
+        );
+    }
+
+    #[test]
+    fn test_scanner_split_at_every_offset() {
+        let bytes = CLAUDE_STREAM.as_bytes();
+        for split in 1..bytes.len() {
+            let mut scanner = AnthropicUsageScanner::default();
+            let first = scanner.push(&bytes[..split]);
+            let second = scanner.push(&bytes[split..]);
+            let usage = first.or(second);
+            assert_eq!(usage, Some(expected_usage()), "split at {split}");
+        }
+    }
+
+    #[test]
+    fn test_scanner_reports_once() {
+        let mut scanner = AnthropicUsageScanner::default();
+        assert!(scanner.push(CLAUDE_STREAM.as_bytes()).is_some());
+        assert!(scanner
+            .push(b"event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n")
+            .is_none());
+    }
+
+    #[test]
+    fn test_scanner_crlf_lines() {
+        for ending in ["\r\n", "\r"] {
+            let mut scanner = AnthropicUsageScanner::default();
+            let stream = CLAUDE_STREAM.replace('\n', ending);
+            assert_eq!(scanner.push(stream.as_bytes()), Some(expected_usage()));
+        }
+    }
+
+    #[test]
+    fn test_parse_message_usage_non_stream() {
+        let body = br#"{
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-haiku-20240307",
+            "content": [{"type": "text", "text": "Hi"}],
+            "stop_reason": "end_turn",
+            "usage": {"input_tokens": 10, "output_tokens": 3}
+        }"#;
+
+        let usage = parse_message_usage(body).unwrap();
+        assert_eq!(usage.model, "claude-3-haiku-20240307");
+        assert_eq!(usage.prompt_tokens, 10);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 13);
+        assert_eq!(usage.finish_reason.as_deref(), Some("end_turn"));
+    }
+}
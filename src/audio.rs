@@ -0,0 +1,203 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{
+    cors, guard_request, proxy_response_headers, query_error_response, redact,
+    reject_disallowed_upstream, upstream, upstream_auth_headers, ProxyUrlParams, RequestMeta,
+};
+
+/// Usage block returned by the newer transcription models
+#[derive(Debug, Default, Deserialize)]
+struct TranscriptionUsage {
+    #[serde(default)]
+    seconds: Option<f64>,
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+    #[serde(default)]
+    total_tokens: u32,
+}
+
+/// The parts of a transcription response needed for analytics
+#[derive(Debug, Default, Deserialize)]
+struct TranscriptionResponseBody {
+    /// Present with `response_format=verbose_json`
+    #[serde(default)]
+    duration: Option<f64>,
+    #[serde(default)]
+    usage: Option<TranscriptionUsage>,
+}
+
+impl TranscriptionResponseBody {
+    /// Audio duration in seconds, from whichever field the model reported
+    fn audio_seconds(&self) -> f64 {
+        self.duration
+            .or_else(|| self.usage.as_ref().and_then(|u| u.seconds))
+            .unwrap_or_default()
+    }
+}
+
+/// Finds the value of a simple text field in a multipart/form-data body.
+///
+/// Only used to pick up the `model` for analytics; the body itself is never modified.
+pub fn multipart_text_field<'a>(body: &'a [u8], name: &str) -> Option<&'a str> {
+    let needle = format!("name=\"{name}\"");
+    let start = find(body, needle.as_bytes())? + needle.len();
+    let value_start = start + find(&body[start..], b"\r\n\r\n")? + 4;
+    let value_len = find(&body[value_start..], b"\r\n")?;
+
+    std::str::from_utf8(&body[value_start..value_start + value_len]).ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Proxies multipart transcription uploads byte-for-byte and records the audio duration
+pub async fn transcriptions_proxy(
+    mut req: Request,
+    ctx: RouteContext<Rc<Context>>,
+) -> Result<Response> {
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, None).await {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    // The boundary lives in the content-type, so it must reach the upstream unchanged
+    let content_type = match req.headers().get("content-type") {
+        Ok(Some(value)) if value.starts_with("multipart/form-data") => value,
+        _ => return Response::error("Expected a multipart/form-data body", 415),
+    };
+
+    let mut proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+    proxy_headers.set("content-type", &content_type)?;
+
+    let data = req.bytes().await?;
+    let model = multipart_text_field(&data, "model")
+        .unwrap_or("unknown")
+        .to_string();
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let status = response.status();
+    let origin = cors::request_origin(&req, &ctx.env);
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            console_error!("Error reading upstream body: {}", e);
+            return Response::error("Internal Server Error!!!!!", 500);
+        }
+    };
+
+    if status.is_success() {
+        // text/srt/vtt response formats carry no metadata; the event is still recorded
+        let parsed = serde_json::from_slice::<TranscriptionResponseBody>(&body).unwrap_or_default();
+        let usage = parsed.usage.as_ref();
+
+        let mut analytics = meta.usage_analytics(
+            model,
+            usage.map(|u| u.input_tokens).unwrap_or_default(),
+            usage.map(|u| u.output_tokens).unwrap_or_default(),
+            usage.map(|u| u.total_tokens).unwrap_or_default(),
+        );
+        analytics.audio_seconds = parsed.audio_seconds();
+        analytics.save(&ctx.env, &ctx.data).await;
+    } else {
+        console_error!("Error {}", status);
+    }
+
+    Ok(Response::from_bytes(body.into())?
+        .with_status(status.as_u16())
+        .with_headers(my_response_headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPART_BODY: &[u8] = b"--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a.mp3\"\r\n\
+Content-Type: audio/mpeg\r\n\r\n\
+\xff\xfb\x90\x00binary\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"model\"\r\n\r\n\
+whisper-1\r\n\
+--XyZ--\r\n";
+
+    #[test]
+    fn test_multipart_text_field() {
+        assert_eq!(
+            multipart_text_field(MULTIPART_BODY, "model"),
+            Some("whisper-1")
+        );
+        assert_eq!(multipart_text_field(MULTIPART_BODY, "language"), None);
+    }
+
+    #[test]
+    fn test_verbose_json_duration() {
+        let body: TranscriptionResponseBody = serde_json::from_str(
+            r#"{"task": "transcribe", "language": "english", "duration": 8.47, "text": "Hello"}"#,
+        )
+        .unwrap();
+        assert_eq!(body.audio_seconds(), 8.47);
+    }
+
+    #[test]
+    fn test_usage_duration() {
+        let body: TranscriptionResponseBody = serde_json::from_str(
+            r#"{"text": "Hello", "usage": {"type": "duration", "seconds": 12}}"#,
+        )
+        .unwrap();
+        assert_eq!(body.audio_seconds(), 12.0);
+    }
+
+    #[test]
+    fn test_usage_tokens() {
+        let body: TranscriptionResponseBody = serde_json::from_str(
+            r#"{"text": "Hi", "usage": {"type": "tokens", "input_tokens": 14, "output_tokens": 45, "total_tokens": 59}}"#,
+        )
+        .unwrap();
+        let usage = body.usage.as_ref().unwrap();
+        assert_eq!(usage.input_tokens, 14);
+        assert_eq!(usage.output_tokens, 45);
+        assert_eq!(usage.total_tokens, 59);
+        assert_eq!(body.audio_seconds(), 0.0);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::borrow::Cow;
+
+use serde_json::Value;
+
+use crate::sse;
+
+/// Azure's content filter verdicts on each choice
+const CHOICE_ANNOTATIONS: [&str; 2] = ["content_filter_results", "content_filter_offsets"];
+
+/// Azure's content filter verdict on the prompt, sent in an event of its own
+const PROMPT_ANNOTATION: &str = "prompt_filter_results";
+
+/// Strips Azure's content filter annotations from a whole event of a stream (`azureCompat=1`),
+/// leaving it as OpenAI would send it; `None` drops the event that only had the prompt's.
+///
+/// Only events carrying annotations are re-serialized, so everything else, including what
+/// isn't a JSON event, is forwarded byte for byte.
+pub fn strip_filter_annotations(frame: &[u8]) -> Option<Cow<'_, [u8]>> {
+    let unchanged = Some(Cow::Borrowed(frame));
+
+    let events = sse::SseParser::default().push(frame);
+    let [event] = events.as_slice() else {
+        return unchanged;
+    };
+    // Spares parsing the events that can't have any
+    if !event.data.contains("_filter_") {
+        return unchanged;
+    }
+    let Ok(Value::Object(mut object)) = serde_json::from_str(&event.data) else {
+        return unchanged;
+    };
+
+    // `shift_remove` keeps the order of the other fields
+    let prompt_annotated = object.shift_remove(PROMPT_ANNOTATION).is_some();
+    let mut stripped = prompt_annotated;
+    if let Some(Value::Array(choices)) = object.get_mut("choices") {
+        for choice in choices.iter_mut().filter_map(Value::as_object_mut) {
+            for annotation in CHOICE_ANNOTATIONS {
+                stripped |= choice.shift_remove(annotation).is_some();
+            }
+        }
+    }
+    if !stripped {
+        return unchanged;
+    }
+
+    let choices = object.get("choices").and_then(Value::as_array);
+    let usage = object.get("usage").filter(|usage| !usage.is_null());
+    if prompt_annotated && choices.is_none_or(Vec::is_empty) && usage.is_none() {
+        return None;
+    }
+
+    let mut rewritten = String::new();
+    if let Some(name) = &event.event {
+        rewritten.push_str(&format!("event: {name}\n"));
+    }
+    if let Some(id) = &event.id {
+        rewritten.push_str(&format!("id: {id}\n"));
+    }
+    rewritten.push_str(&format!("data: {}\n\n", Value::Object(object)));
+    Some(Cow::Owned(rewritten.into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stripped(frame: &str) -> Option<String> {
+        strip_filter_annotations(frame.as_bytes())
+            .map(|frame| String::from_utf8(frame.into_owned()).unwrap())
+    }
+
+    #[test]
+    fn test_prompt_filter_event_dropped() {
+        let frame = concat!(
+            r#"data: {"choices":[],"created":0,"id":"","model":"","object":"","#,
+            r#""prompt_filter_results":[{"prompt_index":0,"content_filter_results":{}}]}"#,
+            "\n\n",
+        );
+        assert_eq!(stripped(frame), None);
+
+        // Not when it comes with content or usage, which only lose the annotation
+        let frame = concat!(
+            r#"data: {"choices":[],"prompt_filter_results":[],"#,
+            r#""usage":{"prompt_tokens":1,"completion_tokens":0,"total_tokens":1}}"#,
+            "\n\n",
+        );
+        assert_eq!(
+            stripped(frame).as_deref(),
+            Some(concat!(
+                r#"data: {"choices":[],"#,
+                r#""usage":{"prompt_tokens":1,"completion_tokens":0,"total_tokens":1}}"#,
+                "\n\n",
+            ))
+        );
+    }
+
+    #[test]
+    fn test_choice_annotations_stripped() {
+        let frame = concat!(
+            r#"data: {"choices":[{"content_filter_results":{"hate":{"filtered":false}},"#,
+            r#""content_filter_offsets":{"check_offset":0},"delta":{"content":"Hi \"there\""},"#,
+            r#""finish_reason":null,"index":0}],"model":"gpt-4o","usage":null}"#,
+            "\r\n\r\n",
+        );
+        assert_eq!(
+            stripped(frame).as_deref(),
+            Some(concat!(
+                r#"data: {"choices":[{"delta":{"content":"Hi \"there\""},"#,
+                r#""finish_reason":null,"index":0}],"model":"gpt-4o","usage":null}"#,
+                "\n\n",
+            ))
+        );
+
+        let frame =
+            "event: chunk\nid: 7\ndata: {\"choices\":[{\"content_filter_results\":{}}]}\n\n";
+        assert_eq!(
+            stripped(frame).as_deref(),
+            Some("event: chunk\nid: 7\ndata: {\"choices\":[{}]}\n\n")
+        );
+    }
+
+    #[test]
+    fn test_other_events_untouched() {
+        for frame in [
+            "data: {\"choices\":[{\"delta\":{\"content\":\"content_filter_results\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"x\"}}],  \"model\" : \"m\"}\n\n",
+            "data: [DONE]\n\n",
+            ": keep-alive\n\n",
+            "data: {\"content_filter_results\":\n\n",
+        ] {
+            let result = strip_filter_annotations(frame.as_bytes()).unwrap();
+            assert!(matches!(result, Cow::Borrowed(_)), "{frame}");
+            assert_eq!(result, frame.as_bytes());
+        }
+    }
+}
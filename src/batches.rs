@@ -0,0 +1,320 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::audio::multipart_text_field;
+use crate::{
+    cors, guard_request, proxy_response_headers, query_error_response, redact,
+    reject_disallowed_upstream, upstream, upstream_auth_headers, ProxyUrlParams, RequestMeta,
+};
+
+/// Model recorded for batch events; the Batch object doesn't name one
+const BATCH_MODEL: &str = "batch";
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct RequestCounts {
+    #[serde(default)]
+    total: u32,
+    #[serde(default)]
+    completed: u32,
+    #[serde(default)]
+    failed: u32,
+}
+
+/// The parts of a Batch object needed for analytics
+#[derive(Debug, Deserialize)]
+struct BatchResponseBody {
+    id: String,
+    status: String,
+    #[serde(default)]
+    request_counts: RequestCounts,
+}
+
+/// Upstream body and metadata, kept intact so SDKs see the exact upstream response
+struct BufferedResponse {
+    status: u16,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl BufferedResponse {
+    fn into_response(self) -> Result<Response> {
+        Ok(Response::from_bytes(self.body)?
+            .with_status(self.status)
+            .with_headers(self.headers))
+    }
+}
+
+async fn send_buffered(
+    request: reqwest::RequestBuilder,
+    origin: Option<&str>,
+) -> Result<BufferedResponse> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Error::from(format!("Request Error: {e}")))?;
+
+    let status = response.status().as_u16();
+    let headers = proxy_response_headers(&response, origin);
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| Error::from(format!("Error reading upstream body: {e}")))?;
+
+    Ok(BufferedResponse {
+        status,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+/// Appends the batch id to `u` unless the caller already passed the full batch URL
+fn batch_url(upstream: &str, id: &str) -> String {
+    let (path, query) = match upstream.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (upstream, None),
+    };
+    let path = path.trim_end_matches('/');
+
+    let mut url = if path.ends_with(&format!("/{id}")) {
+        path.to_string()
+    } else {
+        format!("{path}/{id}")
+    };
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    url
+}
+
+/// Proxies batch creation (`POST /batches`) untouched
+pub async fn create_batch(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let data = req.bytes().await?;
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let mut proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let request = upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data);
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    match send_buffered(request, origin.as_deref()).await {
+        Ok(response) => response.into_response(),
+        Err(e) => {
+            console_error!("{}", e);
+            Response::error("Internal Server Error!!!!", 500)
+        }
+    }
+}
+
+/// Proxies batch retrieval and records the request counts once the batch has completed.
+///
+/// Clients poll this route, so a completed batch may be recorded more than once;
+/// the batch id is stored as `reqId` (when the caller didn't send one) to dedupe on.
+pub async fn get_batch(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let id = match ctx.param("id") {
+        Some(id) => id.to_string(),
+        None => return Response::error("Bad Request", 400),
+    };
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, None).await {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+
+    let proxy_url = batch_url(&xparams.u, &id);
+    console_debug!("Proxy URL: {}", redact::url(&proxy_url));
+
+    let request = upstream::http_client()
+        .get(proxy_url)
+        .headers(proxy_headers.into());
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    let response = match send_buffered(request, origin.as_deref()).await {
+        Ok(response) => response,
+        Err(e) => {
+            console_error!("{}", e);
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    if (200..300).contains(&response.status) {
+        match serde_json::from_slice::<BatchResponseBody>(&response.body) {
+            Ok(batch) if batch.status == "completed" => {
+                meta.request_id.get_or_insert(batch.id);
+
+                let mut analytics = meta.usage_analytics(BATCH_MODEL.to_string(), 0, 0, 0);
+                analytics.batch_total = batch.request_counts.total;
+                analytics.batch_completed = batch.request_counts.completed;
+                analytics.batch_failed = batch.request_counts.failed;
+                analytics.save(&ctx.env, &ctx.data).await;
+            }
+            Ok(_) => {}
+            Err(e) => console_error!("Failed to parse batch response: {e}"),
+        }
+    } else {
+        console_error!("Error {}", response.status);
+    }
+
+    response.into_response()
+}
+
+/// Proxies batch input file uploads (`POST /files`, `purpose=batch`) byte-for-byte
+pub async fn upload_file(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, None).await {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    // The boundary lives in the content-type, so it must reach the upstream unchanged
+    let content_type = match req.headers().get("content-type") {
+        Ok(Some(value)) if value.starts_with("multipart/form-data") => value,
+        _ => return Response::error("Expected a multipart/form-data body", 415),
+    };
+
+    let mut proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+    proxy_headers.set("content-type", &content_type)?;
+
+    let data = req.bytes().await?;
+    if multipart_text_field(&data, "purpose") != Some("batch") {
+        return Response::error("Only purpose=batch uploads are supported", 400);
+    }
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let request = upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data);
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    match send_buffered(request, origin.as_deref()).await {
+        Ok(response) => response.into_response(),
+        Err(e) => {
+            console_error!("{}", e);
+            Response::error("Internal Server Error!!!!", 500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completed_batch() {
+        let batch: BatchResponseBody = serde_json::from_str(
+            r#"{
+                "id": "batch_abc123",
+                "object": "batch",
+                "endpoint": "/v1/chat/completions",
+                "status": "completed",
+                "output_file_id": "file-cvaTdG",
+                "request_counts": {"total": 100, "completed": 95, "failed": 5},
+                "metadata": null
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(batch.id, "batch_abc123");
+        assert_eq!(batch.status, "completed");
+        assert_eq!(
+            batch.request_counts,
+            RequestCounts {
+                total: 100,
+                completed: 95,
+                failed: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_in_progress_batch_without_counts() {
+        let batch: BatchResponseBody =
+            serde_json::from_str(r#"{"id": "batch_abc123", "status": "validating"}"#).unwrap();
+        assert_eq!(batch.status, "validating");
+        assert_eq!(batch.request_counts, RequestCounts::default());
+    }
+
+    #[test]
+    fn test_batch_url() {
+        assert_eq!(
+            batch_url("https://api.openai.com/v1/batches", "batch_1"),
+            "https://api.openai.com/v1/batches/batch_1"
+        );
+        assert_eq!(
+            batch_url("https://api.openai.com/v1/batches/", "batch_1"),
+            "https://api.openai.com/v1/batches/batch_1"
+        );
+        assert_eq!(
+            batch_url("https://api.openai.com/v1/batches/batch_1", "batch_1"),
+            "https://api.openai.com/v1/batches/batch_1"
+        );
+        assert_eq!(
+            batch_url(
+                "https://x.openai.azure.com/openai/batches?api-version=2024-10-21",
+                "batch_1"
+            ),
+            "https://x.openai.azure.com/openai/batches/batch_1?api-version=2024-10-21"
+        );
+    }
+}
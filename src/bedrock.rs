@@ -0,0 +1,476 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use base64::Engine;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use worker::*;
+
+use crate::{
+    cors, forward_upstream, guard_request, on_stream_end, proxy_response_headers,
+    query_error_response, redact, reject_disallowed_upstream, targets::AuthError, upstream,
+    upstream_auth_headers, upstream_error_headers, ProxyUrlParams, RequestMeta,
+};
+
+/// Caller headers forwarded upstream besides its credential; the caller signs the request with
+/// SigV4
+const FORWARDED_HEADERS: [&str; 5] = [
+    "x-amz-date",
+    "x-amz-security-token",
+    "x-amz-content-sha256",
+    "content-type",
+    "accept",
+];
+
+/// Prelude (total length, headers length, prelude CRC) plus the trailing message CRC
+const FRAME_OVERHEAD: usize = 16;
+/// Upper bound for a single frame; AWS caps event-stream messages at 16 MB
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// A decoded `application/vnd.amazon.eventstream` message
+#[derive(Debug, PartialEq)]
+pub struct EventMessage {
+    pub event_type: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+/// Incrementally decodes AWS event-stream frames.
+///
+/// CRCs are not verified: the frames are forwarded to the client untouched and
+/// only scanned here for usage metrics.
+#[derive(Debug, Default)]
+pub struct EventStreamDecoder {
+    buf: Vec<u8>,
+}
+
+impl EventStreamDecoder {
+    /// Feeds a network chunk, returning every message completed by it
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<EventMessage> {
+        self.buf.extend_from_slice(chunk);
+
+        let mut messages = Vec::new();
+        let mut offset = 0;
+
+        while self.buf.len() - offset >= 12 {
+            let frame = &self.buf[offset..];
+            let total_len = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+            let headers_len = u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]) as usize;
+
+            if total_len < FRAME_OVERHEAD + headers_len || total_len > MAX_FRAME_LEN {
+                // Not a frame boundary we can trust: drop what we have
+                self.buf.clear();
+                return messages;
+            }
+            if frame.len() < total_len {
+                break;
+            }
+
+            let headers = &frame[12..12 + headers_len];
+            let payload = &frame[12 + headers_len..total_len - 4];
+            messages.push(EventMessage {
+                event_type: header_value(headers, ":event-type"),
+                payload: payload.to_vec(),
+            });
+            offset += total_len;
+        }
+
+        self.buf.drain(..offset);
+        messages
+    }
+}
+
+/// Looks up a string-typed header in an event-stream header block
+fn header_value(mut headers: &[u8], wanted: &str) -> Option<String> {
+    while !headers.is_empty() {
+        let name_len = *headers.first()? as usize;
+        let name = headers.get(1..1 + name_len)?;
+        let value_type = *headers.get(1 + name_len)?;
+        let rest = headers.get(2 + name_len..)?;
+
+        // Only string (7) and bytes (6) share the u16 length layout; other types are fixed-size
+        let (value, consumed) = match value_type {
+            0 | 1 => (None, 0),
+            2 => (None, 1),
+            3 => (None, 2),
+            4 => (None, 4),
+            5 | 8 => (None, 8),
+            9 => (None, 16),
+            6 | 7 => {
+                let len = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+                (Some(rest.get(2..2 + len)?), 2 + len)
+            }
+            _ => return None,
+        };
+
+        if name == wanted.as_bytes() {
+            return value.and_then(|v| std::str::from_utf8(v).ok().map(str::to_string));
+        }
+        headers = rest.get(consumed..)?;
+    }
+    None
+}
+
+/// Payload of a `chunk` event: the model's own JSON, base64-encoded
+#[derive(Debug, Deserialize)]
+struct ChunkPayload {
+    bytes: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InvocationMetrics {
+    #[serde(default)]
+    input_token_count: u32,
+    #[serde(default)]
+    output_token_count: u32,
+    /// Milliseconds
+    #[serde(default)]
+    invocation_latency: Option<u32>,
+    /// Milliseconds
+    #[serde(default)]
+    first_byte_latency: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelChunk {
+    #[serde(rename = "amazon-bedrock-invocationMetrics")]
+    invocation_metrics: Option<InvocationMetrics>,
+}
+
+/// Token usage mapped onto the OpenAI-style columns used by `UsageAnalytics`, with the
+/// latencies Bedrock measured
+#[derive(Debug, Default, PartialEq)]
+pub struct BedrockUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    pub invocation_latency_ms: Option<u32>,
+    pub first_byte_latency_ms: Option<u32>,
+    /// Whether the stream reported its metrics; some models never do
+    pub captured: bool,
+}
+
+/// Scans a Bedrock `invoke-with-response-stream` body for invocation metrics
+#[derive(Debug, Default)]
+pub struct BedrockUsageScanner {
+    decoder: EventStreamDecoder,
+    metrics: Option<InvocationMetrics>,
+}
+
+impl BedrockUsageScanner {
+    pub fn push(&mut self, chunk: &[u8]) {
+        for message in self.decoder.push(chunk) {
+            if message.event_type.as_deref() != Some("chunk") {
+                continue;
+            }
+            let Ok(payload) = serde_json::from_slice::<ChunkPayload>(&message.payload) else {
+                continue;
+            };
+            let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(payload.bytes)
+            else {
+                continue;
+            };
+            if let Ok(ModelChunk {
+                invocation_metrics: Some(metrics),
+            }) = serde_json::from_slice::<ModelChunk>(&decoded)
+            {
+                self.metrics = Some(metrics);
+            }
+        }
+    }
+
+    /// The usage of the ended stream; zero, and not `captured`, when it had no metrics
+    pub fn finish(&mut self) -> BedrockUsage {
+        let Some(metrics) = self.metrics.take() else {
+            return BedrockUsage::default();
+        };
+        BedrockUsage {
+            prompt_tokens: metrics.input_token_count,
+            completion_tokens: metrics.output_token_count,
+            total_tokens: metrics
+                .input_token_count
+                .saturating_add(metrics.output_token_count),
+            invocation_latency_ms: metrics.invocation_latency,
+            first_byte_latency_ms: metrics.first_byte_latency,
+            captured: true,
+        }
+    }
+}
+
+/// Extracts the model ID from a Bedrock runtime URL (`.../model/{modelId}/invoke...`)
+pub fn model_from_url(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("/model/")?;
+    let model = rest.split(['/', '?']).next()?;
+    Some(model.replace("%3A", ":").replace("%3a", ":")).filter(|model| !model.is_empty())
+}
+
+/// Proxies Bedrock `invoke-with-response-stream` calls, forwarding frames as they arrive
+pub async fn bedrock_proxy(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let data = req.bytes().await?;
+    let env = ctx.env.clone();
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let provider = xparams.provider(&xparams.u);
+    let mut proxy_headers = match upstream_auth_headers(&req, &ctx.env, &meta, provider) {
+        Ok(headers) => headers,
+        Err(AuthError::MissingCredential) => {
+            console_error!("Request Error: Missing authorization headers");
+            return Response::error("Missing authorization header", 401);
+        }
+        Err(e) => return e.to_response(),
+    };
+    for name in FORWARDED_HEADERS {
+        if let Ok(Some(value)) = req.headers().get(name) {
+            proxy_headers.set(name, &value)?;
+        }
+    }
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let model = model_from_url(&xparams.u).unwrap_or_else(|| "unknown".to_string());
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    if !response.status().is_success() {
+        console_error!("Error {}", response.status());
+        let status = response.status();
+        let error_headers = upstream_error_headers(&response, origin.as_deref())?;
+        let text = &response.text().await;
+        let error = Response::error(format!("{:?}", &text), status.into())?;
+        return Ok(error.with_headers(error_headers));
+    }
+
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    let rx = forward_upstream(&env, response, xparams.sends_error_events());
+
+    let scanner = Rc::new(RefCell::new(BedrockUsageScanner::default()));
+    let scanning = scanner.clone();
+
+    let stream = rx.map(move |result| {
+        if let Ok(bytes) = &result {
+            scanning.borrow_mut().push(bytes);
+        }
+        result
+    });
+
+    // The metrics ride on the final chunk, so usage is recorded once the stream ends
+    let event_ctx = ctx.data.clone();
+    let stream = on_stream_end(stream, move || {
+        let usage = scanner.borrow_mut().finish();
+        if usage.captured {
+            console_log!("BEDROCK USAGE: {:?}", usage);
+        } else {
+            console_warn!(
+                "Bedrock stream of {} ended without invocation metrics",
+                model
+            );
+        }
+
+        let mut analytics = meta.usage_analytics(
+            model,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens,
+        );
+        analytics.usage_captured = usage.captured;
+        analytics.invocation_latency_ms = usage.invocation_latency_ms;
+        analytics.first_byte_latency_ms = usage.first_byte_latency_ms;
+
+        // Save analytics data asynchronously (fire-and-forget)
+        analytics.save_in_background(&env, &event_ctx);
+    });
+
+    match Response::from_stream(stream) {
+        Ok(resp) => Ok(resp.with_headers(my_response_headers)),
+        Err(e) => {
+            console_error!("Error creating streaming response: {}", e);
+            Response::error("Internal Server Error!!!!!", 500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes an event-stream frame with string headers (CRCs left as zero)
+    fn encode_frame(headers: &[(&str, &str)], payload: &[u8]) -> Vec<u8> {
+        let mut header_bytes = Vec::new();
+        for (name, value) in headers {
+            header_bytes.push(name.len() as u8);
+            header_bytes.extend_from_slice(name.as_bytes());
+            header_bytes.push(7);
+            header_bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            header_bytes.extend_from_slice(value.as_bytes());
+        }
+
+        let total_len = FRAME_OVERHEAD + header_bytes.len() + payload.len();
+        let mut frame = Vec::with_capacity(total_len);
+        frame.extend_from_slice(&(total_len as u32).to_be_bytes());
+        frame.extend_from_slice(&(header_bytes.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        frame.extend_from_slice(&header_bytes);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&[0; 4]);
+        frame
+    }
+
+    fn chunk_frame(model_json: &str) -> Vec<u8> {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(model_json);
+        encode_frame(
+            &[
+                (":event-type", "chunk"),
+                (":content-type", "application/json"),
+                (":message-type", "event"),
+            ],
+            format!(r#"{{"bytes":"{encoded}","p":"abcdefgh"}}"#).as_bytes(),
+        )
+    }
+
+    fn claude_stream() -> Vec<u8> {
+        let mut stream = chunk_frame(
+            r#"{"type":"message_start","message":{"model":"claude-3-5-sonnet-20240620","usage":{"input_tokens":14,"output_tokens":1}}}"#,
+        );
+        stream.extend(chunk_frame(
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hi"}}"#,
+        ));
+        stream.extend(chunk_frame(
+            r#"{"type":"message_stop","amazon-bedrock-invocationMetrics":{"inputTokenCount":14,"outputTokenCount":22,"invocationLatency":1032,"firstByteLatency":421}}"#,
+        ));
+        stream
+    }
+
+    #[test]
+    fn test_decoder_reads_frames() {
+        let mut decoder = EventStreamDecoder::default();
+        let messages = decoder.push(&claude_stream());
+        assert_eq!(messages.len(), 3);
+        assert!(messages
+            .iter()
+            .all(|m| m.event_type.as_deref() == Some("chunk")));
+    }
+
+    #[test]
+    fn test_scanner_extracts_metrics_at_any_split() {
+        let stream = claude_stream();
+        for split in (1..stream.len()).step_by(5) {
+            let mut scanner = BedrockUsageScanner::default();
+            scanner.push(&stream[..split]);
+            scanner.push(&stream[split..]);
+            assert_eq!(
+                scanner.finish(),
+                BedrockUsage {
+                    prompt_tokens: 14,
+                    completion_tokens: 22,
+                    total_tokens: 36,
+                    invocation_latency_ms: Some(1032),
+                    first_byte_latency_ms: Some(421),
+                    captured: true,
+                },
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_scanner_ignores_exceptions() {
+        let mut scanner = BedrockUsageScanner::default();
+        scanner.push(&encode_frame(
+            &[
+                (":exception-type", "throttlingException"),
+                (":message-type", "exception"),
+            ],
+            br#"{"message":"Too many requests"}"#,
+        ));
+        assert_eq!(scanner.finish(), BedrockUsage::default());
+    }
+
+    #[test]
+    fn test_scanner_fixture() {
+        let stream = include_bytes!("../fixtures/bedrock_claude_stream.bin");
+        assert_eq!(EventStreamDecoder::default().push(stream).len(), 7);
+
+        for chunk_len in [1, 64, stream.len()] {
+            let mut scanner = BedrockUsageScanner::default();
+            for chunk in stream.chunks(chunk_len) {
+                scanner.push(chunk);
+            }
+            assert_eq!(
+                scanner.finish(),
+                BedrockUsage {
+                    prompt_tokens: 38,
+                    completion_tokens: 19,
+                    total_tokens: 57,
+                    invocation_latency_ms: Some(1274),
+                    first_byte_latency_ms: Some(386),
+                    captured: true,
+                },
+                "chunks of {chunk_len}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_scanner_without_metrics() {
+        // The same stream from a model that doesn't report them
+        let mut stream = chunk_frame(
+            r#"{"type":"message_start","message":{"model":"claude-3-5-sonnet-20240620","usage":{"input_tokens":14,"output_tokens":1}}}"#,
+        );
+        stream.extend(chunk_frame(r#"{"type":"message_stop"}"#));
+
+        let mut scanner = BedrockUsageScanner::default();
+        scanner.push(&stream);
+        // Zero usage, not `captured`, rather than the tokens of `message_start`
+        assert_eq!(scanner.finish(), BedrockUsage::default());
+
+        // Metrics without latencies are still captured
+        let mut scanner = BedrockUsageScanner::default();
+        scanner.push(&chunk_frame(
+            r#"{"type":"message_stop","amazon-bedrock-invocationMetrics":{"inputTokenCount":3,"outputTokenCount":4}}"#,
+        ));
+        let usage = scanner.finish();
+        assert!(usage.captured);
+        assert_eq!(usage.total_tokens, 7);
+        assert_eq!(usage.first_byte_latency_ms, None);
+    }
+
+    #[test]
+    fn test_model_from_url() {
+        assert_eq!(
+            model_from_url("https://bedrock-runtime.us-east-1.amazonaws.com/model/anthropic.claude-3-sonnet-20240229-v1%3A0/invoke-with-response-stream"),
+            Some("anthropic.claude-3-sonnet-20240229-v1:0".to_string())
+        );
+        assert_eq!(model_from_url("https://example.com/invoke"), None);
+    }
+}
@@ -0,0 +1,763 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde_json::{json, Map, Value};
+use worker::Env;
+
+use crate::{trim_body, BodyError};
+
+/// Changes `stream_proxy` makes to a JSON request body, applied in one parse/serialize pass
+#[derive(Debug, Default)]
+pub struct BodyEdits<'a> {
+    /// Ask for usage on the last chunk of a stream (`stream_options.include_usage`)
+    pub include_usage: bool,
+    /// OpenAI's `user` field, set unless the client sent one
+    pub user_id: Option<&'a str>,
+    /// Upstream model replacing the client-facing one
+    pub model: Option<&'a str>,
+    /// Tenant ceiling on the completion size
+    pub max_tokens_cap: Option<u32>,
+    /// Sampling parameters pulled back into range instead of rejected
+    pub clamps: SamplingClamps,
+    /// Top-level fields removed before anything else is edited
+    pub strip_fields: &'a [String],
+    /// Guardrail prompt put at the front of `messages`
+    pub system_prompt: Option<&'a str>,
+}
+
+/// A body changed by `BodyEdits::apply`
+#[derive(Debug, PartialEq)]
+pub struct EditedBody {
+    pub body: Vec<u8>,
+    /// The cap, when it lowered or added the completion size
+    pub max_tokens_capped: Option<u32>,
+    /// Sampling parameters that were out of range, for the caller to log
+    pub clamped: Vec<Clamped>,
+    /// Denied fields the client sent
+    pub stripped: Vec<String>,
+    /// Length in characters of the system prompt, when it was inserted
+    pub system_prompt_chars: Option<usize>,
+}
+
+/// A sampling parameter moved to the nearest bound of its range
+#[derive(Debug, PartialEq)]
+pub struct Clamped {
+    pub field: &'static str,
+    pub from: f64,
+    pub to: f64,
+}
+
+impl BodyEdits<'_> {
+    pub fn is_empty(&self) -> bool {
+        !self.include_usage
+            && self.user_id.is_none()
+            && self.model.is_none()
+            && self.max_tokens_cap.is_none()
+            && !self.clamps.is_enabled()
+            && self.strip_fields.is_empty()
+            && self.system_prompt.is_none()
+    }
+
+    /// Applies the edits to a JSON body.
+    ///
+    /// Returns `None` when nothing changed, so the original bytes can be forwarded. A body whose
+    /// root isn't an object is an error when usage must be requested, and left alone otherwise.
+    pub fn apply(&self, body: &[u8]) -> Result<Option<EditedBody>, BodyError> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        let mut value = serde_json::from_slice::<Value>(trim_body(body))
+            .map_err(|e| BodyError::InvalidJson(e.to_string()))?;
+        let Some(object) = value.as_object_mut() else {
+            return match self.include_usage {
+                true => Err(BodyError::NotAnObject),
+                false => Ok(None),
+            };
+        };
+
+        // First, so the proxy's own edits below are kept
+        let stripped = self
+            .strip_fields
+            .iter()
+            .filter(|field| object.remove(field.as_str()).is_some())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut changed = !stripped.is_empty();
+        if self.include_usage {
+            set_include_usage(object);
+            changed = true;
+        }
+        if let Some(user_id) = self.user_id {
+            changed |= set_user(object, user_id);
+        }
+        if let Some(model) = self.model {
+            object.insert("model".to_string(), json!(model));
+            changed = true;
+        }
+        let max_tokens_capped = self
+            .max_tokens_cap
+            .and_then(|cap| cap_max_tokens(object, cap));
+        changed |= max_tokens_capped.is_some();
+        let clamped = self.clamps.apply(object);
+        changed |= !clamped.is_empty();
+        let system_prompt_chars = self
+            .system_prompt
+            .filter(|prompt| insert_system_prompt(object, prompt))
+            .map(|prompt| prompt.chars().count());
+        changed |= system_prompt_chars.is_some();
+
+        if !changed {
+            return Ok(None);
+        }
+
+        let body = serde_json::to_vec(&value).map_err(|e| BodyError::InvalidJson(e.to_string()))?;
+        Ok(Some(EditedBody {
+            body,
+            max_tokens_capped,
+            clamped,
+            stripped,
+            system_prompt_chars,
+        }))
+    }
+}
+
+/// Checks the `messages` of a chat-shaped body: a non-empty array of objects with a `role`.
+///
+/// Only called when the body has `messages`, so embeddings and Responses API bodies pass.
+pub fn check_messages(messages: &Value) -> Result<(), BodyError> {
+    let invalid = |index, reason| BodyError::InvalidMessages { index, reason };
+
+    let Some(messages) = messages.as_array() else {
+        return Err(invalid(None, "must be an array"));
+    };
+    if messages.is_empty() {
+        return Err(invalid(None, "must not be empty"));
+    }
+
+    for (index, message) in messages.iter().enumerate() {
+        let Some(message) = message.as_object() else {
+            return Err(invalid(Some(index), "must be an object"));
+        };
+        if !message.get("role").is_some_and(Value::is_string) {
+            return Err(invalid(Some(index), "must have a `role` string"));
+        }
+    }
+    Ok(())
+}
+
+/// A sampling parameter and the range the providers accept for it
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingParam {
+    pub field: &'static str,
+    pub min: f64,
+    pub max: f64,
+    /// Variable that turns clamping on, its value being the upper bound to clamp to
+    pub clamp_var: &'static str,
+}
+
+pub const TEMPERATURE: SamplingParam = SamplingParam {
+    field: "temperature",
+    min: 0.0,
+    max: 2.0,
+    clamp_var: "CLAMP_TEMPERATURE_MAX",
+};
+
+pub const TOP_P: SamplingParam = SamplingParam {
+    field: "top_p",
+    min: 0.0,
+    max: 1.0,
+    clamp_var: "CLAMP_TOP_P_MAX",
+};
+
+impl SamplingParam {
+    /// Parses a `CLAMP_*_MAX` value, which must lie within the provider range
+    fn parse_clamp_max(&self, value: &str) -> Option<f64> {
+        value
+            .trim()
+            .parse()
+            .ok()
+            .filter(|max| (self.min..=self.max).contains(max))
+    }
+}
+
+/// Upper bounds of the sampling parameters clamped rather than rejected when out of range.
+///
+/// Clamping is off by default: an out-of-range value is then a 400 naming the field, instead
+/// of the upstream's own error.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SamplingClamps {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+}
+
+impl SamplingClamps {
+    /// Reads `CLAMP_TEMPERATURE_MAX` and `CLAMP_TOP_P_MAX`; invalid values leave clamping off
+    pub fn from_env(env: &Env) -> Self {
+        let clamp_max = |param: SamplingParam| {
+            let value = env.var(param.clamp_var).ok()?.to_string();
+            param.parse_clamp_max(&value)
+        };
+        Self {
+            temperature: clamp_max(TEMPERATURE),
+            top_p: clamp_max(TOP_P),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.temperature.is_some() || self.top_p.is_some()
+    }
+
+    fn params(&self) -> [(SamplingParam, Option<f64>); 2] {
+        [(TEMPERATURE, self.temperature), (TOP_P, self.top_p)]
+    }
+
+    /// Rejects an out-of-range value of a parameter that isn't clamped.
+    ///
+    /// Values that aren't numbers are left for the upstream to judge.
+    pub fn check(
+        &self,
+        temperature: Option<&Value>,
+        top_p: Option<&Value>,
+    ) -> Result<(), BodyError> {
+        for ((param, clamp), value) in self.params().into_iter().zip([temperature, top_p]) {
+            let Some(value) = value.and_then(Value::as_f64) else {
+                continue;
+            };
+            if clamp.is_none() && !(param.min..=param.max).contains(&value) {
+                return Err(BodyError::OutOfRange {
+                    field: param.field,
+                    value,
+                    min: param.min,
+                    max: param.max,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the clamped parameters that are out of range to the nearest bound
+    fn apply(&self, object: &mut Map<String, Value>) -> Vec<Clamped> {
+        let mut clamped = Vec::new();
+        for (param, clamp) in self.params() {
+            let Some(max) = clamp else {
+                continue;
+            };
+            let Some(value) = object.get_mut(param.field) else {
+                continue;
+            };
+            let Some(from) = value.as_f64() else {
+                continue;
+            };
+
+            let to = from.clamp(param.min, max);
+            if to != from {
+                *value = json!(to);
+                clamped.push(Clamped {
+                    field: param.field,
+                    from,
+                    to,
+                });
+            }
+        }
+        clamped
+    }
+}
+
+/// Sets `stream_options.include_usage` so the last chunk of a stream carries the usage.
+///
+/// An existing `stream_options` object is merged into rather than duplicated, so the client's
+/// other options are kept; `include_usage: false` is overridden.
+/// https://learn.microsoft.com/en-us/azure/ai-services/openai/reference#chatcompletionstreamoptions
+fn set_include_usage(object: &mut Map<String, Value>) {
+    let options = object.entry("stream_options").or_insert_with(|| json!({}));
+    if !options.is_object() {
+        *options = json!({});
+    }
+    options["include_usage"] = json!(true);
+}
+
+/// Sets OpenAI's `user` field, unless the client sent one; returns whether it was set
+fn set_user(object: &mut Map<String, Value>, user_id: &str) -> bool {
+    if object.contains_key("user") {
+        return false;
+    }
+    object.insert("user".to_string(), json!(user_id));
+    true
+}
+
+/// Hash of a prompt with its whitespace normalized (FNV-1a), so re-sent prompts are recognised
+fn fingerprint(text: &str) -> u64 {
+    text.split_whitespace()
+        .flat_map(|word| word.bytes().chain(std::iter::once(b' ')))
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Puts `prompt` first in `messages`; returns whether it was inserted.
+///
+/// Bodies without a `messages` array are left alone, as are conversations already opening
+/// with the same system prompt (clients replaying the history the proxy returned).
+fn insert_system_prompt(object: &mut Map<String, Value>, prompt: &str) -> bool {
+    let Some(messages) = object.get_mut("messages").and_then(Value::as_array_mut) else {
+        return false;
+    };
+
+    let first = messages.first();
+    let already_sent = first.is_some_and(|message| {
+        message["role"] == "system"
+            && message["content"]
+                .as_str()
+                .is_some_and(|content| fingerprint(content) == fingerprint(prompt))
+    });
+    if already_sent {
+        return false;
+    }
+
+    messages.insert(0, json!({"role": "system", "content": prompt}));
+    true
+}
+
+/// Completion size fields, `max_tokens` being the older name
+const MAX_TOKENS_FIELDS: [&str; 2] = ["max_completion_tokens", "max_tokens"];
+
+/// Lowers the completion size to `cap`, adding `max_tokens` when the client set none.
+///
+/// Only completion-shaped bodies (with `messages` or `prompt`) are capped, so embeddings
+/// and the like pass untouched. Returns the cap when it changed the body.
+fn cap_max_tokens(object: &mut Map<String, Value>, cap: u32) -> Option<u32> {
+    if !object.contains_key("messages") && !object.contains_key("prompt") {
+        return None;
+    }
+
+    let mut present = false;
+    let mut capped = false;
+    for field in MAX_TOKENS_FIELDS {
+        if let Some(value) = object.get_mut(field) {
+            present = true;
+            if value.as_u64().is_none_or(|tokens| tokens > u64::from(cap)) {
+                *value = json!(cap);
+                capped = true;
+            }
+        }
+    }
+    if !present {
+        object.insert("max_tokens".to_string(), json!(cap));
+        capped = true;
+    }
+
+    capped.then_some(cap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(edits: &BodyEdits, body: &str) -> Option<Value> {
+        let edited = edits.apply(body.as_bytes()).unwrap()?;
+        Some(serde_json::from_slice(&edited.body).unwrap())
+    }
+
+    fn usage() -> BodyEdits<'static> {
+        BodyEdits {
+            include_usage: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_edits() {
+        let edits = BodyEdits::default();
+        assert!(edits.is_empty());
+        // Not even parsed
+        assert_eq!(edits.apply(b"not json"), Ok(None));
+    }
+
+    #[test]
+    fn test_include_usage() {
+        assert_eq!(
+            apply(&usage(), r#"{"model":"gpt-4o","stream":true}"#),
+            Some(
+                json!({"model": "gpt-4o", "stream": true, "stream_options": {"include_usage": true}})
+            )
+        );
+        assert_eq!(
+            apply(&usage(), " \r\n\t{\"stream\": true}\n"),
+            Some(json!({"stream": true, "stream_options": {"include_usage": true}}))
+        );
+        assert_eq!(
+            apply(&usage(), "\u{feff}{\"stream\": true}"),
+            Some(json!({"stream": true, "stream_options": {"include_usage": true}}))
+        );
+    }
+
+    #[test]
+    fn test_include_usage_nested_objects() {
+        assert_eq!(
+            apply(
+                &usage(),
+                r#"{"stream":true,"messages":[{"role":"user","content":"{\"a\":1}"}],"stream_options":{"include_usage":false,"other":1}}"#,
+            ),
+            Some(json!({
+                "stream": true,
+                "messages": [{"role": "user", "content": "{\"a\":1}"}],
+                "stream_options": {"include_usage": true, "other": 1},
+            }))
+        );
+        assert_eq!(
+            apply(&usage(), r#"{"stream":true,"stream_options":null}"#),
+            Some(json!({"stream": true, "stream_options": {"include_usage": true}}))
+        );
+    }
+
+    #[test]
+    fn test_include_usage_merges_existing_stream_options() {
+        let cases = [
+            (
+                r#"{"stream":true,"stream_options":{"include_usage":false}}"#,
+                json!({"include_usage": true}),
+            ),
+            (
+                r#"{"stream":true,"stream_options":{"include_obfuscation":false}}"#,
+                json!({"include_obfuscation": false, "include_usage": true}),
+            ),
+            (r#"{"stream":true}"#, json!({"include_usage": true})),
+        ];
+
+        for (body, expected) in cases {
+            let edited = usage().apply(body.as_bytes()).unwrap().unwrap();
+            let edited = String::from_utf8(edited.body).unwrap();
+            assert_eq!(edited.matches("stream_options").count(), 1, "{edited}");
+            assert_eq!(
+                serde_json::from_str::<Value>(&edited).unwrap()["stream_options"],
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_non_objects() {
+        assert_eq!(
+            usage().apply(br#"[{"stream":true}]"#),
+            Err(BodyError::NotAnObject)
+        );
+        assert_eq!(usage().apply(b"\"stream\""), Err(BodyError::NotAnObject));
+        assert!(matches!(
+            usage().apply(b"{\"stream\": tru"),
+            Err(BodyError::InvalidJson(_))
+        ));
+        // The serde message tells the client where the body went wrong
+        let error = usage().apply(b"\n{\"stream\": true,}").unwrap_err();
+        assert!(error.to_string().contains("line 1 column"), "{error}");
+
+        // Without usage to request, other edits leave such bodies alone
+        let edits = BodyEdits {
+            user_id: Some("user-1"),
+            ..Default::default()
+        };
+        assert_eq!(edits.apply(b"[1, 2]"), Ok(None));
+    }
+
+    #[test]
+    fn test_user() {
+        let edits = BodyEdits {
+            user_id: Some("user-1"),
+            ..Default::default()
+        };
+        assert_eq!(
+            apply(&edits, r#"{"model":"gpt-4o"}"#),
+            Some(json!({"model": "gpt-4o", "user": "user-1"}))
+        );
+        // The client's own value wins
+        assert_eq!(apply(&edits, r#"{"user":"client-user"}"#), None);
+    }
+
+    #[test]
+    fn test_model() {
+        let edits = BodyEdits {
+            model: Some("gpt-4o-mini"),
+            ..Default::default()
+        };
+        assert_eq!(
+            apply(
+                &edits,
+                r#"{"model":"em-fast","messages":[{"role":"user","content":"hi"}]}"#
+            ),
+            Some(json!({"model": "gpt-4o-mini", "messages": [{"role": "user", "content": "hi"}]}))
+        );
+    }
+
+    #[test]
+    fn test_max_tokens_cap() {
+        let edits = BodyEdits {
+            max_tokens_cap: Some(1000),
+            ..Default::default()
+        };
+        let capped = |body: &str| {
+            let edited = edits.apply(body.as_bytes()).unwrap()?;
+            let value = serde_json::from_slice::<Value>(&edited.body).unwrap();
+            Some((value, edited.max_tokens_capped))
+        };
+
+        assert_eq!(
+            capped(r#"{"messages":[],"max_tokens":4000}"#),
+            Some((json!({"messages": [], "max_tokens": 1000}), Some(1000)))
+        );
+        assert_eq!(
+            capped(r#"{"messages":[],"max_completion_tokens":4000}"#),
+            Some((
+                json!({"messages": [], "max_completion_tokens": 1000}),
+                Some(1000)
+            ))
+        );
+        // Absent is unbounded, so the cap is added
+        assert_eq!(
+            capped(r#"{"messages":[]}"#),
+            Some((json!({"messages": [], "max_tokens": 1000}), Some(1000)))
+        );
+        assert_eq!(
+            capped(r#"{"prompt":"hi","max_tokens":null}"#),
+            Some((json!({"prompt": "hi", "max_tokens": 1000}), Some(1000)))
+        );
+        // Within the cap, or not a completion
+        assert_eq!(capped(r#"{"messages":[],"max_tokens":1000}"#), None);
+        assert_eq!(capped(r#"{"input":["a","b"]}"#), None);
+    }
+
+    #[test]
+    fn test_edits_in_one_pass() {
+        let edits = BodyEdits {
+            include_usage: true,
+            user_id: Some("user-1"),
+            model: Some("gpt-4o"),
+            max_tokens_cap: Some(256),
+            clamps: SamplingClamps {
+                temperature: Some(1.0),
+                top_p: None,
+            },
+            strip_fields: &["store".to_string(), "user".to_string()],
+            system_prompt: Some("Be brief"),
+        };
+        let edited = edits
+            .apply(br#"{"model":"em-gpt-4o","stream":true,"messages":[],"max_tokens":4096,"temperature":1.5,"user":"u","store":true}"#)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(edited.max_tokens_capped, Some(256));
+        assert_eq!(edited.stripped, vec!["store", "user"]);
+        assert_eq!(edited.system_prompt_chars, Some(8));
+        assert_eq!(
+            edited.clamped,
+            vec![Clamped {
+                field: "temperature",
+                from: 1.5,
+                to: 1.0
+            }]
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&edited.body).unwrap(),
+            json!({
+                "model": "gpt-4o",
+                "stream": true,
+                "messages": [{"role": "system", "content": "Be brief"}],
+                "max_tokens": 256,
+                "temperature": 1.0,
+                "stream_options": {"include_usage": true},
+                "user": "user-1",
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_clamp_max() {
+        assert_eq!(TEMPERATURE.parse_clamp_max("2.0"), Some(2.0));
+        assert_eq!(TEMPERATURE.parse_clamp_max(" 1\n"), Some(1.0));
+        assert_eq!(TOP_P.parse_clamp_max("1.5"), None);
+        assert_eq!(TOP_P.parse_clamp_max("-0.1"), None);
+        assert_eq!(TOP_P.parse_clamp_max("on"), None);
+    }
+
+    #[test]
+    fn test_sampling_rejected_without_clamping() {
+        let clamps = SamplingClamps::default();
+        assert!(!clamps.is_enabled());
+
+        assert_eq!(
+            clamps.check(Some(&json!(7)), None),
+            Err(BodyError::OutOfRange {
+                field: "temperature",
+                value: 7.0,
+                min: 0.0,
+                max: 2.0
+            })
+        );
+        let error = clamps
+            .check(Some(&json!(0.7)), Some(&json!(-0.5)))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`top_p` must be between 0 and 1, got -0.5"
+        );
+
+        // Within range, absent, or not a number
+        assert_eq!(clamps.check(Some(&json!(2)), Some(&json!(0))), Ok(()));
+        assert_eq!(clamps.check(None, None), Ok(()));
+        assert_eq!(clamps.check(Some(&json!("7")), None), Ok(()));
+    }
+
+    #[test]
+    fn test_sampling_clamped() {
+        let clamps = SamplingClamps {
+            temperature: Some(2.0),
+            top_p: Some(0.9),
+        };
+        // Clamped parameters pass the check whatever their value
+        assert_eq!(clamps.check(Some(&json!(7)), Some(&json!(3))), Ok(()));
+
+        let edits = BodyEdits {
+            clamps,
+            ..Default::default()
+        };
+        let edited = edits
+            .apply(br#"{"messages":[],"temperature":7,"top_p":-1}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&edited.body).unwrap(),
+            json!({"messages": [], "temperature": 2.0, "top_p": 0.0})
+        );
+        assert_eq!(
+            edited.clamped,
+            vec![
+                Clamped {
+                    field: "temperature",
+                    from: 7.0,
+                    to: 2.0
+                },
+                Clamped {
+                    field: "top_p",
+                    from: -1.0,
+                    to: 0.0
+                },
+            ]
+        );
+
+        // The configured bound may be tighter than the provider's
+        assert_eq!(
+            apply(&edits, r#"{"top_p":0.95}"#),
+            Some(json!({"top_p": 0.9}))
+        );
+        assert_eq!(apply(&edits, r#"{"temperature":0.7,"top_p":0.5}"#), None);
+    }
+
+    #[test]
+    fn test_strip_fields() {
+        let denied = [
+            "logit_bias".to_string(),
+            "store".to_string(),
+            "tools".to_string(),
+        ];
+        let edits = BodyEdits {
+            strip_fields: &denied,
+            ..Default::default()
+        };
+        let edited = edits
+            .apply(br#"{"messages":[],"store":true,"logit_bias":{"50256":-100},"metadata":{}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(edited.stripped, vec!["logit_bias", "store"]);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&edited.body).unwrap(),
+            json!({"messages": [], "metadata": {}})
+        );
+
+        // Nested fields of the same name are kept
+        assert_eq!(
+            apply(
+                &edits,
+                r#"{"messages":[{"role":"user","content":"hi","store":1}]}"#
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_messages() {
+        let system = json!({"role": "system", "content": "Be brief"});
+        let user = json!({"role": "user", "content": [{"type": "text", "text": "hi"}]});
+        assert_eq!(check_messages(&json!([system, user])), Ok(()));
+
+        let invalid = |messages: Value| check_messages(&messages).unwrap_err();
+        assert_eq!(
+            invalid(json!([])),
+            BodyError::InvalidMessages {
+                index: None,
+                reason: "must not be empty"
+            }
+        );
+        assert_eq!(
+            invalid(json!({"role": "user"})).to_string(),
+            "`messages` must be an array"
+        );
+        assert_eq!(
+            invalid(json!([system, {"content": "hi"}])),
+            BodyError::InvalidMessages {
+                index: Some(1),
+                reason: "must have a `role` string"
+            }
+        );
+        assert_eq!(
+            invalid(json!([user, {"role": 1}])).to_string(),
+            "`messages[1]` must have a `role` string"
+        );
+        assert_eq!(
+            invalid(json!(["hi"])).to_string(),
+            "`messages[0]` must be an object"
+        );
+    }
+
+    #[test]
+    fn test_system_prompt() {
+        let edits = BodyEdits {
+            system_prompt: Some("Only answer questions about pricing."),
+            ..Default::default()
+        };
+        let edited = edits
+            .apply(br#"{"messages":[{"role":"user","content":"hi"}]}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(edited.system_prompt_chars, Some(36));
+        assert_eq!(
+            serde_json::from_slice::<Value>(&edited.body).unwrap(),
+            json!({"messages": [
+                {"role": "system", "content": "Only answer questions about pricing."},
+                {"role": "user", "content": "hi"},
+            ]})
+        );
+
+        // Already there, give or take whitespace
+        assert_eq!(
+            apply(
+                &edits,
+                r#"{"messages":[{"role":"system","content":"Only answer questions\nabout pricing. "}]}"#
+            ),
+            None
+        );
+        // A different system prompt stays behind ours
+        let edited = apply(
+            &edits,
+            r#"{"messages":[{"role":"system","content":"Be brief"}]}"#,
+        );
+        assert_eq!(edited.unwrap()["messages"].as_array().unwrap().len(), 2);
+        // Not a conversation
+        assert_eq!(apply(&edits, r#"{"input":"hi"}"#), None);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint("a  b\n c "), fingerprint("a b c"));
+        assert_ne!(fingerprint("ab c"), fingerprint("a bc"));
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::Serialize;
+use worker::*;
+
+/// Crate version baked in at compile time
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short git SHA of the commit the binary was built from (set by build.rs)
+pub const GIT_SHA: &str = env!("LANGPROXY_GIT_SHA");
+/// Unix timestamp (seconds) of when the binary was built (set by build.rs)
+pub const BUILD_TIMESTAMP: &str = env!("LANGPROXY_BUILD_TIMESTAMP");
+/// Deployment identifier recorded on analytics events when none is configured
+pub const DEPLOYMENT: &str = "cloudflare-worker";
+/// Wrangler var naming the deployment, e.g. `prod-eu`
+pub const DEPLOYMENT_VAR: &str = "DEPLOYMENT_NAME";
+/// Compact build identifier, e.g. `0.1.0+1a2b3c4d5e6f`
+pub const BUILD_ID: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("LANGPROXY_GIT_SHA"));
+/// Response header carrying `BUILD_ID` on every `stream_proxy` response, errors included
+pub const VERSION_HEADER: &str = "X-LangProxy-Version";
+
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub build_timestamp: &'static str,
+    pub deployment: &'static str,
+}
+
+impl BuildInfo {
+    pub const fn current() -> Self {
+        Self {
+            version: VERSION,
+            git_sha: GIT_SHA,
+            build_timestamp: BUILD_TIMESTAMP,
+            deployment: DEPLOYMENT,
+        }
+    }
+}
+
+/// Picks the deployment of a request: the `dep` parameter, then `DEPLOYMENT_NAME`, then `DEPLOYMENT`
+pub fn deployment(env: &Env, param: Option<&str>) -> String {
+    let var = env.var(DEPLOYMENT_VAR).ok().map(|var| var.to_string());
+    resolve_deployment(param, var.as_deref()).to_string()
+}
+
+fn resolve_deployment<'a>(param: Option<&'a str>, var: Option<&'a str>) -> &'a str {
+    [param, var]
+        .into_iter()
+        .flatten()
+        .find(|value| !value.trim().is_empty())
+        .unwrap_or(DEPLOYMENT)
+}
+
+/// Returns the build metadata of the running binary
+pub async fn handle_version(_req: Request, _ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    Response::from_json(&BuildInfo::current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_id_format() {
+        assert_eq!(BUILD_ID, format!("{VERSION}+{GIT_SHA}"));
+        assert!(!GIT_SHA.is_empty());
+        assert!(BUILD_TIMESTAMP.parse::<u64>().is_ok());
+    }
+
+    #[test]
+    fn test_build_info_serialization() {
+        let json = serde_json::to_value(BuildInfo::current()).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["deployment"], "cloudflare-worker");
+        assert_eq!(json["git_sha"], GIT_SHA);
+    }
+
+    #[test]
+    fn test_resolve_deployment() {
+        assert_eq!(resolve_deployment(None, None), DEPLOYMENT);
+        assert_eq!(resolve_deployment(None, Some("prod-eu")), "prod-eu");
+        assert_eq!(
+            resolve_deployment(Some("canary"), Some("prod-eu")),
+            "canary"
+        );
+        assert_eq!(resolve_deployment(Some(""), Some("prod-eu")), "prod-eu");
+        assert_eq!(resolve_deployment(None, Some(" ")), DEPLOYMENT);
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde::Deserialize;
+
+use crate::json_stream::JsonObjectSplitter;
+use crate::usage_extractor::{Extracted, ExtractedUsage, UsageExtractor};
+
+/// `event_type` of the last object of a Cohere chat stream
+const STREAM_END: &str = "stream-end";
+
+/// Tokens Cohere bills for; sent as numbers that may carry a fraction (`12.0`)
+#[derive(Debug, Default, Deserialize)]
+struct BilledUnits {
+    #[serde(default)]
+    input_tokens: f64,
+    #[serde(default)]
+    output_tokens: f64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CohereMeta {
+    #[serde(default)]
+    billed_units: BilledUnits,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CohereResponse {
+    #[serde(default)]
+    meta: Option<CohereMeta>,
+}
+
+/// The parts of a Cohere chat stream object needed for analytics
+#[derive(Debug, Deserialize)]
+struct CohereEvent {
+    #[serde(default)]
+    event_type: Option<String>,
+    /// `COMPLETE`, `MAX_TOKENS`, `ERROR`...; on `stream-end` only
+    #[serde(default)]
+    finish_reason: Option<String>,
+    /// The whole response on `stream-end`, with its `meta`
+    #[serde(default)]
+    response: Option<CohereResponse>,
+    /// Where some deployments put `meta` instead
+    #[serde(default)]
+    meta: Option<CohereMeta>,
+}
+
+/// Token usage mapped onto the OpenAI-style columns used by `UsageAnalytics`
+#[derive(Debug, PartialEq)]
+pub struct CohereUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    /// The stream's `finish_reason`, recorded as is
+    pub finish_reason: Option<String>,
+}
+
+/// Scans a Cohere chat stream (newline-delimited JSON, or SSE) for the billed units of its
+/// `stream-end` object
+#[derive(Debug, Default)]
+pub struct CohereUsageScanner {
+    splitter: JsonObjectSplitter,
+    done: bool,
+}
+
+impl CohereUsageScanner {
+    /// Feeds a network chunk, returning the usage once the `stream-end` object is complete
+    pub fn push(&mut self, chunk: &[u8]) -> Option<CohereUsage> {
+        let mut usage = None;
+        for object in self.splitter.push(chunk) {
+            let Ok(event) = serde_json::from_slice::<CohereEvent>(&object) else {
+                continue;
+            };
+            if self.done || event.event_type.as_deref() != Some(STREAM_END) {
+                continue;
+            }
+
+            self.done = true;
+            let meta = event
+                .response
+                .and_then(|response| response.meta)
+                .or(event.meta);
+            let billed = meta.unwrap_or_default().billed_units;
+            let (input, output) = (billed.input_tokens as u32, billed.output_tokens as u32);
+            usage = Some(CohereUsage {
+                prompt_tokens: input,
+                completion_tokens: output,
+                total_tokens: input.saturating_add(output),
+                finish_reason: event.finish_reason,
+            });
+        }
+        usage
+    }
+}
+
+impl From<CohereUsage> for ExtractedUsage {
+    fn from(usage: CohereUsage) -> Self {
+        ExtractedUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            finish_reason: usage.finish_reason,
+            ..ExtractedUsage::default()
+        }
+    }
+}
+
+/// Cohere streams newline-delimited JSON, or SSE on some deployments, so chunks are read whole
+impl UsageExtractor for CohereUsageScanner {
+    fn reads_chunks(&self) -> bool {
+        true
+    }
+
+    fn feed_chunk(&mut self, chunk: &[u8]) -> Option<Extracted> {
+        self.push(chunk).map(|usage| Ok(usage.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scanner_fixture() {
+        let stream = include_bytes!("../fixtures/cohere_chat_stream.jsonl");
+        for chunk_len in [1, 11, stream.len()] {
+            let mut scanner = CohereUsageScanner::default();
+            let usage = stream
+                .chunks(chunk_len)
+                .filter_map(|chunk| scanner.push(chunk))
+                .collect::<Vec<_>>();
+
+            assert_eq!(
+                usage,
+                vec![CohereUsage {
+                    prompt_tokens: 67,
+                    completion_tokens: 16,
+                    total_tokens: 83,
+                    finish_reason: Some("COMPLETE".to_string()),
+                }],
+                "chunks of {chunk_len}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_scanner_meta_shapes() {
+        // `meta` beside the response rather than in it, with fractional units
+        let mut scanner = CohereUsageScanner::default();
+        let event = concat!(
+            r#"{"event_type":"stream-end","finish_reason":"MAX_TOKENS","#,
+            r#""meta":{"billed_units":{"input_tokens":12.0,"output_tokens":5.0}}}"#,
+            "\n",
+        );
+        let usage = scanner.push(event.as_bytes());
+        assert_eq!(
+            usage,
+            Some(CohereUsage {
+                prompt_tokens: 12,
+                completion_tokens: 5,
+                total_tokens: 17,
+                finish_reason: Some("MAX_TOKENS".to_string()),
+            })
+        );
+        // Only the first `stream-end` counts
+        assert_eq!(scanner.push(b"{\"event_type\":\"stream-end\"}\n"), None);
+
+        // Without billed units the stream still ends with a (zero) record
+        let mut scanner = CohereUsageScanner::default();
+        let usage = scanner.push(b"{\"event_type\":\"stream-end\",\"response\":{}}\n");
+        assert_eq!(usage.map(|usage| usage.total_tokens), Some(0));
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::io::{Read, Write};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use flate2::write::{GzDecoder as GzWriteDecoder, GzEncoder};
+use flate2::Compression;
+
+use crate::BodyError;
+
+/// Set to `1`/`true` to gzip decompressed request bodies again before forwarding them
+pub const RECOMPRESS_VAR: &str = "RECOMPRESS_UPSTREAM_BODY";
+
+/// `accept-encoding` sent upstream: only what `ResponseDecoder` can undo
+pub const ACCEPTED_RESPONSE_ENCODINGS: &str = "gzip";
+
+/// The first bytes of every gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// `content-encoding` of a request body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    /// Parses the `content-encoding` header; encodings other than gzip and deflate are refused
+    pub fn from_header(value: Option<&str>) -> Result<Self, BodyError> {
+        let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+            return Ok(ContentEncoding::Identity);
+        };
+
+        match value.to_ascii_lowercase().as_str() {
+            "identity" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            _ => Err(BodyError::UnsupportedEncoding(value.to_string())),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    /// Decompresses `body`, reading at most one byte past `limit` so the caller can refuse
+    /// bodies that only exceed the limit once inflated.
+    pub fn decode(&self, body: Vec<u8>, limit: usize) -> Result<Vec<u8>, BodyError> {
+        let read = |reader: &mut dyn Read| {
+            let mut decoded = Vec::new();
+            reader
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decoded)
+                .map(|_| decoded)
+                .map_err(|e| BodyError::CorruptEncoding {
+                    encoding: self.name(),
+                    error: e.to_string(),
+                })
+        };
+
+        match self {
+            ContentEncoding::Identity => Ok(body),
+            ContentEncoding::Gzip => read(&mut GzDecoder::new(body.as_slice())),
+            // `deflate` is meant to be zlib-wrapped, but some clients send raw deflate
+            ContentEncoding::Deflate => read(&mut ZlibDecoder::new(body.as_slice()))
+                .or_else(|e| read(&mut DeflateDecoder::new(body.as_slice())).map_err(|_| e)),
+        }
+    }
+}
+
+/// Whether `RECOMPRESS_UPSTREAM_BODY` asks for the forwarded body to be gzipped
+pub fn recompress(var: Option<&str>) -> bool {
+    matches!(var.map(str::trim), Some("1" | "true"))
+}
+
+pub fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+enum DecoderState {
+    /// Holding the first bytes until they tell whether the body is gzipped
+    Sniffing(Vec<u8>),
+    Gzip(GzWriteDecoder<Vec<u8>>),
+    Plain,
+}
+
+/// Incrementally gunzips an upstream response body sent with `content-encoding: gzip`.
+///
+/// The runtime's fetch may have decoded the body already and kept the header, so only a body
+/// that starts like gzip is decoded; anything else, and any other encoding, passes through.
+pub struct ResponseDecoder {
+    state: DecoderState,
+}
+
+impl ResponseDecoder {
+    pub fn new(content_encoding: Option<&str>) -> Self {
+        let state = match ContentEncoding::from_header(content_encoding) {
+            Ok(ContentEncoding::Gzip) => DecoderState::Sniffing(Vec::new()),
+            _ => DecoderState::Plain,
+        };
+        Self { state }
+    }
+
+    /// Feeds a chunk of the body, returning what it decodes to so far
+    pub fn push(&mut self, chunk: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match &mut self.state {
+            DecoderState::Plain => Ok(chunk),
+            DecoderState::Gzip(decoder) => {
+                decoder.write_all(&chunk)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            DecoderState::Sniffing(start) => {
+                start.extend(chunk);
+                if start.len() < GZIP_MAGIC.len() && GZIP_MAGIC.starts_with(start) {
+                    return Ok(Vec::new());
+                }
+
+                let start = std::mem::take(start);
+                self.state = if start.starts_with(&GZIP_MAGIC) {
+                    DecoderState::Gzip(GzWriteDecoder::new(Vec::new()))
+                } else {
+                    DecoderState::Plain
+                };
+                self.push(start)
+            }
+        }
+    }
+
+    /// What's left once the body ended; fails if a gzip stream was cut short
+    pub fn finish(&mut self) -> std::io::Result<Vec<u8>> {
+        match std::mem::replace(&mut self.state, DecoderState::Plain) {
+            DecoderState::Sniffing(start) => Ok(start),
+            DecoderState::Gzip(decoder) => decoder.finish(),
+            DecoderState::Plain => Ok(Vec::new()),
+        }
+    }
+
+    /// Decodes a body read whole
+    pub fn decode(mut self, body: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut decoded = self.push(body)?;
+        decoded.extend(self.finish()?);
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, ZlibEncoder};
+    use serde_json::{json, Value};
+
+    use crate::body::BodyEdits;
+
+    const CHAT: &str =
+        r#"{"model":"gpt-4o","stream":true,"messages":[{"role":"user","content":"hi"}]}"#;
+
+    #[test]
+    fn test_from_header() {
+        assert_eq!(
+            ContentEncoding::from_header(None),
+            Ok(ContentEncoding::Identity)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some("GZIP")),
+            Ok(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some(" deflate ")),
+            Ok(ContentEncoding::Deflate)
+        );
+        assert_eq!(
+            ContentEncoding::from_header(Some("br")),
+            Err(BodyError::UnsupportedEncoding("br".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_gzip_round_trip_through_edits() {
+        let compressed = gzip(CHAT.as_bytes()).unwrap();
+        let body = ContentEncoding::Gzip.decode(compressed, 1024).unwrap();
+        assert_eq!(body, CHAT.as_bytes());
+
+        let edits = BodyEdits {
+            include_usage: true,
+            ..Default::default()
+        };
+        let edited = edits.apply(&body).unwrap().unwrap();
+
+        // Recompressed for the upstream, the edit survives
+        let forwarded = gzip(&edited.body).unwrap();
+        let forwarded = ContentEncoding::Gzip.decode(forwarded, 1024).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&forwarded).unwrap()["stream_options"],
+            json!({"include_usage": true})
+        );
+    }
+
+    #[test]
+    fn test_deflate_zlib_and_raw() {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(CHAT.as_bytes()).unwrap();
+        let decoded = ContentEncoding::Deflate.decode(zlib.finish().unwrap(), 1024);
+        assert_eq!(decoded.unwrap(), CHAT.as_bytes());
+
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(CHAT.as_bytes()).unwrap();
+        let decoded = ContentEncoding::Deflate.decode(raw.finish().unwrap(), 1024);
+        assert_eq!(decoded.unwrap(), CHAT.as_bytes());
+    }
+
+    #[test]
+    fn test_corrupt_body() {
+        let error = ContentEncoding::Gzip
+            .decode(CHAT.as_bytes().to_vec(), 1024)
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid gzip body"),
+            "{error}"
+        );
+
+        // Cut short
+        let mut compressed = gzip(CHAT.as_bytes()).unwrap();
+        compressed.truncate(compressed.len() / 2);
+        assert!(matches!(
+            ContentEncoding::Gzip.decode(compressed, 1024),
+            Err(BodyError::CorruptEncoding {
+                encoding: "gzip",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_decode_stops_past_limit() {
+        let compressed = gzip(&[b' '; 10_000]).unwrap();
+        let decoded = ContentEncoding::Gzip.decode(compressed, 100).unwrap();
+        assert_eq!(decoded.len(), 101);
+    }
+
+    /// What `decoder` makes of `body` fed in `chunk_len` byte chunks
+    fn decoded(mut decoder: ResponseDecoder, body: &[u8], chunk_len: usize) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        for chunk in body.chunks(chunk_len) {
+            decoded.extend(decoder.push(chunk.to_vec()).unwrap());
+        }
+        decoded.extend(decoder.finish().unwrap());
+        decoded
+    }
+
+    #[test]
+    fn test_response_decoder() {
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let compressed = gzip(stream).unwrap();
+        for chunk_len in [1, 2, 3, 64, compressed.len()] {
+            let decoder = ResponseDecoder::new(Some("gzip"));
+            assert_eq!(decoded(decoder, &compressed, chunk_len), stream);
+        }
+
+        // Already decoded by the runtime, or never encoded: relayed as it is
+        for chunk_len in [1, 2, stream.len()] {
+            let decoder = ResponseDecoder::new(Some("gzip"));
+            assert_eq!(decoded(decoder, stream, chunk_len), stream);
+            let decoder = ResponseDecoder::new(None);
+            assert_eq!(decoded(decoder, &compressed, chunk_len), compressed);
+        }
+        assert_eq!(
+            decoded(ResponseDecoder::new(Some("gzip")), &[0x1f], 1),
+            [0x1f]
+        );
+        assert_eq!(decoded(ResponseDecoder::new(Some("gzip")), b"", 1), b"");
+        let decoder = ResponseDecoder::new(Some("gzip"));
+        assert_eq!(decoder.decode(compressed.clone()).unwrap(), stream);
+
+        // Cut short
+        let mut decoder = ResponseDecoder::new(Some("gzip"));
+        decoder
+            .push(compressed[..compressed.len() - 4].to_vec())
+            .unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn test_recompress() {
+        assert!(recompress(Some("1")));
+        assert!(recompress(Some("true")));
+        assert!(!recompress(Some("0")));
+        assert!(!recompress(None));
+    }
+}
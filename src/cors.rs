@@ -0,0 +1,306 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use worker::*;
+
+use crate::upstream;
+
+/// Env var holding the comma-separated origins browsers may call from: exact origins
+/// (`https://www.example.com`), subdomain wildcards (`*.everymundo.com`) or `*` for any
+pub const ALLOWED_ORIGINS_VAR: &str = "ALLOWED_ORIGINS";
+/// Allowlist used when `ALLOWED_ORIGINS` is unset
+const DEFAULT_ALLOWED_ORIGINS: &str = "*";
+/// Methods accepted by the proxy routes
+pub const ALLOW_METHODS: &str = "GET, POST, OPTIONS";
+/// How long (in seconds) browsers may cache a successful preflight
+pub const MAX_AGE: &str = "86400";
+
+/// Request headers that are always allowed, regardless of what the browser asks for
+const BASE_ALLOW_HEADERS: [&str; 3] = ["api-key", "authorization", "content-type"];
+
+/// Response headers browsers may read: upstream request ids and rate limits, and our own
+const EXPOSE_HEADERS: [&str; 16] = [
+    "x-request-id",
+    "apim-request-id",
+    "x-ms-region",
+    "openai-processing-ms",
+    "retry-after",
+    "retry-after-ms",
+    "x-ratelimit-limit-requests",
+    "x-ratelimit-limit-tokens",
+    "x-ratelimit-remaining-requests",
+    "x-ratelimit-remaining-tokens",
+    "x-ratelimit-reset-requests",
+    "x-ratelimit-reset-tokens",
+    "x-langproxy-build",
+    "x-langproxy-version",
+    "x-langproxy-maxtokens-capped",
+    "x-langproxy-request-id",
+];
+
+/// The response CORS headers are built for
+#[derive(Debug, Clone, Copy)]
+pub enum CorsResponse<'a> {
+    /// An OPTIONS preflight, with the request's `Access-Control-Request-Headers`
+    Preflight { requested: Option<&'a str> },
+    /// A proxied response
+    Actual,
+}
+
+/// Whether `origin` matches one allowlist entry; wildcards match the origin's host, any port
+fn origin_matches(origin: &str, pattern: &str) -> bool {
+    if pattern == "*" || origin == pattern {
+        return true;
+    }
+    if !pattern.starts_with("*.") {
+        return false;
+    }
+    let host = origin.split_once("://").map_or(origin, |(_, host)| host);
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+    upstream::host_matches(host, pattern)
+}
+
+/// The request's `Origin` when `allowlist` allows it, to be echoed back; `None` means the
+/// response gets no CORS headers
+pub fn allowed_origin(origin: Option<&str>, allowlist: &str) -> Option<String> {
+    let origin = origin?.trim();
+    let lowercase = origin.to_ascii_lowercase();
+    let allowed = allowlist
+        .split(',')
+        .map(|entry| entry.trim().trim_end_matches('/').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| origin_matches(&lowercase, &entry));
+    (allowed && !origin.is_empty()).then(|| origin.to_string())
+}
+
+/// The origin of `req` the CORS headers answer, checked against `ALLOWED_ORIGINS`
+pub fn request_origin(req: &Request, env: &Env) -> Option<String> {
+    let origin = req.headers().get("Origin").ok().flatten();
+    let allowlist = env.var(ALLOWED_ORIGINS_VAR).ok().map(|var| var.to_string());
+    allowed_origin(
+        origin.as_deref(),
+        allowlist.as_deref().unwrap_or(DEFAULT_ALLOWED_ORIGINS),
+    )
+}
+
+/// The CORS headers of a response to `origin` (as `request_origin` allows it); preflights and
+/// proxied responses are built here alike, and get none for an origin that isn't allowed
+pub fn cors_headers(response: CorsResponse, origin: Option<&str>) -> Vec<(&'static str, String)> {
+    let Some(origin) = origin else {
+        return Vec::new();
+    };
+    let mut headers = vec![
+        ("Access-Control-Allow-Origin", origin.to_string()),
+        ("Vary", "Origin".to_string()),
+    ];
+
+    match response {
+        CorsResponse::Preflight { requested } => {
+            headers.push(("Access-Control-Allow-Methods", ALLOW_METHODS.to_string()));
+            headers.push(("Access-Control-Allow-Headers", allow_headers(requested)));
+            headers.push(("Access-Control-Max-Age", MAX_AGE.to_string()));
+        }
+        CorsResponse::Actual => {
+            headers.push(("Access-Control-Expose-Headers", EXPOSE_HEADERS.join(", ")));
+        }
+    }
+
+    headers
+}
+
+/// Sets the CORS headers of `response` to `origin` on `headers`
+pub fn set_headers(
+    headers: &mut Headers,
+    response: CorsResponse,
+    origin: Option<&str>,
+) -> Result<()> {
+    for (name, value) in cors_headers(response, origin) {
+        // Keeps whatever else the upstream varies on
+        if name == "Vary" {
+            headers.append(name, &value)?;
+        } else {
+            headers.set(name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the `Access-Control-Allow-Headers` value for a preflight.
+///
+/// The base headers are always present; any header listed in the request's
+/// `Access-Control-Request-Headers` is reflected back (lowercased, deduplicated).
+pub fn allow_headers(requested: Option<&str>) -> String {
+    let mut allowed: Vec<String> = BASE_ALLOW_HEADERS.iter().map(|h| h.to_string()).collect();
+
+    if let Some(requested) = requested {
+        for name in requested.split(',') {
+            let name = name.trim().to_ascii_lowercase();
+            if !name.is_empty() && !allowed.contains(&name) {
+                allowed.push(name);
+            }
+        }
+    }
+
+    allowed.join(", ")
+}
+
+/// Answers CORS preflight (OPTIONS) requests for the proxy routes with a 204
+pub async fn handle_preflight(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let requested = req
+        .headers()
+        .get("Access-Control-Request-Headers")
+        .ok()
+        .flatten();
+
+    let mut headers = Headers::new();
+    set_headers(
+        &mut headers,
+        CorsResponse::Preflight {
+            requested: requested.as_deref(),
+        },
+        request_origin(&req, &ctx.env).as_deref(),
+    )?;
+
+    Ok(Response::empty()?.with_status(204).with_headers(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_headers_defaults() {
+        assert_eq!(allow_headers(None), "api-key, authorization, content-type");
+        assert_eq!(
+            allow_headers(Some("")),
+            "api-key, authorization, content-type"
+        );
+    }
+
+    #[test]
+    fn test_allow_headers_reflects_requested() {
+        let value = allow_headers(Some("X-Custom-Header, OpenAI-Beta"));
+        assert_eq!(
+            value,
+            "api-key, authorization, content-type, x-custom-header, openai-beta"
+        );
+    }
+
+    #[test]
+    fn test_allow_headers_deduplicates() {
+        let value = allow_headers(Some("Api-Key,Content-Type , api-key"));
+        assert_eq!(value, "api-key, authorization, content-type");
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        let headers = cors_headers(
+            CorsResponse::Preflight {
+                requested: Some("X-EM-App"),
+            },
+            Some("https://fares.everymundo.com"),
+        );
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    "Access-Control-Allow-Origin",
+                    "https://fares.everymundo.com".to_string()
+                ),
+                ("Vary", "Origin".to_string()),
+                (
+                    "Access-Control-Allow-Methods",
+                    "GET, POST, OPTIONS".to_string()
+                ),
+                (
+                    "Access-Control-Allow-Headers",
+                    "api-key, authorization, content-type, x-em-app".to_string()
+                ),
+                ("Access-Control-Max-Age", "86400".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_actual_response_headers() {
+        assert_eq!(
+            cors_headers(CorsResponse::Actual, Some("https://www.example.com")),
+            vec![
+                (
+                    "Access-Control-Allow-Origin",
+                    "https://www.example.com".to_string()
+                ),
+                ("Vary", "Origin".to_string()),
+                (
+                    "Access-Control-Expose-Headers",
+                    "x-request-id, apim-request-id, x-ms-region, openai-processing-ms, \
+                     retry-after, retry-after-ms, x-ratelimit-limit-requests, \
+                     x-ratelimit-limit-tokens, x-ratelimit-remaining-requests, \
+                     x-ratelimit-remaining-tokens, x-ratelimit-reset-requests, \
+                     x-ratelimit-reset-tokens, x-langproxy-build, x-langproxy-version, \
+                     x-langproxy-maxtokens-capped, x-langproxy-request-id"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_headers_for_disallowed_origin() {
+        assert!(cors_headers(CorsResponse::Actual, None).is_empty());
+        let preflight = CorsResponse::Preflight { requested: None };
+        assert!(cors_headers(preflight, None).is_empty());
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let allowlist = "https://www.example.com, *.everymundo.com";
+
+        // Exact entries match the whole origin, whatever its case
+        assert_eq!(
+            allowed_origin(Some("https://www.example.com"), allowlist).as_deref(),
+            Some("https://www.example.com")
+        );
+        assert_eq!(
+            allowed_origin(Some("HTTPS://WWW.Example.com"), allowlist).as_deref(),
+            Some("HTTPS://WWW.Example.com")
+        );
+        assert_eq!(
+            allowed_origin(Some("http://www.example.com"), allowlist),
+            None
+        );
+        assert_eq!(allowed_origin(Some("https://example.com"), allowlist), None);
+
+        // Wildcards match any subdomain, on any port, but not the domain itself
+        assert_eq!(
+            allowed_origin(Some("https://fares.everymundo.com"), allowlist).as_deref(),
+            Some("https://fares.everymundo.com")
+        );
+        assert_eq!(
+            allowed_origin(Some("http://a.b.everymundo.com:8443"), allowlist).as_deref(),
+            Some("http://a.b.everymundo.com:8443")
+        );
+        assert_eq!(
+            allowed_origin(Some("https://everymundo.com"), allowlist),
+            None
+        );
+        assert_eq!(
+            allowed_origin(Some("https://evileverymundo.com"), allowlist),
+            None
+        );
+        assert_eq!(
+            allowed_origin(Some("https://everymundo.com.evil.io"), allowlist),
+            None
+        );
+
+        // `*` allows any origin, still echoed; no origin gets nothing
+        assert_eq!(
+            allowed_origin(Some("https://anywhere.io"), "*").as_deref(),
+            Some("https://anywhere.io")
+        );
+        assert_eq!(allowed_origin(None, "*"), None);
+        assert_eq!(allowed_origin(Some("https://anywhere.io"), ""), None);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{
+    cors, guard_request, proxy_response_headers, query_error_response, redact,
+    reject_disallowed_upstream, upstream, upstream_auth_headers, ProxyUrlParams, RequestMeta,
+    Usage,
+};
+
+/// The parts of an embeddings response needed for analytics; the vectors are ignored
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponseBody {
+    model: String,
+    usage: Usage,
+}
+
+/// Proxies an embeddings request and records its usage from the JSON response
+pub async fn embeddings_proxy(
+    mut req: Request,
+    ctx: RouteContext<Rc<Context>>,
+) -> Result<Response> {
+    let data = req.bytes().await?;
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let mut proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let status = response.status();
+    let origin = cors::request_origin(&req, &ctx.env);
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+
+    // Embedding batches can be several MB: read once, parse in place, hand the same buffer back
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            console_error!("Error reading upstream body: {}", e);
+            return Response::error("Internal Server Error!!!!!", 500);
+        }
+    };
+
+    if status.is_success() {
+        match serde_json::from_slice::<EmbeddingsResponseBody>(&body) {
+            Ok(parsed) => {
+                meta.usage_analytics(
+                    parsed.model,
+                    parsed.usage.prompt_tokens,
+                    parsed.usage.completion_tokens,
+                    parsed.usage.total_tokens,
+                )
+                .save(&ctx.env, &ctx.data)
+                .await
+            }
+            Err(e) => console_error!("Failed to parse embeddings usage: {e}"),
+        }
+    } else {
+        console_error!("Error {}", status);
+    }
+
+    Ok(Response::from_bytes(body.into())?
+        .with_status(status.as_u16())
+        .with_headers(my_response_headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embeddings_response_deserialization() {
+        let json_str = r#"{
+            "object": "list",
+            "data": [
+                {"object": "embedding", "index": 0, "embedding": [0.0023, -0.0093, 0.0158]},
+                {"object": "embedding", "index": 1, "embedding": [0.0101, 0.0042, -0.0077]}
+            ],
+            "model": "text-embedding-3-small",
+            "usage": {
+                "prompt_tokens": 8,
+                "total_tokens": 8
+            }
+        }"#;
+
+        let body: EmbeddingsResponseBody = serde_json::from_str(json_str).unwrap();
+        assert_eq!(body.model, "text-embedding-3-small");
+        assert_eq!(body.usage.prompt_tokens, 8);
+        assert_eq!(body.usage.completion_tokens, 0);
+        assert_eq!(body.usage.total_tokens, 8);
+    }
+
+    #[test]
+    fn test_embeddings_error_body_is_rejected() {
+        let json_str = r#"{"error": {"code": "429", "message": "Rate limit exceeded"}}"#;
+        assert!(serde_json::from_str::<EmbeddingsResponseBody>(json_str).is_err());
+    }
+}
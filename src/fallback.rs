@@ -0,0 +1,127 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde_json::json;
+use worker::*;
+
+/// Every route registered in `main`, with its methods; keep in sync with the router
+pub const ROUTES: &[(&str, &[&str])] = &[
+    ("/account/:id", &["GET", "PUT", "DELETE"]),
+    ("/accounts", &["GET"]),
+    ("/upload", &["POST"]),
+    ("/echo-bytes", &["POST"]),
+    ("/health", &["GET"]),
+    ("/version", &["GET"]),
+    ("/metrics", &["GET"]),
+    ("/usage/:tenantId", &["GET"]),
+    ("/proxy/universal", &["GET", "POST", "OPTIONS"]),
+    ("/azure-openai/completions", &["POST", "OPTIONS"]),
+    ("/anthropic/messages", &["POST", "OPTIONS"]),
+    ("/proxy/embeddings", &["POST", "OPTIONS"]),
+    ("/audio/transcriptions", &["POST", "OPTIONS"]),
+    ("/images/generations", &["POST", "OPTIONS"]),
+    ("/gemini/generate", &["POST"]),
+    ("/bedrock/invoke", &["POST"]),
+    ("/ollama/chat", &["POST"]),
+    ("/moderations", &["POST", "OPTIONS"]),
+    ("/batches", &["POST", "OPTIONS"]),
+    ("/batches/:id", &["GET", "OPTIONS"]),
+    ("/files", &["POST", "OPTIONS"]),
+    ("/realtime", &["GET"]),
+];
+
+/// Matches a request path against a route pattern, where `:name` matches one segment
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern_segments = pattern.trim_matches('/').split('/');
+    let mut path_segments = path.trim_matches('/').split('/');
+
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (None, None) => return true,
+            (Some(expected), Some(actual)) => {
+                let matches = if expected.starts_with(':') {
+                    !actual.is_empty()
+                } else {
+                    expected == actual
+                };
+                if !matches {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// The methods registered for `path`, or `None` when no route matches it
+pub fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    ROUTES
+        .iter()
+        .find(|(pattern, _)| path_matches(pattern, path))
+        .map(|(_, methods)| *methods)
+}
+
+/// Answers requests that no route handled: 405 for a known path, 404 otherwise
+pub async fn handle_fallback(req: Request, _ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let path = req.path();
+    let method = req.method().to_string();
+
+    match allowed_methods(&path) {
+        Some(methods) => {
+            let allow = methods.join(", ");
+            let mut headers = Headers::new();
+            headers.set("Allow", &allow)?;
+
+            Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "MethodNotAllowed",
+                "path": path,
+                "method": method,
+                "allowed": methods,
+            }))?
+            .with_status(405)
+            .with_headers(headers))
+        }
+        None => {
+            let routes = ROUTES.iter().map(|(path, _)| *path).collect::<Vec<_>>();
+
+            Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "NotFound",
+                "path": path,
+                "allowed": routes,
+            }))?
+            .with_status(404))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("/proxy/universal", "/proxy/universal"));
+        assert!(path_matches("/proxy/universal", "/proxy/universal/"));
+        assert!(path_matches("/batches/:id", "/batches/batch_123"));
+        assert!(!path_matches("/batches/:id", "/batches"));
+        assert!(!path_matches("/batches/:id", "/batches/batch_123/cancel"));
+        assert!(!path_matches("/proxy/universal", "/proxy"));
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        assert_eq!(
+            allowed_methods("/azure-openai/completions"),
+            Some(&["POST", "OPTIONS"][..])
+        );
+        assert_eq!(
+            allowed_methods("/batches/batch_1"),
+            Some(&["GET", "OPTIONS"][..])
+        );
+        assert_eq!(allowed_methods("/v1/chat/completions"), None);
+    }
+}
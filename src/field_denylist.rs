@@ -0,0 +1,68 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use worker::*;
+
+use crate::kv_cache;
+
+/// Comma-separated top-level request fields stripped for every tenant
+pub const STRIP_FIELDS_VAR: &str = "STRIP_FIELDS";
+/// KV namespace of extra fields to strip per tenant, keyed by tenant id
+pub const FIELD_DENYLIST_BINDING: &str = "FIELD_DENYLIST";
+
+/// Whether any request of this deployment may have fields stripped
+pub fn enabled(env: &Env) -> bool {
+    env.kv(FIELD_DENYLIST_BINDING).is_ok() || !global_fields(env).is_empty()
+}
+
+fn global_fields(env: &Env) -> Vec<String> {
+    env.var(STRIP_FIELDS_VAR)
+        .map(|value| parse_fields(&value.to_string()))
+        .unwrap_or_default()
+}
+
+/// Parses a comma-separated list of field names, dropping blanks and duplicates
+fn parse_fields(value: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    for field in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        if !fields.iter().any(|f| f == field) {
+            fields.push(field.to_string());
+        }
+    }
+    fields
+}
+
+/// The fields stripped from `tenant`'s requests: `STRIP_FIELDS` plus its `FIELD_DENYLIST` entry
+pub async fn resolve(env: &Env, tenant: Option<&str>) -> Vec<String> {
+    let mut fields = global_fields(env);
+
+    if let Some(tenant) = tenant.filter(|_| env.kv(FIELD_DENYLIST_BINDING).is_ok()) {
+        match kv_cache::get_text(env, FIELD_DENYLIST_BINDING, tenant).await {
+            Ok(Some(value)) => fields.extend(
+                parse_fields(&value)
+                    .into_iter()
+                    .filter(|field| !fields.contains(field))
+                    .collect::<Vec<_>>(),
+            ),
+            Ok(None) => {}
+            Err(e) => console_error!("FIELD_DENYLIST lookup of {} failed: {}", tenant, e),
+        }
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(
+            parse_fields("logit_bias, store,metadata"),
+            vec!["logit_bias", "store", "metadata"]
+        );
+        assert_eq!(parse_fields(" tools,,tools , "), vec!["tools"]);
+        assert!(parse_fields("").is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use http::HeaderMap;
+use worker::*;
+
+/// Comma-separated request headers copied to the upstream, replacing the default set
+pub const FORWARD_HEADERS_VAR: &str = "FORWARD_HEADERS";
+
+/// Headers forwarded when `FORWARD_HEADERS` isn't set
+const DEFAULT_FORWARD_HEADERS: [&str; 5] = [
+    "openai-organization",
+    "openai-project",
+    "openai-beta",
+    "anthropic-version",
+    "x-ms-client-request-id",
+];
+
+/// Headers meaningful for a single connection only (RFC 7230, section 6.1), never relayed
+pub const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Never forwarded, whatever the allowlist says, besides the hop-by-hop headers: the ones the
+/// upstream request sets itself, and the credentials `upstream_auth_headers` picks
+const EXCLUDED_HEADERS: [&str; 6] = [
+    "host",
+    "content-length",
+    "accept-encoding",
+    "api-key",
+    "x-api-key",
+    "authorization",
+];
+
+fn excluded(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name) || EXCLUDED_HEADERS.contains(&name)
+}
+
+/// Parses `FORWARD_HEADERS`, lowercased, dropping blanks and excluded headers
+fn allowlist(var: Option<&str>) -> Vec<String> {
+    let Some(var) = var else {
+        return DEFAULT_FORWARD_HEADERS.map(str::to_string).to_vec();
+    };
+
+    var.split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty() && !excluded(name))
+        .collect()
+}
+
+/// The allowed headers present in `incoming`, in allowlist order
+fn forwarded_headers(incoming: &HeaderMap, allowlist: &[String]) -> Vec<(String, String)> {
+    allowlist
+        .iter()
+        .filter(|name| !excluded(name))
+        .filter_map(|name| {
+            let value = incoming.get(name.as_str())?.to_str().ok()?;
+            Some((name.clone(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Copies the allowlisted headers of `req` onto the upstream request's headers
+pub fn copy(req: &Request, env: &Env, proxy_headers: &mut Headers) -> Result<()> {
+    let var = env.var(FORWARD_HEADERS_VAR).ok().map(|var| var.to_string());
+    let incoming = HeaderMap::from(req.headers());
+
+    for (name, value) in forwarded_headers(&incoming, &allowlist(var.as_deref())) {
+        proxy_headers.set(&name, &value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    http::HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        assert_eq!(allowlist(None), DEFAULT_FORWARD_HEADERS.to_vec());
+        assert_eq!(
+            allowlist(Some(
+                "OpenAI-Beta, x-custom ,,Connection,Authorization,Accept-Encoding"
+            )),
+            vec!["openai-beta", "x-custom"]
+        );
+        assert!(allowlist(Some("")).is_empty());
+    }
+
+    #[test]
+    fn test_forwarded_headers_defaults() {
+        let incoming = headers(&[
+            ("openai-organization", "org-1"),
+            ("openai-beta", "assistants=v2"),
+            ("x-ms-client-request-id", "abc"),
+            ("cookie", "session=1"),
+            ("x-forwarded-for", "10.0.0.1"),
+            ("api-key", "secret"),
+        ]);
+
+        assert_eq!(
+            forwarded_headers(&incoming, &allowlist(None)),
+            vec![
+                ("openai-organization".to_string(), "org-1".to_string()),
+                ("openai-beta".to_string(), "assistants=v2".to_string()),
+                ("x-ms-client-request-id".to_string(), "abc".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_excluded_headers_never_forwarded() {
+        let incoming = headers(&[
+            ("connection", "keep-alive"),
+            ("transfer-encoding", "chunked"),
+            ("authorization", "Bearer x"),
+            ("x-custom", "1"),
+        ]);
+        // Even when the list was built without `allowlist`
+        let allowed = [
+            "connection",
+            "transfer-encoding",
+            "authorization",
+            "x-custom",
+        ]
+        .map(str::to_string);
+
+        assert_eq!(
+            forwarded_headers(&incoming, &allowed),
+            vec![("x-custom".to_string(), "1".to_string())]
+        );
+    }
+}
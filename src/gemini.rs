@@ -0,0 +1,328 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures_util::StreamExt;
+use serde::Deserialize;
+use worker::*;
+
+use crate::json_stream::JsonObjectSplitter;
+use crate::usage_extractor::{Extracted, ExtractedUsage, UsageExtractor};
+use crate::{
+    cors, forward_upstream, guard_request, on_stream_end, provider::Provider,
+    proxy_response_headers, query_error_response, redact, reject_disallowed_upstream,
+    targets::AuthError, upstream, upstream_auth_headers, upstream_error_headers, ProxyUrlParams,
+    RequestMeta,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u32,
+    #[serde(default)]
+    candidates_token_count: u32,
+    #[serde(default)]
+    total_token_count: u32,
+    /// Tokens thinking models spend reasoning, counted in the total but not the candidates'
+    #[serde(default)]
+    thoughts_token_count: u32,
+}
+
+/// The parts of a `GenerateContentResponse` chunk needed for analytics
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiChunk {
+    #[serde(default)]
+    usage_metadata: Option<UsageMetadata>,
+    #[serde(default)]
+    model_version: Option<String>,
+}
+
+/// Token usage mapped onto the OpenAI-style columns used by `UsageAnalytics`
+#[derive(Debug, PartialEq)]
+pub struct GeminiUsage {
+    pub model: Option<String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    pub reasoning_tokens: u32,
+}
+
+/// Scans a Gemini response (SSE, JSON array or a single object) for `usageMetadata`.
+///
+/// Every chunk may carry cumulative usage, so the last one seen wins and is
+/// reported by `finish` once the upstream body is exhausted.
+#[derive(Debug, Default)]
+pub struct GeminiUsageScanner {
+    splitter: JsonObjectSplitter,
+    usage: Option<UsageMetadata>,
+    model: Option<String>,
+}
+
+impl GeminiUsageScanner {
+    pub fn push(&mut self, chunk: &[u8]) {
+        for object in self.splitter.push(chunk) {
+            if let Ok(parsed) = serde_json::from_slice::<GeminiChunk>(&object) {
+                if parsed.usage_metadata.is_some() {
+                    self.usage = parsed.usage_metadata;
+                }
+                if parsed.model_version.is_some() {
+                    self.model = parsed.model_version;
+                }
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> Option<GeminiUsage> {
+        let usage = self.usage.take()?;
+        Some(GeminiUsage {
+            model: self.model.take(),
+            prompt_tokens: usage.prompt_token_count,
+            completion_tokens: usage.candidates_token_count,
+            total_tokens: usage.total_token_count,
+            reasoning_tokens: usage.thoughts_token_count,
+        })
+    }
+}
+
+impl From<GeminiUsage> for ExtractedUsage {
+    fn from(usage: GeminiUsage) -> Self {
+        ExtractedUsage {
+            model: usage.model,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            reasoning_tokens: usage.reasoning_tokens,
+            ..ExtractedUsage::default()
+        }
+    }
+}
+
+/// The usage is only known to be final once the response ended, so chunks report none
+impl UsageExtractor for GeminiUsageScanner {
+    fn reads_chunks(&self) -> bool {
+        true
+    }
+
+    fn feed_chunk(&mut self, chunk: &[u8]) -> Option<Extracted> {
+        self.push(chunk);
+        None
+    }
+
+    fn finish(&mut self) -> Option<ExtractedUsage> {
+        GeminiUsageScanner::finish(self).map(ExtractedUsage::from)
+    }
+}
+
+/// Extracts the model name from a Gemini URL (`.../models/{model}:streamGenerateContent`)
+pub fn model_from_url(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("/models/")?;
+    let end = rest.find([':', '?', '/']).unwrap_or(rest.len());
+    Some(&rest[..end]).filter(|model| !model.is_empty())
+}
+
+/// Proxies Gemini `generateContent`/`streamGenerateContent` calls and records their usage
+pub async fn gemini_proxy(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let data = req.bytes().await?;
+    let env = ctx.env.clone();
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    // Gemini rejects unknown fields such as `stream_options`, so the body is forwarded untouched
+    let mut proxy_headers = match upstream_auth_headers(&req, &ctx.env, &meta, Provider::Gemini) {
+        Ok(headers) => headers,
+        // The key may also travel inside `u` as `?key=...`, so the header is optional
+        Err(AuthError::MissingCredential) => Headers::new(),
+        Err(e) => return e.to_response(),
+    };
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let url_model = model_from_url(&xparams.u).unwrap_or("unknown").to_string();
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    if !response.status().is_success() {
+        console_error!("Error {}", response.status());
+        let status = response.status();
+        let error_headers = upstream_error_headers(&response, origin.as_deref())?;
+        let text = &response.text().await;
+        let error = Response::error(format!("{:?}", &text), status.into())?;
+        return Ok(error.with_headers(error_headers));
+    }
+
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    let rx = forward_upstream(&env, response, xparams.sends_error_events());
+
+    let scanner = Rc::new(RefCell::new(GeminiUsageScanner::default()));
+    let scanning = scanner.clone();
+
+    let stream = rx.map(move |result| {
+        if let Ok(bytes) = &result {
+            scanning.borrow_mut().push(bytes);
+        }
+        result
+    });
+
+    // The upstream body is exhausted: the last usageMetadata is the final one
+    let event_ctx = ctx.data.clone();
+    let stream = on_stream_end(stream, move || {
+        let finished = scanner.borrow_mut().finish();
+        if let Some(usage) = finished {
+            console_log!("GEMINI USAGE: {:?}", usage);
+
+            let mut analytics = meta.usage_analytics(
+                usage.model.unwrap_or(url_model),
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens,
+            );
+            analytics.reasoning_tokens = usage.reasoning_tokens;
+
+            // Save analytics data asynchronously (fire-and-forget)
+            analytics.save_in_background(&env, &event_ctx);
+        }
+    });
+
+    match Response::from_stream(stream) {
+        Ok(resp) => Ok(resp.with_headers(my_response_headers)),
+        Err(e) => {
+            console_error!("Error creating streaming response: {}", e);
+            Response::error("Internal Server Error!!!!!", 500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GEMINI_SSE: &str = concat!(
+        "data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"Hello\"}],\"role\": \"model\"}}],\"usageMetadata\": {\"promptTokenCount\": 9,\"totalTokenCount\": 9},\"modelVersion\": \"gemini-2.0-flash\"}\r\n\r\n",
+        "data: {\"candidates\": [{\"content\": {\"parts\": [{\"text\": \" there {friend}\"}],\"role\": \"model\"},\"finishReason\": \"STOP\"}],\"usageMetadata\": {\"promptTokenCount\": 9,\"candidatesTokenCount\": 12,\"totalTokenCount\": 21},\"modelVersion\": \"gemini-2.0-flash\"}\r\n\r\n",
+    );
+
+    const GEMINI_JSON_ARRAY: &str = concat!(
+        "[{\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"Hello\"}],\"role\": \"model\"}}],\"usageMetadata\": {\"promptTokenCount\": 9,\"totalTokenCount\": 9},\"modelVersion\": \"gemini-1.5-pro-002\"}\r\n",
+        ",\r\n",
+        "{\"candidates\": [{\"content\": {\"parts\": [{\"text\": \"!\"}],\"role\": \"model\"},\"finishReason\": \"STOP\"}],\"usageMetadata\": {\"promptTokenCount\": 9,\"candidatesTokenCount\": 4,\"totalTokenCount\": 13},\"modelVersion\": \"gemini-1.5-pro-002\"}\r\n",
+        "]",
+    );
+
+    #[test]
+    fn test_scanner_sse() {
+        let mut scanner = GeminiUsageScanner::default();
+        let bytes = GEMINI_SSE.as_bytes();
+        scanner.push(&bytes[..100]);
+        scanner.push(&bytes[100..]);
+
+        assert_eq!(
+            scanner.finish(),
+            Some(GeminiUsage {
+                model: Some("gemini-2.0-flash".to_string()),
+                prompt_tokens: 9,
+                completion_tokens: 12,
+                total_tokens: 21,
+                reasoning_tokens: 0,
+            })
+        );
+        assert_eq!(scanner.finish(), None);
+    }
+
+    #[test]
+    fn test_scanner_json_array() {
+        let mut scanner = GeminiUsageScanner::default();
+        for byte in GEMINI_JSON_ARRAY.as_bytes().chunks(7) {
+            scanner.push(byte);
+        }
+
+        assert_eq!(
+            scanner.finish(),
+            Some(GeminiUsage {
+                model: Some("gemini-1.5-pro-002".to_string()),
+                prompt_tokens: 9,
+                completion_tokens: 4,
+                total_tokens: 13,
+                reasoning_tokens: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_scanner_thoughts_fixtures() {
+        let fixtures = [
+            include_bytes!("../fixtures/gemini_sse_stream.txt").as_slice(),
+            include_bytes!("../fixtures/gemini_json_stream.json").as_slice(),
+        ];
+        for fixture in fixtures {
+            for chunk_len in [1, 13, fixture.len()] {
+                let mut scanner = GeminiUsageScanner::default();
+                for chunk in fixture.chunks(chunk_len) {
+                    scanner.push(chunk);
+                }
+
+                assert_eq!(
+                    scanner.finish(),
+                    Some(GeminiUsage {
+                        model: Some("gemini-2.5-flash".to_string()),
+                        prompt_tokens: 58,
+                        completion_tokens: 17,
+                        total_tokens: 187,
+                        reasoning_tokens: 112,
+                    })
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_scanner_without_usage() {
+        let mut scanner = GeminiUsageScanner::default();
+        scanner.push(b"[{\"candidates\": []}]");
+        assert_eq!(scanner.finish(), None);
+    }
+
+    #[test]
+    fn test_model_from_url() {
+        assert_eq!(
+            model_from_url("https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:streamGenerateContent?alt=sse"),
+            Some("gemini-2.0-flash")
+        );
+        assert_eq!(
+            model_from_url("https://generativelanguage.googleapis.com/v1beta/models/gemini-pro"),
+            Some("gemini-pro")
+        );
+        assert_eq!(model_from_url("https://example.com/v1/chat"), None);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::Serialize;
+use worker::*;
+
+use crate::accounts::ACCOUNTS_BINDING;
+use crate::analytics::ANALYTICS_BINDING;
+use crate::build_info::VERSION;
+
+/// Worker name as deployed (matches `name` in wrangler.toml)
+pub const WORKER_NAME: &str = "langproxy-rs";
+
+/// Bindings that must resolve from `Env` for the worker to be considered healthy
+const REQUIRED_BINDINGS: [&str; 2] = [ACCOUNTS_BINDING, ANALYTICS_BINDING];
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub worker: &'static str,
+    pub version: &'static str,
+    pub healthy: bool,
+    pub missing_bindings: Vec<&'static str>,
+}
+
+impl HealthReport {
+    /// Builds a report from the list of bindings that failed to resolve
+    pub fn new(missing_bindings: Vec<&'static str>) -> Self {
+        Self {
+            worker: WORKER_NAME,
+            version: VERSION,
+            healthy: missing_bindings.is_empty(),
+            missing_bindings,
+        }
+    }
+
+    /// HTTP status the report should be served with
+    pub fn status(&self) -> u16 {
+        if self.healthy {
+            200
+        } else {
+            503
+        }
+    }
+}
+
+/// Checks whether a binding with the given name is present on `Env`
+fn binding_exists(env: &Env, name: &str) -> bool {
+    js_sys::Reflect::get(env, &name.into())
+        .map(|binding| !binding.is_undefined())
+        .unwrap_or(false)
+}
+
+/// Lightweight self-check for load balancer probes; never calls any upstream
+pub async fn handle_health(_req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let missing = REQUIRED_BINDINGS
+        .iter()
+        .copied()
+        .filter(|name| !binding_exists(&ctx.env, name))
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        console_error!("Health check failed, missing bindings: {missing:?}");
+    }
+
+    let report = HealthReport::new(missing);
+    Ok(Response::from_json(&report)?.with_status(report.status()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_report_healthy() {
+        let report = HealthReport::new(vec![]);
+        assert!(report.healthy);
+        assert_eq!(report.status(), 200);
+        assert_eq!(report.worker, "langproxy-rs");
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_health_report_missing_bindings() {
+        let report = HealthReport::new(vec![ANALYTICS_BINDING]);
+        assert!(!report.healthy);
+        assert_eq!(report.status(), 503);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["missing_bindings"][0], "OPENAI_PROXY_USAGE_ANALYTICS");
+    }
+}
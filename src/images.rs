@@ -0,0 +1,209 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use worker::*;
+
+use crate::{
+    cors, guard_request, proxy_response_headers, query_error_response, redact,
+    reject_disallowed_upstream, upstream, upstream_auth_headers, ProxyUrlParams, RequestMeta,
+};
+
+/// Model used by the Images API when the request doesn't name one
+const DEFAULT_IMAGE_MODEL: &str = "dall-e-2";
+
+/// The request fields recorded in analytics
+#[derive(Debug, Default, Deserialize)]
+struct ImageRequestBody {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    size: Option<String>,
+    #[serde(default)]
+    quality: Option<String>,
+}
+
+impl ImageRequestBody {
+    /// Parses `size` (e.g. `1024x1792`) into width and height
+    fn dimensions(&self) -> (u32, u32) {
+        self.size
+            .as_deref()
+            .and_then(|size| size.split_once('x'))
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ImageUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+    #[serde(default)]
+    total_tokens: u32,
+}
+
+/// The response fields recorded in analytics; image payloads are skipped without allocating
+#[derive(Debug, Deserialize)]
+struct ImageResponseBody {
+    data: Vec<IgnoredAny>,
+    #[serde(default)]
+    usage: Option<ImageUsage>,
+}
+
+/// Proxies image generation requests and records the number and size of the images
+pub async fn images_proxy(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let data = req.bytes().await?;
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let image_request = match serde_json::from_slice::<ImageRequestBody>(&data) {
+        Ok(v) => v,
+        Err(e) => {
+            console_error!("JSON Error: {}", e.to_string());
+            return Response::error("Bad Request: invalid JSON body", 400);
+        }
+    };
+
+    let mut proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let status = response.status();
+    let origin = cors::request_origin(&req, &ctx.env);
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    // Base64 payloads can be several MB: read once and hand the same buffer back
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            console_error!("Error reading upstream body: {}", e);
+            return Response::error("Internal Server Error!!!!!", 500);
+        }
+    };
+
+    if status.is_success() {
+        match serde_json::from_slice::<ImageResponseBody>(&body) {
+            Ok(parsed) => {
+                let usage = parsed.usage.unwrap_or_default();
+                let (width, height) = image_request.dimensions();
+
+                let mut analytics = meta.usage_analytics(
+                    image_request
+                        .model
+                        .unwrap_or_else(|| DEFAULT_IMAGE_MODEL.to_string()),
+                    usage.input_tokens,
+                    usage.output_tokens,
+                    usage.total_tokens,
+                );
+                analytics.image_count = parsed.data.len() as u32;
+                analytics.image_width = width;
+                analytics.image_height = height;
+                analytics.image_quality = image_request.quality;
+                analytics.save(&ctx.env, &ctx.data).await;
+            }
+            Err(e) => console_error!("Failed to parse image response: {e}"),
+        }
+    } else {
+        console_error!("Error {}", status);
+    }
+
+    Ok(Response::from_bytes(body.into())?
+        .with_status(status.as_u16())
+        .with_headers(my_response_headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_request_dimensions() {
+        let body: ImageRequestBody = serde_json::from_str(
+            r#"{"model": "dall-e-3", "prompt": "a cat", "n": 1, "size": "1024x1792", "quality": "hd"}"#,
+        )
+        .unwrap();
+        assert_eq!(body.model.as_deref(), Some("dall-e-3"));
+        assert_eq!(body.quality.as_deref(), Some("hd"));
+        assert_eq!(body.dimensions(), (1024, 1792));
+    }
+
+    #[test]
+    fn test_image_request_defaults() {
+        let body: ImageRequestBody = serde_json::from_str(r#"{"prompt": "a cat"}"#).unwrap();
+        assert_eq!(body.model, None);
+        assert_eq!(body.dimensions(), (0, 0));
+
+        let body: ImageRequestBody =
+            serde_json::from_str(r#"{"prompt": "a cat", "size": "auto"}"#).unwrap();
+        assert_eq!(body.dimensions(), (0, 0));
+    }
+
+    #[test]
+    fn test_image_response_counts_images() {
+        let body: ImageResponseBody = serde_json::from_str(
+            r#"{
+                "created": 1713833628,
+                "data": [
+                    {"b64_json": "iVBORw0KGgoAAAANSUhEUgAA", "revised_prompt": "a cat"},
+                    {"b64_json": "iVBORw0KGgoAAAANSUhEUgBB"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(body.data.len(), 2);
+        assert!(body.usage.is_none());
+    }
+
+    #[test]
+    fn test_image_response_with_usage() {
+        let body: ImageResponseBody = serde_json::from_str(
+            r#"{
+                "created": 1713833628,
+                "data": [{"url": "https://example.com/a.png"}],
+                "usage": {"total_tokens": 100, "input_tokens": 50, "output_tokens": 50}
+            }"#,
+        )
+        .unwrap();
+        let usage = body.usage.unwrap();
+        assert_eq!(usage.input_tokens, 50);
+        assert_eq!(usage.output_tokens, 50);
+        assert_eq!(usage.total_tokens, 100);
+    }
+}
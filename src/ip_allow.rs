@@ -0,0 +1,238 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use serde_json::json;
+use worker::*;
+
+use crate::token_cap::TENANT_LIMITS_BINDING;
+use crate::{kv_cache, RequestMeta};
+
+/// An address range such as `203.0.113.0/24` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses a range, or a bare address as a single-address range.
+    ///
+    /// Host bits set in the network address are ignored, as in `10.1.2.3/8`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value.trim(), None),
+        };
+        let network = addr.parse::<IpAddr>().ok()?;
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            // `u8` parsing alone would let `+24` through
+            Some(prefix) if prefix.bytes().all(|b| b.is_ascii_digit()) => prefix.parse().ok()?,
+            Some(_) => return None,
+            None => max,
+        };
+
+        (prefix <= max).then_some(Self { network, prefix })
+    }
+
+    /// Whether `ip` is in the range; IPv4 and IPv6 ranges never match the other family
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // A v4 client may show up as `::ffff:a.b.c.d`
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a stored allowlist: ranges separated by commas or whitespace.
+///
+/// Returns the ranges and the entries that aren't valid ones.
+pub fn parse_list(value: &str) -> (Vec<Cidr>, Vec<&str>) {
+    let mut ranges = Vec::new();
+    let mut invalid = Vec::new();
+    for entry in value
+        .split([',', ' ', '\t', '\n', '\r'])
+        .filter(|e| !e.is_empty())
+    {
+        match Cidr::parse(entry) {
+            Some(range) => ranges.push(range),
+            None => invalid.push(entry),
+        }
+    }
+    (ranges, invalid)
+}
+
+/// Whether `ip` (as sent in `CF-Connecting-IP`) is in any of `ranges`
+pub fn allowed(ranges: &[Cidr], ip: Option<&str>) -> bool {
+    let Some(ip) = ip.and_then(|ip| ip.trim().parse::<IpAddr>().ok()) else {
+        return false;
+    };
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+fn forbidden_response() -> Result<Response> {
+    Ok(Response::from_json(&json!({
+        "error": true,
+        "type": "Forbidden",
+        "code": "ip_not_allowed",
+        "message": "Requests from this address are not allowed for this tenant",
+    }))?
+    .with_status(403))
+}
+
+/// Rejects requests from outside the tenant's allowlist, stored in `TENANT_LIMITS` under
+/// `ipallow:{tenant}`; tenants without one are unaffected.
+///
+/// A list that exists but holds no valid range rejects every request, and so does a failed
+/// lookup, since the tenant may not be served from anywhere but its own addresses.
+pub async fn reject_disallowed_ip(
+    env: &Env,
+    ctx: &Rc<Context>,
+    meta: &RequestMeta,
+) -> Option<Result<Response>> {
+    let tenant = meta.tenant_id.as_deref()?;
+    env.kv(TENANT_LIMITS_BINDING).ok()?;
+
+    let key = format!("ipallow:{tenant}");
+    let list = match kv_cache::get_text(env, TENANT_LIMITS_BINDING, &key).await {
+        Ok(list) => list?,
+        Err(e) => {
+            console_error!("TENANT_LIMITS lookup of {} failed: {}", key, e);
+            return Some(Response::error("Internal Server Error", 500));
+        }
+    };
+
+    let (ranges, invalid) = parse_list(&list);
+    if !invalid.is_empty() {
+        console_warn!("Ignoring invalid {} entries: {:?}", key, invalid);
+    }
+    if allowed(&ranges, meta.ip_address.as_deref()) {
+        return None;
+    }
+
+    console_error!(
+        "Rejected ip={:?} for app={}, tenant={}: not in {}",
+        meta.ip_address,
+        meta.app_id,
+        tenant,
+        key
+    );
+
+    let mut analytics = meta.usage_analytics("unknown".to_string(), 0, 0, 0);
+    analytics.error = Some("ip_not_allowed".to_string());
+    analytics.save_in_background(env, ctx);
+
+    Some(forbidden_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(range: &str, ip: &str) -> bool {
+        Cidr::parse(range).unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Cidr::parse("203.0.113.7"),
+            Some(Cidr {
+                network: "203.0.113.7".parse().unwrap(),
+                prefix: 32
+            })
+        );
+        assert_eq!(Cidr::parse("2001:db8::1").map(|c| c.prefix), Some(128));
+        assert_eq!(Cidr::parse(" 10.0.0.0/8 ").map(|c| c.prefix), Some(8));
+        assert_eq!(Cidr::parse("::/0").map(|c| c.prefix), Some(0));
+
+        for invalid in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/+8",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com",
+            "",
+        ] {
+            assert_eq!(Cidr::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_v4_ranges() {
+        assert!(contains("203.0.113.0/24", "203.0.113.255"));
+        assert!(!contains("203.0.113.0/24", "203.0.114.0"));
+        assert!(contains("10.1.2.3/8", "10.200.0.1"));
+        assert!(contains("198.51.100.7", "198.51.100.7"));
+        assert!(!contains("198.51.100.7", "198.51.100.8"));
+        assert!(contains("0.0.0.0/0", "192.0.2.1"));
+        assert!(contains("172.16.0.0/12", "172.31.255.255"));
+        assert!(!contains("172.16.0.0/12", "172.32.0.0"));
+    }
+
+    #[test]
+    fn test_v6_ranges() {
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        // Masks that don't fall on a group boundary
+        assert!(contains("2001:db8:abcd:1200::/56", "2001:db8:abcd:12ff::1"));
+        assert!(!contains(
+            "2001:db8:abcd:1200::/56",
+            "2001:db8:abcd:1300::1"
+        ));
+        assert!(contains("2001:db8::/127", "2001:db8::1"));
+        assert!(!contains("2001:db8::/127", "2001:db8::2"));
+        // Bits past the first 64 still count
+        assert!(!contains("2001:db8::1/128", "2001:db8::1:1"));
+        assert!(contains("::/0", "2001:db8::1"));
+    }
+
+    #[test]
+    fn test_families_never_mix() {
+        assert!(!contains("::/0", "192.0.2.1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        // IPv4-mapped IPv6 addresses are matched as the IPv4 address they carry
+        assert!(contains("192.0.2.0/24", "::ffff:192.0.2.10"));
+        assert!(!contains("192.0.2.0/24", "::ffff:198.51.100.1"));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let (ranges, invalid) = parse_list("203.0.113.0/24, 2001:db8::/48\nbogus/8  198.51.100.7");
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(invalid, vec!["bogus/8"]);
+
+        assert!(allowed(&ranges, Some("203.0.113.9")));
+        assert!(allowed(&ranges, Some("2001:db8:0:ffff::1")));
+        assert!(allowed(&ranges, Some("198.51.100.7")));
+        assert!(!allowed(&ranges, Some("198.51.100.8")));
+        assert!(!allowed(&ranges, Some("2001:db8:1::1")));
+        // No or garbled client address fails closed
+        assert!(!allowed(&ranges, None));
+        assert!(!allowed(&ranges, Some("unknown")));
+
+        // A list without a valid range allows nobody
+        let (ranges, _) = parse_list("bogus");
+        assert!(!allowed(&ranges, Some("203.0.113.9")));
+    }
+}
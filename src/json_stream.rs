@@ -0,0 +1,121 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+/// Upper bound for a single buffered JSON object; larger objects are skipped
+const MAX_OBJECT_LEN: usize = 1024 * 1024;
+
+/// Incrementally splits a byte stream into its top-level JSON objects.
+///
+/// Anything outside an object (SSE `data:` prefixes, JSON array brackets and
+/// commas, newlines) is ignored, so the same splitter handles SSE, JSON-array
+/// and newline-delimited streams. Objects may span any number of chunks.
+#[derive(Debug, Default)]
+pub struct JsonObjectSplitter {
+    buf: Vec<u8>,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    overflow: bool,
+}
+
+impl JsonObjectSplitter {
+    /// Feeds a network chunk, returning every object completed by it
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        let mut objects = Vec::new();
+
+        for &byte in chunk {
+            if self.depth == 0 {
+                if byte == b'{' {
+                    self.depth = 1;
+                    self.buf.clear();
+                    self.buf.push(byte);
+                    self.overflow = false;
+                }
+                continue;
+            }
+
+            if !self.overflow {
+                if self.buf.len() < MAX_OBJECT_LEN {
+                    self.buf.push(byte);
+                } else {
+                    self.overflow = true;
+                    self.buf = Vec::new();
+                }
+            }
+
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' => self.depth += 1,
+                b'}' => {
+                    self.depth -= 1;
+                    if self.depth == 0 && !self.overflow {
+                        objects.push(std::mem::take(&mut self.buf));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        objects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splits_json_array_stream() {
+        let mut splitter = JsonObjectSplitter::default();
+        let objects = splitter.push(b"[{\"a\":1}\r\n,\r\n{\"b\":{\"c\":2}}]");
+        assert_eq!(
+            objects,
+            vec![b"{\"a\":1}".to_vec(), b"{\"b\":{\"c\":2}}".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_splits_sse_stream() {
+        let mut splitter = JsonObjectSplitter::default();
+        let objects = splitter.push(b"data: {\"a\":1}\r\n\r\ndata: {\"b\":2}\r\n\r\n");
+        assert_eq!(objects, vec![b"{\"a\":1}".to_vec(), b"{\"b\":2}".to_vec()]);
+    }
+
+    #[test]
+    fn test_braces_inside_strings() {
+        let mut splitter = JsonObjectSplitter::default();
+        let objects = splitter.push(br#"{"text":"a } and { and \" }"}"#);
+        assert_eq!(objects, vec![br#"{"text":"a } and { and \" }"}"#.to_vec()]);
+    }
+
+    #[test]
+    fn test_object_split_across_chunks() {
+        let input = br#"[{"text":"he said \"hi\" {"},{"n":2}]"#;
+        for split in 1..input.len() {
+            let mut splitter = JsonObjectSplitter::default();
+            let mut objects = splitter.push(&input[..split]);
+            objects.extend(splitter.push(&input[split..]));
+            assert_eq!(objects.len(), 2, "split at {split}");
+            assert_eq!(objects[1], br#"{"n":2}"#.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_oversized_object_skipped() {
+        let mut splitter = JsonObjectSplitter::default();
+        let mut big = b"{\"x\":\"".to_vec();
+        big.extend(std::iter::repeat_n(b'a', MAX_OBJECT_LEN));
+        big.extend(b"\"}{\"ok\":true}");
+        assert_eq!(splitter.push(&big), vec![b"{\"ok\":true}".to_vec()]);
+    }
+}
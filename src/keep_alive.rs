@@ -0,0 +1,314 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{Stream, StreamExt};
+use worker::*;
+
+use crate::sse;
+
+/// Variable setting the keep-alive interval in seconds for every streamed response
+pub const KEEP_ALIVE_VAR: &str = "SSE_KEEPALIVE_SECS";
+
+/// SSE comment sent while the upstream is silent; clients ignore it
+pub const PING: &[u8] = b": ping\n\n";
+
+/// Parses an interval in whole seconds; `0` or anything invalid means off
+fn parse_interval(value: &str) -> Option<Duration> {
+    let secs = value.trim().parse::<u64>().ok().filter(|secs| *secs > 0)?;
+    Some(Duration::from_secs(secs))
+}
+
+/// The keep-alive interval of a response: the `keepAlive` parameter, else `SSE_KEEPALIVE_SECS`.
+///
+/// Only event streams get one, since a comment would corrupt any other body; `keepAlive=0`
+/// turns it off for a request.
+pub fn interval(env: &Env, param: Option<&str>, content_type: Option<&str>) -> Option<Duration> {
+    if !sse::is_event_stream(content_type) {
+        return None;
+    }
+
+    match param {
+        Some(param) => parse_interval(param),
+        None => parse_interval(&env.var(KEEP_ALIVE_VAR).ok()?.to_string()),
+    }
+}
+
+/// Tracks whether the bytes relayed so far end between events, where a comment can go
+#[derive(Debug)]
+struct EventBoundary {
+    at_boundary: bool,
+    line_empty: bool,
+    after_cr: bool,
+}
+
+impl Default for EventBoundary {
+    fn default() -> Self {
+        Self {
+            at_boundary: true,
+            line_empty: true,
+            after_cr: false,
+        }
+    }
+}
+
+impl EventBoundary {
+    fn push(&mut self, chunk: &[u8]) {
+        for &byte in chunk {
+            let after_cr = std::mem::take(&mut self.after_cr);
+            match byte {
+                // The `\n` of a `\r\n` line ending
+                b'\n' if after_cr => self.after_cr = false,
+                b'\r' | b'\n' => {
+                    // A blank line ends the event
+                    self.at_boundary = self.line_empty;
+                    self.line_empty = true;
+                    self.after_cr = byte == b'\r';
+                }
+                _ => {
+                    self.at_boundary = false;
+                    self.line_empty = false;
+                }
+            }
+        }
+    }
+}
+
+/// Stream wrapper inserting `PING` after every `interval` without upstream data
+pub struct KeepAlive<S, F, T> {
+    stream: Pin<Box<S>>,
+    /// Starts a timer of the interval
+    timer: F,
+    /// The running timer; `None` while data flows or an event is partially relayed
+    delay: Option<Pin<Box<T>>>,
+    boundary: EventBoundary,
+}
+
+impl<S, F, T> Stream for KeepAlive<S, F, T>
+where
+    S: Stream<Item = Result<Vec<u8>>>,
+    F: FnMut() -> T + Unpin,
+    T: Future<Output = ()>,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if let Poll::Ready(item) = this.stream.as_mut().poll_next(cx) {
+            // Data restarts the interval, and the end of the stream ends the pings
+            this.delay = None;
+            if let Some(Ok(chunk)) = &item {
+                this.boundary.push(chunk);
+            }
+            return Poll::Ready(item);
+        }
+
+        if !this.boundary.at_boundary {
+            return Poll::Pending;
+        }
+        let delay = this.delay.get_or_insert_with(|| Box::pin((this.timer)()));
+        match delay.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.delay = None;
+                Poll::Ready(Some(Ok(PING.to_vec())))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Wraps `stream` with keep-alives started by `timer`
+fn with_timer<S, F, T>(stream: S, timer: F) -> KeepAlive<S, F, T>
+where
+    S: Stream<Item = Result<Vec<u8>>>,
+    F: FnMut() -> T + Unpin,
+    T: Future<Output = ()>,
+{
+    KeepAlive {
+        stream: Box::pin(stream),
+        timer,
+        delay: None,
+        boundary: EventBoundary::default(),
+    }
+}
+
+/// Sends a `: ping` comment every `interval` the upstream is silent between events, if any
+pub fn wrap<S>(stream: S, interval: Option<Duration>) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    S: Stream<Item = Result<Vec<u8>>>,
+{
+    match interval {
+        Some(interval) => with_timer(stream, move || Delay::from(interval)).left_stream(),
+        None => stream.right_stream(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use futures_channel::mpsc;
+
+    use super::*;
+
+    /// A timer that fires when the test says so
+    #[derive(Clone, Default)]
+    struct Clock {
+        ticks: Rc<Cell<u32>>,
+    }
+
+    impl Clock {
+        fn timer(&self) -> impl FnMut() -> Tick + Unpin {
+            let ticks = self.ticks.clone();
+            move || Tick {
+                ticks: ticks.clone(),
+                at: ticks.get() + 1,
+            }
+        }
+
+        fn advance(&self) {
+            self.ticks.set(self.ticks.get() + 1);
+        }
+    }
+
+    struct Tick {
+        ticks: Rc<Cell<u32>>,
+        at: u32,
+    }
+
+    impl Future for Tick {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+            if self.ticks.get() >= self.at {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll<S: Stream + Unpin>(stream: &mut S) -> Poll<Option<S::Item>> {
+        let waker = futures_util::task::noop_waker();
+        stream.poll_next_unpin(&mut Context::from_waker(&waker))
+    }
+
+    fn chunk<S: Stream<Item = Result<Vec<u8>>> + Unpin>(stream: &mut S) -> Option<String> {
+        match poll(stream) {
+            Poll::Ready(Some(Ok(chunk))) => Some(String::from_utf8(chunk).unwrap()),
+            Poll::Ready(other) => panic!("unexpected {other:?}"),
+            Poll::Pending => None,
+        }
+    }
+
+    fn send(tx: &mut mpsc::UnboundedSender<Result<Vec<u8>>>, data: &str) {
+        tx.unbounded_send(Ok(data.as_bytes().to_vec())).unwrap();
+    }
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("15"), Some(Duration::from_secs(15)));
+        assert_eq!(parse_interval(" 30\n"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_interval("0"), None);
+        assert_eq!(parse_interval("1.5"), None);
+        assert_eq!(parse_interval("off"), None);
+    }
+
+    #[test]
+    fn test_event_boundary() {
+        let at_boundary = |chunks: &[&str]| {
+            let mut boundary = EventBoundary::default();
+            chunks.iter().for_each(|c| boundary.push(c.as_bytes()));
+            boundary.at_boundary
+        };
+
+        assert!(at_boundary(&[]));
+        assert!(at_boundary(&["data: {}\n\n"]));
+        assert!(at_boundary(&["data: {}\r\n\r\n"]));
+        assert!(at_boundary(&["data: {}\r\r"]));
+        assert!(at_boundary(&["data: {}\n", "\n"]));
+        assert!(at_boundary(&["data: {}\r\n\r", "\n"]));
+        assert!(at_boundary(&[": comment\n\n", "\n"]));
+
+        assert!(!at_boundary(&["data: {"]));
+        assert!(!at_boundary(&["data: {}\n"]));
+        assert!(!at_boundary(&["data: {}\r\n"]));
+        assert!(!at_boundary(&["event: delta\ndata: {}\n"]));
+        assert!(!at_boundary(&["data: {}\n\n", "data"]));
+    }
+
+    #[test]
+    fn test_pings_while_upstream_stalls() {
+        let clock = Clock::default();
+        let (mut tx, rx) = mpsc::unbounded();
+        let mut stream = with_timer(rx, clock.timer());
+
+        // Thinking before the first token
+        assert_eq!(chunk(&mut stream), None);
+        clock.advance();
+        assert_eq!(chunk(&mut stream).as_deref(), Some(": ping\n\n"));
+        assert_eq!(chunk(&mut stream), None);
+        clock.advance();
+        assert_eq!(chunk(&mut stream).as_deref(), Some(": ping\n\n"));
+
+        // Data restarts the interval
+        send(&mut tx, "data: 1\n\n");
+        assert_eq!(chunk(&mut stream).as_deref(), Some("data: 1\n\n"));
+        send(&mut tx, "data: 2\n\n");
+        clock.advance();
+        assert_eq!(chunk(&mut stream).as_deref(), Some("data: 2\n\n"));
+        assert_eq!(chunk(&mut stream), None);
+        clock.advance();
+        assert_eq!(chunk(&mut stream).as_deref(), Some(": ping\n\n"));
+
+        // Nothing after the end of the stream
+        drop(tx);
+        clock.advance();
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+    }
+
+    #[test]
+    fn test_no_ping_inside_an_event() {
+        let clock = Clock::default();
+        let (mut tx, rx) = mpsc::unbounded();
+        let mut stream = with_timer(rx, clock.timer());
+
+        send(&mut tx, "data: {\"choices\":");
+        assert_eq!(chunk(&mut stream).as_deref(), Some("data: {\"choices\":"));
+        for _ in 0..3 {
+            clock.advance();
+            assert_eq!(chunk(&mut stream), None);
+        }
+
+        // Once the event is complete, the next stall gets its ping
+        send(&mut tx, "[]}\n");
+        assert_eq!(chunk(&mut stream).as_deref(), Some("[]}\n"));
+        clock.advance();
+        assert_eq!(chunk(&mut stream), None);
+        send(&mut tx, "\n");
+        assert_eq!(chunk(&mut stream).as_deref(), Some("\n"));
+        assert_eq!(chunk(&mut stream), None);
+        clock.advance();
+        assert_eq!(chunk(&mut stream).as_deref(), Some(": ping\n\n"));
+    }
+
+    #[test]
+    fn test_relays_errors_and_ends() {
+        let clock = Clock::default();
+        let stream = futures_util::stream::iter([
+            Ok(b"data: 1\n\n".to_vec()),
+            Err(Error::from("connection reset")),
+        ]);
+        let mut stream = with_timer(stream, clock.timer());
+
+        assert_eq!(chunk(&mut stream).as_deref(), Some("data: 1\n\n"));
+        assert!(matches!(poll(&mut stream), Poll::Ready(Some(Err(_)))));
+        assert!(matches!(poll(&mut stream), Poll::Ready(None)));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use worker::*;
+
+/// How long a KV read, hit or miss, is reused before KV is read again
+const CACHE_TTL_MS: u64 = 60_000;
+
+/// Cache key: KV binding and key within it
+type CacheKey = (&'static str, String);
+
+/// In-isolate cache of small KV values such as configuration.
+///
+/// Misses are cached too, as most lookups find nothing.
+#[derive(Debug, Default)]
+struct TtlCache {
+    entries: HashMap<CacheKey, (Option<String>, u64)>,
+}
+
+impl TtlCache {
+    /// The cached read of `key`, unless it has expired
+    fn get(&self, key: &CacheKey, now: u64) -> Option<Option<String>> {
+        self.entries
+            .get(key)
+            .filter(|(_, expires_at)| now < *expires_at)
+            .map(|(value, _)| value.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, value: Option<String>, now: u64) {
+        // Expired entries go first so the map doesn't grow with every key ever read
+        self.entries.retain(|_, (_, expires_at)| now < *expires_at);
+        self.entries.insert(key, (value, now + CACHE_TTL_MS));
+    }
+}
+
+thread_local! {
+    // Workers run single-threaded, so a thread-local cache needs no synchronisation
+    static CACHE: RefCell<TtlCache> = RefCell::new(TtlCache::default());
+}
+
+/// Reads `key` from the `binding` KV namespace through the in-isolate cache.
+///
+/// Failed reads aren't cached, so the next request tries again.
+pub async fn get_text(env: &Env, binding: &'static str, key: &str) -> Result<Option<String>> {
+    let now = Date::now().as_millis();
+    let cache_key = (binding, key.to_string());
+
+    if let Some(value) = CACHE.with(|cache| cache.borrow().get(&cache_key, now)) {
+        return Ok(value);
+    }
+
+    let value = env.kv(binding)?.get(key).text().await?;
+    CACHE.with(|cache| cache.borrow_mut().insert(cache_key, value.clone(), now));
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> CacheKey {
+        ("MODEL_MAP", key.to_string())
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut cache = TtlCache::default();
+        cache.insert(key("em-fast"), Some("gpt-4o-mini".to_string()), 1_000);
+        cache.insert(key("gpt-4o"), None, 1_000);
+
+        assert_eq!(
+            cache.get(&key("em-fast"), 1_000 + CACHE_TTL_MS - 1),
+            Some(Some("gpt-4o-mini".to_string()))
+        );
+        // Misses are remembered as well
+        assert_eq!(cache.get(&key("gpt-4o"), 2_000), Some(None));
+        assert_eq!(cache.get(&key("em-fast"), 1_000 + CACHE_TTL_MS), None);
+        assert_eq!(cache.get(&key("em-gpt-4o"), 1_000), None);
+        // Bindings don't share keys
+        assert_eq!(cache.get(&("TARGETS", "em-fast".to_string()), 1_000), None);
+
+        // Inserting after expiry prunes the stale entries
+        cache.insert(key("em-gpt-4o"), None, 1_000 + CACHE_TTL_MS);
+        assert_eq!(cache.entries.len(), 1);
+    }
+}
@@ -4,52 +4,101 @@
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 // use hashbrown::HashMap;
-use futures_util::StreamExt;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+use std::time::Duration;
+
+use base64::Engine;
+use futures_util::{FutureExt, StreamExt};
 use heapless::String as HString;
 
 use worker::*;
 
+mod accounts;
+mod admin;
 mod analytics;
 use analytics::UsageAnalytics;
+mod analytics_engine;
+mod analytics_sink;
+
+mod anthropic;
+mod audio;
+mod azure_compat;
+mod batches;
+mod bedrock;
+mod body;
+mod build_info;
+mod cohere;
+mod compression;
+use compression::ContentEncoding;
+mod cors;
+mod embeddings;
+mod fallback;
+mod field_denylist;
+mod forward_headers;
+mod gemini;
+mod health;
+mod images;
+mod ip_allow;
+mod json_stream;
+mod jwt;
+mod keep_alive;
+mod kv_cache;
+mod log_throttle;
+mod metrics;
+mod model_map;
+mod moderations;
+mod ollama;
+mod passthrough;
+mod provider;
+use provider::Provider;
+mod proxy_keys;
+mod realtime;
+mod redact;
+mod request_id;
+mod responses;
+mod signature;
+mod sse;
+mod system_prompt;
+mod targets;
+mod tenant_usage;
+mod token_cap;
+mod upstream;
+mod usage;
+mod usage_archive;
+mod usage_db;
+mod usage_extractor;
+use usage_extractor::{ContentStats, Extracted, ExtractedUsage, UnparsedUsage, UsageExtractor};
+mod webhook;
 
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     // Create an instance of the Router, which can use parameters (/user/:name) or wildcard values
-    // (/file/*pathname). Alternatively, use `Router::with_data(D)` and pass in arbitrary data for
-    // routes to access and share using the `ctx.data()` method.
-    let router = Router::new();
+    // (/file/*pathname). Routes get the fetch event's context as `ctx.data`, which keeps the
+    // request alive for the analytics written after its response (`Context::wait_until`).
+    let router = Router::with_data(Rc::new(ctx));
 
-    // useful for JSON APIs
-    #[derive(Deserialize, Serialize)]
-    struct Account {
-        id: u64,
-        // ...
-    }
     router
-        .get_async("/account/:id", |_req, ctx| async move {
-            if let Some(id) = ctx.param("id") {
-                let accounts = ctx.kv("ACCOUNTS")?;
-                return match accounts.get(id).json::<Account>().await? {
-                    Some(account) => Response::from_json(&account),
-                    None => Response::error("Not found", 404),
-                };
-            }
-
-            Response::error("Bad Request", 400)
-        })
+        .get_async("/account/:id", accounts::get_account)
+        .put_async("/account/:id", accounts::put_account)
+        .delete_async("/account/:id", accounts::delete_account)
+        .get_async("/accounts", accounts::list_accounts)
+        .post_async("/proxy-keys", proxy_keys::mint_key)
+        .delete_async("/proxy-keys/:hash", proxy_keys::revoke_key)
         // handle files and fields from multipart/form-data requests
         .post_async("/upload", |mut req, _ctx| async move {
             let form = req.form_data().await?;
             if let Some(entry) = form.get("file") {
                 match entry {
                     FormEntry::File(file) => {
-                        let bytes = file.bytes().await?;
+                        let _bytes = file.bytes().await?;
                     }
                     FormEntry::Field(_) => return Response::error("Bad Request", 400),
                 }
                 // ...
 
-                if let Some(permissions) = form.get("permissions") {
+                if let Some(_permissions) = form.get("permissions") {
                     // permissions == "a,b,c,d"
                 }
                 // or call `form.get_all("permissions")` if using multiple entries per field
@@ -66,319 +115,620 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
 
             Response::from_bytes(data)
         })
+        .get_async("/health", health::handle_health)
+        .get_async("/version", build_info::handle_version)
+        .get_async("/metrics", metrics::handle_metrics)
+        .get_async("/usage/:tenantId", usage::handle_usage)
+        .get_async("/proxy/universal", passthrough::get_passthrough)
         .post_async("/proxy/universal", stream_proxy)
         .post_async("/azure-openai/completions", stream_proxy)
+        .post_async("/anthropic/messages", anthropic::anthropic_proxy)
+        .post_async("/proxy/embeddings", embeddings::embeddings_proxy)
+        .post_async("/audio/transcriptions", audio::transcriptions_proxy)
+        .post_async("/images/generations", images::images_proxy)
+        .post_async("/gemini/generate", gemini::gemini_proxy)
+        .post_async("/bedrock/invoke", bedrock::bedrock_proxy)
+        .post_async("/ollama/chat", ollama::ollama_proxy)
+        .post_async("/moderations", moderations::moderations_proxy)
+        .post_async("/batches", batches::create_batch)
+        .get_async("/batches/:id", batches::get_batch)
+        .post_async("/files", batches::upload_file)
+        .get_async("/realtime", realtime::realtime_proxy)
+        .options_async("/proxy/universal", cors::handle_preflight)
+        .options_async("/azure-openai/completions", cors::handle_preflight)
+        .options_async("/anthropic/messages", cors::handle_preflight)
+        .options_async("/proxy/embeddings", cors::handle_preflight)
+        .options_async("/audio/transcriptions", cors::handle_preflight)
+        .options_async("/images/generations", cors::handle_preflight)
+        .options_async("/gemini/generate", cors::handle_preflight)
+        .options_async("/bedrock/invoke", cors::handle_preflight)
+        .options_async("/ollama/chat", cors::handle_preflight)
+        .options_async("/moderations", cors::handle_preflight)
+        .options_async("/batches", cors::handle_preflight)
+        .options_async("/batches/:id", cors::handle_preflight)
+        .options_async("/files", cors::handle_preflight)
+        .or_else_any_method_async("/*path", fallback::handle_fallback)
         .run(req, env)
         .await
 }
 
-async fn stream_proxy(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let data = req.bytes().await?;
+/// Proxies a request to the upstream, tagging every response with the build that served it and
+/// the request's id
+async fn stream_proxy(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let mut request_id = None;
+    let mut response = match proxy_request(req, ctx, &mut request_id).await {
+        Ok(response) => response,
+        Err(e) => {
+            console_error!("Proxy Error: {}", e);
+            Response::error("Internal Server Error", 500)?
+        }
+    };
+
+    response
+        .headers_mut()
+        .set(build_info::VERSION_HEADER, build_info::BUILD_ID)?;
+    if let Some(request_id) = request_id {
+        response
+            .headers_mut()
+            .set(request_id::RESPONSE_HEADER, &request_id)?;
+    }
+    Ok(response)
+}
+
+/// Proxies a request to the upstream, setting `echoed_id` to the request's id once it's known
+async fn proxy_request(
+    mut req: Request,
+    ctx: RouteContext<Rc<Context>>,
+    echoed_id: &mut Option<String>,
+) -> Result<Response> {
+    let route = req.path();
+    metrics::increment(metrics::Metric::Requests, &route);
 
-    // Extract metadata for analytics
-    let ip_address = req.headers().get("CF-Connecting-IP").ok().flatten();
-    let country = req.headers().get("CF-IPCountry").ok().flatten();
-    let cf_ray = req.headers().get("CF-Ray").ok().flatten();
-    let domain = req.headers().get("Host").ok().flatten();
-    // For deployment, we could use environment variables or default value
-    let deployment = Some("cloudflare-worker".to_string());
     let env = ctx.env.clone();
+    let event_ctx = ctx.data.clone();
+    // Proxied responses carry CORS headers only for an allowed origin
+    let origin = cors::request_origin(&req, &env);
 
-    let xparams: ProxyUrlParams = match req.query() {
+    let xparams = match ProxyUrlParams::from_request(&req) {
         Ok(v) => v,
-        Err(e) => {
-            console_error!("Query String Error: {}", e.to_string());
+        Err(e) => return query_error_response(e),
+    };
 
-            return match Response::from_json(&json!({
-                "error": true,
-                "type": "Query String Error",
-                "message": e.to_string(),
-            })) {
-                Ok(v) => Ok(v.with_status(400)),
-                Err(e) => {
-                    console_error!("Response Builder Error: {}", e.to_string());
-                    Response::error("Internal Server Error!", 500)
-                }
-            };
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    // Requests without an id get one, so the client, the upstream and analytics share one
+    if meta.request_id.is_none() {
+        meta.request_id = request_id::random_uuid().ok();
+    }
+    echoed_id.clone_from(&meta.request_id);
+
+    if let Some(rejection) = guard_request(&req, &env, &event_ctx, &mut meta, None).await {
+        return rejection;
+    }
+
+    // A named target replaces `u` and is trusted, as its record is managed by us
+    let target = match xparams.target.as_deref() {
+        Some(name) => match targets::resolve(&env, name).await? {
+            Some(target) => Some(target),
+            None => {
+                console_error!("Unknown target: {}", name);
+                return targets::unknown_target_response(name);
+            }
+        },
+        None => None,
+    };
+
+    let upstream_url = target.as_ref().map_or(&xparams.u, |target| &target.url);
+    let provider = xparams.provider(upstream_url);
+    let usage_format = xparams.usage_format(upstream_url);
+
+    if target.is_none() {
+        if let Some(rejection) = reject_disallowed_upstream(&env, &event_ctx, &meta, &xparams.u) {
+            metrics::upstream_error(&route, Some(403));
+            return rejection;
+        }
+    }
+
+    let method = match upstream_method(xparams.m.as_deref()) {
+        Ok(method) => method,
+        Err(message) => {
+            return query_error_response(
+                ParamError::invalid("m", message).with_request_id(xparams.req_id.clone()),
+            )
         }
     };
 
-    console_debug!("XParams: {xparams:?}");
+    let mut encoding = ContentEncoding::Identity;
+    if method_sends_body(&method) {
+        let content_type = req.headers().get("content-type").ok().flatten();
+        let content_encoding = req.headers().get("content-encoding").ok().flatten();
+        let checked = check_content_type(content_type.as_deref())
+            .and_then(|_| ContentEncoding::from_header(content_encoding.as_deref()));
+        match checked {
+            Ok(content_encoding) => encoding = content_encoding,
+            Err(e) => {
+                console_error!("Rejected body: {}", e);
+                return e.to_response();
+            }
+        }
+    }
+
+    // Without a change to make, the body is piped to the upstream instead of buffered (and so
+    // not checked for out-of-range sampling parameters either)
+    let clamps = body::SamplingClamps::from_env(&env);
+    let pipe_body = method_sends_body(&method)
+        && encoding == ContentEncoding::Identity
+        && !jwt::enabled(&env)
+        && xparams.pipes_body()
+        && !model_map::enabled(&env)
+        && !token_cap::enabled(&env)
+        && !clamps.is_enabled()
+        && !field_denylist::enabled(&env)
+        && !system_prompt::enabled(&env);
+    let mut model_alias = None;
+    let mut max_tokens_capped = None;
+    let mut fields_stripped = 0;
+    let mut system_prompt_chars = None;
+    let mut request_model = None;
+    // Bodies asking for no stream get one JSON response, read whole for its usage
+    let mut json_response = false;
 
     // let a = std::time::Instant::now();
-    let data = match serde_json::from_slice::<AzureReqBodyStream>(&data) {
-        Ok(stream_params) => {
-            console_debug!("Stream Params: {stream_params:?}");
-            if stream_params.stream == false {
-                data
-            } else {
-                match std::str::from_utf8(&data) {
-                    Ok(s) => {
-                        #[cfg(debug_assertions)]
-                        console_error!("ORIGINAL: {}", s);
-                        // https://learn.microsoft.com/en-us/azure/ai-services/openai/reference#chatcompletionstreamoptions
-                        // {"stream_options":{"include_usage": true}
-                        // let trimmed = s.trim();
-                        // let concat = format!("{}{}", &trimmed[..(trimmed.len() - 1)], r#","stream_options":{"include_usage": true}}"#);
-                        let concat = format!(
-                            "{}{}",
-                            r#"{"stream_options":{"include_usage": true},"#,
-                            &s.trim()[1..]
-                        );
-                        #[cfg(debug_assertions)]
-                        console_error!("CONCAT: {concat}");
-                        // #[cfg(debug_assertions)]
-                        match serde_json::from_str::<serde_json::Value>(&concat) {
-                            Ok(_) => {
-                                console_log!("Parsed Ok!");
-                            }
-                            Err(e) => {
-                                console_error!("Invalid JSON: {}", e);
-
-                                return Response::error("Invalid UTF-8", 400);
-                            }
-                        }
-                        // console_log!("=== Took {:?}", a.elapsed());
-                        concat
-                    }
-                    Err(e) => {
-                        console_error!("Invalid UTF-8: {}", e);
-                        return Response::error("Invalid UTF-8", 400);
-                    }
-                }
-                .as_str()
-                .into()
+    let data = if !method_sends_body(&method) {
+        None
+    } else if pipe_body {
+        // Chunked bodies have no length to check up front
+        let size = req.headers().get("content-length").ok().flatten();
+        if let Some(size) = size.and_then(|size| size.parse().ok()) {
+            if let Some(rejection) = reject_oversized_body(&env, &event_ctx, &meta, size) {
+                return rejection;
             }
         }
-        Err(e) => {
-            console_error!("JSON Error: {}", e.to_string());
-            return Response::error("Internal Server Error!!", 500);
+        None
+    } else {
+        let data = req.bytes().await?;
+        // Before anything parses or copies the body
+        if let Some(rejection) = reject_oversized_body(&env, &event_ctx, &meta, data.len()) {
+            return rejection;
+        }
+        // Inflated bodies are held to the same limit
+        let data = match encoding.decode(data, body_limit(&env)) {
+            Ok(data) => data,
+            Err(e) => {
+                console_error!("Rejected body: {}", e);
+                return e.to_response();
+            }
+        };
+        if encoding != ContentEncoding::Identity {
+            if let Some(rejection) = reject_oversized_body(&env, &event_ctx, &meta, data.len()) {
+                return rejection;
+            }
+        }
+
+        let body = trim_body(&data);
+        let stream_params = match serde_json::from_slice::<AzureReqBodyStream>(body) {
+            Ok(stream_params) => stream_params,
+            Err(e) => {
+                console_error!("JSON Error: {}", e.to_string());
+                return BodyError::InvalidJson(e.to_string()).to_response();
+            }
+        };
+
+        // Rejected here rather than surfacing as a confusing upstream error
+        let temperature = stream_params.temperature.as_ref();
+        if let Err(e) = clamps.check(temperature, stream_params.top_p.as_ref()) {
+            console_error!("Invalid body: {}", e);
+            return e.to_response();
+        }
+        // Only chat-shaped bodies are checked; embeddings and responses have no `messages`
+        if let Some(Err(e)) = stream_params.messages.as_ref().map(body::check_messages) {
+            console_error!("Invalid body: {}", e);
+            return e.to_response();
+        }
+
+        // The other APIs stream usage unasked, and reject `stream_options`
+        let include_usage =
+            stream_params.stream && !xparams.skips_usage() && usage_format.requests_usage();
+        if include_usage && stream_params.disables_usage() {
+            console_log!("Overriding the client's stream_options.include_usage: false");
         }
-    };
 
-    let proxy_headers = {
-        static API_KEY_STR: &str = "api-key";
-        static AUTH_KEY_STR: &str = "authorization";
+        // Friendly model names are swapped for the ones the upstream expects
+        let tenant = meta.tenant_id.as_deref();
+        let model = match stream_params.model.as_deref() {
+            Some(alias) => model_map::resolve(&env, tenant, alias).await,
+            None => None,
+        };
 
-        let mut proxy_headers = Headers::new();
+        request_model = model.clone().or_else(|| stream_params.model.clone());
+        // Other providers' JSON responses don't report usage the way `Usage` reads it
+        json_response = !stream_params.stream
+            && !xparams.skips_usage()
+            && matches!(usage_format, UsageFormat::OpenAi | UsageFormat::Mistral);
 
-        let (header_name, header_value) = match req.headers().get(API_KEY_STR) {
-            Ok(Some(key)) => (API_KEY_STR, key),
-            _ => match req.headers().get(AUTH_KEY_STR) {
-                Ok(Some(key)) => (AUTH_KEY_STR, key),
-                _ => {
-                    console_error!("Request Error: Missing authorization headers");
-                    return Response::error("Internal Server Error!!!", 500);
-                }
-            },
+        let system_prompt = system_prompt::resolve(&env, tenant, &meta.app_id).await;
+        let edits = body::BodyEdits {
+            include_usage,
+            // Gives the provider's abuse monitoring a stable id for the end user
+            user_id: meta.user_id.as_deref(),
+            model: model.as_deref(),
+            max_tokens_cap: token_cap::resolve(&env, tenant).await,
+            clamps,
+            strip_fields: &field_denylist::resolve(&env, tenant).await,
+            system_prompt: system_prompt.as_deref(),
         };
+        Some(match edits.apply(body) {
+            Ok(Some(edited)) => {
+                if model.is_some() {
+                    model_alias = stream_params.model;
+                }
+                max_tokens_capped = edited.max_tokens_capped;
+                if !edited.stripped.is_empty() {
+                    console_debug!("Stripped denied fields: {:?}", edited.stripped);
+                    fields_stripped = edited.stripped.len() as u32;
+                }
+                system_prompt_chars = edited.system_prompt_chars.map(|chars| chars as u32);
+                for clamped in &edited.clamped {
+                    let body::Clamped { field, from, to } = clamped;
+                    console_log!("Clamped {} from {} to {}", field, from, to);
+                }
+                // The edits may have grown the body past the limit checked on arrival
+                let size = edited.body.len();
+                if let Some(rejection) = reject_oversized_body(&env, &event_ctx, &meta, size) {
+                    return rejection;
+                }
+                edited.body
+            }
+            Ok(None) => trim_body_vec(data),
+            Err(e) => {
+                console_error!("Invalid body: {}", e);
+                return e.to_response();
+            }
+        })
+    };
 
-        proxy_headers
-            .set(header_name, &header_value)
-            .expect("Should set a header value");
+    let tenant = meta.verified_tenant.as_deref();
+    let auth = targets::upstream_auth(
+        &req,
+        &env,
+        target.as_ref(),
+        tenant,
+        provider,
+        meta.authenticated,
+    );
+    let mut proxy_headers = match auth {
+        Ok(headers) => headers,
+        Err(e) => return e.to_response(),
+    };
+    forward_headers::copy(&req, &env, &mut proxy_headers)?;
+    // Only encodings `forward_upstream` can decode, whatever the client accepts
+    proxy_headers.set("Accept-Encoding", compression::ACCEPTED_RESPONSE_ENCODINGS)?;
 
-        proxy_headers
+    // Decompressed bodies go out plain unless the deployment asks for them gzipped again
+    let recompress_var = env.var(compression::RECOMPRESS_VAR).ok().map(|v| v.to_string());
+    let data = match data {
+        Some(data)
+            if encoding != ContentEncoding::Identity
+                && compression::recompress(recompress_var.as_deref()) =>
+        {
+            proxy_headers.set("Content-Encoding", "gzip")?;
+            Some(compression::gzip(&data).map_err(|e| Error::from(e.to_string()))?)
+        }
+        data => data,
     };
 
-    let proxy_url = xparams.u.clone();
+    let proxy_url = match target {
+        Some(target) => target.url,
+        None => xparams.u.clone(),
+    };
+    let (proxy_url, api_version) =
+        upstream::with_api_version(&proxy_url, xparams.api_version.as_deref());
+    // Lets the provider's logs be searched by our id, unless the client forwarded its own
+    let upstream_id_header = request_id::upstream_header(provider);
+    if let Some(id) = &meta.request_id {
+        if !proxy_headers.has(upstream_id_header)? {
+            proxy_headers.set(upstream_id_header, id)?;
+        }
+    }
 
-    console_debug!("Proxy URL: {proxy_url}");
+    console_debug!("Proxy URL: {}", redact::url(&proxy_url));
+    let ensure_done = xparams.ensures_done(&proxy_url);
+    // Gemini names the model in the URL, and only sometimes in the response; Cohere's doesn't
+    let known_model = match usage_format {
+        UsageFormat::Gemini => gemini::model_from_url(&proxy_url).map(str::to_string),
+        UsageFormat::Cohere => request_model,
+        _ => None,
+    };
+    let logged_headers = redact::headers(&http::HeaderMap::from(&proxy_headers));
+    console_debug!("Proxy headers:\n{}", logged_headers);
+    console_log!("Effective api-version: {:?}", api_version);
 
-    let reqwester = reqwest::Client::new();
-    let response = match reqwester
-        .post(proxy_url)
-        .headers(proxy_headers.into())
-        .body(data)
-        .send()
-        .await
-    {
+    if pipe_body {
+        let mut analytics = meta.usage_analytics("unknown".to_string(), 0, 0, 0);
+        analytics.target = xparams.target.clone();
+        analytics.api_version = api_version;
+        analytics.usage_captured = false;
+        analytics.provider = Some(provider.name().to_string());
+
+        let (headers, ctx) = (proxy_headers, event_ctx);
+        return pipe_upstream(&req, &proxy_url, &method, headers, route, env, ctx, analytics).await;
+    }
+
+    let reqwester = upstream::http_client();
+    let mut upstream_request = reqwester
+        .request(method.clone(), proxy_url)
+        .headers(proxy_headers.into());
+    // Bodyless methods go out without a body (and so without a content-type)
+    if let Some(data) = data {
+        upstream_request = upstream_request.body(data);
+    }
+
+    let dispatched_at = UsageAnalytics::current_timestamp();
+    let response = match upstream_request.send().await {
         Ok(res) => res,
         Err(e) => {
             console_error!("Request Error: {}", e.to_string());
+            metrics::upstream_error(&route, None);
             return Response::error("Internal Server Error!!!!", 500);
         }
     };
+    meta.upstream_request_id = request_id::upstream_id(|name| {
+        let value = response.headers().get(name)?.to_str().ok()?;
+        Some(value.to_string())
+    });
 
-    if response.status().is_success() {
-        let mut my_response_headers = Headers::new();
+    // No-content responses (e.g. DELETE) have nothing to stream or scan
+    if matches!(response.status().as_u16(), 204 | 205) {
+        let status = response.status().as_u16();
+        return Ok(Response::empty()?
+            .with_status(status)
+            .with_headers(proxy_response_headers(&response, origin.as_deref())));
+    }
 
-        for (header_name, header_value) in response.headers() {
-            if let Ok(value_str) = header_value.to_str() {
-                my_response_headers
-                    .append(header_name.as_str(), value_str)
-                    .expect("Should set response header");
-            }
+    if response.status().is_success() {
+        let status = response.status().as_u16();
+        let mut my_response_headers = proxy_response_headers(&response, origin.as_deref());
+        // Tells the client its completion may be shorter than it asked for
+        if let Some(cap) = max_tokens_capped {
+            my_response_headers.set("X-LangProxy-MaxTokens-Capped", &cap.to_string())?;
         }
 
-        // Set content type to match what's expected for streaming responses
-        if !my_response_headers.has("content-type").unwrap_or(false) {
-            my_response_headers
-                .set("content-type", "text/event-stream")
-                .expect("Should set content-type header");
-        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let keep_alive =
+            keep_alive::interval(&env, xparams.keep_alive.as_deref(), content_type.as_deref());
 
-        // Add CORS headers if needed
-        my_response_headers
-            .set("Access-Control-Allow-Origin", "*")
-            .expect("Should set CORS header");
+        if xparams.skips_usage() {
+            let rx = forward_upstream(&env, response, xparams.sends_error_events());
+            let failure = rx.failure.clone();
 
-        // Create a streaming response
-        let status = response.status().as_u16();
-        let (mut tx, rx) = futures_channel::mpsc::channel(10);
-
-        // Spawn a task to process the incoming stream and send chunks to our channel
-        wasm_bindgen_futures::spawn_local(async move {
-            let mut stream = response.bytes_stream();
-
-            while let Some(item) = stream.next().await {
-                match item {
-                    Ok(chunk) => {
-                        // console_log!("Forwarding chunk of size: {}", chunk.len());
-                        if tx.try_send(Ok(chunk.to_vec())).is_err() {
-                            console_error!("Failed to forward chunk, receiver dropped");
-                            break;
-                        }
-                        // worker::Delay::from(std::time::Duration::from_millis(100)).await;
+            // Nothing to scan: record the request with unknown usage once it's done
+            let mut analytics = meta.usage_analytics("unknown".to_string(), 0, 0, 0);
+            analytics.target = xparams.target.clone();
+            analytics.api_version = api_version;
+            analytics.usage_captured = false;
+            analytics.provider = Some(provider.name().to_string());
+            analytics.model_alias = model_alias;
+            analytics.max_tokens_capped = max_tokens_capped;
+            analytics.fields_stripped = fields_stripped;
+            analytics.system_prompt_chars = system_prompt_chars;
+            analytics.status_code = status;
+
+            // Without a scanner, only a body that isn't streamed gets its latency timed
+            let timed = !sse::is_event_stream(content_type.as_deref());
+
+            // Saved once, by whichever of completion and disconnection comes first
+            let analytics = Rc::new(RefCell::new(Some(analytics)));
+            let abandoned = analytics.clone();
+            let abandoned_env = env.clone();
+            let abandoned_ctx = event_ctx.clone();
+            let abandoned_failure = failure.clone();
+            let stream = on_stream_end(rx, move || {
+                metrics::increment(metrics::Metric::StreamsCompleted, &route);
+                if let Some(mut analytics) = analytics.borrow_mut().take() {
+                    if timed {
+                        let now = UsageAnalytics::current_timestamp();
+                        analytics.ttft_ms = Some(elapsed_ms(dispatched_at, now));
                     }
-                    Err(e) => {
-                        console_error!("Error while streaming: {}", e);
-                        let _ = tx.try_send(Err(Error::from(e.to_string())));
-                        break;
+                    if let Some(error) = failure.borrow().clone() {
+                        analytics.error = Some(error);
                     }
+                    analytics.save_in_background(&env, &event_ctx);
                 }
-            }
+            });
+            let stream = on_stream_abandoned(stream, move || {
+                if let Some(mut analytics) = abandoned.borrow_mut().take() {
+                    analytics.client_disconnected = true;
+                    analytics.error = abandoned_failure.borrow().clone();
+                    analytics.save_in_background(&abandoned_env, &abandoned_ctx);
+                }
+            });
 
-            console_log!("Upstream stream completed with status {}", status);
-        });
+            let stream = keep_alive::wrap(stream, keep_alive);
+            return match stream_response(stream, status, my_response_headers) {
+                Ok(resp) => Ok(resp),
+                Err(e) => {
+                    console_error!("Error creating streaming response: {}", e);
+                    Response::error("Internal Server Error!!!!!", 500)
+                }
+            };
+        }
 
-        // let mut temp_str: heapless::String<512> = heapless::String::new();
-        let mut temp_str = String::new();
-
-        // Capture analytics metadata for use in the stream closure
-        let analytics_metadata = (
-            xparams.app.clone(),
-            xparams.ten_id.clone(),
-            xparams.mod_id.clone(),
-            xparams.ses_id.clone(),
-            xparams.req_id.clone(),
-            xparams.env_id.clone(),
-            ip_address.clone(),
-            country.clone(),
-            cf_ray.clone(),
-            domain.clone(),
-            deployment.clone(),
-            env.clone(),
-        );
+        // Attribution shared by the usage records of this request
+        let mut template = meta.usage_analytics("unknown".to_string(), 0, 0, 0);
+        template.http_method = method.to_string();
+        template.provider = Some(provider.name().to_string());
+        template.target = xparams.target.clone();
+        template.api_version = api_version;
+        template.model_alias = model_alias;
+        template.max_tokens_capped = max_tokens_capped;
+        template.fields_stripped = fields_stripped;
+        template.system_prompt_chars = system_prompt_chars;
+        template.status_code = status;
+        if let Some(model) = known_model {
+            template.model = model;
+        }
 
-        // Create a ReadableStream from our channel receiver
-        let stream = rx.map(move |result| {
-            match result {
-                Ok(bytes) => {
-                    let chunk_str = unsafe{ std::str::from_utf8_unchecked(&bytes) };
-                    if temp_str.len() > 0 {
-                        console_log!("TEMP STRING LEN: {}", temp_str.len());
-                        if let Some(pos) = chunk_str.find("\n") {
-                            temp_str.push_str(&chunk_str[..pos])
-                                //.expect("Failed to second push chunk")
-                                ;
-                        let choices_str = &temp_str;
-                        console_debug!("TEMP STRING2: <!--\n{}\n-->", choices_str);
-
-                        match serde_json::from_str::<StatsChunk>(choices_str) {
-                            Ok(stats_chunk) => {
-                                console_log!("STATS CHUNK A: <!--\n{:?}\n-->", stats_chunk);
-
-                                // Collect analytics data
-                                let analytics = UsageAnalytics::new(
-                                    analytics_metadata.0.clone(), // app_id
-                                    analytics_metadata.1.clone(), // tenant_id
-                                    analytics_metadata.2.clone(), // module_id  
-                                    analytics_metadata.3.clone(), // session_id
-                                    analytics_metadata.4.clone(), // request_id
-                                    analytics_metadata.5.clone(), // env_id
-                                    analytics_metadata.6.clone(), // ip_address
-                                    analytics_metadata.7.clone(), // country
-                                    analytics_metadata.8.clone(), // cf_ray
-                                    analytics_metadata.9.clone(), // domain
-                                    analytics_metadata.10.clone(), // deployment
-                                    stats_chunk.model.to_string(),
-                                    stats_chunk.usage.prompt_tokens,
-                                    stats_chunk.usage.completion_tokens,
-                                    stats_chunk.usage.total_tokens,
-                                );
-                                
-                                // Save analytics data asynchronously (fire-and-forget)
-                                let env_clone = analytics_metadata.11.clone();
-                                wasm_bindgen_futures::spawn_local(async move {
-                                    analytics.save(&env_clone).await;
-                                });
-                            }
-                            Err(e) => {
-                                console_error!("B: Failed to parse choices chunk: <!--\n{choices_str}\n-->\nError: {e}");
-                            }
-                        }
-                        temp_str.clear();
-                    }
+        let parse_route = route.clone();
+        let log_bodies = redact::LogBodies::from_env(&env);
+
+        let log_usage = move |record: UsageRecord| match record {
+            Ok(analytics) => {
+                console_log!(
+                    "STATS CHUNK: model={}, prompt_tokens={}, completion_tokens={}, \
+                     total_tokens={}",
+                    analytics.model,
+                    analytics.prompt_tokens,
+                    analytics.completion_tokens,
+                    analytics.total_tokens
+                );
+            }
+            Err(UnparsedUsage { data, error, reported_usage }) => {
+                metrics::increment(metrics::Metric::UsageParseFailures, &parse_route);
+                let logged = || log_bodies.body(&data).unwrap_or_default();
+                if reported_usage {
+                    let logged = logged();
+                    console_error!(
+                        "Failed to parse usage event: <!--\n{logged}\n-->\nError: {error}"
+                    );
+                } else if let Some(unlogged) =
+                    log_throttle::parse_miss(UsageAnalytics::current_timestamp() as u64)
+                {
+                    // Content events in an unexpected shape; likely many, and rarely usage
+                    let logged = logged();
+                    console_debug!(
+                        "Unreadable stream event ({unlogged} more since last logged): \
+                         <!--\n{logged}\n-->\nError: {error}"
+                    );
+                }
+            }
+        };
+
+        // Upstreams may stream anyway; those responses go through the scanner
+        if json_response && !sse::is_event_stream(content_type.as_deref()) {
+            let encoding = response.headers().get(reqwest::header::CONTENT_ENCODING);
+            let decoder =
+                compression::ResponseDecoder::new(encoding.and_then(|value| value.to_str().ok()));
+            let body = match response.bytes().await {
+                Ok(body) => decoder.decode(body.into()).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let body = match body {
+                Ok(body) => body,
+                Err(e) => {
+                    console_error!("Error reading upstream body: {}", e);
+                    metrics::upstream_error(&route, None);
+                    return Response::error("Internal Server Error!!!!!", 500);
                 }
+            };
 
-                if let Some(choices_position) = chunk_str.find(r#"{"choices":[]"#) {
-                    console_debug!("CHOICES CHUNK: <!--\n{}\n-->", &chunk_str[choices_position..]);
-                    if let Some(newline_position) = chunk_str.find("\n") {
-                        let choices_str = &chunk_str[choices_position..newline_position];
-                        console_debug!("CHOICES STRING: <!--\n{}\n-->", choices_str);
-                        match serde_json::from_str::<StatsChunk>(choices_str) {
-                            Ok(stats_chunk) => {
-                                console_log!("STATS CHUNK B: <!--\n{:?}\n-->", stats_chunk);
-
-                                // Collect analytics data
-                                let analytics = UsageAnalytics::new(
-                                    analytics_metadata.0.clone(), // app_id
-                                    analytics_metadata.1.clone(), // tenant_id
-                                    analytics_metadata.2.clone(), // module_id  
-                                    analytics_metadata.3.clone(), // session_id
-                                    analytics_metadata.4.clone(), // request_id
-                                    analytics_metadata.5.clone(), // env_id
-                                    analytics_metadata.6.clone(), // ip_address
-                                    analytics_metadata.7.clone(), // country
-                                    analytics_metadata.8.clone(), // cf_ray
-                                    analytics_metadata.9.clone(), // domain
-                                    analytics_metadata.10.clone(), // deployment
-                                    stats_chunk.model.to_string(),
-                                    stats_chunk.usage.prompt_tokens,
-                                    stats_chunk.usage.completion_tokens,
-                                    stats_chunk.usage.total_tokens,
-                                );
-                                
-                                // Save analytics data asynchronously (fire-and-forget)
-                                let env_clone = analytics_metadata.11.clone();
-                                wasm_bindgen_futures::spawn_local(async move {
-                                    analytics.save(&env_clone).await;
-                                });
-                            }
-                            Err(e) => {
-                                console_error!("A: Failed to parse choices chunk:\nError: {:?}", e);
-                            }
-                        }
-                    } else {
-                        console_debug!(": CHOICES ELSE: NO ENTER IN STRING");
-                        temp_str.clear();
-                        temp_str.push_str(&chunk_str[choices_position..])
-                            // .expect("Failed to push first chunk")
-                            ;
-                        console_debug!("TEMP STRING1: ----\n{}\n----", temp_str);
-                    }
-                    console_log!("CHUNK: ----\n{}\n----", chunk_str);
+            let record = json_usage(&template, &body);
+            let mut analytics = match &record {
+                Ok(analytics) => analytics.clone(),
+                Err(_) => {
+                    template.usage_captured = false;
+                    template
                 }
-                // console_log!("CHUNK: ----\n{}\n----", unsafe{ std::str::from_utf8_unchecked(&bytes) });
-                Ok(bytes)
-            },
-            Err(e) => Err(Error::from(e.to_string())),
+            };
+            log_usage(record);
+            let now = UsageAnalytics::current_timestamp();
+            analytics.ttft_ms = Some(elapsed_ms(dispatched_at, now));
+            analytics.response_bytes = body.len() as u64;
+            if analytics.finish_reason.as_deref() == Some(CONTENT_FILTER_FINISH) {
+                metrics::increment(metrics::Metric::ContentFilterFinishes, &route);
+            }
+            // Written after the response goes out, like every other event of the route
+            analytics.save_in_background(&env, &event_ctx);
+
+            return Ok(Response::from_bytes(body)?
+                .with_status(status)
+                .with_headers(my_response_headers));
         }
-    });
 
+        let rx = forward_upstream(&env, response, xparams.sends_error_events());
+        let failure = rx.failure.clone();
+
+        let mut scanner = UsageScanner::new(template)
+            .timed_from(dispatched_at)
+            .format(usage_format);
+        // Only OpenAI has an event just for usage; the others' rides on events the client
+        // needs, which stripping leaves alone
+        let chunk_format = matches!(usage_format, UsageFormat::OpenAi | UsageFormat::Mistral);
+        if chunk_format && xparams.strips_usage() {
+            scanner = scanner.strip_usage();
+        }
+        // Only event streams have events to rewrite
+        if xparams.azure_compat() && sse::is_event_stream(content_type.as_deref()) {
+            scanner = scanner.azure_compat();
+        }
+        if ensure_done && sse::is_event_stream(content_type.as_deref()) {
+            scanner = scanner.ensure_done();
+        }
+        let scanner = Rc::new(RefCell::new(scanner));
+        let abandoned = scanner.clone();
+        let rest = scanner.clone();
+        let abandoned_env = env.clone();
+        let abandoned_ctx = event_ctx.clone();
+
+        // Create a ReadableStream from our channel receiver
+        let stream = rx.filter_map(move |result| {
+            let bytes = match result {
+                Ok(bytes) => bytes,
+                Err(e) => return futures_util::future::ready(Some(Err(e))),
+            };
+            let (bytes, records) = scanner.borrow_mut().relay(bytes);
+            records.into_iter().for_each(&log_usage);
+            // Stripping holds back partial events, leaving some chunks with nothing to relay
+            futures_util::future::ready((!bytes.is_empty()).then_some(Ok(bytes)))
+        });
+        // An event the upstream cut off is still the client's, and the stream is recorded once
+        // nothing more is relayed, with or without usage
+        let rest_env = env.clone();
+        let rest_ctx = event_ctx.clone();
+        let rest_route = route.clone();
+        let rest_failure = failure.clone();
+        let rest = futures_util::stream::once(async move {
+            let mut scanner = rest.borrow_mut();
+            let rest = scanner.finish();
+            if let Some(mut analytics) = scanner.record(false) {
+                if let Some(error) = rest_failure.borrow().clone() {
+                    analytics.error = Some(error);
+                }
+                if analytics.finish_reason.as_deref() == Some(CONTENT_FILTER_FINISH) {
+                    metrics::increment(metrics::Metric::ContentFilterFinishes, &rest_route);
+                }
+                // Save analytics data asynchronously (fire-and-forget)
+                analytics.save_in_background(&rest_env, &rest_ctx);
+            }
+            rest
+        })
+        .filter(|rest| futures_util::future::ready(!rest.is_empty()))
+        .map(Ok);
+        let stream = stream.chain(rest);
+
+        let stream = on_stream_end(stream, move || {
+            metrics::increment(metrics::Metric::StreamsCompleted, &route);
+        });
+        // Streams the client left are recorded with what was relayed until then
+        let stream = on_stream_abandoned(stream, move || {
+            if let Some(mut analytics) = abandoned.borrow_mut().record(true) {
+                analytics.error = failure.borrow().clone();
+                console_warn!(
+                    "Client disconnected after ~{} completion tokens",
+                    analytics.completion_tokens
+                );
+                analytics.save_in_background(&abandoned_env, &abandoned_ctx);
+            }
+        });
+
+        let stream = keep_alive::wrap(stream, keep_alive);
         // Return a streaming response
-        match Response::from_stream(stream) {
-            Ok(resp) => Ok(resp.with_headers(my_response_headers)),
+        match stream_response(stream, status, my_response_headers) {
+            Ok(resp) => Ok(resp),
             Err(e) => {
                 console_error!("Error creating streaming response: {}", e);
                 Response::error("Internal Server Error!!!!!", 500)
@@ -387,283 +737,4416 @@ async fn stream_proxy(mut req: Request, ctx: RouteContext<()>) -> Result<Respons
     } else {
         console_error!("Error {}", response.status());
         let status = response.status();
+        metrics::upstream_error(&route, Some(status.as_u16()));
+        let error_headers = upstream_error_headers(&response, origin.as_deref())?;
         let text = &response.text().await;
-        Response::error(format!("{:?}", &text), status.into())
+        let mut message = format!("{:?}", &text);
+        if !xparams.skips_usage() {
+            if let Ok(body) = text {
+                if let Some(hint) = stream_options_hint(status.as_u16(), body) {
+                    message.push_str(hint);
+                }
+            }
+        }
+        Ok(Response::error(message, status.into())?.with_headers(error_headers))
     }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct ProxyUrlParams {
-    pub app: String,
-    pub u: String,
-    pub env_id: Option<String>,
-    pub ten_id: Option<String>,
-    pub mod_id: Option<String>,
-    pub ses_id: Option<String>,
-    pub req_id: Option<String>,
-    #[serde(rename = "api-version")]
-    pub api_version: Option<String>,
+/// Points clients at `noUsage=1` when the upstream refused the injected `stream_options`
+fn stream_options_hint(status: u16, upstream_body: &str) -> Option<&'static str> {
+    (status == 400 && upstream_body.contains("stream_options")).then_some(
+        " (the upstream rejected `stream_options`, which the proxy adds to capture usage; \
+         add `noUsage=1` to the query string to forward the body untouched)",
+    )
 }
 
-#[derive(Debug, Deserialize)]
-struct AzureReqBodyStream {
-    #[serde(default)]
-    pub stream: bool,
+/// Caller metadata captured from the incoming request for analytics
+#[derive(Clone, Debug)]
+struct RequestMeta {
+    app_id: String,
+    tenant_id: Option<String>,
+    module_id: Option<String>,
+    session_id: Option<String>,
+    request_id: Option<String>,
+    env_id: Option<String>,
+    ip_address: Option<String>,
+    country: Option<String>,
+    cf_ray: Option<String>,
+    domain: Option<String>,
+    deployment: Option<String>,
+    method: String,
+    user_id: Option<String>,
+    extra: BTreeMap<String, String>,
+    /// Hash of the `X-EM-Proxy-Key` the request was checked against
+    proxy_key: Option<String>,
+    /// Whether `guard_request` validated a proxy key or end-user token of the caller
+    authenticated: bool,
+    /// The tenant the caller's token or proxy key is bound to, whose upstream key it may use
+    verified_tenant: Option<String>,
+    /// The upstream's own id of the request, once it has answered
+    upstream_request_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct StatsChunk {
-    pub model: HString<64>,
-    pub usage: Usage,
+impl RequestMeta {
+    /// Collects the metadata of a request, given its params from `ProxyUrlParams::from_request`
+    fn from_request(req: &Request, env: &Env, xparams: &ProxyUrlParams) -> Self {
+        let header = |name: &str| req.headers().get(name).ok().flatten();
+
+        Self {
+            app_id: xparams.app.clone(),
+            tenant_id: xparams.ten_id.clone(),
+            module_id: xparams.mod_id.clone(),
+            session_id: xparams.ses_id.clone(),
+            request_id: xparams.req_id.clone(),
+            env_id: xparams.env_id.clone(),
+            ip_address: header("CF-Connecting-IP"),
+            country: header("CF-IPCountry"),
+            cf_ray: header("CF-Ray"),
+            domain: header("Host"),
+            deployment: Some(build_info::deployment(env, xparams.dep.as_deref())),
+            method: req.method().to_string(),
+            user_id: xparams.usr_id.as_deref().and_then(sanitize_user_id),
+            extra: xparams.extra.clone().into_iter().collect(),
+            proxy_key: None,
+            authenticated: false,
+            verified_tenant: None,
+            upstream_request_id: None,
+        }
+    }
+
+    /// Builds the analytics record for a usage event of this request
+    fn usage_analytics(
+        &self,
+        model: String,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        total_tokens: u32,
+    ) -> UsageAnalytics {
+        let mut analytics = UsageAnalytics::new(
+            self.app_id.clone(),
+            self.tenant_id.clone(),
+            self.module_id.clone(),
+            self.session_id.clone(),
+            self.request_id.clone(),
+            self.env_id.clone(),
+            self.ip_address.clone(),
+            self.country.clone(),
+            self.cf_ray.clone(),
+            self.domain.clone(),
+            self.deployment.clone(),
+            model,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens,
+        );
+        analytics.http_method = self.method.clone();
+        analytics.user_id = self.user_id.clone();
+        analytics.extra = self.extra.clone();
+        analytics.proxy_key = self.proxy_key.clone();
+        analytics.upstream_request_id = self.upstream_request_id.clone();
+        analytics
+    }
 }
-#[derive(Debug, Deserialize)]
-struct Usage {
-    #[serde(default)]
-    pub completion_tokens: u32,
-    pub prompt_tokens: u32,
-    pub total_tokens: u32,
+
+/// Checks the caller before a proxy route contacts any upstream: the end-user token (under
+/// `JWT_JWKS_URL`), the proxy key (under `REQUIRE_PROXY_KEY`), the tenant's IP allowlist, then
+/// the `X-EM-Signature` of apps with a signing secret.
+///
+/// Every proxy route runs it as soon as it knows the request's `RequestMeta`, which gets the
+/// token's user and tenant and the hash of the checked key. `body` is the raw body when the route
+/// has already read it; otherwise a signed request's body is read from a copy of it.
+async fn guard_request(
+    req: &Request,
+    env: &Env,
+    ctx: &Rc<Context>,
+    meta: &mut RequestMeta,
+    body: Option<&[u8]>,
+) -> Option<Result<Response>> {
+    // A validated end-user token names the user, whatever `usrId` says, and its tenant
+    match jwt::authenticate(req, env).await {
+        Some(Ok(claims)) => {
+            meta.user_id = sanitize_user_id(&claims.subject);
+            meta.tenant_id = match claims.tenant_for(meta.tenant_id.as_deref()) {
+                Ok(tenant) => tenant,
+                Err(e) => return Some(e.to_response()),
+            };
+            meta.verified_tenant = claims.tenant_id;
+            meta.authenticated = true;
+        }
+        Some(Err(e)) => return Some(e.to_response()),
+        None => {}
+    }
+
+    // Attribution is only as good as the key binding the claimed app and tenant
+    if proxy_keys::enabled(env) {
+        match proxy_keys::check(req, env, meta).await {
+            Ok((hash, record)) => {
+                meta.proxy_key = Some(hash);
+                // Only a key bound to a tenant verifies it; an app-wide key takes any `tenId`
+                meta.verified_tenant = record.tenant_id.or(meta.verified_tenant.take());
+                meta.authenticated = true;
+            }
+            Err(rejection) => return Some(rejection),
+        }
+    }
+
+    if let Some(rejection) = ip_allow::reject_disallowed_ip(env, ctx, meta).await {
+        return Some(rejection);
+    }
+
+    // Over the bytes as received, before they're decompressed or edited
+    let secret = signature::signing_secret(env, &meta.app_id).await?;
+    let copied;
+    let body = match body {
+        Some(body) => body,
+        None => match copied_body(req).await {
+            Ok(body) => {
+                copied = body;
+                &copied
+            }
+            Err(e) => return Some(Err(e)),
+        },
+    };
+    signature::reject_unsigned(req, env, &secret, body)
 }
 
-#[derive(Debug, Deserialize)]
-struct AzurePartialResponseBody {
-    pub id: HString<64>,
-    pub created: u32,
-    pub model: HString<64>,
-    pub usage: Usage,
+/// The body of a copy of `req`, leaving the original's to be streamed
+async fn copied_body(req: &Request) -> Result<Vec<u8>> {
+    req.clone()?.bytes().await
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Refuses upstream URLs outside `ALLOWED_UPSTREAM_HOSTS` and records who tried.
+///
+/// Deployments without the variable keep accepting any upstream.
+fn reject_disallowed_upstream(
+    env: &Env,
+    ctx: &Rc<Context>,
+    meta: &RequestMeta,
+    upstream_url: &str,
+) -> Option<Result<Response>> {
+    let allowlist = env.var(upstream::ALLOWED_UPSTREAM_HOSTS_VAR).ok()?.to_string();
+    let error = upstream::validate_upstream_url(upstream_url, &allowlist).err()?;
 
-    #[test]
-    fn test_proxy_url_params_deserialization() {
-        let json_str = r#"{
-            "app": "test-app",
-            "u": "https://api.openai.com/v1/chat/completions",
-            "envId": "prod",
-            "tenId": "tenant123",
-            "modId": "module456",
-            "sesId": "session789",
-            "reqId": "request101",
-            "api-version": "2023-05-15"
-        }"#;
+    console_error!(
+        "Rejected upstream for app={}, tenant={:?}, ip={:?}: {}",
+        meta.app_id,
+        meta.tenant_id,
+        meta.ip_address,
+        error
+    );
 
-        let params: ProxyUrlParams = serde_json::from_str(json_str).unwrap();
-        assert_eq!(params.app, "test-app");
-        assert_eq!(params.u, "https://api.openai.com/v1/chat/completions");
-        assert_eq!(params.env_id, Some("prod".to_string()));
-        assert_eq!(params.ten_id, Some("tenant123".to_string()));
-        assert_eq!(params.mod_id, Some("module456".to_string()));
-        assert_eq!(params.ses_id, Some("session789".to_string()));
-        assert_eq!(params.req_id, Some("request101".to_string()));
-        assert_eq!(params.api_version, Some("2023-05-15".to_string()));
-    }
+    let mut analytics = meta.usage_analytics("unknown".to_string(), 0, 0, 0);
+    analytics.rejected_upstream = Some(error.rejected().to_string());
+    analytics.save_in_background(env, ctx);
 
-    #[test]
-    fn test_proxy_url_params_minimal() {
-        let json_str = r#"{
-            "app": "minimal-app",
-            "u": "https://test.example.com"
-        }"#;
+    Some(error.to_response())
+}
 
-        let params: ProxyUrlParams = serde_json::from_str(json_str).unwrap();
-        assert_eq!(params.app, "minimal-app");
-        assert_eq!(params.u, "https://test.example.com");
-        assert_eq!(params.env_id, None);
-        assert_eq!(params.ten_id, None);
-        assert_eq!(params.mod_id, None);
-        assert_eq!(params.ses_id, None);
-        assert_eq!(params.req_id, None);
-        assert_eq!(params.api_version, None);
-    }
+/// Variable overriding the largest request body accepted, in bytes
+const MAX_BODY_BYTES_VAR: &str = "MAX_BODY_BYTES";
+/// Largest request body accepted when `MAX_BODY_BYTES` isn't set
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
 
-    #[test]
-    fn test_azure_req_body_stream_defaults() {
-        let json_str = r#"{}"#;
-        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
-        assert_eq!(body.stream, false); // default value
-    }
+/// Parses `MAX_BODY_BYTES`, falling back to the default when unset or invalid
+fn max_body_bytes(var: Option<&str>) -> usize {
+    var.and_then(|var| var.trim().parse().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
 
-    #[test]
-    fn test_azure_req_body_stream_explicit() {
-        let json_str = r#"{"stream": true}"#;
-        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
-        assert_eq!(body.stream, true);
+/// The request body limit of this deployment
+fn body_limit(env: &Env) -> usize {
+    let var = env.var(MAX_BODY_BYTES_VAR).ok().map(|var| var.to_string());
+    max_body_bytes(var.as_deref())
+}
 
-        let json_str = r#"{"stream": false}"#;
-        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
-        assert_eq!(body.stream, false);
+/// Refuses bodies over `MAX_BODY_BYTES` with a 413 and records who sent them
+fn reject_oversized_body(
+    env: &Env,
+    ctx: &Rc<Context>,
+    meta: &RequestMeta,
+    size: usize,
+) -> Option<Result<Response>> {
+    let limit = body_limit(env);
+    if size <= limit {
+        return None;
     }
 
-    #[test]
-    fn test_usage_deserialization() {
-        let json_str = r#"{
-            "prompt_tokens": 150,
-            "completion_tokens": 75,
-            "total_tokens": 225
-        }"#;
+    console_error!(
+        "Rejected {} byte body (limit {}) for app={}, tenant={:?}",
+        size,
+        limit,
+        meta.app_id,
+        meta.tenant_id
+    );
 
-        let usage: Usage = serde_json::from_str(json_str).unwrap();
-        assert_eq!(usage.prompt_tokens, 150);
+    let mut analytics = meta.usage_analytics("unknown".to_string(), 0, 0, 0);
+    analytics.error = Some("body_too_large".to_string());
+    analytics.save_in_background(env, ctx);
+
+    Some(BodyError::TooLarge { size, limit }.to_response())
+}
+
+/// The upstream auth header of a route without targets, built by `targets::upstream_auth` from
+/// what `guard_request` verified of the caller
+fn upstream_auth_headers(
+    req: &Request,
+    env: &Env,
+    meta: &RequestMeta,
+    provider: Provider,
+) -> std::result::Result<Headers, targets::AuthError> {
+    let tenant = meta.verified_tenant.as_deref();
+    targets::upstream_auth(req, env, None, tenant, provider, meta.authenticated)
+}
+
+/// Headers carrying proxy metadata for callers that can't change the query string
+const APP_HEADER: &str = "X-EM-App";
+const TENANT_ID_HEADER: &str = "X-EM-Tenant";
+const MODULE_ID_HEADER: &str = "X-EM-Module";
+const SESSION_ID_HEADER: &str = "X-EM-Session";
+const REQUEST_ID_HEADER: &str = "X-EM-Request";
+
+/// A missing or invalid proxy query parameter
+#[derive(Debug, PartialEq, Serialize)]
+struct ParamError {
+    /// Machine-readable failure kind, e.g. `missing_param`
+    code: &'static str,
+    /// The offending parameter, when the failure is tied to one
+    field: Option<&'static str>,
+    message: String,
+    #[serde(rename = "reqId", skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl ParamError {
+    fn missing(field: &'static str) -> Self {
+        Self {
+            code: "missing_param",
+            field: Some(field),
+            message: format!("Missing required parameter `{field}`"),
+            request_id: None,
+        }
+    }
+
+    fn empty(field: &'static str) -> Self {
+        Self {
+            code: "empty_param",
+            field: Some(field),
+            message: format!("Parameter `{field}` must not be empty"),
+            request_id: None,
+        }
+    }
+
+    fn invalid(field: &'static str, message: String) -> Self {
+        Self {
+            code: "invalid_param",
+            field: Some(field),
+            message,
+            request_id: None,
+        }
+    }
+
+    fn invalid_query(message: String) -> Self {
+        Self {
+            code: "invalid_query",
+            field: None,
+            message,
+            request_id: None,
+        }
+    }
+
+    fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+}
+
+/// Builds the 400 response returned when the proxy query string is missing or invalid
+fn query_error_response(e: ParamError) -> Result<Response> {
+    console_error!("Query String Error: {:?}", e);
+
+    let mut body = json!({
+        "error": true,
+        "type": "Query String Error",
+    });
+    if let (Some(body), Ok(serde_json::Value::Object(details))) =
+        (body.as_object_mut(), serde_json::to_value(&e))
+    {
+        body.extend(details);
+    }
+
+    match Response::from_json(&body) {
+        Ok(v) => Ok(v.with_status(400)),
+        Err(e) => {
+            console_error!("Response Builder Error: {}", e.to_string());
+            Response::error("Internal Server Error!", 500)
+        }
+    }
+}
+
+/// Copies the upstream response headers and adds the proxy's own (CORS, build)
+fn proxy_response_headers(response: &reqwest::Response, origin: Option<&str>) -> Headers {
+    relayed_headers(response.headers(), origin)
+}
+
+/// `proxy_response_headers` of upstream response headers from any client
+fn relayed_headers(headers: &http::HeaderMap, origin: Option<&str>) -> Headers {
+    let mut my_response_headers = Headers::new();
+
+    // The runtime or `forward_upstream` decodes compressed bodies, so the body we relay is never
+    // the one the upstream sent
+    for (header_name, value_str) in filter_response_headers(headers, true) {
+        my_response_headers
+            .append(header_name, value_str)
+            .expect("Should set response header");
+    }
+
+    with_proxy_headers(my_response_headers, origin)
+}
+
+/// Upstream rate-limit headers client SDKs throttle themselves by, relayed on errors too
+const RATE_LIMIT_HEADERS: [&str; 8] = [
+    "retry-after",
+    "retry-after-ms",
+    "x-ratelimit-limit-requests",
+    "x-ratelimit-limit-tokens",
+    "x-ratelimit-remaining-requests",
+    "x-ratelimit-remaining-tokens",
+    "x-ratelimit-reset-requests",
+    "x-ratelimit-reset-tokens",
+];
+
+/// The upstream response headers that still hold for the re-streamed body.
+///
+/// Drops the hop-by-hop headers (and any the upstream's `connection` names), `content-length`,
+/// which no longer matches the re-chunked stream, and `content-encoding` once the body was
+/// decoded. Values that aren't visible ASCII are dropped as well. `RATE_LIMIT_HEADERS` are
+/// always kept.
+fn filter_response_headers(headers: &http::HeaderMap, body_decoded: bool) -> Vec<(&str, &str)> {
+    let connection_listed = headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            if RATE_LIMIT_HEADERS.contains(&name) {
+                return true;
+            }
+            let dropped = forward_headers::HOP_BY_HOP_HEADERS.contains(&name)
+                || connection_listed.iter().any(|listed| listed == name)
+                || name == "content-length"
+                || (body_decoded && name == "content-encoding");
+            !dropped
+        })
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+        .collect()
+}
+
+/// Adds the proxy's own headers to those copied from the upstream response, with CORS headers
+/// for `origin` (see `cors::request_origin`)
+fn with_proxy_headers(mut my_response_headers: Headers, origin: Option<&str>) -> Headers {
+    // Set content type to match what's expected for streaming responses
+    if !my_response_headers.has("content-type").unwrap_or(false) {
+        my_response_headers
+            .set("content-type", "text/event-stream")
+            .expect("Should set content-type header");
+    }
+
+    // Browsers may only read the headers we expose
+    cors::set_headers(&mut my_response_headers, cors::CorsResponse::Actual, origin)
+        .expect("Should set CORS headers");
+
+    my_response_headers
+        .set("X-LangProxy-Build", build_info::BUILD_ID)
+        .expect("Should set build header");
+
+    my_response_headers
+}
+
+/// The upstream headers relayed on an error response: just `RATE_LIMIT_HEADERS`, so clients
+/// see `retry-after` on a 429
+fn error_response_headers(headers: &http::HeaderMap) -> Vec<(&str, &str)> {
+    filter_response_headers(headers, true)
+        .into_iter()
+        .filter(|(name, _)| RATE_LIMIT_HEADERS.contains(name))
+        .collect()
+}
+
+/// The headers of an upstream error relayed to the client: its `RATE_LIMIT_HEADERS`, and CORS
+/// headers for `origin`
+fn upstream_error_headers(response: &reqwest::Response, origin: Option<&str>) -> Result<Headers> {
+    relayed_error_headers(response.headers(), origin)
+}
+
+/// `upstream_error_headers` of upstream response headers from any client
+fn relayed_error_headers(headers: &http::HeaderMap, origin: Option<&str>) -> Result<Headers> {
+    let mut error_headers = Headers::new();
+    for (header_name, value_str) in error_response_headers(headers) {
+        error_headers.append(header_name, value_str)?;
+    }
+    // Browsers may only read the headers we expose
+    cors::set_headers(&mut error_headers, cors::CorsResponse::Actual, origin)?;
+    Ok(error_headers)
+}
+
+/// Sends the body of `req` to the upstream as a stream and relays the response.
+///
+/// reqwest can't stream request bodies on wasm, so this goes through the Workers fetch API.
+/// Only used when the body is forwarded untouched (`noUsage=1`), so usage isn't scanned;
+/// `analytics` is saved once the response has been relayed.
+#[allow(clippy::too_many_arguments)]
+async fn pipe_upstream(
+    req: &Request,
+    url: &str,
+    method: &reqwest::Method,
+    headers: Headers,
+    route: String,
+    env: Env,
+    ctx: Rc<Context>,
+    mut analytics: UsageAnalytics,
+) -> Result<Response> {
+    let mut init = RequestInit::new();
+    init.with_method(Method::from(method.to_string()))
+        .with_headers(headers)
+        .with_body(req.inner().body().map(Into::into));
+
+    let upstream_request = Request::new_with_init(url, &init)?;
+    let dispatched_at = UsageAnalytics::current_timestamp();
+    let mut response = match Fetch::Request(upstream_request).send().await {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            metrics::upstream_error(&route, None);
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    analytics.upstream_request_id =
+        request_id::upstream_id(|name| response.headers().get(name).ok().flatten());
+
+    let status = response.status_code();
+    let origin = cors::request_origin(req, &env);
+    let upstream_headers = http::HeaderMap::from(response.headers());
+    let my_response_headers = relayed_headers(&upstream_headers, origin.as_deref());
+
+    if matches!(status, 204 | 205) {
+        return Ok(Response::empty()?
+            .with_status(status)
+            .with_headers(my_response_headers));
+    }
+
+    if !(200..300).contains(&status) {
+        console_error!("Error {}", status);
+        metrics::upstream_error(&route, Some(status));
+        let error_headers = relayed_error_headers(&upstream_headers, origin.as_deref())?;
+        // Relayed as the upstream wrote it
+        let text = response.text().await.unwrap_or_default();
+        return Ok(Response::error(text, status)?.with_headers(error_headers));
+    }
+
+    analytics.status_code = status;
+    // Nothing is parsed, so only a body that isn't streamed gets its latency timed
+    let content_type = response.headers().get("content-type").ok().flatten();
+    let timed = !sse::is_event_stream(content_type.as_deref());
+    let stream = on_stream_end(response.stream()?, move || {
+        metrics::increment(metrics::Metric::StreamsCompleted, &route);
+        if timed {
+            let now = UsageAnalytics::current_timestamp();
+            analytics.ttft_ms = Some(elapsed_ms(dispatched_at, now));
+        }
+        analytics.save_in_background(&env, &ctx);
+    });
+
+    stream_response(stream, status, my_response_headers)
+}
+
+/// A streamed response relaying the upstream's status along with `headers`.
+///
+/// `Response::from_stream` alone always answers 200.
+fn stream_response<S>(stream: S, status: u16, headers: Headers) -> Result<Response>
+where
+    S: futures_util::TryStream + 'static,
+    S::Ok: Into<Vec<u8>>,
+    S::Error: Into<Error>,
+{
+    Ok(Response::from_stream(stream)?
+        .with_status(status)
+        .with_headers(headers))
+}
+
+/// Body of an upstream response as relayed to the client, fed by `forward_upstream`'s task.
+///
+/// Dropping it, as the runtime does when the client disconnects, cancels that task.
+struct UpstreamBody {
+    rx: futures_channel::mpsc::Receiver<Result<Vec<u8>>>,
+    _cancel: futures_channel::oneshot::Sender<()>,
+    /// Set when the task cut the stream short, to the error it's recorded with
+    failure: Rc<RefCell<Option<String>>>,
+}
+
+impl futures_util::Stream for UpstreamBody {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// How `pump_upstream` finished
+#[derive(Debug, PartialEq)]
+enum PumpEnd {
+    Completed,
+    ClientDisconnected,
+    UpstreamError(String),
+    /// The upstream sent nothing for the idle timeout
+    IdleTimeout,
+}
+
+/// Waits for room rather than dropping chunks while the client catches up
+async fn send_chunk<T>(
+    tx: &mut futures_channel::mpsc::Sender<T>,
+    item: T,
+) -> std::result::Result<(), futures_channel::mpsc::SendError> {
+    futures_util::future::poll_fn(|cx| tx.poll_ready(cx)).await?;
+    tx.start_send(item)
+}
+
+/// Forwards `stream` into `tx` until it ends or fails, the client goes away, or a timer from
+/// `idle_timer` fires before the next chunk.
+///
+/// Returning drops `stream`, which for a reqwest body aborts the fetch, so an abandoned upstream
+/// stops generating tokens nobody reads.
+async fn pump_upstream<S, B, E, F, T>(
+    stream: S,
+    tx: &mut futures_channel::mpsc::Sender<Result<Vec<u8>>>,
+    mut cancelled: futures_channel::oneshot::Receiver<()>,
+    mut idle_timer: F,
+) -> PumpEnd
+where
+    S: futures_util::Stream<Item = std::result::Result<B, E>>,
+    B: Into<Vec<u8>>,
+    E: std::fmt::Display,
+    F: FnMut() -> T,
+    T: std::future::Future<Output = ()>,
+{
+    use futures_util::future::{select, Either};
+
+    let mut stream = std::pin::pin!(stream);
+    loop {
+        // Restarted for every chunk; waiting on a slow client doesn't count
+        let idle = std::pin::pin!(idle_timer());
+        let item = match select(select(stream.next(), idle), &mut cancelled).await {
+            Either::Left((Either::Left((Some(item), _)), _)) => item,
+            Either::Left((Either::Left((None, _)), _)) => return PumpEnd::Completed,
+            Either::Left((Either::Right(_), _)) => return PumpEnd::IdleTimeout,
+            // The sender only goes away with the body
+            Either::Right(_) => return PumpEnd::ClientDisconnected,
+        };
+
+        match item {
+            Ok(chunk) => {
+                if send_chunk(tx, Ok(chunk.into())).await.is_err() {
+                    return PumpEnd::ClientDisconnected;
+                }
+            }
+            Err(e) => return PumpEnd::UpstreamError(e.to_string()),
+        }
+    }
+}
+
+/// Variable overriding how many upstream chunks are buffered for a slow client
+const STREAM_BUFFER_CHUNKS_VAR: &str = "STREAM_BUFFER_CHUNKS";
+/// Chunks buffered for a slow client when `STREAM_BUFFER_CHUNKS` isn't set
+const DEFAULT_STREAM_BUFFER_CHUNKS: usize = 10;
+
+/// Parses `STREAM_BUFFER_CHUNKS`, falling back to the default when unset or invalid
+fn stream_buffer_chunks(var: Option<&str>) -> usize {
+    var.and_then(|var| var.trim().parse().ok())
+        .filter(|chunks| *chunks > 0)
+        .unwrap_or(DEFAULT_STREAM_BUFFER_CHUNKS)
+}
+
+/// Variable overriding how long a stream may go without an upstream chunk
+const STREAM_IDLE_TIMEOUT_VAR: &str = "STREAM_IDLE_TIMEOUT_SECS";
+/// Idle timeout when `STREAM_IDLE_TIMEOUT_SECS` isn't set
+const DEFAULT_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Error code of streams ended for the upstream's silence, sent to the client and recorded
+const IDLE_TIMEOUT_CODE: &str = "stream_idle_timeout";
+/// Error code sent to the client when the upstream stream fails
+const UPSTREAM_STREAM_ERROR_CODE: &str = "upstream_stream_error";
+
+/// Parses `STREAM_IDLE_TIMEOUT_SECS`, where `0` turns the timeout off, falling back to the
+/// default when unset or invalid
+fn stream_idle_timeout(var: Option<&str>) -> Option<Duration> {
+    match var.and_then(|var| var.trim().parse::<u64>().ok()) {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(DEFAULT_STREAM_IDLE_TIMEOUT),
+    }
+}
+
+/// An `event: error` ending an event stream.
+///
+/// Leads with a blank line to end whatever event the upstream stopped in; between events that
+/// is just ignored.
+fn error_event(kind: &str, code: &str, message: &str) -> Vec<u8> {
+    let error = json!({
+        "error": true,
+        "type": kind,
+        "code": code,
+        "message": message,
+    });
+    format!("\n\nevent: error\ndata: {error}\n\n").into_bytes()
+}
+
+impl PumpEnd {
+    /// The error recorded for a stream that ended this way
+    fn error(&self) -> Option<String> {
+        match self {
+            PumpEnd::UpstreamError(e) => Some(e.clone()),
+            PumpEnd::IdleTimeout => Some(IDLE_TIMEOUT_CODE.to_string()),
+            PumpEnd::Completed | PumpEnd::ClientDisconnected => None,
+        }
+    }
+
+    /// What the client gets after the upstream's chunks, given the `timeout` that applied.
+    ///
+    /// With `error_events`, failures end the stream with an `event: error`; without, an
+    /// upstream error fails the stream and a timeout just ends it.
+    fn closing_item(&self, timeout: Duration, error_events: bool) -> Option<Result<Vec<u8>>> {
+        match self {
+            PumpEnd::UpstreamError(e) if error_events => Some(Ok(error_event(
+                "UpstreamError",
+                UPSTREAM_STREAM_ERROR_CODE,
+                &format!("The upstream stream failed: {e}"),
+            ))),
+            PumpEnd::UpstreamError(e) => Some(Err(Error::from(e.clone()))),
+            PumpEnd::IdleTimeout if error_events => Some(Ok(error_event(
+                "Timeout",
+                IDLE_TIMEOUT_CODE,
+                &format!("The upstream sent nothing for {} seconds", timeout.as_secs()),
+            ))),
+            _ => None,
+        }
+    }
+}
+
+/// Spawns a task that reads the upstream body and forwards its chunks into a channel.
+///
+/// Once `STREAM_BUFFER_CHUNKS` chunks wait on the client, the task stops reading upstream until
+/// it catches up, so nothing is dropped. A gzipped body is relayed decoded, as its headers say
+/// (see `filter_response_headers`). An upstream silent for `STREAM_IDLE_TIMEOUT_SECS` has its
+/// stream ended. Event streams that fail or time out end with an `event: error` when
+/// `error_events` is set, as it is unless the request has `errorEvents=0`.
+fn forward_upstream(env: &Env, response: reqwest::Response, error_events: bool) -> UpstreamBody {
+    let status = response.status().as_u16();
+    let var = env.var(STREAM_BUFFER_CHUNKS_VAR).ok().map(|var| var.to_string());
+    let (mut tx, rx) = futures_channel::mpsc::channel(stream_buffer_chunks(var.as_deref()));
+    let (cancel, cancelled) = futures_channel::oneshot::channel();
+
+    let var = env.var(STREAM_IDLE_TIMEOUT_VAR).ok().map(|var| var.to_string());
+    let timeout = stream_idle_timeout(var.as_deref());
+    let idle_timer = move || match timeout {
+        Some(timeout) => Delay::from(timeout).left_future(),
+        None => futures_util::future::pending().right_future(),
+    };
+    let content_type = response.headers().get(reqwest::header::CONTENT_TYPE);
+    // Anything else would be corrupted by an event
+    let error_events =
+        error_events && sse::is_event_stream(content_type.and_then(|value| value.to_str().ok()));
+
+    let encoding = response.headers().get(reqwest::header::CONTENT_ENCODING);
+    let decoder = compression::ResponseDecoder::new(encoding.and_then(|value| value.to_str().ok()));
+    let body = decoded_body(response.bytes_stream(), decoder);
+
+    let failure = Rc::new(RefCell::new(None));
+    let failed = failure.clone();
+    wasm_bindgen_futures::spawn_local(async move {
+        let end = pump_upstream(body, &mut tx, cancelled, idle_timer).await;
+        match &end {
+            PumpEnd::Completed => console_log!("Upstream stream completed with status {}", status),
+            PumpEnd::ClientDisconnected => {
+                console_warn!("Client disconnected, aborted upstream stream")
+            }
+            PumpEnd::UpstreamError(e) => console_error!("Error while streaming: {}", e),
+            PumpEnd::IdleTimeout => {
+                console_error!("Upstream silent for {:?}, ended the stream", timeout)
+            }
+        }
+
+        *failed.borrow_mut() = end.error();
+        if let Some(item) = end.closing_item(timeout.unwrap_or_default(), error_events) {
+            let _ = send_chunk(&mut tx, item).await;
+        }
+    });
+
+    UpstreamBody {
+        rx,
+        _cancel: cancel,
+        failure,
+    }
+}
+
+/// Decodes the chunks of an upstream body with `decoder`, leaving out those that decode to nothing
+fn decoded_body<S, B, E>(
+    stream: S,
+    decoder: compression::ResponseDecoder,
+) -> impl futures_util::Stream<Item = std::result::Result<Vec<u8>, String>>
+where
+    S: futures_util::Stream<Item = std::result::Result<B, E>>,
+    B: Into<Vec<u8>>,
+    E: std::fmt::Display,
+{
+    let decoder = Rc::new(RefCell::new(decoder));
+    let finishing = decoder.clone();
+    stream
+        .map(move |chunk| match chunk {
+            Ok(chunk) => decoder.borrow_mut().push(chunk.into()).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        })
+        .chain(futures_util::stream::once(async move {
+            finishing.borrow_mut().finish().map_err(|e| e.to_string())
+        }))
+        .filter(|chunk| std::future::ready(!matches!(chunk, Ok(chunk) if chunk.is_empty())))
+}
+
+/// Runs `on_end` once every item of `stream` has been forwarded to the client
+fn on_stream_end<S, F>(stream: S, on_end: F) -> impl futures_util::Stream<Item = S::Item>
+where
+    S: futures_util::Stream,
+    F: FnOnce() + 'static,
+{
+    stream.chain(
+        futures_util::stream::once(async move { on_end() }).filter_map(|_| async { None }),
+    )
+}
+
+/// Stream wrapper calling `on_abandoned` if dropped before the stream ended
+struct AbandonGuard<S, F: FnOnce()> {
+    stream: std::pin::Pin<Box<S>>,
+    on_abandoned: Option<F>,
+}
+
+impl<S: futures_util::Stream, F: FnOnce() + Unpin> futures_util::Stream for AbandonGuard<S, F> {
+    type Item = S::Item;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let item = futures_util::ready!(self.stream.as_mut().poll_next(cx));
+        if item.is_none() {
+            self.on_abandoned = None;
+        }
+        std::task::Poll::Ready(item)
+    }
+}
+
+impl<S, F: FnOnce()> Drop for AbandonGuard<S, F> {
+    fn drop(&mut self) {
+        if let Some(on_abandoned) = self.on_abandoned.take() {
+            on_abandoned();
+        }
+    }
+}
+
+/// Calls `on_abandoned` if the relayed stream is dropped before it ends, as it is when the
+/// client disconnects
+fn on_stream_abandoned<S, F>(stream: S, on_abandoned: F) -> AbandonGuard<S, F>
+where
+    S: futures_util::Stream,
+    F: FnOnce() + Unpin,
+{
+    AbandonGuard {
+        stream: Box::pin(stream),
+        on_abandoned: Some(on_abandoned),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProxyUrlParams {
+    /// Required; defaulted only so `validate` can report it as a structured error
+    #[serde(default)]
+    pub app: String,
+    /// Upstream URL; replaced by the decoded `ub` when that is present
+    #[serde(default)]
+    pub u: String,
+    /// Upstream URL encoded as base64url, immune to query-string mangling
+    pub ub: Option<String>,
+    pub env_id: Option<String>,
+    pub ten_id: Option<String>,
+    pub mod_id: Option<String>,
+    pub ses_id: Option<String>,
+    pub req_id: Option<String>,
+    #[serde(rename = "api-version")]
+    pub api_version: Option<String>,
+    /// Upstream HTTP method (`GET`, `POST`, `PUT`, `PATCH` or `DELETE`); defaults to `POST`
+    pub m: Option<String>,
+    /// Named upstream from the `TARGETS` KV namespace, used instead of `u`
+    pub target: Option<String>,
+    /// End-user identifier; see `sanitize_user_id`
+    pub usr_id: Option<String>,
+    /// Deployment recorded on analytics, overriding `DEPLOYMENT_NAME`
+    pub dep: Option<String>,
+    /// `noUsage=1` forwards streams untouched, for upstreams that reject `stream_options`
+    pub no_usage: Option<String>,
+    /// `stripUsage=1` keeps the usage event the proxy asked for out of the client's stream
+    pub strip_usage: Option<String>,
+    /// `ensureDone=0` relays streams that end without `data: [DONE]` as they are; see
+    /// `ensures_done`
+    pub ensure_done: Option<String>,
+    /// `keepAlive=15` pings event streams every 15 seconds of upstream silence; `0` disables
+    pub keep_alive: Option<String>,
+    /// `errorEvents=0` closes event streams that fail upstream as they are, with no
+    /// `event: error` for the client
+    pub error_events: Option<String>,
+    /// `azureCompat=1` strips Azure's content filter annotations from streamed events, for
+    /// clients written against OpenAI; not with `noUsage=1`, which relays streams untouched
+    pub azure_compat: Option<String>,
+    /// `provider=anthropic` (or `openai`, `azure`, `gemini`, `cohere`, `mistral`) treats the
+    /// upstream as that provider's API, for gateways that speak it on another host; see
+    /// `ProxyUrlParams::provider`
+    pub provider: Option<String>,
+    /// `meta.*` parameters (prefix stripped); any other unknown parameter is dropped
+    #[serde(flatten)]
+    pub extra: HashMap<String, String>,
+}
+
+impl ProxyUrlParams {
+    /// Whether the client opted out of `stream_options` injection and usage scanning
+    fn skips_usage(&self) -> bool {
+        matches!(self.no_usage.as_deref(), Some("1" | "true"))
+    }
+
+    /// Whether the client wants streams without their usage event, for SDKs that choke on it
+    fn strips_usage(&self) -> bool {
+        matches!(self.strip_usage.as_deref(), Some("1" | "true"))
+    }
+
+    /// Whether the client asked for streams without Azure's content filter annotations
+    fn azure_compat(&self) -> bool {
+        matches!(self.azure_compat.as_deref(), Some("1" | "true"))
+    }
+
+    /// Whether streams that fail upstream end with an `event: error`, unless turned off
+    fn sends_error_events(&self) -> bool {
+        !matches!(self.error_events.as_deref(), Some("0" | "false"))
+    }
+
+    /// Whether a stream the upstream ends without `data: [DONE]` gets one, so clients waiting
+    /// for it don't hang: by default for (chat) completions, which always end with it
+    fn ensures_done(&self, upstream: &str) -> bool {
+        match self.ensure_done.as_deref() {
+            Some("0" | "false") => false,
+            Some("1" | "true") => true,
+            _ => Url::parse(upstream)
+                .is_ok_and(|url| url.path().trim_end_matches('/').ends_with("/completions")),
+        }
+    }
+
+    /// The provider of `upstream`: the `provider` parameter's, else the one serving its host
+    fn provider(&self, upstream: &str) -> Provider {
+        let named = self.provider.as_deref().and_then(Provider::from_name);
+        named.unwrap_or_else(|| Provider::from_url(upstream))
+    }
+
+    /// The format the usage of a response to `upstream` comes in: its provider's, where a
+    /// `/responses` path on OpenAI or Azure is the Responses API's
+    fn usage_format(&self, upstream: &str) -> UsageFormat {
+        let format = UsageFormat::from(self.provider(upstream));
+
+        // OpenAI's Responses API, on OpenAI or Azure, streams events of its own
+        let url = Url::parse(upstream).ok();
+        let path = url.as_ref().map(|url| url.path().trim_end_matches('/'));
+        if format == UsageFormat::OpenAi && path.is_some_and(|path| path.ends_with("/responses")) {
+            return UsageFormat::Responses;
+        }
+        format
+    }
+
+    /// Whether `stream_proxy` forwards the body untouched, so it needn't be buffered
+    fn pipes_body(&self) -> bool {
+        self.skips_usage() && self.usr_id.as_deref().and_then(sanitize_user_id).is_none()
+    }
+
+    /// Parses and validates the proxy parameters of a request, resolving `ub` into `u`
+    fn from_request(req: &Request) -> std::result::Result<Self, ParamError> {
+        let url = req
+            .url()
+            .map_err(|e| ParamError::invalid_query(e.to_string()))?;
+        let param = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+        };
+
+        let header = |name: &str| req.headers().get(name).ok().flatten();
+        let request_id = || param("reqId").or_else(|| header(REQUEST_ID_HEADER));
+
+        let mut params: Self = req
+            .query()
+            .map_err(|e| ParamError::invalid_query(e.to_string()).with_request_id(request_id()))?;
+        if params.ub.is_none() {
+            if let Some(u) = reattach_upstream_params(&params.u, url.query()) {
+                console_warn!("Re-attached the query of a `u` that wasn't URL-encoded: {u}");
+                params.u = u;
+            }
+        }
+        params
+            .prepare(|key| param(key).is_some(), header)
+            .map_err(|e| e.with_request_id(request_id()))?;
+
+        let dropped = params.keep_meta_params();
+        if !dropped.is_empty() {
+            console_warn!("Dropped meta parameters over the limit: {:?}", dropped);
+        }
+        Ok(params)
+    }
+
+    /// Reduces `extra` to the bounded `meta.*` parameters, returning the dropped `meta.*` keys
+    fn keep_meta_params(&mut self) -> Vec<String> {
+        let mut meta = std::mem::take(&mut self.extra)
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(META_PARAM_PREFIX)?.to_string(), value)))
+            .filter(|(key, _)| !key.is_empty())
+            .collect::<Vec<_>>();
+        // Sorted so the same entries survive the cap on every request
+        meta.sort();
+
+        let dropped = meta
+            .split_off(meta.len().min(MAX_META_PARAMS))
+            .into_iter()
+            .map(|(key, _)| format!("{META_PARAM_PREFIX}{key}"))
+            .collect();
+
+        self.extra = meta
+            .into_iter()
+            .map(|(key, value)| (key, value.chars().take(MAX_META_VALUE_LEN).collect()))
+            .collect();
+
+        dropped
+    }
+
+    /// Fills metadata from headers, then validates.
+    ///
+    /// `present` tells whether a parameter was in the query string; `header` looks up a header.
+    fn prepare(
+        &mut self,
+        present: impl Fn(&str) -> bool,
+        header: impl Fn(&str) -> Option<String>,
+    ) -> std::result::Result<(), ParamError> {
+        let filled = self.fill_from_headers(&present, header);
+        self.validate(|key| present(key) || filled.contains(&key))
+    }
+
+    /// Takes the metadata absent from the query string from its `X-EM-*` header, if any.
+    ///
+    /// Returns the parameters that were filled; query parameters always win.
+    fn fill_from_headers(
+        &mut self,
+        present: impl Fn(&str) -> bool,
+        header: impl Fn(&str) -> Option<String>,
+    ) -> Vec<&'static str> {
+        let mut filled = Vec::new();
+
+        if !present("app") {
+            if let Some(app) = header(APP_HEADER) {
+                self.app = app;
+                filled.push("app");
+            }
+        }
+
+        let optional = [
+            ("tenId", TENANT_ID_HEADER, &mut self.ten_id),
+            ("modId", MODULE_ID_HEADER, &mut self.mod_id),
+            ("sesId", SESSION_ID_HEADER, &mut self.ses_id),
+            ("reqId", REQUEST_ID_HEADER, &mut self.req_id),
+        ];
+        for (param, name, field) in optional {
+            if present(param) {
+                continue;
+            }
+            if let Some(value) = header(name) {
+                *field = Some(value);
+                filled.push(param);
+            }
+        }
+
+        filled
+    }
+
+    /// Checks the required parameters and resolves `ub` into `u`.
+    ///
+    /// `present` tells whether a parameter was in the query at all, to tell missing from empty.
+    fn validate(&mut self, present: impl Fn(&str) -> bool) -> std::result::Result<(), ParamError> {
+        if !present("app") {
+            return Err(ParamError::missing("app"));
+        }
+        if self.app.trim().is_empty() {
+            return Err(ParamError::empty("app"));
+        }
+
+        let identifiers = [
+            ("app", Some(&self.app)),
+            ("envId", self.env_id.as_ref()),
+            ("tenId", self.ten_id.as_ref()),
+            ("modId", self.mod_id.as_ref()),
+            ("sesId", self.ses_id.as_ref()),
+            ("reqId", self.req_id.as_ref()),
+            ("dep", self.dep.as_ref()),
+        ];
+        for (field, value) in identifiers {
+            if let Some(value) = value {
+                check_identifier(field, value)?;
+            }
+        }
+        if let Some(provider) = self.provider.as_deref() {
+            if Provider::from_name(provider).is_none() {
+                let names = Provider::ALL.map(Provider::name).join(", ");
+                let message = format!("`provider` must be one of {names}");
+                return Err(ParamError::invalid("provider", message));
+            }
+        }
+
+        if let Some(encoded) = self.ub.as_deref() {
+            self.u = decode_upstream_param(encoded)
+                .map_err(|e| ParamError::invalid("ub", format!("Invalid `ub` parameter: {e}")))?;
+            return Ok(());
+        }
+
+        // A named target replaces `u`
+        if self.target.is_some() && self.u.is_empty() {
+            return Ok(());
+        }
+
+        if !present("u") {
+            return Err(ParamError::missing("u"));
+        }
+        if self.u.trim().is_empty() {
+            return Err(ParamError::empty("u"));
+        }
+        if let Err(e) = Url::parse(&self.u) {
+            return Err(ParamError {
+                code: "invalid_url",
+                field: Some("u"),
+                message: format!("`u` is not a valid URL: {e}"),
+                request_id: None,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes a base64url upstream URL (padding optional) and checks that it is an http(s) URL
+fn decode_upstream_param(encoded: &str) -> std::result::Result<String, String> {
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(encoded.trim().trim_end_matches('='))
+        .map_err(|e| format!("not base64url: {e}"))?;
+    let decoded = String::from_utf8(bytes).map_err(|_| "not valid UTF-8".to_string())?;
+
+    match Url::parse(&decoded) {
+        Ok(url) if matches!(url.scheme(), "http" | "https" | "ws" | "wss") => Ok(decoded),
+        Ok(url) => Err(format!("unsupported scheme `{}`", url.scheme())),
+        Err(e) => Err(format!("not a URL: {e}")),
+    }
+}
+
+/// Prefix of the free-form analytics dimensions (`meta.exp=chatbot-v2`)
+const META_PARAM_PREFIX: &str = "meta.";
+
+/// How a streamed response reports its usage
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UsageFormat {
+    /// A usage event before `data: [DONE]`, once `stream_options.include_usage` asks for it
+    OpenAi,
+    /// Input tokens on `message_start`, output tokens on the last `message_delta`
+    Anthropic,
+    /// Cumulative `usageMetadata` on every object, SSE (`alt=sse`) or a JSON array
+    Gemini,
+    /// Billed units on the `stream-end` object of newline-delimited JSON
+    Cohere,
+    /// OpenAI's chunks, with usage on the last one with choices; `stream_options` gets a 422
+    Mistral,
+    /// OpenAI's Responses API: `response.*` events, with usage on `response.completed`
+    Responses,
+}
+
+impl From<Provider> for UsageFormat {
+    /// The format of the provider's chat API
+    fn from(provider: Provider) -> Self {
+        match provider {
+            Provider::OpenAi | Provider::Azure => UsageFormat::OpenAi,
+            Provider::Anthropic => UsageFormat::Anthropic,
+            Provider::Gemini => UsageFormat::Gemini,
+            Provider::Cohere => UsageFormat::Cohere,
+            Provider::Mistral => UsageFormat::Mistral,
+        }
+    }
+}
+
+impl UsageFormat {
+    /// Whether streams only report usage when asked to with `stream_options.include_usage`
+    fn requests_usage(self) -> bool {
+        self == UsageFormat::OpenAi
+    }
+
+    /// The extractor reading usage in this format
+    fn extractor(self) -> Box<dyn UsageExtractor> {
+        match self {
+            UsageFormat::OpenAi | UsageFormat::Mistral => Box::<ChunkUsageExtractor>::default(),
+            UsageFormat::Anthropic => Box::<anthropic::AnthropicUsageScanner>::default(),
+            UsageFormat::Gemini => Box::<gemini::GeminiUsageScanner>::default(),
+            UsageFormat::Cohere => Box::<cohere::CohereUsageScanner>::default(),
+            UsageFormat::Responses => Box::<responses::ResponsesUsageExtractor>::default(),
+        }
+    }
+}
+
+/// Query parameters consumed by the proxy itself (see `ProxyUrlParams`)
+const PROXY_PARAM_KEYS: [&str; 20] = [
+    "app",
+    "u",
+    "ub",
+    "envId",
+    "tenId",
+    "modId",
+    "sesId",
+    "reqId",
+    "api-version",
+    "m",
+    "target",
+    "usrId",
+    "dep",
+    "noUsage",
+    "stripUsage",
+    "ensureDone",
+    "keepAlive",
+    "errorEvents",
+    "azureCompat",
+    "provider",
+];
+
+/// Whether a query parameter is the proxy's own rather than the upstream's
+fn is_proxy_param(key: &str) -> bool {
+    PROXY_PARAM_KEYS.contains(&key) || key.starts_with(META_PARAM_PREFIX)
+}
+
+/// Rebuilds a `u` that was sent without percent-encoding.
+///
+/// In `u=https://host/path?api-version=1&deployment=x&app=a`, the `&` splits the upstream
+/// query, so `deployment=x` arrives as a parameter of its own. The run of unknown parameters
+/// right after such a `u` is appended back to it, as raw segments. Returns `None` when `u`
+/// was encoded (its raw segment has no literal `?`) or lost nothing.
+fn reattach_upstream_params(u: &str, raw_query: Option<&str>) -> Option<String> {
+    let mut segments = raw_query?.split('&');
+    let raw_u = segments.find(|pair| pair.starts_with("u="))?;
+    if !raw_u.contains('?') {
+        return None;
+    }
+
+    let stray = segments
+        .take_while(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            !key.is_empty() && !is_proxy_param(key)
+        })
+        .collect::<Vec<_>>();
+    if stray.is_empty() {
+        return None;
+    }
+
+    Some(format!("{u}&{}", stray.join("&")))
+}
+
+/// Most `meta.*` parameters kept per request
+const MAX_META_PARAMS: usize = 8;
+/// Longest `meta.*` value kept, in characters
+const MAX_META_VALUE_LEN: usize = 64;
+
+/// Longest `app`/`envId`/`tenId`/`modId`/`sesId`/`reqId`/`dep` accepted, in characters
+const MAX_IDENTIFIER_LEN: usize = 128;
+
+/// Rejects identifiers that are too long for analytics blobs or carry unexpected characters
+fn check_identifier(field: &'static str, value: &str) -> std::result::Result<(), ParamError> {
+    if value.chars().count() > MAX_IDENTIFIER_LEN {
+        return Err(ParamError::invalid(
+            field,
+            format!("`{field}` must be at most {MAX_IDENTIFIER_LEN} characters"),
+        ));
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    {
+        return Err(ParamError::invalid(
+            field,
+            format!("`{field}` may only contain letters, digits, `-`, `_`, `.` and `:`"),
+        ));
+    }
+    Ok(())
+}
+
+/// Longest end-user identifier kept, in characters
+const MAX_USER_ID_LEN: usize = 128;
+
+/// Keeps the characters of an end-user id that are safe in blobs and upstream bodies
+fn sanitize_user_id(raw: &str) -> Option<String> {
+    let sanitized = raw
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@' | ':' | '+'))
+        .take(MAX_USER_ID_LEN)
+        .collect::<String>();
+
+    (!sanitized.is_empty()).then_some(sanitized)
+}
+
+/// Why a request body can't be forwarded
+#[derive(Debug, PartialEq)]
+enum BodyError {
+    /// The body isn't JSON
+    InvalidJson(String),
+    /// The body is JSON, but its root isn't an object
+    NotAnObject,
+    /// The body is over the `MAX_BODY_BYTES` limit
+    TooLarge { size: usize, limit: usize },
+    /// The `content-type` isn't JSON
+    UnsupportedContentType(String),
+    /// The `content-encoding` is neither gzip nor deflate
+    UnsupportedEncoding(String),
+    /// The body doesn't decompress with its `content-encoding`
+    CorruptEncoding {
+        encoding: &'static str,
+        error: String,
+    },
+    /// `messages` isn't a non-empty array of messages with a `role`
+    InvalidMessages {
+        index: Option<usize>,
+        reason: &'static str,
+    },
+    /// A sampling parameter is outside the range the providers accept
+    OutOfRange {
+        field: &'static str,
+        value: f64,
+        min: f64,
+        max: f64,
+    },
+}
+
+impl std::fmt::Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyError::InvalidJson(e) => write!(f, "Invalid JSON body: {e}"),
+            BodyError::NotAnObject => write!(f, "The JSON body must be an object"),
+            BodyError::TooLarge { size, limit } => {
+                write!(f, "The body is {size} bytes, over the {limit} byte limit")
+            }
+            BodyError::UnsupportedContentType(content_type) => write!(
+                f,
+                "Unsupported content-type `{content_type}`: this route expects application/json; \
+                 send multipart uploads to /audio/transcriptions or /files"
+            ),
+            BodyError::UnsupportedEncoding(encoding) => write!(
+                f,
+                "Unsupported content-encoding `{encoding}`, expected gzip or deflate"
+            ),
+            BodyError::CorruptEncoding { encoding, error } => {
+                write!(f, "Invalid {encoding} body: {error}")
+            }
+            BodyError::InvalidMessages {
+                index: Some(index),
+                reason,
+            } => write!(f, "`messages[{index}]` {reason}"),
+            BodyError::InvalidMessages { index: None, reason } => {
+                write!(f, "`messages` {reason}")
+            }
+            BodyError::OutOfRange {
+                field,
+                value,
+                min,
+                max,
+            } => write!(f, "`{field}` must be between {min} and {max}, got {value}"),
+        }
+    }
+}
+
+impl BodyError {
+    fn to_response(&self) -> Result<Response> {
+        match self {
+            BodyError::TooLarge { size, limit } => Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "Payload Too Large",
+                "message": self.to_string(),
+                "limit": limit,
+                "size": size,
+            }))?
+            .with_status(413)),
+            BodyError::UnsupportedContentType(content_type) => Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "Unsupported Media Type",
+                "message": self.to_string(),
+                "contentType": content_type,
+            }))?
+            .with_status(415)),
+            BodyError::UnsupportedEncoding(encoding) => Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "Unsupported Media Type",
+                "message": self.to_string(),
+                "contentEncoding": encoding,
+            }))?
+            .with_status(415)),
+            BodyError::InvalidMessages { index, .. } => Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "Invalid Messages",
+                "message": self.to_string(),
+                "index": index,
+            }))?
+            .with_status(400)),
+            BodyError::OutOfRange { field, .. } => Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "Invalid Parameter",
+                "message": self.to_string(),
+                "field": field,
+            }))?
+            .with_status(400)),
+            _ => Response::error(self.to_string(), 400),
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Accepts `application/json` bodies, with or without parameters such as `charset`.
+///
+/// A missing `content-type` is let through: the body still has to parse as JSON.
+fn check_content_type(content_type: Option<&str>) -> std::result::Result<(), BodyError> {
+    let Some(content_type) = content_type else {
+        return Ok(());
+    };
+
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    if essence.eq_ignore_ascii_case("application/json") {
+        Ok(())
+    } else {
+        Err(BodyError::UnsupportedContentType(content_type.to_string()))
+    }
+}
+
+/// `trim_body` for a buffered body, reusing its allocation rather than copying it
+fn trim_body_vec(mut body: Vec<u8>) -> Vec<u8> {
+    let trimmed = trim_body(&body);
+    let start = trimmed.as_ptr() as usize - body.as_ptr() as usize;
+    let end = start + trimmed.len();
+
+    body.truncate(end);
+    body.drain(..start);
+    body
+}
+
+/// Drops the UTF-8 byte order mark and the surrounding whitespace some clients send
+fn trim_body(body: &[u8]) -> &[u8] {
+    body.trim_ascii_start()
+        .strip_prefix(UTF8_BOM)
+        .unwrap_or(body)
+        .trim_ascii()
+}
+
+/// Methods `stream_proxy` may use towards the upstream
+const UPSTREAM_METHODS: [reqwest::Method; 5] = [
+    reqwest::Method::GET,
+    reqwest::Method::POST,
+    reqwest::Method::PUT,
+    reqwest::Method::PATCH,
+    reqwest::Method::DELETE,
+];
+
+/// Resolves the `m` query parameter to an allowed upstream method
+fn upstream_method(m: Option<&str>) -> std::result::Result<reqwest::Method, String> {
+    let Some(m) = m else {
+        return Ok(reqwest::Method::POST);
+    };
+
+    UPSTREAM_METHODS
+        .into_iter()
+        .find(|method| method.as_str().eq_ignore_ascii_case(m))
+        .ok_or_else(|| format!("Unsupported method `{m}`, expected one of GET, POST, PUT, PATCH, DELETE"))
+}
+
+/// Whether the request body is forwarded for `method`
+fn method_sends_body(method: &reqwest::Method) -> bool {
+    !matches!(*method, reqwest::Method::GET | reqwest::Method::DELETE)
+}
+
+#[derive(Debug, Deserialize)]
+struct AzureReqBodyStream {
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub stream_options: Option<serde_json::Value>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<serde_json::Value>,
+    #[serde(default)]
+    pub top_p: Option<serde_json::Value>,
+    #[serde(default)]
+    pub messages: Option<serde_json::Value>,
+}
+
+impl AzureReqBodyStream {
+    /// Whether the client explicitly sent `stream_options.include_usage: false`
+    fn disables_usage(&self) -> bool {
+        self.stream_options
+            .as_ref()
+            .and_then(|options| options.get("include_usage"))
+            .is_some_and(|include| include == false)
+    }
+}
+
+/// Milliseconds between two `UsageAnalytics::current_timestamp` readings
+fn elapsed_ms(since: f64, now: f64) -> u32 {
+    (now - since).max(0.0) as u32
+}
+
+/// What a usage event becomes: its record, or why it couldn't be read
+type UsageRecord = std::result::Result<UsageAnalytics, UnparsedUsage>;
+
+/// Scans a relayed SSE stream for its usage events.
+///
+/// Events are reassembled from however many network chunks they arrive in, up to the parser's
+/// size cap, so usage split across chunks isn't lost. The stream's one analytics record comes
+/// from `record` once it ended, along with what was relayed.
+struct UsageScanner {
+    parser: sse::SseParser,
+    /// The request's attribution, completed with each usage event's model and tokens
+    template: UsageAnalytics,
+    /// Reads the usage the way the upstream's provider reports it
+    extractor: Box<dyn UsageExtractor>,
+    /// The usage the last usage event reported
+    usage: Option<ExtractedUsage>,
+    /// Whether `record` handed out the stream's record
+    recorded: bool,
+    /// Network chunks and bytes relayed to the client
+    relayed_chunks: u32,
+    relayed_bytes: u64,
+    /// When the first and the last network chunk were relayed
+    first_chunk_at: Option<f64>,
+    last_chunk_at: Option<f64>,
+    /// Set when events are relayed once complete, to be stripped or rewritten
+    framer: Option<sse::SseFramer>,
+    /// Whether usage events are kept from the client (`stripUsage=1`)
+    strip_usage: bool,
+    /// Whether Azure's content filter annotations are (`azureCompat=1`)
+    azure_compat: bool,
+    /// Whether `finish` ends a stream the upstream left without `data: [DONE]` with one
+    ensure_done: bool,
+    /// Whether `data: [DONE]` was relayed
+    done: bool,
+    /// When the upstream request was sent, for the time to the first choice (`ttft_ms`)
+    dispatched_at: Option<f64>,
+    clock: fn() -> f64,
+}
+
+impl UsageScanner {
+    fn new(template: UsageAnalytics) -> Self {
+        Self {
+            parser: sse::SseParser::default(),
+            template,
+            extractor: UsageFormat::OpenAi.extractor(),
+            usage: None,
+            recorded: false,
+            relayed_chunks: 0,
+            relayed_bytes: 0,
+            first_chunk_at: None,
+            last_chunk_at: None,
+            framer: None,
+            strip_usage: false,
+            azure_compat: false,
+            ensure_done: false,
+            done: false,
+            dispatched_at: None,
+            clock: UsageAnalytics::current_timestamp,
+        }
+    }
+
+    /// Records on every record the time from `dispatched_at` to the first event with choices
+    fn timed_from(mut self, dispatched_at: f64) -> Self {
+        self.dispatched_at = Some(dispatched_at);
+        self
+    }
+
+    /// Reads the usage in `format` instead of OpenAI's
+    fn format(mut self, format: UsageFormat) -> Self {
+        self.extractor = format.extractor();
+        self
+    }
+
+    /// Ends streams the upstream closed without `data: [DONE]` with one, in `finish`
+    fn ensure_done(mut self) -> Self {
+        self.ensure_done = true;
+        self
+    }
+
+    /// Keeps the usage events it reads out of what `relay` forwards
+    fn strip_usage(mut self) -> Self {
+        self.framer = Some(sse::SseFramer::default());
+        self.strip_usage = true;
+        self
+    }
+
+    /// Strips Azure's content filter annotations from what `relay` forwards
+    fn azure_compat(mut self) -> Self {
+        self.framer = Some(sse::SseFramer::default());
+        self.azure_compat = true;
+        self
+    }
+
+    /// Feeds a network chunk, returning the bytes to forward to the client along with the
+    /// usage events it completes.
+    ///
+    /// Without `strip_usage` or `azure_compat` that's the chunk itself; with them, whole
+    /// events are forwarded once complete, byte for byte unless they had to be rewritten.
+    fn relay(&mut self, chunk: Vec<u8>) -> (Vec<u8>, Vec<UsageRecord>) {
+        let now = (self.clock)();
+        self.first_chunk_at.get_or_insert(now);
+        self.last_chunk_at = Some(now);
+        self.relayed_chunks += 1;
+
+        let (relayed, records) = match self.framer.as_mut() {
+            None => {
+                let records = self.push(&chunk);
+                (chunk, records)
+            }
+            Some(framer) => {
+                let mut relayed = Vec::new();
+                let mut records = Vec::new();
+                for frame in framer.push(&chunk) {
+                    let frame_records = self.push(&frame);
+                    let usage = frame_records.iter().any(|record| record.is_ok());
+                    records.extend(frame_records);
+
+                    if self.strip_usage && usage {
+                        continue;
+                    }
+                    if !self.azure_compat {
+                        relayed.extend(frame);
+                    } else if let Some(frame) = azure_compat::strip_filter_annotations(&frame) {
+                        relayed.extend_from_slice(&frame);
+                    }
+                }
+                (relayed, records)
+            }
+        };
+        self.relayed_bytes += relayed.len() as u64;
+        (relayed, records)
+    }
+
+    /// The bytes that still go to the client once the upstream stream ended: what `relay`
+    /// holds of an event it ended in the middle of, and with `ensure_done` the missing
+    /// `data: [DONE]`
+    fn finish(&mut self) -> Vec<u8> {
+        let mut rest = self.framer.as_mut().map(sse::SseFramer::finish).unwrap_or_default();
+        if self.ensure_done && !self.done {
+            // Whatever the upstream cut off is ended first, or `[DONE]` would join it; the
+            // parser never sees the partial event the framer held back
+            if !rest.is_empty() || !self.parser.is_between_events() {
+                rest.extend(b"\n\n");
+            }
+            rest.extend(sse::DONE_EVENT);
+            self.done = true;
+        }
+        self.relayed_bytes += rest.len() as u64;
+        rest
+    }
+
+    /// Feeds a network chunk, returning a record for every usage event it completes
+    fn push(&mut self, chunk: &[u8]) -> Vec<UsageRecord> {
+        // Streams that may not be SSE are read whole
+        if self.extractor.reads_chunks() {
+            let extracted = self.extractor.feed_chunk(chunk);
+            return extracted.map(|extracted| self.usage_record(extracted)).into_iter().collect();
+        }
+
+        let mut records = Vec::new();
+        for event in self.parser.push(chunk) {
+            if event.is_done() {
+                self.done = true;
+                continue;
+            }
+
+            let started = self.choices_seen();
+            let extracted = self.extractor.feed_event(&event);
+            if !started && self.choices_seen() {
+                let now = (self.clock)();
+                self.template.ttft_ms = self.dispatched_at.map(|at| elapsed_ms(at, now));
+            }
+            if let Some(extracted) = extracted {
+                records.push(self.usage_record(extracted));
+            }
+        }
+        records
+    }
+
+    /// Whether an event with choices was seen, for the time to the first one
+    fn choices_seen(&self) -> bool {
+        self.extractor.content().is_some_and(|content| content.chunks > 0)
+    }
+
+    /// The record of a usage event, whose usage stands unless another follows
+    fn usage_record(&mut self, extracted: Extracted) -> UsageRecord {
+        let usage = extracted?;
+        let analytics = self.usage_analytics(&usage);
+        self.usage = Some(usage);
+        Ok(analytics)
+    }
+
+    /// The template completed with `usage`
+    fn usage_analytics(&self, usage: &ExtractedUsage) -> UsageAnalytics {
+        let mut analytics = self.template.clone();
+        if let Some(model) = &usage.model {
+            analytics.model = model.clone();
+        }
+        analytics.prompt_tokens = usage.prompt_tokens;
+        analytics.completion_tokens = usage.completion_tokens;
+        analytics.total_tokens = usage.total_tokens;
+        analytics.cached_tokens = usage.cached_tokens;
+        analytics.reasoning_tokens = usage.reasoning_tokens;
+        analytics.finish_reason = usage.finish_reason.clone();
+        analytics.system_fingerprint = usage.system_fingerprint.clone();
+        analytics.service_tier = usage.service_tier.clone();
+        analytics
+    }
+
+    /// The stream's one record, with what was relayed: once it ended, or when the client left
+    /// it (`disconnected`); `None` once handed out.
+    ///
+    /// A stream without usage is recorded without tokens, or when the client left it with the
+    /// completion tokens estimated from the events relayed until then.
+    fn record(&mut self, disconnected: bool) -> Option<UsageAnalytics> {
+        if std::mem::replace(&mut self.recorded, true) {
+            return None;
+        }
+
+        let usage = self.extractor.finish().or_else(|| self.usage.take());
+        let content = self.extractor.content();
+        let mut analytics = match usage {
+            Some(usage) => self.usage_analytics(&usage),
+            None => {
+                let mut analytics = self.template.clone();
+                if let Some(content) = content {
+                    if let Some(model) = &content.model {
+                        analytics.model = model.clone();
+                    }
+                    analytics.system_fingerprint = content.system_fingerprint.clone();
+                    analytics.service_tier = content.service_tier.clone();
+                }
+                if disconnected {
+                    let chunks = content.map_or(0, |content| content.chunks);
+                    analytics.completion_tokens = chunks;
+                    analytics.total_tokens = chunks;
+                }
+                analytics.usage_captured = false;
+                analytics
+            }
+        };
+        if let Some(content) = content {
+            analytics.completion_chars = content.completion_chars;
+            analytics.finish_reason = content.finish_reason.clone();
+            analytics.tool_call_count = content.tool_call_count;
+            analytics.tool_names = content.tool_names.clone();
+        }
+        analytics.ttft_ms = self.template.ttft_ms;
+        analytics.client_disconnected = disconnected;
+        analytics.chunk_count = self.relayed_chunks;
+        analytics.response_bytes = self.relayed_bytes;
+        if let (Some(first), Some(last)) = (self.first_chunk_at, self.last_chunk_at) {
+            analytics.duration_ms = elapsed_ms(first, last);
+        }
+        Some(analytics)
+    }
+}
+
+/// Reads the usage of OpenAI-style chunk streams (OpenAI, Azure, Mistral and compatible
+/// upstreams), along with what their content events showed.
+///
+/// Usage comes from the usage event `include_usage` adds, else from a content event carrying
+/// it; the first usage event stands, whatever follows.
+#[derive(Debug, Default)]
+struct ChunkUsageExtractor {
+    content: ContentStats,
+    /// The tool calls of the first choice
+    tool_calls: Vec<ToolCallKey>,
+    /// The stream's usage so far
+    usage: Option<ExtractedUsage>,
+    /// Whether `usage` came from the usage event, after which any other usage is ignored
+    usage_event: bool,
+}
+
+impl ChunkUsageExtractor {
+    /// Counts the calls the fragments start; the others continue a call's arguments
+    fn push_tool_calls(&mut self, fragments: &[ProbeToolCall]) {
+        for fragment in fragments {
+            let Some(key) = fragment.key() else { continue };
+            if self.tool_calls.contains(&key) {
+                continue;
+            }
+            self.tool_calls.push(key);
+            self.content.tool_call_count += 1;
+
+            let name = fragment.function.as_ref().and_then(|f| f.name.as_deref());
+            if let Some(name) = name.filter(|name| !name.is_empty()) {
+                if self.content.tool_names.len() < MAX_TOOL_NAMES {
+                    self.content.tool_names.push(name.to_string());
+                }
+            }
+        }
+    }
+}
+
+impl UsageExtractor for ChunkUsageExtractor {
+    fn feed_event(&mut self, event: &sse::SseEvent) -> Option<Extracted> {
+        let probe = UsageProbe::parse(&event.data);
+        if let Some(probe) = &probe {
+            if probe.choices.as_ref().is_some_and(|choices| !choices.is_empty()) {
+                self.content.chunks += 1;
+                self.content.completion_chars += probe.content_chars();
+                if let Some(reason) = probe.finish_reason() {
+                    self.content.finish_reason = Some(reason.to_string());
+                }
+                self.push_tool_calls(probe.tool_calls());
+            }
+            if self.content.model.is_none() {
+                self.content.model = probe.model.clone().filter(|model| !model.is_empty());
+            }
+            if self.content.system_fingerprint.is_none() {
+                self.content.system_fingerprint = probe.system_fingerprint.clone();
+            }
+            if self.content.service_tier.is_none() {
+                self.content.service_tier = probe.service_tier.clone();
+            }
+        }
+
+        match stats_chunk(&event.data, probe.as_ref())? {
+            // `"usage": null` on an event the probe couldn't read, as on every content event
+            // of Azure streams with `include_usage`
+            Ok(StatsChunk { usage: None, .. }) => None,
+            // Only the first usage event of a stream counts
+            Ok(_) if self.usage_event => None,
+            Ok(StatsChunk { model, usage: Some(stats) }) => {
+                let usage = ExtractedUsage {
+                    model: Some(model),
+                    prompt_tokens: stats.prompt_tokens,
+                    completion_tokens: stats.completion_tokens,
+                    total_tokens: stats.total_tokens,
+                    finish_reason: self.content.finish_reason.clone(),
+                    cached_tokens: stats.cached_tokens(),
+                    reasoning_tokens: stats.reasoning_tokens(),
+                    system_fingerprint: self.content.system_fingerprint.clone(),
+                    service_tier: self.content.service_tier.clone(),
+                };
+                self.usage = Some(usage.clone());
+
+                // Usage on a content event stands unless a summary event follows
+                let usage_event = probe.as_ref().is_some_and(UsageProbe::is_usage_event);
+                self.usage_event = usage_event;
+                usage_event.then_some(Ok(usage))
+            }
+            Err(error) => Some(Err(UnparsedUsage {
+                data: event.data.clone(),
+                error,
+                // Only events the probe read as carrying usage are parsed, but for the ones
+                // it couldn't read at all
+                reported_usage: probe.is_some(),
+            })),
+        }
+    }
+
+    fn finish(&mut self) -> Option<ExtractedUsage> {
+        self.usage.take()
+    }
+
+    fn content(&self) -> Option<&ContentStats> {
+        Some(&self.content)
+    }
+}
+
+/// The `finish_reason` of completions the provider's content filter cut off
+const CONTENT_FILTER_FINISH: &str = "content_filter";
+
+/// Just enough of a stream event to tell whether it carries usage
+#[derive(Debug, Deserialize)]
+struct UsageProbe {
+    /// `null` on every event but the last when the upstream streams usage
+    usage: Option<serde::de::IgnoredAny>,
+    /// Empty on the usage event (and on Azure's prompt filter results)
+    #[serde(default)]
+    choices: Option<Vec<ProbeChoice>>,
+    #[serde(default)]
+    model: Option<String>,
+    /// On every event of streams whose upstream reports them
+    #[serde(default)]
+    system_fingerprint: Option<String>,
+    #[serde(default)]
+    service_tier: Option<String>,
+}
+
+impl UsageProbe {
+    fn parse(data: &str) -> Option<Self> {
+        serde_json::from_str(data).ok()
+    }
+
+    /// Why the first choice ended, on the event that ends it
+    fn finish_reason(&self) -> Option<&str> {
+        self.choices.as_deref()?.first()?.finish_reason.as_deref()
+    }
+
+    /// The tool call fragments of the first choice's delta
+    fn tool_calls(&self) -> &[ProbeToolCall] {
+        let delta = self.choices.as_deref().and_then(|choices| choices.first()?.delta.as_ref());
+        delta.and_then(|delta| delta.tool_calls.as_deref()).unwrap_or_default()
+    }
+
+    /// Characters of the first choice's content delta; tool call deltas have none
+    fn content_chars(&self) -> u32 {
+        let content = self.choices.as_deref().and_then(|choices| choices.first()?.delta.as_ref());
+        match content.and_then(|delta| delta.content.as_ref()) {
+            Some(serde_json::Value::String(content)) => content.chars().count() as u32,
+            _ => 0,
+        }
+    }
+
+    /// Whether this is the event `include_usage` adds: usage, and no (or empty) choices
+    fn is_usage_event(&self) -> bool {
+        self.usage.is_some() && self.choices.as_ref().is_none_or(|choices| choices.is_empty())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeChoice {
+    #[serde(default)]
+    delta: Option<ProbeDelta>,
+    /// Set on the last event with choices: `stop`, `length`, `content_filter`, `tool_calls`...
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeDelta {
+    /// Any JSON, so an unexpected content type doesn't hide the event's usage
+    #[serde(default)]
+    content: Option<serde_json::Value>,
+    /// Fragments of tool calls; a call's first one has its id and name
+    #[serde(default)]
+    tool_calls: Option<Vec<ProbeToolCall>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeToolCall {
+    #[serde(default)]
+    index: Option<u32>,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    function: Option<ProbeFunction>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFunction {
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// Tells the tool calls of a stream apart: by their index, or their id when there's none
+#[derive(Debug, PartialEq, Eq)]
+enum ToolCallKey {
+    Index(u32),
+    Id(String),
+}
+
+impl ProbeToolCall {
+    fn key(&self) -> Option<ToolCallKey> {
+        match (self.index, &self.id) {
+            (Some(index), _) => Some(ToolCallKey::Index(index)),
+            (None, Some(id)) => Some(ToolCallKey::Id(id.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Tool names kept per record; calls past it are still counted
+const MAX_TOOL_NAMES: usize = 16;
+
+/// The usage of a stream event's `data`, given its `probe`; `None` for the events without any.
+///
+/// Usage is read from the parsed event, whatever its field order or spacing, so usage-like
+/// text in content never counts.
+fn stats_chunk(
+    data: &str,
+    probe: Option<&UsageProbe>,
+) -> Option<std::result::Result<StatsChunk, serde_json::Error>> {
+    match probe {
+        Some(UsageProbe { usage: None, .. }) => None,
+        // Whatever isn't a JSON object is reported too, as it may be a mangled usage event
+        _ => Some(serde_json::from_str::<StatsChunk>(data)),
+    }
+}
+
+/// The parts of a JSON (not streamed) completion or embeddings response needed for analytics
+#[derive(Debug, Deserialize)]
+struct JsonResponseBody {
+    #[serde(deserialize_with = "truncated_model")]
+    model: String,
+    usage: Usage,
+    /// Embeddings have none
+    #[serde(default)]
+    choices: Vec<ProbeChoice>,
+    #[serde(default)]
+    system_fingerprint: Option<String>,
+    #[serde(default)]
+    service_tier: Option<String>,
+}
+
+/// The record of a JSON response, from the template of the request's records
+fn json_usage(template: &UsageAnalytics, body: &[u8]) -> UsageRecord {
+    let parsed = serde_json::from_slice::<JsonResponseBody>(body).map_err(|error| {
+        let data = String::from_utf8_lossy(body).into_owned();
+        UnparsedUsage {
+            data,
+            error,
+            reported_usage: true,
+        }
+    })?;
+
+    let mut analytics = template.clone();
+    analytics.model = parsed.model;
+    analytics.prompt_tokens = parsed.usage.prompt_tokens;
+    analytics.completion_tokens = parsed.usage.completion_tokens;
+    analytics.total_tokens = parsed.usage.total_tokens;
+    analytics.cached_tokens = parsed.usage.cached_tokens();
+    analytics.reasoning_tokens = parsed.usage.reasoning_tokens();
+    analytics.finish_reason = parsed.choices.into_iter().next().and_then(|c| c.finish_reason);
+    analytics.system_fingerprint = parsed.system_fingerprint;
+    analytics.service_tier = parsed.service_tier;
+    Ok(analytics)
+}
+
+/// Longest model name recorded, in bytes: room for Azure deployment names and fine-tune ids,
+/// while a runaway one can't bloat the analytics record
+const MAX_MODEL_LEN: usize = 256;
+
+/// Deserializes a model name, cut to `MAX_MODEL_LEN` bytes at a character boundary rather than
+/// failing the event it's on
+fn truncated_model<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(truncate_model)
+}
+
+/// `model` cut to `MAX_MODEL_LEN` bytes at a character boundary
+fn truncate_model(mut model: String) -> String {
+    if model.len() > MAX_MODEL_LEN {
+        let end = (0..=MAX_MODEL_LEN).rev().find(|&end| model.is_char_boundary(end));
+        model.truncate(end.unwrap_or(0));
+    }
+    model
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsChunk {
+    #[serde(deserialize_with = "truncated_model")]
+    pub model: String,
+    /// `null` on content events; those the usage probe can't read are parsed here as well
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+#[derive(Debug, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    pub completion_tokens: u32,
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+    /// Missing from older API versions, and `null` from some deployments
+    #[serde(default)]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default)]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+impl Usage {
+    /// Prompt tokens read from the prompt cache, billed at a discount
+    fn cached_tokens(&self) -> u32 {
+        let details = self.prompt_tokens_details.as_ref();
+        details.and_then(|details| details.cached_tokens).unwrap_or(0)
+    }
+
+    /// Completion tokens the model reasoned with (o-series), not seen in the content
+    fn reasoning_tokens(&self) -> u32 {
+        let details = self.completion_tokens_details.as_ref();
+        details.and_then(|details| details.reasoning_tokens).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CompletionTokensDetails {
+    #[serde(default)]
+    pub reasoning_tokens: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct AzurePartialResponseBody {
+    pub id: HString<64>,
+    pub created: u32,
+    #[serde(deserialize_with = "truncated_model")]
+    pub model: String,
+    pub usage: Usage,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_url_params_deserialization() {
+        let json_str = r#"{
+            "app": "test-app",
+            "u": "https://api.openai.com/v1/chat/completions",
+            "envId": "prod",
+            "tenId": "tenant123",
+            "modId": "module456",
+            "sesId": "session789",
+            "reqId": "request101",
+            "api-version": "2023-05-15"
+        }"#;
+
+        let params: ProxyUrlParams = serde_json::from_str(json_str).unwrap();
+        assert_eq!(params.app, "test-app");
+        assert_eq!(params.u, "https://api.openai.com/v1/chat/completions");
+        assert_eq!(params.env_id, Some("prod".to_string()));
+        assert_eq!(params.ten_id, Some("tenant123".to_string()));
+        assert_eq!(params.mod_id, Some("module456".to_string()));
+        assert_eq!(params.ses_id, Some("session789".to_string()));
+        assert_eq!(params.req_id, Some("request101".to_string()));
+        assert_eq!(params.api_version, Some("2023-05-15".to_string()));
+    }
+
+    #[test]
+    fn test_proxy_url_params_minimal() {
+        let json_str = r#"{
+            "app": "minimal-app",
+            "u": "https://test.example.com"
+        }"#;
+
+        let params: ProxyUrlParams = serde_json::from_str(json_str).unwrap();
+        assert_eq!(params.app, "minimal-app");
+        assert_eq!(params.u, "https://test.example.com");
+        assert_eq!(params.env_id, None);
+        assert_eq!(params.ten_id, None);
+        assert_eq!(params.mod_id, None);
+        assert_eq!(params.ses_id, None);
+        assert_eq!(params.req_id, None);
+        assert_eq!(params.api_version, None);
+        assert_eq!(params.m, None);
+        assert_eq!(params.ub, None);
+        assert_eq!(params.target, None);
+        assert_eq!(params.usr_id, None);
+        assert!(params.extra.is_empty());
+    }
+
+    /// Deserializes and validates params the way `from_request` does for a query string
+    fn validated(query: serde_json::Value) -> std::result::Result<ProxyUrlParams, ParamError> {
+        let keys = query.as_object().unwrap().keys().cloned().collect::<Vec<_>>();
+        let mut params: ProxyUrlParams = serde_json::from_value(query).unwrap();
+        params.validate(|key| keys.iter().any(|k| k == key))?;
+        Ok(params)
+    }
+
+    /// Like `validated`, with request headers available for the `X-EM-*` fallback
+    fn prepared(
+        query: serde_json::Value,
+        headers: &[(&str, &str)],
+    ) -> std::result::Result<ProxyUrlParams, ParamError> {
+        let keys = query.as_object().unwrap().keys().cloned().collect::<Vec<_>>();
+        let mut params: ProxyUrlParams = serde_json::from_value(query).unwrap();
+        params.prepare(
+            |key| keys.iter().any(|k| k == key),
+            |name| {
+                headers
+                    .iter()
+                    .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.to_string())
+            },
+        )?;
+        Ok(params)
+    }
+
+    fn encode(url: &str) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(url)
+    }
+
+    #[test]
+    fn test_ub_takes_precedence_over_u() {
+        let upstream = "https://em.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-02-01&x=a%26b#frag";
+        let params = validated(json!({
+            "app": "test-app",
+            "u": "https://mangled.example.com/?api-version",
+            "ub": encode(upstream),
+        }))
+        .unwrap();
+
+        assert_eq!(params.u, upstream);
+    }
+
+    #[test]
+    fn test_ub_without_u() {
+        let upstream = "https://api.openai.com/v1/files?purpose=batch&limit=10";
+        let params = validated(json!({
+            "app": "test-app",
+            "ub": format!("{}==", encode(upstream)),
+        }))
+        .unwrap();
+
+        assert_eq!(params.u, upstream);
+    }
+
+    #[test]
+    fn test_meta_params() {
+        let mut params: ProxyUrlParams = serde_json::from_value(json!({
+            "app": "test-app",
+            "u": "https://api.openai.com/v1/chat/completions",
+            "meta.exp": "chatbot-v2",
+            "meta.locale": "es",
+            "meta.long": "x".repeat(100),
+            "meta.": "no key",
+            "seed": "42",
+        }))
+        .unwrap();
+
+        assert!(params.keep_meta_params().is_empty());
+        assert_eq!(params.extra.len(), 3);
+        assert_eq!(params.extra["exp"], "chatbot-v2");
+        assert_eq!(params.extra["locale"], "es");
+        assert_eq!(params.extra["long"].len(), MAX_META_VALUE_LEN);
+    }
+
+    #[test]
+    fn test_meta_params_capped() {
+        let mut query = json!({"app": "test-app", "u": "https://api.openai.com"});
+        for i in 0..10 {
+            query[format!("meta.k{i}")] = json!(format!("v{i}"));
+        }
+        let mut params: ProxyUrlParams = serde_json::from_value(query).unwrap();
+
+        let dropped = params.keep_meta_params();
+        assert_eq!(params.extra.len(), MAX_META_PARAMS);
+        assert_eq!(dropped, vec!["meta.k8".to_string(), "meta.k9".to_string()]);
+        assert_eq!(params.extra["k0"], "v0");
+    }
+
+    #[test]
+    fn test_sanitize_user_id() {
+        assert_eq!(sanitize_user_id(" user-42@example.com "), Some("user-42@example.com".to_string()));
+        assert_eq!(sanitize_user_id("a<script>b\n"), Some("ascriptb".to_string()));
+        assert_eq!(sanitize_user_id("\"';"), None);
+        assert_eq!(sanitize_user_id(&"x".repeat(500)).unwrap().len(), MAX_USER_ID_LEN);
+    }
+
+    #[test]
+    fn test_trim_body() {
+        let expected = json!({
+            "stream": true,
+            "messages": [{"role": "user", "content": "¿Qué tal? 👋"}],
+        });
+        let compact = r#"{"stream":true,"messages":[{"role":"user","content":"¿Qué tal? 👋"}]}"#;
+        let cases = [
+            compact.to_string(),
+            format!("\n{compact}"),
+            format!("\r\n{compact}\r\n"),
+            format!("\t  {compact}  \n"),
+            format!("\u{feff}{compact}"),
+            format!("\u{feff}\n  {compact}\n"),
+            format!(" \u{feff}{compact}"),
+            // Escaped non-ASCII and loose spacing
+            r#"{ "stream" : true , "messages" : [ { "role" : "user" ,
+                "content" : "\u00bfQu\u00e9 tal? \ud83d\udc4b" } ] }"#
+                .to_string(),
+        ];
+
+        for body in cases {
+            let trimmed = trim_body(body.as_bytes());
+            assert_eq!(
+                serde_json::from_slice::<serde_json::Value>(trimmed).unwrap(),
+                expected,
+                "{body:?}"
+            );
+
+            let edits = body::BodyEdits {
+                include_usage: true,
+                ..Default::default()
+            };
+            let edited = edits.apply(body.as_bytes()).unwrap().unwrap();
+            let edited = serde_json::from_slice::<serde_json::Value>(&edited.body).unwrap();
+            assert_eq!(edited["stream_options"], json!({"include_usage": true}), "{body:?}");
+            assert_eq!(edited["messages"], expected["messages"], "{body:?}");
+        }
+
+        assert_eq!(trim_body(b""), b"");
+        assert_eq!(trim_body(UTF8_BOM), b"");
+    }
+
+    #[test]
+    fn test_filter_response_headers() {
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in [
+            ("content-type", "text/event-stream"),
+            ("transfer-encoding", "chunked"),
+            ("connection", "keep-alive, x-upstream-hop"),
+            ("keep-alive", "timeout=5"),
+            ("x-upstream-hop", "1"),
+            ("content-length", "1234"),
+            ("content-encoding", "gzip"),
+            ("x-request-id", "req-1"),
+            ("x-ratelimit-remaining-tokens", "9000"),
+            ("x-ratelimit-remaining-requests", "99"),
+            ("apim-request-id", "apim-1"),
+        ] {
+            headers.append(name, http::HeaderValue::from_static(value));
+        }
+
+        assert_eq!(
+            filter_response_headers(&headers, true),
+            vec![
+                ("content-type", "text/event-stream"),
+                ("x-request-id", "req-1"),
+                ("x-ratelimit-remaining-tokens", "9000"),
+                ("x-ratelimit-remaining-requests", "99"),
+                ("apim-request-id", "apim-1"),
+            ]
+        );
+        // An untouched body keeps its encoding
+        assert!(filter_response_headers(&headers, false).contains(&("content-encoding", "gzip")));
+    }
+
+    #[test]
+    fn test_rate_limit_headers_relayed() {
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in [
+            ("content-type", "application/json"),
+            // Even when the upstream marks one hop-by-hop
+            ("connection", "close, x-ratelimit-reset-tokens"),
+            ("retry-after", "20"),
+            ("retry-after-ms", "19500"),
+            ("x-ratelimit-limit-requests", "100"),
+            ("x-ratelimit-limit-tokens", "10000"),
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-requests", "20s"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+            ("x-request-id", "req-1"),
+        ] {
+            headers.append(name, http::HeaderValue::from_static(value));
+        }
+        let rate_limits = [
+            ("retry-after", "20"),
+            ("retry-after-ms", "19500"),
+            ("x-ratelimit-limit-requests", "100"),
+            ("x-ratelimit-limit-tokens", "10000"),
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-requests", "20s"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ];
+
+        let success = filter_response_headers(&headers, true);
+        for header in rate_limits {
+            assert!(success.contains(&header), "{header:?} dropped on success");
+        }
+        assert!(success.contains(&("x-request-id", "req-1")));
+
+        // Errors relay the rate-limit family and nothing else
+        assert_eq!(error_response_headers(&headers), rate_limits.to_vec());
+    }
+
+    #[test]
+    fn test_disables_usage() {
+        let parse = |body: &str| serde_json::from_str::<AzureReqBodyStream>(body).unwrap();
+
+        assert!(parse(r#"{"stream_options":{"include_usage":false}}"#).disables_usage());
+        assert!(!parse(r#"{"stream_options":{"include_usage":true}}"#).disables_usage());
+        assert!(!parse(r#"{"stream_options":{"other":1}}"#).disables_usage());
+        assert!(!parse(r#"{"stream":true,"stream_options":null}"#).disables_usage());
+        assert!(!parse(r#"{"stream":true}"#).disables_usage());
+    }
+
+    #[test]
+    fn test_sniffed_sampling_checked() {
+        let check = |body: &str, clamps: body::SamplingClamps| {
+            let sniffed = serde_json::from_str::<AzureReqBodyStream>(body).unwrap();
+            clamps.check(sniffed.temperature.as_ref(), sniffed.top_p.as_ref())
+        };
+
+        let body = r#"{"model":"gpt-4o","temperature":7,"messages":[]}"#;
+        let error = check(body, body::SamplingClamps::default()).unwrap_err();
+        assert_eq!(error.to_string(), "`temperature` must be between 0 and 2, got 7");
+
+        let clamps = body::SamplingClamps {
+            temperature: Some(2.0),
+            top_p: None,
+        };
+        assert_eq!(check(body, clamps), Ok(()));
+        assert!(check(r#"{"top_p":1.2}"#, clamps).is_err());
+    }
+
+    #[test]
+    fn test_max_body_bytes() {
+        assert_eq!(max_body_bytes(None), DEFAULT_MAX_BODY_BYTES);
+        assert_eq!(max_body_bytes(Some("1048576")), 1048576);
+        assert_eq!(max_body_bytes(Some(" 512 ")), 512);
+        assert_eq!(max_body_bytes(Some("2MB")), DEFAULT_MAX_BODY_BYTES);
+        assert_eq!(max_body_bytes(Some("0")), DEFAULT_MAX_BODY_BYTES);
+    }
+
+    #[test]
+    fn test_stream_buffer_chunks() {
+        assert_eq!(stream_buffer_chunks(None), DEFAULT_STREAM_BUFFER_CHUNKS);
+        assert_eq!(stream_buffer_chunks(Some("64")), 64);
+        assert_eq!(stream_buffer_chunks(Some(" 1\n")), 1);
+        assert_eq!(stream_buffer_chunks(Some("0")), DEFAULT_STREAM_BUFFER_CHUNKS);
+        assert_eq!(stream_buffer_chunks(Some("lots")), DEFAULT_STREAM_BUFFER_CHUNKS);
+    }
+
+    #[test]
+    fn test_stream_idle_timeout() {
+        assert_eq!(stream_idle_timeout(None), Some(DEFAULT_STREAM_IDLE_TIMEOUT));
+        assert_eq!(stream_idle_timeout(Some("300")), Some(Duration::from_secs(300)));
+        assert_eq!(stream_idle_timeout(Some(" 5\n")), Some(Duration::from_secs(5)));
+        assert_eq!(stream_idle_timeout(Some("0")), None);
+        assert_eq!(stream_idle_timeout(Some("2m")), Some(DEFAULT_STREAM_IDLE_TIMEOUT));
+    }
+
+    #[test]
+    fn test_body_too_large_message() {
+        let error = BodyError::TooLarge {
+            size: 62914560,
+            limit: DEFAULT_MAX_BODY_BYTES,
+        };
+        assert_eq!(
+            error.to_string(),
+            "The body is 62914560 bytes, over the 2097152 byte limit"
+        );
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        assert_eq!(check_content_type(Some("application/json")), Ok(()));
+        assert_eq!(check_content_type(Some("application/json; charset=utf-8")), Ok(()));
+        assert_eq!(check_content_type(Some("Application/JSON;charset=UTF-8")), Ok(()));
+        assert_eq!(check_content_type(None), Ok(()));
+
+        let form = "multipart/form-data; boundary=----abc";
+        let error = check_content_type(Some(form)).unwrap_err();
+        assert_eq!(error, BodyError::UnsupportedContentType(form.to_string()));
+        assert!(error.to_string().contains("/audio/transcriptions"), "{error}");
+
+        assert!(check_content_type(Some("text/plain")).is_err());
+        assert!(check_content_type(Some("application/x-www-form-urlencoded")).is_err());
+        assert!(check_content_type(Some("")).is_err());
+    }
+
+    #[test]
+    fn test_pipes_body() {
+        let query = json!({"app": "a", "u": "https://api.openai.com", "noUsage": "1"});
+        assert!(validated(query.clone()).unwrap().pipes_body());
+
+        // The `user` field has to be injected
+        let mut with_user = query.clone();
+        with_user["usrId"] = json!("user-1");
+        assert!(!validated(with_user).unwrap().pipes_body());
+
+        // Nothing injectable left after sanitizing
+        let mut with_bad_user = query;
+        with_bad_user["usrId"] = json!("!!!");
+        assert!(validated(with_bad_user).unwrap().pipes_body());
+
+        let query = json!({"app": "a", "u": "https://api.openai.com"});
+        assert!(!validated(query).unwrap().pipes_body());
+    }
+
+    #[test]
+    fn test_trim_body_vec_reuses_allocation() {
+        // A multi-megabyte body, as large embedding batches get
+        let input = (0..50_000)
+            .map(|i| format!("\"input {i} with some padding text to make it long\""))
+            .collect::<Vec<_>>()
+            .join(",");
+        let compact = format!("{{\"model\":\"text-embedding-3-large\",\"input\":[{input}]}}");
+        assert!(compact.len() > 2 * 1024 * 1024);
+
+        let body = format!("\u{feff}\n{compact}\n").into_bytes();
+        let (pointer, capacity) = (body.as_ptr(), body.capacity());
+
+        // Sniffing borrows the buffer
+        let sniffed = trim_body(&body);
+        assert!(!serde_json::from_slice::<AzureReqBodyStream>(sniffed).unwrap().stream);
+
+        // ... and forwarding it untouched neither copies nor reallocates it
+        let forwarded = trim_body_vec(body);
+        assert_eq!(forwarded, compact.as_bytes());
+        assert_eq!((forwarded.as_ptr(), forwarded.capacity()), (pointer, capacity));
+
+        assert_eq!(trim_body_vec(b"  {}  ".to_vec()), b"{}");
+        assert_eq!(trim_body_vec(b"{}".to_vec()), b"{}");
+        assert_eq!(trim_body_vec(Vec::new()), b"");
+    }
+
+    #[test]
+    fn test_skips_usage() {
+        let skips = |no_usage: Option<&str>| {
+            let mut query = json!({"app": "a", "u": "https://api.openai.com"});
+            if let Some(no_usage) = no_usage {
+                query["noUsage"] = json!(no_usage);
+            }
+            validated(query).unwrap().skips_usage()
+        };
+
+        assert!(!skips(None));
+        assert!(skips(Some("1")));
+        assert!(skips(Some("true")));
+        assert!(!skips(Some("0")));
+    }
+
+    #[test]
+    fn test_azure_compat_param() {
+        let compat = |azure_compat: Option<&str>| {
+            let mut query = json!({"app": "a", "u": "https://x.openai.azure.com"});
+            if let Some(azure_compat) = azure_compat {
+                query["azureCompat"] = json!(azure_compat);
+            }
+            validated(query).unwrap().azure_compat()
+        };
+
+        assert!(!compat(None));
+        assert!(compat(Some("1")));
+        assert!(compat(Some("true")));
+        assert!(!compat(Some("0")));
+    }
+
+    #[test]
+    fn test_usage_format() {
+        let format = |upstream: &str, provider: Option<&str>| {
+            let mut query = json!({"app": "a", "u": upstream});
+            if let Some(provider) = provider {
+                query["provider"] = json!(provider);
+            }
+            validated(query).map(|params| params.usage_format(upstream))
+        };
+
+        let anthropic = "https://api.anthropic.com/v1/messages";
+        let gateway = "https://llm-gateway.example.com/anthropic/v1/messages";
+        assert_eq!(format(anthropic, None).unwrap(), UsageFormat::Anthropic);
+        assert_eq!(format(gateway, None).unwrap(), UsageFormat::OpenAi);
+        assert_eq!(format(gateway, Some("anthropic")).unwrap(), UsageFormat::Anthropic);
+        assert_eq!(format(anthropic, Some("openai")).unwrap(), UsageFormat::OpenAi);
+        assert_eq!(
+            format("https://x.openai.azure.com/openai/deployments/gpt-4o/chat/completions", None)
+                .unwrap(),
+            UsageFormat::OpenAi
+        );
+        let gemini = "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash:\
+                      streamGenerateContent?alt=sse";
+        assert_eq!(format(gemini, None).unwrap(), UsageFormat::Gemini);
+        let vertex = "https://us-central1-aiplatform.googleapis.com/v1/projects/p/locations/\
+                      us-central1/publishers/google/models/gemini-2.5-flash:streamGenerateContent";
+        assert_eq!(format(vertex, None).unwrap(), UsageFormat::Gemini);
+        assert_eq!(format(gateway, Some("gemini")).unwrap(), UsageFormat::Gemini);
+        let cohere = "https://api.cohere.com/v1/chat";
+        assert_eq!(format(cohere, None).unwrap(), UsageFormat::Cohere);
+        assert_eq!(format(gateway, Some("cohere")).unwrap(), UsageFormat::Cohere);
+        let mistral = "https://api.mistral.ai/v1/chat/completions";
+        assert_eq!(format(mistral, None).unwrap(), UsageFormat::Mistral);
+        assert_eq!(format(gateway, Some("mistral")).unwrap(), UsageFormat::Mistral);
+        assert!(UsageFormat::OpenAi.requests_usage());
+
+        let responses = "https://api.openai.com/v1/responses";
+        assert_eq!(format(responses, None).unwrap(), UsageFormat::Responses);
+        let azure = "https://x.openai.azure.com/openai/responses?api-version=2025-04-01-preview";
+        assert_eq!(format(azure, None).unwrap(), UsageFormat::Responses);
+        assert!(!UsageFormat::Responses.requests_usage());
+        // Another provider's API is that provider's, whatever the path
+        assert_eq!(format(responses, Some("anthropic")).unwrap(), UsageFormat::Anthropic);
+        // Only the host counts
+        let lookalike = "https://anthropic.com.example.com/v1/messages";
+        assert_eq!(format(lookalike, None).unwrap(), UsageFormat::OpenAi);
+
+        let error = format(gateway, Some("claude")).unwrap_err();
+        assert_eq!(error.field, Some("provider"));
+        assert!(error.message.contains("openai, azure, anthropic"), "{}", error.message);
+    }
+
+    #[test]
+    fn test_provider_param() {
+        let provider = |upstream: &str, provider: Option<&str>| {
+            let mut query = json!({"app": "a", "u": upstream});
+            if let Some(provider) = provider {
+                query["provider"] = json!(provider);
+            }
+            validated(query).unwrap().provider(upstream)
+        };
+
+        let azure = "https://em-eastus.openai.azure.com/openai/deployments/gpt-4o/chat/completions";
+        assert_eq!(provider(azure, None), Provider::Azure);
+        let gateway = "https://llm-gateway.example.com/openai/deployments/gpt-4o/chat/completions";
+        assert_eq!(provider(gateway, None), Provider::OpenAi);
+        assert_eq!(provider(gateway, Some("azure")), Provider::Azure);
+        assert_eq!(provider(azure, Some("openai")), Provider::OpenAi);
+        assert_eq!(UsageFormat::from(Provider::Azure), UsageFormat::OpenAi);
+    }
+
+    #[test]
+    fn test_sends_error_events() {
+        let sends = |error_events: Option<&str>| {
+            let mut query = json!({"app": "a", "u": "https://api.openai.com"});
+            if let Some(error_events) = error_events {
+                query["errorEvents"] = json!(error_events);
+            }
+            validated(query).unwrap().sends_error_events()
+        };
+
+        assert!(sends(None));
+        assert!(sends(Some("1")));
+        assert!(!sends(Some("0")));
+        assert!(!sends(Some("false")));
+    }
+
+    #[test]
+    fn test_ensures_done() {
+        let ensures = |upstream: &str, ensure_done: Option<&str>| {
+            let mut query = json!({"app": "a", "u": upstream});
+            if let Some(ensure_done) = ensure_done {
+                query["ensureDone"] = json!(ensure_done);
+            }
+            let xparams = validated(query).unwrap();
+            xparams.ensures_done(&xparams.u)
+        };
+
+        let chat = "https://x.openai.azure.com/openai/deployments/gpt-4o/chat/completions";
+        assert!(ensures(chat, None));
+        assert!(ensures("https://api.openai.com/v1/chat/completions/", None));
+        assert!(ensures("https://api.openai.com/v1/completions?x=1", None));
+        assert!(!ensures(chat, Some("0")));
+        assert!(!ensures("https://api.openai.com/v1/responses", None));
+        assert!(ensures("https://api.openai.com/v1/responses", Some("1")));
+    }
+
+    #[test]
+    fn test_stream_options_hint() {
+        let body = r#"{"error":{"message":"Unrecognized request argument: stream_options"}}"#;
+        assert!(stream_options_hint(400, body).unwrap().contains("noUsage=1"));
+        assert_eq!(stream_options_hint(500, body), None);
+        assert_eq!(stream_options_hint(400, r#"{"error":"bad model"}"#), None);
+    }
+
+    #[test]
+    fn test_target_without_u() {
+        let params = validated(json!({"app": "test-app", "target": "gpt4o-eastus"})).unwrap();
+        assert_eq!(params.target.as_deref(), Some("gpt4o-eastus"));
+        assert_eq!(params.u, "");
+    }
+
+    #[test]
+    fn test_invalid_ub() {
+        assert!(decode_upstream_param("not*base64").is_err());
+        assert!(decode_upstream_param(&encode("just some text")).is_err());
+        assert!(decode_upstream_param(&encode("ftp://files.example.com/a")).is_err());
+
+        let error = validated(json!({"app": "test-app", "ub": "%%%"})).unwrap_err();
+        assert_eq!(error.code, "invalid_param");
+        assert_eq!(error.field, Some("ub"));
+        assert!(error.message.contains("`ub`"), "{}", error.message);
+    }
+
+    #[test]
+    fn test_metadata_from_headers_only() {
+        let params = prepared(
+            json!({"u": "https://api.openai.com"}),
+            &[
+                ("x-em-app", "header-app"),
+                ("X-EM-Tenant", "header-tenant"),
+                ("X-EM-Module", "header-module"),
+                ("X-EM-Session", "header-session"),
+                ("X-EM-Request", "header-request"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(params.app, "header-app");
+        assert_eq!(params.ten_id, Some("header-tenant".to_string()));
+        assert_eq!(params.mod_id, Some("header-module".to_string()));
+        assert_eq!(params.ses_id, Some("header-session".to_string()));
+        assert_eq!(params.req_id, Some("header-request".to_string()));
+    }
+
+    #[test]
+    fn test_metadata_from_query_only() {
+        let params = prepared(
+            json!({
+                "app": "query-app",
+                "u": "https://api.openai.com",
+                "tenId": "query-tenant",
+                "reqId": "query-request",
+            }),
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(params.app, "query-app");
+        assert_eq!(params.ten_id, Some("query-tenant".to_string()));
+        assert_eq!(params.mod_id, None);
+        assert_eq!(params.ses_id, None);
+        assert_eq!(params.req_id, Some("query-request".to_string()));
+    }
+
+    #[test]
+    fn test_metadata_query_wins_over_headers() {
+        let params = prepared(
+            json!({
+                "app": "query-app",
+                "u": "https://api.openai.com",
+                "tenId": "query-tenant",
+                "sesId": "",
+            }),
+            &[
+                ("X-EM-App", "header-app"),
+                ("X-EM-Tenant", "header-tenant"),
+                ("X-EM-Module", "header-module"),
+                ("X-EM-Session", "header-session"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(params.app, "query-app");
+        assert_eq!(params.ten_id, Some("query-tenant".to_string()));
+        assert_eq!(params.mod_id, Some("header-module".to_string()));
+        // Present in the query, even if empty
+        assert_eq!(params.ses_id, Some(String::new()));
+        assert_eq!(params.req_id, None);
+
+        // An empty header doesn't satisfy the required `app`
+        assert_eq!(
+            prepared(json!({"u": "https://api.openai.com"}), &[("X-EM-App", "")]).unwrap_err(),
+            ParamError::empty("app")
+        );
+    }
+
+    #[test]
+    fn test_validate_missing_params() {
+        assert_eq!(
+            validated(json!({"u": "https://api.openai.com"})).unwrap_err(),
+            ParamError::missing("app")
+        );
+        assert_eq!(
+            validated(json!({"app": "test-app"})).unwrap_err(),
+            ParamError::missing("u")
+        );
+    }
+
+    #[test]
+    fn test_validate_empty_params() {
+        assert_eq!(
+            validated(json!({"app": "", "u": "https://api.openai.com"})).unwrap_err(),
+            ParamError::empty("app")
+        );
+        assert_eq!(
+            validated(json!({"app": "test-app", "u": " "})).unwrap_err(),
+            ParamError::empty("u")
+        );
+    }
+
+    #[test]
+    fn test_validate_identifiers() {
+        let at_limit = "a".repeat(MAX_IDENTIFIER_LEN);
+        let params = validated(json!({
+            "app": at_limit,
+            "u": "https://api.openai.com",
+            "tenId": at_limit,
+            "sesId": "ses_01:abc.def-2",
+        }))
+        .unwrap();
+        assert_eq!(params.app.len(), MAX_IDENTIFIER_LEN);
+
+        let over_limit = "a".repeat(MAX_IDENTIFIER_LEN + 1);
+        for field in ["app", "envId", "tenId", "modId", "sesId", "reqId"] {
+            let mut query = json!({"app": "test-app", "u": "https://api.openai.com"});
+            query[field] = json!(over_limit);
+            let error = validated(query).unwrap_err();
+            assert_eq!((error.code, error.field), ("invalid_param", Some(field)));
+
+            let mut query = json!({"app": "test-app", "u": "https://api.openai.com"});
+            query[field] = json!("bad\u{0}value");
+            let error = validated(query).unwrap_err();
+            assert_eq!((error.code, error.field), ("invalid_param", Some(field)));
+        }
+
+        let error = validated(json!({
+            "app": "test-app",
+            "u": "https://api.openai.com",
+            "tenId": "tenant 1",
+        }))
+        .unwrap_err();
+        assert_eq!(error.field, Some("tenId"));
+    }
+
+    /// Parses a raw query string the way `from_request` does, `X-EM-*` headers aside
+    fn from_query(raw_query: &str) -> std::result::Result<ProxyUrlParams, ParamError> {
+        let url = Url::parse(&format!("https://proxy.example.com/proxy/universal?{raw_query}"))
+            .unwrap();
+        let query = url
+            .query_pairs()
+            .map(|(k, v)| (k.into_owned(), json!(v)))
+            .collect::<serde_json::Map<_, _>>();
+
+        let mut params = validated(serde_json::Value::Object(query))?;
+        if let Some(u) = reattach_upstream_params(&params.u, url.query()) {
+            params.u = u;
+        }
+        Ok(params)
+    }
+
+    #[test]
+    fn test_encoded_u_with_query() {
+        let params = from_query(
+            "app=test-app&u=https%3A%2F%2Fem.openai.azure.com%2Fopenai%2Fchat%3Fapi-version%3D2024-02-01%26deployment%3Dgpt-4o&tenId=t1",
+        )
+        .unwrap();
+        assert_eq!(
+            params.u,
+            "https://em.openai.azure.com/openai/chat?api-version=2024-02-01&deployment=gpt-4o"
+        );
+        assert_eq!(params.api_version, None);
+
+        // An encoded `u` keeps trailing unknown parameters out of the upstream URL
+        let params = from_query(
+            "app=test-app&u=https%3A%2F%2Fem.openai.azure.com%2Fopenai%2Fchat%3Fx%3D1&deployment=gpt-4o",
+        )
+        .unwrap();
+        assert_eq!(params.u, "https://em.openai.azure.com/openai/chat?x=1");
+    }
+
+    #[test]
+    fn test_raw_u_with_query() {
+        let params = from_query(
+            "app=test-app&u=https://em.openai.azure.com/openai/chat?deployment=gpt-4o&api-version=2024-02-01&tenId=t1",
+        )
+        .unwrap();
+        // `api-version` is one of ours and is appended back by `with_api_version`
+        assert_eq!(params.u, "https://em.openai.azure.com/openai/chat?deployment=gpt-4o");
+        assert_eq!(params.api_version, Some("2024-02-01".to_string()));
+        assert_eq!(params.ten_id, Some("t1".to_string()));
+
+        let params = from_query(
+            "u=https://em.openai.azure.com/openai/chat?api-version=2024-02-01&deployment=gpt-4o&foo=a%20b&app=test-app&meta.exp=v2",
+        )
+        .unwrap();
+        assert_eq!(
+            params.u,
+            "https://em.openai.azure.com/openai/chat?api-version=2024-02-01&deployment=gpt-4o&foo=a%20b"
+        );
+        assert_eq!(params.app, "test-app");
+    }
+
+    #[test]
+    fn test_reattach_without_stray_params() {
+        assert_eq!(
+            reattach_upstream_params(
+                "https://api.openai.com/v1/chat/completions",
+                Some("app=a&u=https://api.openai.com/v1/chat/completions&foo=bar"),
+            ),
+            None
+        );
+        assert_eq!(
+            reattach_upstream_params(
+                "https://x.openai.azure.com/chat?api-version=1",
+                Some("u=https://x.openai.azure.com/chat?api-version=1&app=a"),
+            ),
+            None
+        );
+        assert_eq!(reattach_upstream_params("", None), None);
+    }
+
+    #[test]
+    fn test_validate_invalid_url() {
+        let error = validated(json!({"app": "test-app", "u": "api.openai.com/v1"})).unwrap_err();
+        assert_eq!(error.code, "invalid_url");
+        assert_eq!(error.field, Some("u"));
+    }
+
+    #[test]
+    fn test_param_error_body() {
+        let error = ParamError::missing("app").with_request_id(Some("req-1".to_string()));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({
+                "code": "missing_param",
+                "field": "app",
+                "message": "Missing required parameter `app`",
+                "reqId": "req-1",
+            })
+        );
+
+        let error = ParamError::invalid_query("bad".to_string());
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            json!({"code": "invalid_query", "field": null, "message": "bad"})
+        );
+    }
+
+    #[test]
+    fn test_upstream_method() {
+        assert_eq!(upstream_method(None), Ok(reqwest::Method::POST));
+        assert_eq!(upstream_method(Some("GET")), Ok(reqwest::Method::GET));
+        assert_eq!(upstream_method(Some("delete")), Ok(reqwest::Method::DELETE));
+        assert_eq!(upstream_method(Some("Patch")), Ok(reqwest::Method::PATCH));
+        assert!(upstream_method(Some("HEAD")).is_err());
+        assert!(upstream_method(Some("CONNECT")).is_err());
+
+        assert!(!method_sends_body(&reqwest::Method::GET));
+        assert!(!method_sends_body(&reqwest::Method::DELETE));
+        assert!(method_sends_body(&reqwest::Method::PATCH));
+    }
+
+    #[test]
+    fn test_azure_req_body_stream_defaults() {
+        let json_str = r#"{}"#;
+        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
+        assert!(!body.stream); // default value
+    }
+
+    #[test]
+    fn test_azure_req_body_stream_explicit() {
+        let json_str = r#"{"stream": true}"#;
+        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
+        assert!(body.stream);
+
+        let json_str = r#"{"stream": false}"#;
+        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
+        assert!(!body.stream);
+    }
+
+    #[test]
+    fn test_usage_deserialization() {
+        let json_str = r#"{
+            "prompt_tokens": 150,
+            "completion_tokens": 75,
+            "total_tokens": 225
+        }"#;
+
+        let usage: Usage = serde_json::from_str(json_str).unwrap();
+        assert_eq!(usage.prompt_tokens, 150);
         assert_eq!(usage.completion_tokens, 75);
         assert_eq!(usage.total_tokens, 225);
+        assert_eq!((usage.cached_tokens(), usage.reasoning_tokens()), (0, 0));
+    }
+
+    #[test]
+    fn test_usage_token_details() {
+        let json_str = r#"{
+            "prompt_tokens": 1418,
+            "completion_tokens": 210,
+            "total_tokens": 1628,
+            "prompt_tokens_details": {"audio_tokens": 0, "cached_tokens": 1280},
+            "completion_tokens_details": {"reasoning_tokens": 192, "audio_tokens": 0}
+        }"#;
+        let usage: Usage = serde_json::from_str(json_str).unwrap();
+        assert_eq!((usage.cached_tokens(), usage.reasoning_tokens()), (1280, 192));
+
+        // Details that are `null`, or lack the counts, read as none
+        let json_str = r#"{
+            "prompt_tokens": 10,
+            "total_tokens": 10,
+            "prompt_tokens_details": null,
+            "completion_tokens_details": {"audio_tokens": 0, "reasoning_tokens": null}
+        }"#;
+        let usage: Usage = serde_json::from_str(json_str).unwrap();
+        assert_eq!((usage.cached_tokens(), usage.reasoning_tokens()), (0, 0));
+    }
+
+    #[test]
+    fn test_reasoning_stream_usage() {
+        let stream = include_bytes!("../fixtures/reasoning_chat_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template());
+        let records = scanner.push(stream);
+        assert_eq!(records.len(), 1);
+        let analytics = records[0].as_ref().unwrap();
+        assert_eq!(analytics.model, "o3-mini-2025-01-31");
+        assert_eq!(
+            (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+            (1418, 210, 1628)
+        );
+        assert_eq!((analytics.cached_tokens, analytics.reasoning_tokens), (1280, 192));
+
+        let analytics = scanner.record(false).unwrap();
+        assert!(analytics.usage_captured);
+        assert_eq!((analytics.cached_tokens, analytics.reasoning_tokens), (1280, 192));
+
+        // Streams from before the details were added record none
+        let stream = include_bytes!("../fixtures/tool_call_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template());
+        let records = scanner.push(stream);
+        let analytics = records[0].as_ref().unwrap();
+        assert_eq!((analytics.cached_tokens, analytics.reasoning_tokens), (0, 0));
+    }
+
+    #[test]
+    fn test_usage_with_default_completion_tokens() {
+        let json_str = r#"{
+            "prompt_tokens": 100,
+            "total_tokens": 100
+        }"#;
+
+        let usage: Usage = serde_json::from_str(json_str).unwrap();
+        assert_eq!(usage.prompt_tokens, 100);
+        assert_eq!(usage.completion_tokens, 0); // default value
+        assert_eq!(usage.total_tokens, 100);
+    }
+
+    #[test]
+    fn test_stats_chunk_deserialization() {
+        let json_str = r#"{
+            "model": "gpt-4",
+            "usage": {
+                "prompt_tokens": 200,
+                "completion_tokens": 100,
+                "total_tokens": 300
+            }
+        }"#;
+
+        let stats: StatsChunk = serde_json::from_str(json_str).unwrap();
+        assert_eq!(stats.model.as_str(), "gpt-4");
+        let usage = stats.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 200);
+        assert_eq!(usage.completion_tokens, 100);
+        assert_eq!(usage.total_tokens, 300);
+    }
+
+    #[test]
+    fn test_azure_partial_response_body_deserialization() {
+        let json_str = r#"{
+            "id": "chatcmpl-123",
+            "created": 1640995200,
+            "model": "gpt-3.5-turbo",
+            "usage": {
+                "prompt_tokens": 50,
+                "completion_tokens": 25,
+                "total_tokens": 75
+            }
+        }"#;
+
+        let response: AzurePartialResponseBody = serde_json::from_str(json_str).unwrap();
+        assert_eq!(response.id.as_str(), "chatcmpl-123");
+        assert_eq!(response.created, 1640995200);
+        assert_eq!(response.model.as_str(), "gpt-3.5-turbo");
+        assert_eq!(response.usage.prompt_tokens, 50);
+        assert_eq!(response.usage.completion_tokens, 25);
+        assert_eq!(response.usage.total_tokens, 75);
+    }
+
+    #[test]
+    fn test_json_parsing_error_handling() {
+        // Test invalid JSON for ProxyUrlParams
+        let result = validated(json!({"app": "test"})); // missing required 'u' (or 'ub') field
+        assert!(result.is_err());
+
+        // missing required 'app' field
+        let result = validated(json!({"u": "https://api.openai.com"}));
+        assert!(result.is_err());
+
+        // Test invalid JSON for Usage
+        let invalid_usage = r#"{"prompt_tokens": "not_a_number"}"#;
+        let result = serde_json::from_str::<Usage>(invalid_usage);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_heapless_string_limits() {
+        // Test that model names up to 64 characters parse
+        let long_model_name = "a".repeat(64);
+        let json_str = format!(
+            r#"{{"model": "{}", "usage": {{"prompt_tokens": 10, "total_tokens": 10}}}}"#,
+            long_model_name
+        );
+
+        let stats = serde_json::from_str::<StatsChunk>(&json_str);
+        assert!(stats.is_ok());
+
+        // Longer ones too, as Azure deployment names can be
+        let too_long_model_name = "gpt-4o-2024-11-20-em-production-eastus2-longname-fare-assistant";
+        let too_long_model_name = format!("{too_long_model_name}-v2");
+        let json_str = format!(
+            r#"{{"model": "{}", "usage": {{"prompt_tokens": 10, "total_tokens": 10}}}}"#,
+            too_long_model_name
+        );
+
+        let stats = serde_json::from_str::<StatsChunk>(&json_str).unwrap();
+        assert!(too_long_model_name.len() > 64);
+        assert_eq!(stats.model, too_long_model_name);
+
+        let json_str = format!(
+            r#"{{"id": "chatcmpl-1", "created": 1, "model": "{}", "usage": {}}}"#,
+            too_long_model_name, r#"{"prompt_tokens": 1, "total_tokens": 1}"#
+        );
+        let body = serde_json::from_str::<AzurePartialResponseBody>(&json_str).unwrap();
+        assert_eq!(body.model, too_long_model_name);
+    }
+
+    #[test]
+    fn test_model_name_truncation() {
+        let usage = r#""usage": {"prompt_tokens": 10, "total_tokens": 10}"#;
+        let parse = |model: &str| {
+            let json_str = format!(r#"{{"model": "{model}", {usage}}}"#);
+            serde_json::from_str::<StatsChunk>(&json_str).unwrap().model
+        };
+
+        // Names of up to `MAX_MODEL_LEN` bytes are kept whole
+        let model = "m".repeat(MAX_MODEL_LEN);
+        assert_eq!(parse(&model), model);
+
+        // Longer ones are cut to that many bytes...
+        let model = "m".repeat(MAX_MODEL_LEN + 40);
+        assert_eq!(parse(&model), "m".repeat(MAX_MODEL_LEN));
+
+        // ...or fewer, so a multi-byte character isn't split
+        let model = format!("{}é-suffix", "m".repeat(MAX_MODEL_LEN - 1));
+        assert_eq!(parse(&model), "m".repeat(MAX_MODEL_LEN - 1));
+    }
+
+    #[test]
+    fn test_complex_proxy_url_params_scenarios() {
+        // Test with special characters in URL
+        let json_str = r#"{
+            "app": "test-app",
+            "u": "https://api.openai.com/v1/chat/completions?model=gpt-4&stream=true",
+            "envId": "prod-2024",
+            "tenId": "tenant_123"
+        }"#;
+
+        let params: ProxyUrlParams = serde_json::from_str(json_str).unwrap();
+        assert_eq!(params.app, "test-app");
+        assert_eq!(
+            params.u,
+            "https://api.openai.com/v1/chat/completions?model=gpt-4&stream=true"
+        );
+        assert_eq!(params.env_id, Some("prod-2024".to_string()));
+        assert_eq!(params.ten_id, Some("tenant_123".to_string()));
+    }
+
+    #[test]
+    fn test_azure_req_body_with_extra_fields() {
+        // Test that extra fields in JSON are ignored
+        let json_str = r#"{
+            "stream": true,
+            "model": "gpt-4",
+            "messages": [{"role": "user", "content": "Hello"}],
+            "temperature": 0.7,
+            "extra_field": "ignored"
+        }"#;
+
+        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
+        assert!(body.stream);
+    }
+
+    #[test]
+    fn test_usage_zero_values() {
+        let json_str = r#"{
+            "prompt_tokens": 0,
+            "completion_tokens": 0,
+            "total_tokens": 0
+        }"#;
+
+        let usage: Usage = serde_json::from_str(json_str).unwrap();
+        assert_eq!(usage.prompt_tokens, 0);
+        assert_eq!(usage.completion_tokens, 0);
+        assert_eq!(usage.total_tokens, 0);
+    }
+
+    #[test]
+    fn test_stats_chunk_with_minimal_model_name() {
+        let json_str = r#"{
+            "model": "a",
+            "usage": {
+                "prompt_tokens": 1,
+                "total_tokens": 1
+            }
+        }"#;
+
+        let stats: StatsChunk = serde_json::from_str(json_str).unwrap();
+        assert_eq!(stats.model.as_str(), "a");
+        let usage = stats.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 1);
+        assert_eq!(usage.completion_tokens, 0); // default
+        assert_eq!(usage.total_tokens, 1);
+    }
+
+    #[test]
+    fn test_stats_chunk_from_events() {
+        let stats_chunk = |data: &str| stats_chunk(data, UsageProbe::parse(data).as_ref());
+
+        // Only the usage event is parsed, however the stream was chunked
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        for split in (0..stream.len()).step_by(97) {
+            let mut parser = sse::SseParser::default();
+            let mut events = parser.push(&stream[..split]);
+            events.extend(parser.push(&stream[split..]));
+
+            let stats = events
+                .iter()
+                .filter(|event| !event.is_done())
+                .filter_map(|event| stats_chunk(&event.data))
+                .collect::<Vec<_>>();
+            assert_eq!(stats.len(), 1, "split at {split}");
+            let stats = stats[0].as_ref().unwrap();
+            assert_eq!(stats.model.as_str(), "gpt-4o-2024-08-06");
+            let usage = stats.usage.as_ref().unwrap();
+            assert_eq!(usage.prompt_tokens, 24);
+            assert_eq!(usage.completion_tokens, 13);
+            assert_eq!(usage.total_tokens, 37);
+        }
+
+        // OpenAI sends `"usage": null` on the other events
+        assert!(stats_chunk(r#"{"choices":[{"delta":{}}],"usage":null}"#).is_none());
+        assert!(stats_chunk(r#"{"choices":[],"usage":{"prompt_tokens":1}}"#).unwrap().is_err());
+        assert!(stats_chunk(r#"{"choices":[],"usage":{"prompt"#).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_usage_event_detection() {
+        let stats_chunk = |data: &str| stats_chunk(data, UsageProbe::parse(data).as_ref());
+        let usage = r#""usage":{"prompt_tokens":5,"completion_tokens":2,"total_tokens":7}"#;
+
+        // Reordered fields, spacing and a missing `choices` don't matter
+        for data in [
+            format!(r#"{{"choices":[],"model":"gpt-4o",{usage}}}"#),
+            format!(r#"{{{usage}, "model": "gpt-4o", "choices": [ ]}}"#),
+            format!("{{\n  \"model\" : \"gpt-4o\",\n  {usage}\n}}"),
+        ] {
+            let stats = stats_chunk(&data).unwrap().unwrap();
+            let total_tokens = stats.usage.unwrap().total_tokens;
+            assert_eq!((stats.model.as_str(), total_tokens), ("gpt-4o", 7), "{data}");
+        }
+
+        // Content echoing a usage event is just content
+        let decoy = format!(r#"{{\"choices\":[],{}}}"#, usage.replace('"', r#"\""#));
+        let content = json!({
+            "choices": [{"delta": {"content": decoy}, "index": 0}],
+            "model": "gpt-4o",
+            "usage": null,
+        });
+        assert!(stats_chunk(&content.to_string()).is_none());
+
+
+        // Nor is Azure's prompt filter event, with its empty choices but no usage
+        assert!(stats_chunk(r#"{"choices":[],"model":"","prompt_filter_results":[]}"#).is_none());
+    }
+
+    #[test]
+    fn test_decoy_content_not_recorded() {
+        let decoy = json!({
+            "choices": [{"delta": {"content": "{\"choices\":[],\"usage\":{\"total_tokens\":1}}"}}],
+            "model": "gpt-4o",
+            "usage": null,
+        });
+        let stream = format!("data: {decoy}\n\ndata: [DONE]\n\n");
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(stream.as_bytes()).is_empty());
+        assert_eq!(scanner.record(true).unwrap().completion_tokens, 1);
+    }
+
+    #[test]
+    fn test_stream_backend_fields() {
+        // Recorded off the content events, even when the stream reports no usage
+        let event = json!({
+            "choices": [{"delta": {"content": "Hi"}, "index": 0}],
+            "model": "gpt-4o-mini",
+            "service_tier": "default",
+            "system_fingerprint": "fp_06737a9306",
+        });
+        let stream = format!("data: {event}\n\ndata: [DONE]\n\n");
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(stream.as_bytes()).is_empty());
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!(analytics.system_fingerprint.as_deref(), Some("fp_06737a9306"));
+        assert_eq!(analytics.service_tier.as_deref(), Some("default"));
+
+        // Streams without them still parse
+        let stream = include_bytes!("../fixtures/mistral_chat_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template()).format(UsageFormat::Mistral);
+        assert!(scanner.push(stream).iter().all(|record| record.is_ok()));
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!(analytics.total_tokens, 36);
+        assert_eq!((analytics.system_fingerprint, analytics.service_tier), (None, None));
+    }
+
+    #[test]
+    fn test_usage_null_on_every_event() {
+        let stream = include_bytes!("../fixtures/azure_usage_null_stream.txt");
+        for chunk_len in [1, 64, stream.len()] {
+            let mut scanner = UsageScanner::new(usage_template());
+            let records = stream
+                .chunks(chunk_len)
+                .flat_map(|chunk| scanner.push(chunk))
+                .collect::<Vec<_>>();
+
+            // Nothing to log as an error, and the usage event alone is recorded
+            assert_eq!(records.len(), 1, "chunks of {chunk_len}");
+            assert_eq!(records[0].as_ref().unwrap().total_tokens, 37);
+            let analytics = scanner.record(false).unwrap();
+            assert_eq!((analytics.total_tokens, analytics.completion_chars), (37, 45));
+            assert!(scanner.record(false).is_none());
+        }
+
+        // A content event the probe can't read still has no usage to report
+        let unreadable = r#"{"choices":[{"delta":"Hola"}],"model":"gpt-4o","usage":null}"#;
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(format!("data: {unreadable}\n\n").as_bytes()).is_empty());
+
+        // Events that can't be read at all are parse misses, not failed usage events
+        let records = scanner.push(b"data: {\"choices\":[{\"delta\":\"Hola\"}]}\n\n");
+        assert!(matches!(records[..], [Err(UnparsedUsage { reported_usage: false, .. })]));
+        let records = scanner.push(b"data: {\"choices\":[],\"usage\":{\"total_tokens\":7}}\n\n");
+        assert!(matches!(records[..], [Err(UnparsedUsage { reported_usage: true, .. })]));
+    }
+
+    #[test]
+    fn test_usage_event_split_byte_by_byte() {
+        let template = || {
+            let mut template = UsageAnalytics::new(
+                "test-app".to_string(),
+                Some("acme".to_string()),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                "unknown".to_string(),
+                0,
+                0,
+                0,
+            );
+            template.model_alias = Some("em-gpt-4o".to_string());
+            template.status_code = 201;
+            template
+        };
+        let usage_event = concat!(
+            r#"data: {"choices":[],"created":1733404810,"id":"chatcmpl-AbCdEf0123456789","#,
+            r#""model":"gpt-4o-2024-08-06","object":"chat.completion.chunk","#,
+            r#""usage":{"completion_tokens":13,"prompt_tokens":24,"total_tokens":37}}"#,
+            "\n\n",
+        );
+
+        // The recorded usage event alone, then a whole stream, both one byte at a time
+        let stream = include_str!("../fixtures/azure_chat_stream.txt");
+        for input in [usage_event, stream] {
+            let mut scanner = UsageScanner::new(template());
+            let records = input
+                .as_bytes()
+                .chunks(1)
+                .flat_map(|byte| scanner.push(byte))
+                .collect::<Vec<_>>();
+
+            assert_eq!(records.len(), 1);
+            let analytics = records[0].as_ref().unwrap();
+            assert_eq!(analytics.model, "gpt-4o-2024-08-06");
+            assert_eq!(analytics.prompt_tokens, 24);
+            assert_eq!(analytics.completion_tokens, 13);
+            assert_eq!(analytics.total_tokens, 37);
+            // The request's attribution is kept
+            assert_eq!(analytics.app_id, "test-app");
+            assert_eq!(analytics.tenant_id.as_deref(), Some("acme"));
+            assert_eq!(analytics.model_alias.as_deref(), Some("em-gpt-4o"));
+            assert_eq!(analytics.status_code, 201);
+        }
+
+        // A usage event that never completes yields nothing
+        let mut scanner = UsageScanner::new(template());
+        assert!(scanner.push(&usage_event.as_bytes()[..usage_event.len() - 1]).is_empty());
+    }
+
+    fn usage_template() -> UsageAnalytics {
+        UsageAnalytics::new(
+            "test-app".to_string(),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "unknown".to_string(),
+            0,
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_abandoned_stream_record() {
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let usage_event = stream
+            .windows(6)
+            .rposition(|window| window == b"data: ")
+            .and_then(|done| stream[..done - 2].windows(6).rposition(|w| w == b"data: "))
+            .unwrap();
+
+        // Left before the usage event: the relayed choices stand in for completion tokens
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(&stream[..usage_event]).is_empty());
+        let analytics = scanner.record(true).unwrap();
+        assert!(analytics.client_disconnected);
+        assert!(!analytics.usage_captured);
+        assert_eq!(analytics.model, "gpt-4o-2024-08-06");
+        assert_eq!(analytics.completion_tokens, 6);
+        assert_eq!(analytics.total_tokens, 6);
+        assert_eq!(analytics.app_id, "test-app");
+
+        // The stream is recorded once
+        assert_eq!(scanner.push(&stream[usage_event..]).len(), 1);
+        assert!(scanner.record(false).is_none());
+
+        // Left after the usage event: the reported usage is recorded
+        let mut scanner = UsageScanner::new(usage_template());
+        assert_eq!(scanner.push(stream).len(), 1);
+        let analytics = scanner.record(true).unwrap();
+        assert!(analytics.client_disconnected);
+        assert!(analytics.usage_captured);
+        assert_eq!(analytics.total_tokens, 37);
+
+        let mut scanner = UsageScanner::new(usage_template());
+        let analytics = scanner.record(true).unwrap();
+        assert_eq!((analytics.model.as_str(), analytics.total_tokens), ("unknown", 0));
+    }
+
+    /// What the client receives of `stream` relayed in `chunk_len` byte chunks
+    fn relayed(mut scanner: UsageScanner, stream: &[u8], chunk_len: usize) -> (Vec<u8>, usize) {
+        let mut client = Vec::new();
+        let mut records = 0;
+        for chunk in stream.chunks(chunk_len) {
+            let (bytes, chunk_records) = scanner.relay(chunk.to_vec());
+            client.extend(bytes);
+            records += chunk_records.len();
+        }
+        client.extend(scanner.finish());
+        (client, records)
+    }
+
+    #[test]
+    fn test_usage_with_crlf_lines() {
+        // Lines ending in `\r\n` or a bare `\r` used to leave the `\r` inside the JSON
+        let stream = include_str!("../fixtures/azure_chat_stream.txt");
+        for ending in ["\r\n", "\r"] {
+            let stream = stream.replace('\n', ending);
+            for chunk_len in [1, 7, stream.len()] {
+                let mut scanner = UsageScanner::new(usage_template());
+                let records = stream
+                    .as_bytes()
+                    .chunks(chunk_len)
+                    .flat_map(|chunk| scanner.push(chunk))
+                    .collect::<Vec<_>>();
+                assert_eq!(records.len(), 1, "{ending:?} in chunks of {chunk_len}");
+                let analytics = records[0].as_ref().unwrap();
+                assert_eq!(analytics.total_tokens, 37);
+            }
+        }
+    }
+
+    #[test]
+    fn test_anthropic_stream_usage() {
+        let stream = include_bytes!("../fixtures/claude_message_stream.txt");
+        for chunk_len in [1, 7, stream.len()] {
+            let mut scanner = UsageScanner::new(usage_template()).format(UsageFormat::Anthropic);
+            let mut client = Vec::new();
+            let mut records = Vec::new();
+            for chunk in stream.chunks(chunk_len) {
+                let (bytes, chunk_records) = scanner.relay(chunk.to_vec());
+                client.extend(bytes);
+                records.extend(chunk_records);
+            }
+            client.extend(scanner.finish());
+            assert_eq!(client, stream);
+            assert_eq!(records.len(), 1, "chunks of {chunk_len}");
+
+            let analytics = records[0].as_ref().unwrap();
+            assert_eq!(analytics.model, "claude-3-5-sonnet-20241022");
+            assert_eq!(analytics.prompt_tokens, 472);
+            assert_eq!(analytics.completion_tokens, 19);
+            assert_eq!(analytics.total_tokens, 491);
+
+            let analytics = scanner.record(false).unwrap();
+            assert!(analytics.usage_captured);
+            assert_eq!(analytics.total_tokens, 491);
+            assert_eq!(analytics.finish_reason.as_deref(), Some("end_turn"));
+        }
+
+        // Read as OpenAI's, the usage on `message_delta` fails to parse
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(stream).iter().all(|record| record.is_err()));
+        let analytics = scanner.record(false).unwrap();
+        assert!(!analytics.usage_captured);
+        assert_eq!(analytics.total_tokens, 0);
+    }
+
+    #[test]
+    fn test_responses_stream_usage() {
+        let stream = include_bytes!("../fixtures/responses_stream.txt");
+        for chunk_len in [1, 7, stream.len()] {
+            let scanner = UsageScanner::new(usage_template()).format(UsageFormat::Responses);
+            let (client, records) = relayed(scanner, stream, chunk_len);
+            assert_eq!(client, stream);
+            assert_eq!(records, 1, "chunks of {chunk_len}");
+        }
+
+        let mut scanner = UsageScanner::new(usage_template()).format(UsageFormat::Responses);
+        let records = scanner.push(stream);
+        assert_eq!(records.len(), 1);
+        let analytics = records[0].as_ref().unwrap();
+        assert_eq!(analytics.model, "o4-mini-2025-04-16");
+        assert_eq!(
+            (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+            (31, 210, 241)
+        );
+        assert_eq!(analytics.reasoning_tokens, 192);
+
+        let analytics = scanner.record(false).unwrap();
+        assert!(analytics.usage_captured);
+        assert_eq!(analytics.reasoning_tokens, 192);
+    }
+
+    #[test]
+    fn test_mistral_stream_usage() {
+        let stream = include_bytes!("../fixtures/mistral_chat_stream.txt");
+        for chunk_len in [1, 13, stream.len()] {
+            // The usage rides on the last content chunk, so it waits for the end of the stream
+            let (client, records) = relayed(UsageScanner::new(usage_template()), stream, chunk_len);
+            assert_eq!(client, stream);
+            assert_eq!(records, 0);
+        }
+
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(stream).is_empty());
+        let analytics = scanner.record(false).unwrap();
+        assert!(analytics.usage_captured);
+        assert_eq!(analytics.model, "mistral-large-latest");
+        assert_eq!(
+            (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+            (21, 15, 36)
+        );
+        assert_eq!(analytics.finish_reason.as_deref(), Some("stop"));
+        let content = "Flight AA 100 departs JFK at 18:10 and is on time.";
+        assert_eq!(analytics.completion_chars, content.len() as u32);
+    }
+
+    #[test]
+    fn test_json_response_usage() {
+        let body = include_bytes!("../fixtures/chat_completion.json");
+        let analytics = json_usage(&usage_template(), body).unwrap();
+        // Longer than the 64 bytes a usage event's model may have
+        assert_eq!(
+            analytics.model,
+            "ft:gpt-4o-mini-2024-07-18:everymundo:fare-assistant-routes-and-schedules:B8lWVEnq"
+        );
+        assert_eq!(
+            (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+            (1342, 18, 1360)
+        );
+        assert_eq!((analytics.cached_tokens, analytics.reasoning_tokens), (1152, 0));
+        assert_eq!(analytics.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(analytics.system_fingerprint.as_deref(), Some("fp_06737a9306"));
+        assert_eq!(analytics.service_tier.as_deref(), Some("default"));
+        assert!(analytics.usage_captured);
+        assert_eq!(analytics.app_id, usage_template().app_id);
+
+        // Embeddings have no completion tokens, and no choices
+        let body = json!({
+            "object": "list",
+            "data": [{"object": "embedding", "index": 0, "embedding": [0.0023, -0.0093]}],
+            "model": "text-embedding-3-small",
+            "usage": {"prompt_tokens": 8, "total_tokens": 8}
+        });
+        let analytics = json_usage(&usage_template(), body.to_string().as_bytes()).unwrap();
+        assert_eq!(analytics.model, "text-embedding-3-small");
+        assert_eq!(
+            (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+            (8, 0, 8)
+        );
+        assert_eq!(analytics.finish_reason, None);
+        assert_eq!((analytics.cached_tokens, analytics.reasoning_tokens), (0, 0));
+        assert_eq!((analytics.system_fingerprint, analytics.service_tier), (None, None));
+
+        // An error body has no usage, and is kept for the log
+        let body = r#"{"error":{"code":"429","message":"Rate limit exceeded"}}"#;
+        let Err(unparsed) = json_usage(&usage_template(), body.as_bytes()) else {
+            panic!("error body parsed as usage");
+        };
+        assert_eq!(unparsed.data, body);
+    }
+
+    #[test]
+    fn test_mistral_body_forwarded_unmodified() {
+        let query = json!({"app": "a", "u": "https://api.mistral.ai/v1/chat/completions"});
+        let params = validated(query).unwrap();
+        let format = params.usage_format(&params.u);
+        assert_eq!(format, UsageFormat::Mistral);
+        assert!(!format.requests_usage());
+
+        let body = br#"{"model":"mistral-large-latest","stream":true,"messages":[]}"#;
+        let edits = body::BodyEdits {
+            include_usage: format.requests_usage(),
+            ..Default::default()
+        };
+        assert_eq!(edits.apply(body).unwrap(), None);
     }
 
     #[test]
-    fn test_usage_with_default_completion_tokens() {
-        let json_str = r#"{
-            "prompt_tokens": 100,
-            "total_tokens": 100
-        }"#;
+    fn test_cohere_stream_usage() {
+        let stream = include_bytes!("../fixtures/cohere_chat_stream.jsonl");
+        for chunk_len in [1, 7, stream.len()] {
+            let mut template = usage_template();
+            template.model = "command-r-plus".to_string();
+            let mut scanner = UsageScanner::new(template).format(UsageFormat::Cohere);
+            let mut client = Vec::new();
+            let mut records = Vec::new();
+            for chunk in stream.chunks(chunk_len) {
+                let (bytes, chunk_records) = scanner.relay(chunk.to_vec());
+                client.extend(bytes);
+                records.extend(chunk_records);
+            }
+            assert_eq!(client, stream);
+            assert_eq!(records.len(), 1, "chunks of {chunk_len}");
 
-        let usage: Usage = serde_json::from_str(json_str).unwrap();
-        assert_eq!(usage.prompt_tokens, 100);
-        assert_eq!(usage.completion_tokens, 0); // default value
-        assert_eq!(usage.total_tokens, 100);
+            let analytics = records[0].as_ref().unwrap();
+            assert_eq!(analytics.model, "command-r-plus");
+            assert_eq!(
+                (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+                (67, 16, 83)
+            );
+
+            let analytics = scanner.record(false).unwrap();
+            assert!(analytics.usage_captured);
+            assert_eq!(analytics.total_tokens, 83);
+            assert_eq!(analytics.finish_reason.as_deref(), Some("COMPLETE"));
+        }
+    }
+
+    /// Feeds a whole stream to the extractor of `format` the way `UsageScanner` does, returning
+    /// the usage it reported on the way and the usage `finish` has
+    fn extracted(
+        format: UsageFormat,
+        stream: &[u8],
+    ) -> (Vec<ExtractedUsage>, Option<ExtractedUsage>) {
+        let mut extractor = format.extractor();
+        let reported = match extractor.reads_chunks() {
+            true => extractor.feed_chunk(stream).into_iter().collect::<Vec<_>>(),
+            false => sse::SseParser::default()
+                .push(stream)
+                .iter()
+                .filter(|event| !event.is_done())
+                .filter_map(|event| extractor.feed_event(event))
+                .collect(),
+        };
+        let reported = reported.into_iter().map(|usage| usage.unwrap()).collect();
+        (reported, extractor.finish())
     }
 
     #[test]
-    fn test_stats_chunk_deserialization() {
-        let json_str = r#"{
-            "model": "gpt-4",
-            "usage": {
-                "prompt_tokens": 200,
-                "completion_tokens": 100,
-                "total_tokens": 300
+    fn test_usage_extractors() {
+        let usage = |model: Option<&str>, tokens: (u32, u32, u32), finish: Option<&str>| {
+            ExtractedUsage {
+                model: model.map(str::to_string),
+                prompt_tokens: tokens.0,
+                completion_tokens: tokens.1,
+                total_tokens: tokens.2,
+                finish_reason: finish.map(str::to_string),
+                ..ExtractedUsage::default()
             }
-        }"#;
+        };
 
-        let stats: StatsChunk = serde_json::from_str(json_str).unwrap();
-        assert_eq!(stats.model.as_str(), "gpt-4");
-        assert_eq!(stats.usage.prompt_tokens, 200);
-        assert_eq!(stats.usage.completion_tokens, 100);
-        assert_eq!(stats.usage.total_tokens, 300);
+        let azure = ExtractedUsage {
+            system_fingerprint: Some("fp_04751d0b65".to_string()),
+            ..usage(Some("gpt-4o-2024-08-06"), (24, 13, 37), Some("stop"))
+        };
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        assert_eq!(
+            extracted(UsageFormat::OpenAi, stream),
+            (vec![azure.clone()], Some(azure))
+        );
+
+        let reasoning = ExtractedUsage {
+            cached_tokens: 1280,
+            reasoning_tokens: 192,
+            system_fingerprint: Some("fp_42bfad963b".to_string()),
+            ..usage(Some("o3-mini-2025-01-31"), (1418, 210, 1628), Some("stop"))
+        };
+        let stream = include_bytes!("../fixtures/reasoning_chat_stream.txt");
+        assert_eq!(
+            extracted(UsageFormat::OpenAi, stream),
+            (vec![reasoning.clone()], Some(reasoning))
+        );
+
+        // Usage on a content event is only known to stand once the stream ended
+        let mistral = usage(Some("mistral-large-latest"), (21, 15, 36), Some("stop"));
+        let stream = include_bytes!("../fixtures/mistral_chat_stream.txt");
+        assert_eq!(extracted(UsageFormat::Mistral, stream), (vec![], Some(mistral)));
+
+        let claude = usage(Some("claude-3-5-sonnet-20241022"), (472, 19, 491), Some("end_turn"));
+        let stream = include_bytes!("../fixtures/claude_message_stream.txt");
+        assert_eq!(extracted(UsageFormat::Anthropic, stream), (vec![claude], None));
+
+        let gemini = ExtractedUsage {
+            reasoning_tokens: 112,
+            ..usage(Some("gemini-2.5-flash"), (58, 17, 187), None)
+        };
+        let stream = include_bytes!("../fixtures/gemini_json_stream.json");
+        assert_eq!(extracted(UsageFormat::Gemini, stream), (vec![], Some(gemini)));
+
+        let cohere = usage(None, (67, 16, 83), Some("COMPLETE"));
+        let stream = include_bytes!("../fixtures/cohere_chat_stream.jsonl");
+        assert_eq!(extracted(UsageFormat::Cohere, stream), (vec![cohere], None));
+
+        let responses = ExtractedUsage {
+            reasoning_tokens: 192,
+            ..usage(Some("o4-mini-2025-04-16"), (31, 210, 241), None)
+        };
+        let stream = include_bytes!("../fixtures/responses_stream.txt");
+        assert_eq!(extracted(UsageFormat::Responses, stream), (vec![responses], None));
+
+        // Only the OpenAI-style extractor reads the content
+        assert!(UsageFormat::Mistral.extractor().content().is_some());
+        assert!(UsageFormat::Anthropic.extractor().content().is_none());
     }
 
     #[test]
-    fn test_azure_partial_response_body_deserialization() {
-        let json_str = r#"{
-            "id": "chatcmpl-123",
-            "created": 1640995200,
-            "model": "gpt-3.5-turbo",
-            "usage": {
-                "prompt_tokens": 50,
-                "completion_tokens": 25,
-                "total_tokens": 75
+    fn test_gemini_stream_usage() {
+        let fixtures = [
+            include_bytes!("../fixtures/gemini_sse_stream.txt").as_slice(),
+            include_bytes!("../fixtures/gemini_json_stream.json").as_slice(),
+        ];
+        for stream in fixtures {
+            for chunk_len in [1, 7, stream.len()] {
+                let scanner = UsageScanner::new(usage_template()).format(UsageFormat::Gemini);
+                let (client, records) = relayed(scanner, stream, chunk_len);
+                assert_eq!(client, stream);
+                assert_eq!(records, 0);
             }
-        }"#;
 
-        let response: AzurePartialResponseBody = serde_json::from_str(json_str).unwrap();
-        assert_eq!(response.id.as_str(), "chatcmpl-123");
-        assert_eq!(response.created, 1640995200);
-        assert_eq!(response.model.as_str(), "gpt-3.5-turbo");
-        assert_eq!(response.usage.prompt_tokens, 50);
-        assert_eq!(response.usage.completion_tokens, 25);
-        assert_eq!(response.usage.total_tokens, 75);
+            // The last `usageMetadata` is recorded once the response ended
+            let mut scanner = UsageScanner::new(usage_template()).format(UsageFormat::Gemini);
+            assert!(scanner.push(stream).is_empty());
+            let analytics = scanner.record(false).unwrap();
+            assert!(analytics.usage_captured);
+            assert_eq!(analytics.model, "gemini-2.5-flash");
+            assert_eq!(
+                (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+                (58, 17, 187)
+            );
+            assert_eq!(analytics.reasoning_tokens, 112);
+        }
+
+        let mut scanner = UsageScanner::new(usage_template()).format(UsageFormat::Gemini);
+        assert!(!scanner.record(false).unwrap().usage_captured);
     }
 
     #[test]
-    fn test_json_parsing_error_handling() {
-        // Test invalid JSON for ProxyUrlParams
-        let invalid_json = r#"{"app": "test"}"#; // missing required 'u' field
-        let result = serde_json::from_str::<ProxyUrlParams>(invalid_json);
-        assert!(result.is_err());
+    fn test_usage_of_gzipped_stream() {
+        use futures_util::FutureExt;
 
-        // Test invalid JSON for Usage
-        let invalid_usage = r#"{"prompt_tokens": "not_a_number"}"#;
-        let result = serde_json::from_str::<Usage>(invalid_usage);
-        assert!(result.is_err());
+        let gzipped = include_bytes!("../fixtures/azure_chat_stream.txt.gz");
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        for chunk_len in [1, 7, gzipped.len()] {
+            let upstream = futures_util::stream::iter(
+                gzipped.chunks(chunk_len).map(Ok::<_, &str>).collect::<Vec<_>>(),
+            );
+            let decoder = compression::ResponseDecoder::new(Some("gzip"));
+            let chunks = decoded_body(upstream.map(|chunk| chunk.map(<[u8]>::to_vec)), decoder)
+                .collect::<Vec<_>>()
+                .now_or_never()
+                .unwrap();
+            assert!(chunks.iter().all(|chunk| !chunk.as_ref().unwrap().is_empty()));
+
+            let mut scanner = UsageScanner::new(usage_template());
+            let mut client = Vec::new();
+            let mut records = Vec::new();
+            for chunk in chunks {
+                let (bytes, chunk_records) = scanner.relay(chunk.unwrap());
+                client.extend(bytes);
+                records.extend(chunk_records);
+            }
+            client.extend(scanner.finish());
+            assert_eq!(client, stream, "chunks of {chunk_len}");
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0].as_ref().unwrap().total_tokens, 37);
+        }
+
+        // A body cut short fails once it ends
+        let upstream = futures_util::stream::iter([Ok::<_, &str>(gzipped[..100].to_vec())]);
+        let decoder = compression::ResponseDecoder::new(Some("gzip"));
+        let chunks = decoded_body(upstream, decoder).collect::<Vec<_>>().now_or_never().unwrap();
+        assert!(chunks.last().unwrap().is_err());
     }
 
     #[test]
-    fn test_heapless_string_limits() {
-        // Test that HString<64> can handle strings up to 64 characters
-        let long_model_name = "a".repeat(64);
-        let json_str = format!(
-            r#"{{"model": "{}", "usage": {{"prompt_tokens": 10, "total_tokens": 10}}}}"#,
-            long_model_name
+    fn test_usage_on_final_content_chunk() {
+        let stream = include_bytes!("../fixtures/usage_on_final_chunk_stream.txt");
+        for chunk_len in [1, 13, stream.len()] {
+            let mut scanner = UsageScanner::new(usage_template());
+            let records = stream
+                .chunks(chunk_len)
+                .flat_map(|chunk| scanner.push(chunk))
+                .collect::<Vec<_>>();
+            // No usage event follows the content, so the usage waits for the end of the stream
+            assert!(records.is_empty());
+
+            let analytics = scanner.record(false).unwrap();
+            assert_eq!(analytics.model, "llama-3.1-70b-instruct");
+            assert_eq!(
+                (analytics.prompt_tokens, analytics.completion_tokens, analytics.total_tokens),
+                (18, 5, 23)
+            );
+            assert!(analytics.usage_captured);
+            assert!(!analytics.client_disconnected);
+            assert!(scanner.record(false).is_none());
+            assert!(scanner.record(true).is_none());
+        }
+
+        // A client leaving before the end still gets the reported usage recorded
+        let mut scanner = UsageScanner::new(usage_template());
+        let done_at = stream.len() - sse::DONE_EVENT.len();
+        assert!(scanner.push(&stream[..done_at]).is_empty());
+        let analytics = scanner.record(true).unwrap();
+        assert!(analytics.client_disconnected);
+        assert!(analytics.usage_captured);
+        assert_eq!(analytics.total_tokens, 23);
+
+        // The content event carrying it is never stripped
+        let scanner = UsageScanner::new(usage_template()).strip_usage();
+        assert_eq!(relayed(scanner, stream, 7).0, stream);
+    }
+
+    #[test]
+    fn test_usage_on_final_chunk_and_summary_recorded_once() {
+        let stream = include_bytes!("../fixtures/usage_on_final_and_summary_stream.txt");
+        for chunk_len in [1, 13, stream.len()] {
+            let mut scanner = UsageScanner::new(usage_template());
+            let records = stream
+                .chunks(chunk_len)
+                .flat_map(|chunk| scanner.push(chunk))
+                .collect::<Vec<_>>();
+            assert_eq!(records.len(), 1, "chunks of {chunk_len}");
+            let analytics = records[0].as_ref().unwrap();
+            assert_eq!(analytics.model, "mistral-large-2411");
+            assert_eq!(analytics.total_tokens, 14);
+            assert_eq!(scanner.record(false).unwrap().total_tokens, 14);
+        }
+
+        // Later usage events aren't recorded again either
+        let mut scanner = UsageScanner::new(usage_template());
+        assert_eq!(scanner.push(stream).len(), 1);
+        assert!(scanner.push(stream).is_empty());
+        assert_eq!(scanner.record(false).unwrap().total_tokens, 14);
+    }
+
+    #[test]
+    fn test_time_to_first_choice() {
+        thread_local! {
+            static NOW: std::cell::Cell<f64> = const { std::cell::Cell::new(0.0) };
+        }
+        let set_now = |now: f64| NOW.with(|cell| cell.set(now));
+
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template()).timed_from(1_000.0);
+        scanner.clock = || NOW.with(|cell| cell.get());
+
+        // Azure's prompt filter event comes first, with no choices yet
+        let second_event = stream.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+        set_now(1_200.0);
+        assert!(scanner.push(&stream[..second_event]).is_empty());
+        assert_eq!(scanner.template.ttft_ms, None);
+
+        set_now(1_850.4);
+        scanner.push(&stream[second_event..second_event + 1]);
+        scanner.push(&stream[second_event + 1..second_event + 40]);
+        assert_eq!(scanner.template.ttft_ms, None);
+        let usage_at = std::str::from_utf8(stream).unwrap().rfind("data: {").unwrap();
+        assert!(scanner.push(&stream[second_event + 40..usage_at]).is_empty());
+
+        // The usage arriving later doesn't move it
+        set_now(9_000.0);
+        let records = scanner.push(&stream[usage_at..]);
+        assert_eq!(records[0].as_ref().unwrap().ttft_ms, Some(850));
+
+        // Not timed without a dispatch time
+        let mut scanner = UsageScanner::new(usage_template());
+        assert_eq!(scanner.push(stream)[0].as_ref().unwrap().ttft_ms, None);
+
+        assert_eq!(elapsed_ms(1_000.0, 1_000.9), 0);
+        assert_eq!(elapsed_ms(1_000.0, 999.0), 0);
+    }
+
+    #[test]
+    fn test_completion_chars() {
+        // Counted in characters, not bytes, alongside the reported usage
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template());
+        assert_eq!(scanner.push(stream).len(), 1);
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!((analytics.completion_chars, analytics.completion_tokens), (45, 13));
+
+        // Tool call deltas, other choices and unexpected content add nothing, and hide no usage
+        let stream = concat!(
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":null,",
+            "\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"q\\\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}},",
+            "{\"index\":1,\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":[\"?\"]}}],\"model\":\"m\",",
+            "\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":2,\"total_tokens\":5}}\n\n",
+            "data: {\"choices\":[{\"finish_reason\":\"stop\"}]}\n\n",
         );
+        let mut scanner = UsageScanner::new(usage_template());
+        assert!(scanner.push(stream.as_bytes()).is_empty());
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!((analytics.completion_chars, analytics.total_tokens), (2, 5));
+    }
 
-        let stats = serde_json::from_str::<StatsChunk>(&json_str);
-        assert!(stats.is_ok());
+    #[test]
+    fn test_finish_reason() {
+        // From the last event with choices, before the usage event
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template());
+        scanner.push(stream);
+        assert_eq!(scanner.record(false).unwrap().finish_reason.as_deref(), Some("stop"));
 
-        // Test that strings longer than 64 characters should fail
-        let too_long_model_name = "a".repeat(65);
-        let json_str = format!(
-            r#"{{"model": "{}", "usage": {{"prompt_tokens": 10, "total_tokens": 10}}}}"#,
-            too_long_model_name
+        // Also without usage, and on the content event carrying it
+        let stream = include_bytes!("../fixtures/usage_on_final_chunk_stream.txt");
+        let mut scanner = UsageScanner::new(usage_template());
+        scanner.push(stream);
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!(analytics.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(analytics.total_tokens, 23);
+
+        let stream = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Sure\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"content_filter\"}]}\n\n",
+            "data: [DONE]\n\n",
         );
+        let mut scanner = UsageScanner::new(usage_template());
+        scanner.push(stream.as_bytes());
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!(analytics.finish_reason.as_deref(), Some(CONTENT_FILTER_FINISH));
+        assert!(!analytics.usage_captured);
 
-        let stats = serde_json::from_str::<StatsChunk>(&json_str);
-        assert!(stats.is_err());
+        // Not known for a stream left before its end
+        let mut scanner = UsageScanner::new(usage_template());
+        scanner.push(&stream.as_bytes()[..80]);
+        assert_eq!(scanner.record(true).unwrap().finish_reason, None);
     }
 
     #[test]
-    fn test_complex_proxy_url_params_scenarios() {
-        // Test with special characters in URL
-        let json_str = r#"{
-            "app": "test-app",
-            "u": "https://api.openai.com/v1/chat/completions?model=gpt-4&stream=true",
-            "envId": "prod-2024",
-            "tenId": "tenant_123"
-        }"#;
+    fn test_tool_calls_counted() {
+        let stream = include_bytes!("../fixtures/tool_call_stream.txt");
+        for chunk_len in [1, 29, stream.len()] {
+            let mut scanner = UsageScanner::new(usage_template());
+            let records = stream
+                .chunks(chunk_len)
+                .flat_map(|chunk| scanner.push(chunk))
+                .collect::<Vec<_>>();
+            assert_eq!(records.len(), 1);
 
-        let params: ProxyUrlParams = serde_json::from_str(json_str).unwrap();
-        assert_eq!(params.app, "test-app");
-        assert_eq!(
-            params.u,
-            "https://api.openai.com/v1/chat/completions?model=gpt-4&stream=true"
+            // Three calls, however many fragments their arguments took
+            let analytics = scanner.record(false).unwrap();
+            assert_eq!(analytics.tool_call_count, 3, "chunks of {chunk_len}");
+            assert_eq!(
+                analytics.tool_names,
+                ["get_flight_status", "get_weather", "get_weather"]
+            );
+            assert_eq!(analytics.finish_reason.as_deref(), Some("tool_calls"));
+            assert_eq!((analytics.completion_chars, analytics.total_tokens), (0, 183));
+        }
+
+        // Calls without an index are told apart by id, and names stop at the bound
+        let call = |id: usize| {
+            let tool_call = json!({"id": format!("call_{id}"), "function": {"name": "f"}});
+            let delta = json!({"tool_calls": [tool_call]});
+            format!("data: {}\n\n", json!({"choices": [{"delta": delta}]}))
+        };
+        let more = MAX_TOOL_NAMES + 2;
+        let stream = (0..more).map(call).chain([call(0)]).collect::<String>();
+        let mut scanner = UsageScanner::new(usage_template());
+        scanner.push(stream.as_bytes());
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!(analytics.tool_call_count, more as u32);
+        assert_eq!(analytics.tool_names.len(), MAX_TOOL_NAMES);
+    }
+
+    #[test]
+    fn test_every_event_of_a_chunk_scanned() {
+        // The end of a stream, flushed in a single network chunk
+        let chunk = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"!\"},\"finish_reason\":\"stop\"}],",
+            "\"model\":\"gpt-4o\",\"usage\":null}\n\n",
+            "data: {\"choices\":[],\"model\":\"gpt-4o\",",
+            "\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":4,\"total_tokens\":13}}\n\n",
+            "data: [DONE]\n\n",
         );
-        assert_eq!(params.env_id, Some("prod-2024".to_string()));
-        assert_eq!(params.ten_id, Some("tenant_123".to_string()));
+
+        let mut scanner = UsageScanner::new(usage_template()).ensure_done();
+        let records = scanner.push(chunk.as_bytes());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].as_ref().unwrap().total_tokens, 13);
+        // Both events around the usage were seen too
+        assert_eq!(scanner.extractor.content().unwrap().chunks, 1);
+        assert!(scanner.done);
+        assert_eq!(scanner.finish(), b"");
+
+        // Stripped from the middle of the chunk, with its neighbours relayed
+        let mut scanner = UsageScanner::new(usage_template()).strip_usage();
+        let (bytes, records) = scanner.relay(chunk.as_bytes().to_vec());
+        assert_eq!(records.len(), 1);
+        let (first, _) = chunk.split_once("\n\n").unwrap();
+        assert_eq!(bytes, format!("{first}\n\ndata: [DONE]\n\n").into_bytes());
     }
 
     #[test]
-    fn test_azure_req_body_with_extra_fields() {
-        // Test that extra fields in JSON are ignored
-        let json_str = r#"{
-            "stream": true,
-            "model": "gpt-4",
-            "messages": [{"role": "user", "content": "Hello"}],
-            "temperature": 0.7,
-            "extra_field": "ignored"
-        }"#;
+    fn test_relay_keeps_usage_by_default() {
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        for chunk_len in [1, 7, 97, stream.len()] {
+            let (client, records) = relayed(UsageScanner::new(usage_template()), stream, chunk_len);
+            assert_eq!(client, stream, "chunks of {chunk_len}");
+            assert_eq!(records, 1);
+        }
 
-        let body: AzureReqBodyStream = serde_json::from_str(json_str).unwrap();
-        assert_eq!(body.stream, true);
+        // Each chunk is passed on as it arrives
+        let mut scanner = UsageScanner::new(usage_template());
+        assert_eq!(scanner.relay(b"data: {\"a\"".to_vec()).0, b"data: {\"a\"");
     }
 
     #[test]
-    fn test_usage_zero_values() {
-        let json_str = r#"{
-            "prompt_tokens": 0,
-            "completion_tokens": 0,
-            "total_tokens": 0
-        }"#;
+    fn test_relay_strips_usage() {
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let text = std::str::from_utf8(stream).unwrap();
+        let usage_start = text.rfind("data: {").unwrap();
+        let usage_end = usage_start + text[usage_start..].find("\n\n").unwrap() + 2;
+        assert!(text[usage_start..usage_end].contains("\"total_tokens\":37"));
+        let expected = [&stream[..usage_start], &stream[usage_end..]].concat();
 
-        let usage: Usage = serde_json::from_str(json_str).unwrap();
-        assert_eq!(usage.prompt_tokens, 0);
-        assert_eq!(usage.completion_tokens, 0);
-        assert_eq!(usage.total_tokens, 0);
+        for chunk_len in [1, 2, 7, 97, stream.len()] {
+            let scanner = UsageScanner::new(usage_template()).strip_usage();
+            let (client, records) = relayed(scanner, stream, chunk_len);
+            // Usage is still recorded, and everything else arrives byte for byte
+            assert_eq!(records, 1);
+            assert_eq!(client, expected, "chunks of {chunk_len}");
+        }
+
+        // Partial events wait to be complete, and a cut-off one is still forwarded
+        let mut scanner = UsageScanner::new(usage_template()).strip_usage();
+        assert_eq!(scanner.relay(b"data: {\"a\"".to_vec()).0, b"");
+        assert_eq!(scanner.relay(b":1}\n\ndata: {".to_vec()).0, b"data: {\"a\":1}\n\n");
+        assert_eq!(scanner.finish(), b"data: {");
+
+        // Unparsable usage isn't recorded, so it's left for the client
+        let mut scanner = UsageScanner::new(usage_template()).strip_usage();
+        let bad_usage = b"data: {\"model\":7,\"usage\":{}}\n\n";
+        let (bytes, records) = scanner.relay(bad_usage.to_vec());
+        assert_eq!(bytes, bad_usage);
+        assert!(records[0].is_err());
     }
 
     #[test]
-    fn test_stats_chunk_with_minimal_model_name() {
-        let json_str = r#"{
-            "model": "a",
-            "usage": {
-                "prompt_tokens": 1,
-                "total_tokens": 1
+    fn test_relay_azure_compat() {
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let events = |bytes: &[u8]| {
+            let events = sse::SseParser::default().push(bytes);
+            events
+                .into_iter()
+                .map(|event| match serde_json::from_str::<serde_json::Value>(&event.data) {
+                    Ok(value) => value,
+                    Err(_) => json!(event.data),
+                })
+                .collect::<Vec<_>>()
+        };
+        let upstream = events(stream);
+
+        for chunk_len in [1, 7, 97, stream.len()] {
+            // Off, the client gets the upstream's bytes
+            let scanner = UsageScanner::new(usage_template());
+            assert_eq!(relayed(scanner, stream, chunk_len).0, stream);
+            let scanner = UsageScanner::new(usage_template()).strip_usage();
+            let client = relayed(scanner, stream, chunk_len).0;
+            assert!(client.windows(22).any(|w| w == b"content_filter_results"));
+
+            let scanner = UsageScanner::new(usage_template()).azure_compat();
+            let (client, records) = relayed(scanner, stream, chunk_len);
+            assert_eq!(records, 1);
+            assert!(!client.windows(7).any(|w| w == b"_filter"), "chunks of {chunk_len}");
+
+            // The prompt filter event is gone, and the others kept all but the annotations
+            let client = events(&client);
+            assert_eq!(client.len(), upstream.len() - 1);
+            assert!(upstream[0]["prompt_filter_results"].is_array());
+            for (client, upstream) in client.iter().zip(&upstream[1..]) {
+                let mut upstream = upstream.clone();
+                let choices = upstream.get_mut("choices").and_then(|c| c.as_array_mut());
+                if let Some(choices) = choices {
+                    for choice in choices.iter_mut().filter_map(|c| c.as_object_mut()) {
+                        choice.shift_remove("content_filter_results");
+                        choice.shift_remove("content_filter_offsets");
+                    }
+                }
+                assert_eq!(*client, upstream);
             }
-        }"#;
+        }
 
-        let stats: StatsChunk = serde_json::from_str(json_str).unwrap();
-        assert_eq!(stats.model.as_str(), "a");
-        assert_eq!(stats.usage.prompt_tokens, 1);
-        assert_eq!(stats.usage.completion_tokens, 0); // default
-        assert_eq!(stats.usage.total_tokens, 1);
+        // Along with stripped usage
+        let scanner = UsageScanner::new(usage_template()).azure_compat().strip_usage();
+        let (client, records) = relayed(scanner, stream, 7);
+        assert_eq!(records, 1);
+        assert_eq!(events(&client).len(), upstream.len() - 2);
+    }
+
+    #[test]
+    fn test_relay_ensures_done() {
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let done_at = stream.len() - sse::DONE_EVENT.len();
+        assert_eq!(&stream[done_at..], sse::DONE_EVENT);
+        let without_done = &stream[..done_at];
+        let usage_at = std::str::from_utf8(without_done).unwrap().rfind("data: ").unwrap();
+
+        for chunk_len in [1, 7, stream.len()] {
+            // Never a second `[DONE]`
+            let scanner = UsageScanner::new(usage_template()).ensure_done();
+            assert_eq!(relayed(scanner, stream, chunk_len).0, stream);
+
+            let scanner = UsageScanner::new(usage_template()).ensure_done();
+            assert_eq!(relayed(scanner, without_done, chunk_len).0, stream);
+
+            // Along with stripped usage, and only when asked for
+            let scanner = UsageScanner::new(usage_template()).strip_usage().ensure_done();
+            let (client, records) = relayed(scanner, without_done, chunk_len);
+            assert_eq!(client, [&stream[..usage_at], sse::DONE_EVENT].concat());
+            assert_eq!(records, 1);
+            let scanner = UsageScanner::new(usage_template());
+            assert_eq!(relayed(scanner, without_done, chunk_len).0, without_done);
+        }
+
+        // A stream cut off inside an event has it ended before the `[DONE]`
+        let cut = b"data: {\"choices\":[]}\n\ndata: {\"cho";
+        let mut scanner = UsageScanner::new(usage_template()).ensure_done();
+        assert_eq!(scanner.relay(cut.to_vec()).0, cut);
+        assert_eq!(scanner.finish(), b"\n\ndata: [DONE]\n\n");
+        assert_eq!(scanner.finish(), b"");
+
+        let mut scanner = UsageScanner::new(usage_template()).strip_usage().ensure_done();
+        assert_eq!(scanner.relay(cut.to_vec()).0, b"data: {\"choices\":[]}\n\n");
+        assert_eq!(scanner.finish(), b"data: {\"cho\n\ndata: [DONE]\n\n");
+    }
+
+    #[test]
+    fn test_relay_counters_recorded() {
+        thread_local! {
+            static NOW: std::cell::Cell<f64> = const { std::cell::Cell::new(0.0) };
+        }
+        let set_now = |now: f64| NOW.with(|cell| cell.set(now));
+
+        let stream = include_bytes!("../fixtures/azure_chat_stream.txt");
+        let usage_at = std::str::from_utf8(stream).unwrap().rfind("data: {").unwrap();
+        let mut scanner = UsageScanner::new(usage_template()).strip_usage().ensure_done();
+        scanner.clock = || NOW.with(|cell| cell.get());
+
+        let mut client = Vec::new();
+        for (i, chunk) in stream[..usage_at].chunks(100).enumerate() {
+            set_now(5_000.0 + 40.0 * i as f64);
+            client.extend(scanner.relay(chunk.to_vec()).0);
+        }
+        set_now(6_000.0);
+        let (bytes, records) = scanner.relay(stream[usage_at..].to_vec());
+        assert_eq!(records.len(), 1);
+        client.extend(bytes);
+        client.extend(scanner.finish());
+
+        // Usage on the record, with what reached the client since the first chunk
+        let analytics = scanner.record(false).unwrap();
+        assert_eq!(analytics.total_tokens, 37);
+        assert_eq!(analytics.chunk_count, usage_at.div_ceil(100) as u32 + 1);
+        assert_eq!(analytics.response_bytes, client.len() as u64);
+        assert_eq!(analytics.duration_ms, 1_000);
+
+        // A stream without usage is still recorded, without tokens
+        let body = b"{\"id\":\"embd-1\",\"data\":[]}";
+        let mut scanner = UsageScanner::new(usage_template());
+        scanner.relay(body[..10].to_vec());
+        scanner.relay(body[10..].to_vec());
+        assert_eq!(scanner.finish(), b"");
+        let analytics = scanner.record(false).unwrap();
+        assert!(!analytics.usage_captured);
+        assert!(!analytics.client_disconnected);
+        assert_eq!((analytics.prompt_tokens, analytics.total_tokens), (0, 0));
+        assert_eq!((analytics.chunk_count, analytics.response_bytes), (2, body.len() as u64));
+    }
+
+    #[test]
+    fn test_stream_abandoned_only_when_dropped_early() {
+        use futures_util::FutureExt;
+
+        let abandoned = Rc::new(RefCell::new(0));
+        let guarded = |items: Vec<u8>| {
+            let abandoned = abandoned.clone();
+            on_stream_abandoned(futures_util::stream::iter(items), move || {
+                *abandoned.borrow_mut() += 1
+            })
+        };
+
+        let mut stream = guarded(vec![1, 2]);
+        assert_eq!(stream.next().now_or_never(), Some(Some(1)));
+        drop(stream);
+        assert_eq!(*abandoned.borrow(), 1);
+
+        let stream = guarded(vec![1, 2]);
+        assert_eq!(stream.collect::<Vec<_>>().now_or_never(), Some(vec![1, 2]));
+        assert_eq!(*abandoned.borrow(), 1);
+    }
+
+    /// A channel and the body it feeds, as `forward_upstream` sets them up
+    fn upstream_body(
+        capacity: usize,
+    ) -> (
+        futures_channel::mpsc::Sender<Result<Vec<u8>>>,
+        UpstreamBody,
+        futures_channel::oneshot::Receiver<()>,
+    ) {
+        let (tx, rx) = futures_channel::mpsc::channel(capacity);
+        let (cancel, cancelled) = futures_channel::oneshot::channel();
+        let body = UpstreamBody {
+            rx,
+            _cancel: cancel,
+            failure: Rc::default(),
+        };
+        (tx, body, cancelled)
+    }
+
+    /// An idle timer that never fires
+    fn never() -> futures_util::future::Pending<()> {
+        futures_util::future::pending()
+    }
+
+    #[test]
+    fn test_pump_stops_when_client_disconnects() {
+        use std::future::Future;
+        use std::task::{Context, Poll};
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // An upstream that never sends another chunk: the pump ends as soon as the body goes
+        let (mut tx, body, cancelled) = upstream_body(10);
+        let upstream = futures_util::stream::pending::<std::result::Result<Vec<u8>, String>>();
+        let mut pump = std::pin::pin!(pump_upstream(upstream, &mut tx, cancelled, never));
+
+        assert!(pump.as_mut().poll(&mut cx).is_pending());
+        drop(body);
+        assert_eq!(
+            pump.as_mut().poll(&mut cx),
+            Poll::Ready(PumpEnd::ClientDisconnected)
+        );
+
+        // A client that stopped reading with the channel full
+        let (mut tx, body, cancelled) = upstream_body(0);
+        let upstream = futures_util::stream::repeat(Ok::<_, String>(b"data: {}\n\n".to_vec()));
+        let mut pump = std::pin::pin!(pump_upstream(upstream, &mut tx, cancelled, never));
+
+        assert!(pump.as_mut().poll(&mut cx).is_pending());
+        drop(body);
+        assert_eq!(
+            pump.as_mut().poll(&mut cx),
+            Poll::Ready(PumpEnd::ClientDisconnected)
+        );
+    }
+
+    #[test]
+    fn test_pump_waits_for_slow_client() {
+        use std::cell::Cell;
+        use std::future::Future;
+        use std::task::{Context, Poll};
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let capacity = 2;
+        let (mut tx, mut body, cancelled) = upstream_body(capacity);
+        let read = Cell::new(0);
+        let upstream = futures_util::stream::iter(0..500u32)
+            .inspect(|_| read.set(read.get() + 1))
+            .map(|i| Ok::<_, String>(format!("data: {i}\n\n").into_bytes()));
+        let pump = async move {
+            let end = pump_upstream(upstream, &mut tx, cancelled, never).await;
+            // The body ends with the task, and so its sender
+            drop(tx);
+            end
+        };
+        let mut pump = std::pin::pin!(pump);
+
+        // The client reads one chunk for every seven times the upstream could produce one
+        let mut relayed = Vec::new();
+        let mut ended = false;
+        for turn in 0.. {
+            if !ended {
+                if let Poll::Ready(end) = pump.as_mut().poll(&mut cx) {
+                    assert_eq!(end, PumpEnd::Completed);
+                    ended = true;
+                }
+            }
+            if turn % 7 == 0 {
+                match body.poll_next_unpin(&mut cx) {
+                    Poll::Ready(Some(chunk)) => relayed.push(chunk.unwrap()),
+                    Poll::Ready(None) => break,
+                    Poll::Pending => {}
+                }
+            }
+            // Upstream is only read as far ahead as the buffer (plus the sender's own slot and
+            // the chunk waiting for it) allows
+            assert!(read.get() <= relayed.len() + capacity + 2, "read ahead at {turn}");
+        }
+
+        assert!(ended);
+        let expected = (0..500)
+            .map(|i| format!("data: {i}\n\n").into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(relayed, expected);
+    }
+
+    #[test]
+    fn test_pump_relays_until_upstream_ends() {
+        use futures_util::FutureExt;
+
+        // Relays what the upstream sent before failing, then closes as `forward_upstream` does
+        let relayed = |error_events: bool| {
+            let (mut tx, body, cancelled) = upstream_body(10);
+            let upstream = futures_util::stream::iter([
+                Ok(b"data: 1\n\n".to_vec()),
+                Ok(b"data: 2\n\n".to_vec()),
+                Ok(b"data: 3\n\n".to_vec()),
+                Err("connection reset"),
+            ]);
+
+            let end = pump_upstream(upstream, &mut tx, cancelled, never).now_or_never();
+            assert_eq!(end, Some(PumpEnd::UpstreamError("connection reset".to_string())));
+            let end = end.unwrap();
+            assert_eq!(end.error().as_deref(), Some("connection reset"));
+            if let Some(item) = end.closing_item(DEFAULT_STREAM_IDLE_TIMEOUT, error_events) {
+                send_chunk(&mut tx, item).now_or_never().unwrap().unwrap();
+            }
+            drop(tx);
+            body.collect::<Vec<_>>().now_or_never().unwrap()
+        };
+
+        // A final event explains the failure
+        let relayed_events = relayed(true);
+        assert_eq!(relayed_events.len(), 4);
+        let client = relayed_events.into_iter().flat_map(|chunk| chunk.unwrap());
+        let events = sse::SseParser::default().push(&client.collect::<Vec<_>>());
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[2].data, "3");
+        assert_eq!(events[3].event.as_deref(), Some("error"));
+        let error = serde_json::from_str::<serde_json::Value>(&events[3].data).unwrap();
+        assert_eq!(error["error"], true);
+        assert_eq!(error["code"], UPSTREAM_STREAM_ERROR_CODE);
+        assert_eq!(error["message"], "The upstream stream failed: connection reset");
+
+        // Or, without error events, the stream fails
+        let relayed = relayed(false);
+        assert_eq!(relayed.len(), 4);
+        assert_eq!(relayed[2].as_ref().unwrap(), b"data: 3\n\n");
+        assert!(relayed[3].is_err());
+
+        let end = PumpEnd::Completed;
+        assert_eq!(end.error(), None);
+        assert!(end.closing_item(DEFAULT_STREAM_IDLE_TIMEOUT, true).is_none());
+    }
+
+    #[test]
+    fn test_pump_times_out_when_upstream_stalls() {
+        use std::cell::Cell;
+        use std::future::Future;
+        use std::task::{Context, Poll};
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // Timers fire when the test says so; every chunk starts a new one
+        let fired = Cell::new(false);
+        let started = Cell::new(0);
+        let idle_timer = || {
+            started.set(started.get() + 1);
+            futures_util::future::poll_fn(|_| {
+                if fired.get() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+        };
+
+        let (mut tx, mut body, cancelled) = upstream_body(10);
+        let (upstream_tx, upstream) = futures_channel::mpsc::unbounded();
+        let upstream = upstream.map(Ok::<_, String>);
+        let mut pump = std::pin::pin!(pump_upstream(upstream, &mut tx, cancelled, idle_timer));
+
+        for i in 0..3 {
+            upstream_tx.unbounded_send(format!("data: {i}\n\n").into_bytes()).unwrap();
+            assert!(pump.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(started.get(), 4);
+
+        fired.set(true);
+        assert_eq!(pump.as_mut().poll(&mut cx), Poll::Ready(PumpEnd::IdleTimeout));
+        for i in 0..3 {
+            let chunk = body.poll_next_unpin(&mut cx);
+            let expected = format!("data: {i}\n\n").into_bytes();
+            assert!(matches!(chunk, Poll::Ready(Some(Ok(chunk))) if chunk == expected));
+        }
+
+        // The client is told why its stream ended, in an event of its own
+        let end = PumpEnd::IdleTimeout;
+        assert_eq!(end.error().as_deref(), Some(IDLE_TIMEOUT_CODE));
+        assert!(end.closing_item(Duration::from_secs(120), false).is_none());
+        let event = end.closing_item(Duration::from_secs(120), true).unwrap().unwrap();
+        let mut parser = sse::SseParser::default();
+        let events = parser.push(&[b"data: {\"a\"".as_slice(), &event].concat());
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event.as_deref(), Some("error"));
+        let error = serde_json::from_str::<serde_json::Value>(&events[1].data).unwrap();
+        assert_eq!(error["code"], IDLE_TIMEOUT_CODE);
+        assert_eq!(error["message"], "The upstream sent nothing for 120 seconds");
+        assert!(parser.is_between_events());
     }
 
     #[test]
@@ -695,7 +5178,6 @@ mod tests {
             r#"{"app": "test""#,             // Unclosed JSON
             r#"{"app": test, "u": "url"}"#,  // Unquoted string
             r#"{"app": "test", "u": null}"#, // Null for required string field
-            r#"{}"#,                         // Missing required fields
         ];
 
         for json_str in malformed_cases {
@@ -706,6 +5188,9 @@ mod tests {
                 json_str
             );
         }
+
+        // Missing required fields are reported by validation instead
+        assert_eq!(validated(json!({})).unwrap_err(), ParamError::missing("app"));
     }
 
     #[test]
@@ -0,0 +1,57 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::cell::RefCell;
+
+/// How long a throttled log line stays quiet once written
+const WINDOW_MS: u64 = 60_000;
+
+/// Lets a kind of log line through once per window, counting the ones held back in between
+#[derive(Debug, Default)]
+struct LogThrottle {
+    /// When the last line went through
+    logged_at: Option<u64>,
+    suppressed: u32,
+}
+
+impl LogThrottle {
+    /// Whether a line may be written at `now`, with how many were held back since the last one
+    fn allow(&mut self, now: u64) -> Option<u32> {
+        let quiet_until = self.logged_at.map(|at| at.saturating_add(WINDOW_MS));
+        if quiet_until.is_some_and(|until| now < until) {
+            self.suppressed = self.suppressed.saturating_add(1);
+            return None;
+        }
+        self.logged_at = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+thread_local! {
+    // Per isolate, like the metrics counters; workers run single-threaded
+    static PARSE_MISSES: RefCell<LogThrottle> = RefCell::new(LogThrottle::default());
+}
+
+/// Whether a stream event that couldn't be read may be logged at `now` (ms), with how many
+/// went unlogged since the last one
+pub fn parse_miss(now: u64) -> Option<u32> {
+    PARSE_MISSES.with(|throttle| throttle.borrow_mut().allow(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_once_per_window() {
+        let mut throttle = LogThrottle::default();
+        assert_eq!(throttle.allow(1_000), Some(0));
+        assert_eq!(throttle.allow(1_001), None);
+        assert_eq!(throttle.allow(1_000 + WINDOW_MS - 1), None);
+
+        // The next line owns up to the ones held back
+        assert_eq!(throttle.allow(1_000 + WINDOW_MS), Some(2));
+        assert_eq!(throttle.allow(1_000 + WINDOW_MS), None);
+        assert_eq!(throttle.allow(1_000 + 3 * WINDOW_MS), Some(1));
+    }
+}
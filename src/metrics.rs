@@ -0,0 +1,174 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use worker::*;
+
+/// Counters kept per isolate; they reset whenever the isolate is recycled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Metric {
+    Requests,
+    UpstreamErrors,
+    UsageParseFailures,
+    StreamsCompleted,
+    ContentFilterFinishes,
+}
+
+impl Metric {
+    const ALL: [Metric; 5] = [
+        Metric::Requests,
+        Metric::UpstreamErrors,
+        Metric::UsageParseFailures,
+        Metric::StreamsCompleted,
+        Metric::ContentFilterFinishes,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Metric::Requests => "langproxy_requests_total",
+            Metric::UpstreamErrors => "langproxy_upstream_errors_total",
+            Metric::UsageParseFailures => "langproxy_usage_parse_failures_total",
+            Metric::StreamsCompleted => "langproxy_streams_completed_total",
+            Metric::ContentFilterFinishes => "langproxy_content_filter_finishes_total",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Metric::Requests => "Proxy requests received.",
+            Metric::UpstreamErrors => "Upstream failures by status class.",
+            Metric::UsageParseFailures => "Usage chunks that could not be parsed.",
+            Metric::StreamsCompleted => "Streams forwarded to the client until the end.",
+            Metric::ContentFilterFinishes => "Completions ended by the content filter.",
+        }
+    }
+}
+
+/// Counter key: metric, route label and the optional status class label
+type CounterKey = (Metric, String, Option<&'static str>);
+
+thread_local! {
+    // Workers run single-threaded, so a thread-local map needs no synchronisation
+    static COUNTERS: RefCell<BTreeMap<CounterKey, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn add(key: CounterKey) {
+    COUNTERS.with(|counters| *counters.borrow_mut().entry(key).or_default() += 1);
+}
+
+/// Increments `metric` for `route`
+pub fn increment(metric: Metric, route: &str) {
+    add((metric, route.to_string(), None));
+}
+
+/// Counts an upstream failure; `None` means the upstream couldn't be reached at all
+pub fn upstream_error(route: &str, status: Option<u16>) {
+    let class = match status {
+        Some(100..=199) => "1xx",
+        Some(200..=299) => "2xx",
+        Some(300..=399) => "3xx",
+        Some(400..=499) => "4xx",
+        Some(500..=599) => "5xx",
+        Some(_) => "other",
+        None => "network",
+    };
+    add((Metric::UpstreamErrors, route.to_string(), Some(class)));
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders every counter in the Prometheus text exposition format
+pub fn render() -> String {
+    COUNTERS.with(|counters| {
+        let counters = counters.borrow();
+        let mut out = String::new();
+
+        for metric in Metric::ALL {
+            let _ = writeln!(out, "# HELP {} {}", metric.name(), metric.help());
+            let _ = writeln!(out, "# TYPE {} counter", metric.name());
+
+            let samples = counters
+                .iter()
+                .filter(|((counter, _, _), _)| *counter == metric);
+
+            for ((_, route, class), value) in samples {
+                let _ = match class {
+                    Some(class) => writeln!(
+                        out,
+                        "{}{{route=\"{}\",class=\"{}\"}} {}",
+                        metric.name(),
+                        escape_label(route),
+                        class,
+                        value
+                    ),
+                    None => writeln!(
+                        out,
+                        "{}{{route=\"{}\"}} {}",
+                        metric.name(),
+                        escape_label(route),
+                        value
+                    ),
+                };
+            }
+        }
+
+        out
+    })
+}
+
+/// Serves the isolate's counters; values are best-effort since isolates are ephemeral
+pub async fn handle_metrics(_req: Request, _ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("content-type", "text/plain; version=0.0.4")?;
+
+    Ok(Response::ok(render())?.with_headers(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        increment(Metric::Requests, "/azure-openai/completions");
+        increment(Metric::Requests, "/azure-openai/completions");
+        increment(Metric::Requests, "/proxy/universal");
+        upstream_error("/proxy/universal", Some(429));
+        upstream_error("/proxy/universal", None);
+        increment(Metric::StreamsCompleted, "/azure-openai/completions");
+
+        let rendered = render();
+
+        assert!(rendered.contains("# TYPE langproxy_requests_total counter\n"));
+        assert!(
+            rendered.contains("langproxy_requests_total{route=\"/azure-openai/completions\"} 2\n")
+        );
+        assert!(rendered.contains("langproxy_requests_total{route=\"/proxy/universal\"} 1\n"));
+        assert!(rendered.contains(
+            "langproxy_upstream_errors_total{route=\"/proxy/universal\",class=\"4xx\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "langproxy_upstream_errors_total{route=\"/proxy/universal\",class=\"network\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "langproxy_streams_completed_total{route=\"/azure-openai/completions\"} 1\n"
+        ));
+        // Families without samples still announce themselves
+        assert!(rendered.contains("# TYPE langproxy_usage_parse_failures_total counter\n"));
+        assert!(!rendered.contains("langproxy_usage_parse_failures_total{"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label(r#"/a"b\c"#), r#"/a\"b\\c"#);
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use worker::*;
+
+use crate::kv_cache;
+
+/// KV namespace mapping client-facing model names (`em-gpt-4o`) to upstream ones
+pub const MODEL_MAP_BINDING: &str = "MODEL_MAP";
+
+/// Whether the deployment has a `MODEL_MAP` namespace bound
+pub fn enabled(env: &Env) -> bool {
+    env.kv(MODEL_MAP_BINDING).is_ok()
+}
+
+/// KV keys tried for an alias, most specific first: `{tenant}:{alias}`, then `{alias}`
+fn lookup_keys(tenant: Option<&str>, alias: &str) -> Vec<String> {
+    tenant
+        .map(|tenant| format!("{tenant}:{alias}"))
+        .into_iter()
+        .chain(std::iter::once(alias.to_string()))
+        .collect()
+}
+
+/// Resolves a client-facing model name; `None` leaves the request's model unchanged
+pub async fn resolve(env: &Env, tenant: Option<&str>, alias: &str) -> Option<String> {
+    if !enabled(env) {
+        return None;
+    }
+
+    for key in lookup_keys(tenant, alias) {
+        let model = match kv_cache::get_text(env, MODEL_MAP_BINDING, &key).await {
+            Ok(model) => model
+                .map(|model| model.trim().to_string())
+                .filter(|model| !model.is_empty()),
+            Err(e) => {
+                console_error!("MODEL_MAP lookup of {} failed: {}", key, e);
+                return None;
+            }
+        };
+
+        if let Some(model) = model {
+            console_log!("Rewriting model {} to {}", alias, model);
+            return Some(model);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_keys() {
+        assert_eq!(
+            lookup_keys(Some("acme"), "em-fast"),
+            vec!["acme:em-fast".to_string(), "em-fast".to_string()]
+        );
+        assert_eq!(lookup_keys(None, "em-fast"), vec!["em-fast".to_string()]);
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde::Deserialize;
+use worker::*;
+
+use crate::{
+    cors, guard_request, proxy_response_headers, query_error_response, redact,
+    reject_disallowed_upstream, upstream, upstream_auth_headers, ProxyUrlParams, RequestMeta,
+};
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: HashMap<String, bool>,
+    #[serde(default)]
+    category_scores: HashMap<String, f64>,
+}
+
+/// The parts of a moderation response needed for analytics
+#[derive(Debug, Deserialize)]
+struct ModerationResponseBody {
+    model: String,
+    results: Vec<ModerationResult>,
+}
+
+impl ModerationResponseBody {
+    /// Number of inputs the upstream flagged
+    fn flagged_count(&self) -> u32 {
+        self.results.iter().filter(|r| r.flagged).count() as u32
+    }
+
+    /// The flagged category with the highest score across all inputs
+    fn top_category(&self) -> Option<&str> {
+        self.results
+            .iter()
+            .flat_map(|result| {
+                result
+                    .categories
+                    .iter()
+                    .filter(|(_, flagged)| **flagged)
+                    .map(|(name, _)| {
+                        let score = result.category_scores.get(name).copied();
+                        (name.as_str(), score.unwrap_or_default())
+                    })
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(name, _)| name)
+    }
+}
+
+/// Proxies moderation checks and records the flag rate per tenant
+pub async fn moderations_proxy(
+    mut req: Request,
+    ctx: RouteContext<Rc<Context>>,
+) -> Result<Response> {
+    let data = req.bytes().await?;
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let mut proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let status = response.status();
+    let origin = cors::request_origin(&req, &ctx.env);
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            console_error!("Error reading upstream body: {}", e);
+            return Response::error("Internal Server Error!!!!!", 500);
+        }
+    };
+
+    if status.is_success() {
+        match serde_json::from_slice::<ModerationResponseBody>(&body) {
+            Ok(parsed) => {
+                // The moderation API has no token usage, so the token columns stay at zero
+                let mut analytics = meta.usage_analytics(parsed.model.clone(), 0, 0, 0);
+                analytics.moderation_flagged = parsed.flagged_count();
+                analytics.moderation_top_category = parsed.top_category().map(str::to_string);
+                analytics.save(&ctx.env, &ctx.data).await;
+            }
+            Err(e) => console_error!("Failed to parse moderation response: {e}"),
+        }
+    } else {
+        console_error!("Error {}", status);
+    }
+
+    Ok(Response::from_bytes(body.into())?
+        .with_status(status.as_u16())
+        .with_headers(my_response_headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moderation_flagged_results() {
+        let body: ModerationResponseBody = serde_json::from_str(
+            r#"{
+                "id": "modr-0d9740456c391e43c445bf0f010940c7",
+                "model": "omni-moderation-latest",
+                "results": [
+                    {
+                        "flagged": true,
+                        "categories": {"harassment": true, "violence": true, "sexual": false},
+                        "category_scores": {"harassment": 0.42, "violence": 0.91, "sexual": 0.99}
+                    },
+                    {
+                        "flagged": false,
+                        "categories": {"harassment": false, "violence": false, "sexual": false},
+                        "category_scores": {"harassment": 0.01, "violence": 0.02, "sexual": 0.0}
+                    },
+                    {
+                        "flagged": true,
+                        "categories": {"self-harm": true},
+                        "category_scores": {"self-harm": 0.87}
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(body.model, "omni-moderation-latest");
+        assert_eq!(body.flagged_count(), 2);
+        // "sexual" scores highest but wasn't flagged
+        assert_eq!(body.top_category(), Some("violence"));
+    }
+
+    #[test]
+    fn test_moderation_nothing_flagged() {
+        let body: ModerationResponseBody = serde_json::from_str(
+            r#"{
+                "model": "text-moderation-007",
+                "results": [{"flagged": false, "categories": {"hate": false}, "category_scores": {"hate": 0.001}}]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(body.flagged_count(), 0);
+        assert_eq!(body.top_category(), None);
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures_util::StreamExt;
+use serde::Deserialize;
+use worker::*;
+
+use crate::json_stream::JsonObjectSplitter;
+use crate::{
+    cors, forward_upstream, guard_request, on_stream_end, proxy_response_headers,
+    query_error_response, redact, reject_disallowed_upstream, targets::AuthError, upstream,
+    upstream_auth_headers, upstream_error_headers, ProxyUrlParams, RequestMeta,
+};
+
+/// The parts of an Ollama `/api/chat` or `/api/generate` object needed for analytics
+#[derive(Debug, Deserialize)]
+struct OllamaChunk {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
+}
+
+/// Token usage mapped onto the OpenAI-style columns used by `UsageAnalytics`
+#[derive(Debug, PartialEq)]
+pub struct OllamaUsage {
+    pub model: Option<String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+/// Scans an Ollama NDJSON stream (or a single non-streamed object) for the final `done` object
+#[derive(Debug, Default)]
+pub struct OllamaUsageScanner {
+    splitter: JsonObjectSplitter,
+    usage: Option<OllamaUsage>,
+}
+
+impl OllamaUsageScanner {
+    pub fn push(&mut self, chunk: &[u8]) {
+        for object in self.splitter.push(chunk) {
+            match serde_json::from_slice::<OllamaChunk>(&object) {
+                Ok(parsed) if parsed.done => {
+                    self.usage = Some(OllamaUsage {
+                        model: parsed.model,
+                        prompt_tokens: parsed.prompt_eval_count,
+                        completion_tokens: parsed.eval_count,
+                        total_tokens: parsed.prompt_eval_count + parsed.eval_count,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> Option<OllamaUsage> {
+        self.usage.take()
+    }
+}
+
+/// Proxies Ollama chat/generate calls untouched and records the eval counts
+pub async fn ollama_proxy(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let data = req.bytes().await?;
+    let env = ctx.env.clone();
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, Some(&data)).await
+    {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    // Ollama rejects unknown fields such as `stream_options`, so the body is forwarded untouched.
+    // On-prem instances usually have no auth, but one behind a gateway may need the caller's key
+    // or the tenant's.
+    let provider = xparams.provider(&xparams.u);
+    let mut proxy_headers = match upstream_auth_headers(&req, &ctx.env, &meta, provider) {
+        Ok(headers) => headers,
+        Err(AuthError::MissingCredential | AuthError::KeyMissing(_)) => Headers::new(),
+        Err(e) => return e.to_response(),
+    };
+    proxy_headers.set("content-type", "application/json")?;
+
+    console_debug!("Proxy URL: {}", redact::url(&xparams.u));
+
+    let response = match upstream::http_client()
+        .post(&xparams.u)
+        .headers(proxy_headers.into())
+        .body(data)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let origin = cors::request_origin(&req, &ctx.env);
+    if !response.status().is_success() {
+        console_error!("Error {}", response.status());
+        let status = response.status();
+        let error_headers = upstream_error_headers(&response, origin.as_deref())?;
+        let text = &response.text().await;
+        let error = Response::error(format!("{:?}", &text), status.into())?;
+        return Ok(error.with_headers(error_headers));
+    }
+
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    let rx = forward_upstream(&env, response, xparams.sends_error_events());
+
+    let scanner = Rc::new(RefCell::new(OllamaUsageScanner::default()));
+    let scanning = scanner.clone();
+
+    let stream = rx.map(move |result| {
+        if let Ok(bytes) = &result {
+            scanning.borrow_mut().push(bytes);
+        }
+        result
+    });
+
+    let event_ctx = ctx.data.clone();
+    let stream = on_stream_end(stream, move || {
+        let finished = scanner.borrow_mut().finish();
+        if let Some(usage) = finished {
+            console_log!("OLLAMA USAGE: {:?}", usage);
+
+            let analytics = meta.usage_analytics(
+                usage.model.unwrap_or_else(|| "unknown".to_string()),
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens,
+            );
+
+            // Save analytics data asynchronously (fire-and-forget)
+            analytics.save_in_background(&env, &event_ctx);
+        }
+    });
+
+    match Response::from_stream(stream) {
+        Ok(resp) => Ok(resp.with_headers(my_response_headers)),
+        Err(e) => {
+            console_error!("Error creating streaming response: {}", e);
+            Response::error("Internal Server Error!!!!!", 500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLLAMA_NDJSON: &str = concat!(
+        "{\"model\":\"llama3.2\",\"created_at\":\"2025-06-30T12:00:00.1Z\",\"message\":{\"role\":\"assistant\",\"content\":\"The\"},\"done\":false}\n",
+        "{\"model\":\"llama3.2\",\"created_at\":\"2025-06-30T12:00:00.2Z\",\"message\":{\"role\":\"assistant\",\"content\":\" sky {is} blue\"},\"done\":false}\n",
+        "{\"model\":\"llama3.2\",\"created_at\":\"2025-06-30T12:00:00.3Z\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done_reason\":\"stop\",\"done\":true,\"total_duration\":4883583458,\"load_duration\":1334875,\"prompt_eval_count\":26,\"prompt_eval_duration\":342546000,\"eval_count\":282,\"eval_duration\":4535599000}\n",
+    );
+
+    #[test]
+    fn test_final_object_split_across_chunks() {
+        let bytes = OLLAMA_NDJSON.as_bytes();
+        let final_start = OLLAMA_NDJSON.rfind("{\"model\"").unwrap();
+        let split = final_start + 120;
+
+        let mut scanner = OllamaUsageScanner::default();
+        scanner.push(&bytes[..split]);
+        assert_eq!(scanner.usage, None);
+        scanner.push(&bytes[split..]);
+
+        assert_eq!(
+            scanner.finish(),
+            Some(OllamaUsage {
+                model: Some("llama3.2".to_string()),
+                prompt_tokens: 26,
+                completion_tokens: 282,
+                total_tokens: 308,
+            })
+        );
+        assert_eq!(scanner.finish(), None);
+    }
+
+    #[test]
+    fn test_non_streamed_response() {
+        let mut scanner = OllamaUsageScanner::default();
+        scanner.push(
+            br#"{"model":"mistral","response":"Hi","done":true,"context":[1,2,3],"prompt_eval_count":5,"eval_count":2}"#,
+        );
+
+        let usage = scanner.finish().unwrap();
+        assert_eq!(usage.model.as_deref(), Some("mistral"));
+        assert_eq!(usage.total_tokens, 7);
+    }
+
+    #[test]
+    fn test_incomplete_stream_has_no_usage() {
+        let mut scanner = OllamaUsageScanner::default();
+        scanner
+            .push(b"{\"model\":\"llama3.2\",\"message\":{\"content\":\"The\"},\"done\":false}\n");
+        assert_eq!(scanner.finish(), None);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use worker::*;
+
+use crate::{
+    cors, forward_upstream, guard_request, is_proxy_param, proxy_response_headers,
+    query_error_response, redact, reject_disallowed_upstream, upstream, upstream_auth_headers,
+    ProxyUrlParams, RequestMeta,
+};
+
+/// Appends every query parameter that isn't one of ours to the upstream URL.
+///
+/// Parameters are copied as raw `key=value` segments so their encoding is untouched.
+/// Segments already in the upstream query (re-attached from a raw `u`) are skipped.
+pub fn upstream_url_with_extra_params(upstream: &str, raw_query: Option<&str>) -> String {
+    let existing = upstream
+        .split_once('?')
+        .map(|(_, query)| query.split('&').collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let extra = raw_query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            !key.is_empty() && !is_proxy_param(key) && !existing.contains(pair)
+        })
+        .collect::<Vec<_>>();
+
+    if extra.is_empty() {
+        return upstream.to_string();
+    }
+
+    let separator = if upstream.contains('?') { '&' } else { '?' };
+    format!("{upstream}{separator}{}", extra.join("&"))
+}
+
+/// Forwards GET requests (model listing, file retrieval, ...) to the upstream as-is
+pub async fn get_passthrough(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_log!(
+        "GET passthrough: app={}, tenant={:?}",
+        xparams.app,
+        xparams.ten_id
+    );
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, None).await {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+
+    let proxy_headers =
+        match upstream_auth_headers(&req, &ctx.env, &meta, xparams.provider(&xparams.u)) {
+            Ok(headers) => headers,
+            Err(e) => return e.to_response(),
+        };
+
+    let url = req.url()?;
+    let proxy_url = upstream_url_with_extra_params(&xparams.u, url.query());
+    let (proxy_url, _) = upstream::with_api_version(&proxy_url, xparams.api_version.as_deref());
+
+    console_debug!("Proxy URL: {}", redact::url(&proxy_url));
+
+    let response = match upstream::http_client()
+        .get(proxy_url)
+        .headers(proxy_headers.into())
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let status = response.status().as_u16();
+    let origin = cors::request_origin(&req, &ctx.env);
+    let my_response_headers = proxy_response_headers(&response, origin.as_deref());
+    let rx = forward_upstream(&ctx.env, response, xparams.sends_error_events());
+
+    match Response::from_stream(rx) {
+        Ok(resp) => Ok(resp.with_status(status).with_headers(my_response_headers)),
+        Err(e) => {
+            console_error!("Error creating streaming response: {}", e);
+            Response::error("Internal Server Error!!!!!", 500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_extra_params() {
+        let url = upstream_url_with_extra_params(
+            "https://api.openai.com/v1/models",
+            Some("app=test&u=https%3A%2F%2Fapi.openai.com%2Fv1%2Fmodels&tenId=t1&meta.exp=v2"),
+        );
+        assert_eq!(url, "https://api.openai.com/v1/models");
+
+        let url = upstream_url_with_extra_params("https://api.openai.com/v1/models", None);
+        assert_eq!(url, "https://api.openai.com/v1/models");
+    }
+
+    #[test]
+    fn test_extra_params_appended_untouched() {
+        let url = upstream_url_with_extra_params(
+            "https://api.openai.com/v1/files",
+            Some("app=test&u=x&purpose=fine-tune&after=file%2Dabc&limit=10"),
+        );
+        assert_eq!(
+            url,
+            "https://api.openai.com/v1/files?purpose=fine-tune&after=file%2Dabc&limit=10"
+        );
+    }
+
+    #[test]
+    fn test_extra_params_with_existing_query() {
+        let url = upstream_url_with_extra_params(
+            "https://x.openai.azure.com/openai/models?api-version=2024-02-01",
+            Some("app=test&u=x&reqId=r1&limit=5"),
+        );
+        assert_eq!(
+            url,
+            "https://x.openai.azure.com/openai/models?api-version=2024-02-01&limit=5"
+        );
+    }
+
+    #[test]
+    fn test_reattached_params_not_duplicated() {
+        let url = upstream_url_with_extra_params(
+            "https://api.openai.com/v1/files?purpose=batch&limit=5",
+            Some("app=test&u=https://api.openai.com/v1/files?purpose=batch&limit=5&tenId=t1&after=f1"),
+        );
+        assert_eq!(
+            url,
+            "https://api.openai.com/v1/files?purpose=batch&limit=5&after=f1"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use worker::Url;
+
+use crate::targets::AuthHeader;
+
+/// The API an upstream speaks, which decides how its body is edited, which header carries the
+/// credential and how its usage is read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    /// OpenAI, and any OpenAI-compatible upstream on a host not known to be another provider's
+    OpenAi,
+    Azure,
+    Anthropic,
+    Gemini,
+    Cohere,
+    Mistral,
+}
+
+impl Provider {
+    /// Every provider, in the order the `provider` parameter's values are listed
+    pub const ALL: [Provider; 6] = [
+        Provider::OpenAi,
+        Provider::Azure,
+        Provider::Anthropic,
+        Provider::Gemini,
+        Provider::Cohere,
+        Provider::Mistral,
+    ];
+
+    /// The `provider` parameter value and analytics name
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::OpenAi => "openai",
+            Provider::Azure => "azure",
+            Provider::Anthropic => "anthropic",
+            Provider::Gemini => "gemini",
+            Provider::Cohere => "cohere",
+            Provider::Mistral => "mistral",
+        }
+    }
+
+    /// The provider a `provider` parameter names
+    pub fn from_name(name: &str) -> Option<Self> {
+        Provider::ALL
+            .into_iter()
+            .find(|provider| provider.name() == name)
+    }
+
+    /// The provider serving `url`, by its host; OpenAI for unknown hosts and unparsable URLs
+    pub fn from_url(url: &str) -> Self {
+        let url = Url::parse(url).ok();
+        let host = url.as_ref().and_then(Url::host_str).unwrap_or_default();
+        let on = |domain: &str| host == domain || host.ends_with(&format!(".{domain}"));
+
+        if on("openai.azure.com") {
+            Provider::Azure
+        } else if on("anthropic.com") {
+            Provider::Anthropic
+        } else if host == "generativelanguage.googleapis.com"
+            || host.ends_with("aiplatform.googleapis.com")
+        {
+            Provider::Gemini
+        } else if on("cohere.com") || on("cohere.ai") {
+            Provider::Cohere
+        } else if on("mistral.ai") {
+            Provider::Mistral
+        } else {
+            Provider::OpenAi
+        }
+    }
+
+    /// The header the provider expects the credential in; `None` takes it in the caller's, as
+    /// OpenAI-compatible gateways differ
+    pub fn auth_header(self) -> Option<AuthHeader> {
+        match self {
+            Provider::OpenAi => None,
+            Provider::Azure => Some(AuthHeader::ApiKey),
+            Provider::Anthropic => Some(AuthHeader::XApiKey),
+            Provider::Gemini => Some(AuthHeader::XGoogApiKey),
+            Provider::Cohere | Provider::Mistral => Some(AuthHeader::Authorization),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_url() {
+        let cases = [
+            (
+                "https://em-eastus.openai.azure.com/openai/deployments/gpt-4o/chat/completions",
+                Provider::Azure,
+            ),
+            (
+                "https://api.openai.com/v1/chat/completions",
+                Provider::OpenAi,
+            ),
+            ("https://API.anthropic.com/v1/messages", Provider::Anthropic),
+            (
+                "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash:\
+                 streamGenerateContent?alt=sse",
+                Provider::Gemini,
+            ),
+            (
+                "https://us-central1-aiplatform.googleapis.com/v1/projects/p/locations/\
+                 us-central1/publishers/google/models/gemini-2.5-flash:streamGenerateContent",
+                Provider::Gemini,
+            ),
+            ("https://api.cohere.com/v1/chat", Provider::Cohere),
+            (
+                "https://api.mistral.ai/v1/chat/completions",
+                Provider::Mistral,
+            ),
+            // Unknown hosts, and hosts only named like a provider's
+            (
+                "https://llm-gateway.example.com/v1/chat/completions",
+                Provider::OpenAi,
+            ),
+            (
+                "https://anthropic.com.example.com/v1/messages",
+                Provider::OpenAi,
+            ),
+            (
+                "https://openai.azure.com.example.com/openai",
+                Provider::OpenAi,
+            ),
+            ("not a url", Provider::OpenAi),
+        ];
+        for (url, provider) in cases {
+            assert_eq!(Provider::from_url(url), provider, "{url}");
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        for provider in Provider::ALL {
+            assert_eq!(Provider::from_name(provider.name()), Some(provider));
+        }
+        assert_eq!(Provider::from_name("Azure"), None);
+        assert_eq!(Provider::from_name("bedrock"), None);
+    }
+
+    #[test]
+    fn test_auth_header() {
+        assert_eq!(Provider::Azure.auth_header(), Some(AuthHeader::ApiKey));
+        assert_eq!(Provider::Anthropic.auth_header(), Some(AuthHeader::XApiKey));
+        assert_eq!(
+            Provider::Gemini.auth_header(),
+            Some(AuthHeader::XGoogApiKey)
+        );
+        assert_eq!(
+            Provider::Mistral.auth_header(),
+            Some(AuthHeader::Authorization)
+        );
+        assert_eq!(Provider::OpenAi.auth_header(), None);
+    }
+}
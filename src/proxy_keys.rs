@@ -0,0 +1,237 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use worker::*;
+
+use crate::{admin, kv_cache, RequestMeta};
+
+/// Header carrying the caller's proxy key
+pub const PROXY_KEY_HEADER: &str = "X-EM-Proxy-Key";
+/// KV namespace of proxy keys, stored under the hex SHA-256 of the key
+pub const API_KEYS_BINDING: &str = "API_KEYS";
+/// Set to `1`/`true` to require a proxy key bound to the claimed app and tenant
+pub const REQUIRE_PROXY_KEY_VAR: &str = "REQUIRE_PROXY_KEY";
+
+/// Prefix of minted keys, so leaked ones are easy to recognise
+const KEY_PREFIX: &str = "emk_";
+
+/// What a proxy key is bound to
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ProxyKey {
+    pub app: String,
+    /// Any tenant of the app when absent
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub created_at: u64,
+}
+
+impl ProxyKey {
+    /// Whether the key may be used for `app` and `tenant`
+    fn allows(&self, app: &str, tenant: Option<&str>) -> bool {
+        self.app == app
+            && self
+                .tenant_id
+                .as_deref()
+                .is_none_or(|bound| Some(bound) == tenant)
+    }
+}
+
+/// The hex SHA-256 of a raw key, the only form of it stored or logged
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub fn enabled(env: &Env) -> bool {
+    env.var(REQUIRE_PROXY_KEY_VAR)
+        .is_ok_and(|var| matches!(var.to_string().trim(), "1" | "true"))
+}
+
+fn error_response(status: u16, code: &str, message: &str) -> Result<Response> {
+    Ok(Response::from_json(&json!({
+        "error": true,
+        "type": if status == 401 { "Unauthorized" } else { "Forbidden" },
+        "code": code,
+        "message": message,
+    }))?
+    .with_status(status))
+}
+
+/// Checks the request's proxy key against the app and tenant it claims.
+///
+/// Returns the key's hash for analytics with what the key is bound to, or the response refusing
+/// the request.
+pub async fn check(
+    req: &Request,
+    env: &Env,
+    meta: &RequestMeta,
+) -> std::result::Result<(String, ProxyKey), Result<Response>> {
+    let key = req.headers().get(PROXY_KEY_HEADER).ok().flatten();
+    let Some(key) = key.filter(|key| !key.trim().is_empty()) else {
+        return Err(error_response(
+            401,
+            "proxy_key_missing",
+            "Missing X-EM-Proxy-Key header",
+        ));
+    };
+
+    let hash = hash_key(key.trim());
+    // Revocations reach isolates with a cached key within the cache TTL
+    let record = match kv_cache::get_text(env, API_KEYS_BINDING, &hash).await {
+        Ok(record) => record.and_then(|record| serde_json::from_str::<ProxyKey>(&record).ok()),
+        Err(e) => {
+            console_error!("API_KEYS lookup of {} failed: {}", hash, e);
+            return Err(Response::error("Internal Server Error", 500));
+        }
+    };
+    let Some(record) = record else {
+        console_error!("Unknown proxy key {}", hash);
+        return Err(error_response(
+            401,
+            "proxy_key_invalid",
+            "Unknown or revoked proxy key",
+        ));
+    };
+
+    let tenant = meta.tenant_id.as_deref();
+    if !record.allows(&meta.app_id, tenant) {
+        console_error!(
+            "Proxy key {} is bound to app={}, tenant={:?}, not app={}, tenant={:?}",
+            hash,
+            record.app,
+            record.tenant_id,
+            meta.app_id,
+            tenant
+        );
+        return Err(error_response(
+            403,
+            "proxy_key_mismatch",
+            "The proxy key is not valid for this app or tenant",
+        ));
+    }
+
+    Ok((hash, record))
+}
+
+#[derive(Debug, Deserialize)]
+struct MintRequest {
+    app: String,
+    #[serde(default)]
+    tenant_id: Option<String>,
+}
+
+fn bad_request(message: &str) -> Result<Response> {
+    Ok(Response::from_json(&json!({
+        "error": true,
+        "type": "Bad Request",
+        "message": message,
+    }))?
+    .with_status(400))
+}
+
+/// Mints a key for an app (and optionally a tenant); the raw key is only ever in this response
+pub async fn mint_key(mut req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if let Some(rejection) = admin::reject_non_admin(&req, &ctx.env) {
+        return rejection;
+    }
+
+    let mint = match req.json::<MintRequest>().await {
+        Ok(mint) => mint,
+        Err(e) => return bad_request(&format!("Invalid proxy key JSON: {e}")),
+    };
+    if mint.app.trim().is_empty() {
+        return bad_request("`app` must not be empty");
+    }
+
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| Error::from(e.to_string()))?;
+    let key = format!("{KEY_PREFIX}{}", hex::encode(secret));
+    let hash = hash_key(&key);
+
+    let record = ProxyKey {
+        app: mint.app,
+        tenant_id: mint.tenant_id.filter(|tenant| !tenant.is_empty()),
+        created_at: Date::now().as_millis(),
+    };
+    ctx.kv(API_KEYS_BINDING)?
+        .put(&hash, serde_json::to_string(&record)?)?
+        .execute()
+        .await?;
+    console_log!("Minted proxy key {} for app={}", hash, record.app);
+
+    Ok(Response::from_json(&json!({
+        "key": key,
+        "hash": hash,
+        "app": record.app,
+        "tenant_id": record.tenant_id,
+        "created_at": record.created_at,
+    }))?
+    .with_status(201))
+}
+
+/// Revokes a key by its hash
+pub async fn revoke_key(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if let Some(rejection) = admin::reject_non_admin(&req, &ctx.env) {
+        return rejection;
+    }
+
+    let hash = match ctx.param("hash") {
+        Some(hash) => hash.to_string(),
+        None => return Response::error("Bad Request", 400),
+    };
+
+    let keys = ctx.kv(API_KEYS_BINDING)?;
+    if keys.get(&hash).text().await?.is_none() {
+        return Response::error("Not found", 404);
+    }
+    keys.delete(&hash).await?;
+    console_log!("Revoked proxy key {}", hash);
+
+    Ok(Response::empty()?.with_status(204))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_key() {
+        assert_eq!(
+            hash_key("emk_test"),
+            "6987144f6f53bd13d2dce8aadbdbafd0b51bda11a53fc5546afdcdb3e3e43251"
+        );
+    }
+
+    #[test]
+    fn test_key_binding() {
+        let app_key = ProxyKey {
+            app: "pricing".to_string(),
+            tenant_id: None,
+            created_at: 0,
+        };
+        assert!(app_key.allows("pricing", Some("acme")));
+        assert!(app_key.allows("pricing", None));
+        assert!(!app_key.allows("search", Some("acme")));
+
+        let tenant_key = ProxyKey {
+            tenant_id: Some("acme".to_string()),
+            ..app_key
+        };
+        assert!(tenant_key.allows("pricing", Some("acme")));
+        assert!(!tenant_key.allows("pricing", Some("globex")));
+        assert!(!tenant_key.allows("pricing", None));
+    }
+
+    #[test]
+    fn test_key_record() {
+        let record: ProxyKey = serde_json::from_str(r#"{"app": "pricing"}"#).unwrap();
+        assert_eq!(record.tenant_id, None);
+        assert_eq!(record.created_at, 0);
+        assert!(serde_json::from_str::<ProxyKey>(r#"{"tenant_id": "acme"}"#).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::rc::Rc;
+
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use worker::wasm_bindgen::JsCast;
+use worker::*;
+
+use crate::{
+    guard_request, query_error_response, redact, reject_disallowed_upstream, targets::AuthError,
+    upstream_auth_headers, ProxyUrlParams, RequestMeta,
+};
+
+/// Browsers can't set headers on a WebSocket, so the key is sent as a subprotocol instead
+const API_KEY_PROTOCOL_PREFIX: &str = "openai-insecure-api-key.";
+/// Subprotocol echoed back to browser clients that offered it
+const REALTIME_PROTOCOL: &str = "realtime";
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct RealtimeUsage {
+    #[serde(default)]
+    pub input_tokens: u32,
+    #[serde(default)]
+    pub output_tokens: u32,
+    #[serde(default)]
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct RealtimeSession {
+    #[serde(default)]
+    model: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RealtimeResponse {
+    #[serde(default)]
+    usage: Option<RealtimeUsage>,
+}
+
+/// The server events needed for analytics; everything else is relayed without parsing further
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ServerEvent {
+    #[serde(rename = "session.created", alias = "session.updated")]
+    Session { session: RealtimeSession },
+    #[serde(rename = "response.done")]
+    ResponseDone { response: RealtimeResponse },
+    #[serde(other)]
+    Other,
+}
+
+/// Follows the server events of one Realtime session
+#[derive(Debug, Default)]
+pub struct RealtimeUsageTracker {
+    pub model: Option<String>,
+}
+
+impl RealtimeUsageTracker {
+    /// Inspects a text frame from the upstream, returning the usage of a finished response
+    pub fn observe(&mut self, text: &str) -> Option<RealtimeUsage> {
+        match serde_json::from_str::<ServerEvent>(text).ok()? {
+            ServerEvent::Session { session } => {
+                if session.model.is_some() {
+                    self.model = session.model;
+                }
+                None
+            }
+            ServerEvent::ResponseDone { response } => response.usage,
+            ServerEvent::Other => None,
+        }
+    }
+}
+
+/// Finds the API key a browser client passed in `Sec-WebSocket-Protocol`
+pub fn api_key_from_protocols(protocols: &str) -> Option<&str> {
+    protocols
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| protocol.strip_prefix(API_KEY_PROTOCOL_PREFIX))
+        .filter(|key| !key.is_empty())
+}
+
+/// Maps a received close code onto one that may be sent with `close()`.
+///
+/// 1005/1006/1015 are reserved for reporting and must never be sent on the wire.
+pub fn relayable_close_code(code: u16) -> u16 {
+    match code {
+        1000 | 3000..=4999 => code,
+        1001..=1014 if code != 1005 && code != 1006 => code,
+        _ => 1000,
+    }
+}
+
+/// Sends a message event's payload as-is; binary audio frames stay in their `ArrayBuffer`
+fn relay_message(event: &MessageEvent, to: &WebSocket) -> Result<()> {
+    let data = event.as_ref().data();
+    let socket: &worker_sys::web_sys::WebSocket = to.as_ref();
+
+    if let Some(text) = data.as_string() {
+        socket.send_with_str(&text)?;
+    } else if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+        socket.send_with_array_buffer(buffer)?;
+    } else if let Some(view) = data.dyn_ref::<js_sys::Uint8Array>() {
+        socket.send_with_js_u8_array(view)?;
+    }
+
+    Ok(())
+}
+
+enum Side {
+    Client,
+    Upstream,
+}
+
+/// Relays frames between the client and the upstream until either side closes
+async fn relay(
+    client: WebSocket,
+    upstream: WebSocket,
+    meta: RequestMeta,
+    model: String,
+    env: Env,
+    ctx: Rc<Context>,
+) {
+    let (client_events, upstream_events) = match (client.events(), upstream.events()) {
+        (Ok(client_events), Ok(upstream_events)) => (client_events, upstream_events),
+        _ => {
+            console_error!("Failed to listen on the realtime sockets");
+            let _ = client.close(Some(1011), Some("Upstream unavailable"));
+            let _ = upstream.close(Some(1011), None::<&str>);
+            return;
+        }
+    };
+
+    // Listeners are attached before accepting so no early frame is lost
+    if client.accept().is_err() || upstream.accept().is_err() {
+        console_error!("Failed to accept the realtime sockets");
+        return;
+    }
+
+    let mut events = stream::select(
+        client_events.map(|event| (Side::Client, event)),
+        upstream_events.map(|event| (Side::Upstream, event)),
+    );
+
+    let mut tracker = RealtimeUsageTracker::default();
+
+    while let Some((side, event)) = events.next().await {
+        let (from, to) = match side {
+            Side::Client => (&client, &upstream),
+            Side::Upstream => (&upstream, &client),
+        };
+
+        match event {
+            Ok(WebsocketEvent::Message(message)) => {
+                if let (Side::Upstream, Some(text)) = (&side, message.text()) {
+                    if let Some(usage) = tracker.observe(&text) {
+                        console_log!("REALTIME USAGE: {:?}", usage);
+
+                        let analytics = meta.usage_analytics(
+                            tracker.model.clone().unwrap_or_else(|| model.clone()),
+                            usage.input_tokens,
+                            usage.output_tokens,
+                            usage.total_tokens,
+                        );
+                        analytics.save_in_background(&env, &ctx);
+                    }
+                }
+
+                if let Err(e) = relay_message(&message, to) {
+                    console_error!("Failed to relay realtime frame: {}", e);
+                    let _ = from.close(Some(1011), Some("Relay failed"));
+                    let _ = to.close(Some(1011), Some("Relay failed"));
+                    break;
+                }
+            }
+            Ok(WebsocketEvent::Close(close)) => {
+                let _ = to.close(
+                    Some(relayable_close_code(close.code())),
+                    Some(close.reason()),
+                );
+                break;
+            }
+            Err(e) => {
+                console_error!("Realtime socket error: {}", e);
+                let _ = from.close(Some(1011), None::<&str>);
+                let _ = to.close(Some(1011), None::<&str>);
+                break;
+            }
+        }
+    }
+}
+
+/// Proxies a Realtime API WebSocket session and records the usage of every response
+pub async fn realtime_proxy(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    if !matches!(req.headers().get("upgrade"), Ok(Some(value)) if value.eq_ignore_ascii_case("websocket"))
+    {
+        return Response::error("Expected a WebSocket upgrade", 426);
+    }
+
+    let xparams = match ProxyUrlParams::from_request(&req) {
+        Ok(v) => v,
+        Err(e) => return query_error_response(e),
+    };
+
+    console_debug!("XParams: {xparams:?}");
+
+    let mut meta = RequestMeta::from_request(&req, &ctx.env, &xparams);
+    if let Some(rejection) = guard_request(&req, &ctx.env, &ctx.data, &mut meta, None).await {
+        return rejection;
+    }
+    if let Some(rejection) = reject_disallowed_upstream(&ctx.env, &ctx.data, &meta, &xparams.u) {
+        return rejection;
+    }
+    let protocols = req
+        .headers()
+        .get("sec-websocket-protocol")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    let provider = xparams.provider(&xparams.u);
+    let mut proxy_headers = match upstream_auth_headers(&req, &ctx.env, &meta, provider) {
+        Ok(headers) => headers,
+        Err(AuthError::MissingCredential) => match api_key_from_protocols(&protocols) {
+            Some(key) => {
+                let mut headers = Headers::new();
+                headers.set("authorization", &format!("Bearer {key}"))?;
+                headers
+            }
+            None => {
+                console_error!("Request Error: Missing authorization headers");
+                return Response::error("Internal Server Error!!!", 500);
+            }
+        },
+        Err(e) => return e.to_response(),
+    };
+    proxy_headers.set("upgrade", "websocket")?;
+    if let Ok(Some(beta)) = req.headers().get("openai-beta") {
+        proxy_headers.set("openai-beta", &beta)?;
+    }
+
+    // Workers open outbound WebSockets through fetch, which only speaks http(s)
+    let mut upstream_url = match Url::parse(&xparams.u) {
+        Ok(url) => url,
+        Err(e) => {
+            console_error!("Invalid upstream URL: {}", e);
+            return Response::error("Bad Request: invalid upstream URL", 400);
+        }
+    };
+    let scheme = match upstream_url.scheme() {
+        "ws" => "http",
+        "wss" => "https",
+        scheme => scheme,
+    }
+    .to_string();
+    let _ = upstream_url.set_scheme(&scheme);
+
+    let model = upstream_url
+        .query_pairs()
+        .find(|(key, _)| key == "model")
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    console_debug!("Proxy URL: {}", redact::url(upstream_url.as_str()));
+
+    let mut init = RequestInit::new();
+    init.with_headers(proxy_headers);
+    let upstream_request = Request::new_with_init(upstream_url.as_str(), &init)?;
+
+    let upstream = match Fetch::Request(upstream_request).send().await {
+        Ok(response) => match response.websocket() {
+            Some(upstream) => upstream,
+            None => {
+                console_error!("Upstream did not accept the WebSocket upgrade");
+                return Response::error("Bad Gateway", 502);
+            }
+        },
+        Err(e) => {
+            console_error!("Request Error: {}", e.to_string());
+            return Response::error("Internal Server Error!!!!", 500);
+        }
+    };
+
+    let pair = WebSocketPair::new()?;
+    wasm_bindgen_futures::spawn_local(relay(pair.server, upstream, meta, model, ctx.env, ctx.data));
+
+    let mut response_headers = Headers::new();
+    if protocols.split(',').any(|p| p.trim() == REALTIME_PROTOCOL) {
+        response_headers.set("sec-websocket-protocol", REALTIME_PROTOCOL)?;
+    }
+
+    Ok(Response::from_websocket(pair.client)?.with_headers(response_headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker_response_done() {
+        let mut tracker = RealtimeUsageTracker::default();
+
+        assert_eq!(
+            tracker.observe(
+                r#"{"type": "session.created", "event_id": "event_1", "session": {"id": "sess_1", "model": "gpt-4o-realtime-preview-2024-12-17", "modalities": ["text", "audio"]}}"#
+            ),
+            None
+        );
+        assert_eq!(
+            tracker.observe(r#"{"type": "response.audio.delta", "delta": "UklGRg=="}"#),
+            None
+        );
+
+        let usage = tracker.observe(
+            r#"{
+                "type": "response.done",
+                "event_id": "event_3",
+                "response": {
+                    "id": "resp_1",
+                    "status": "completed",
+                    "output": [],
+                    "usage": {
+                        "total_tokens": 275,
+                        "input_tokens": 127,
+                        "output_tokens": 148,
+                        "input_token_details": {"cached_tokens": 0, "text_tokens": 119, "audio_tokens": 8}
+                    }
+                }
+            }"#,
+        );
+
+        assert_eq!(
+            usage,
+            Some(RealtimeUsage {
+                input_tokens: 127,
+                output_tokens: 148,
+                total_tokens: 275,
+            })
+        );
+        assert_eq!(
+            tracker.model.as_deref(),
+            Some("gpt-4o-realtime-preview-2024-12-17")
+        );
+    }
+
+    #[test]
+    fn test_tracker_ignores_non_json() {
+        let mut tracker = RealtimeUsageTracker::default();
+        assert_eq!(tracker.observe("not json"), None);
+        assert_eq!(
+            tracker.observe(r#"{"type": "response.done", "response": {"status": "cancelled"}}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn test_api_key_from_protocols() {
+        assert_eq!(
+            api_key_from_protocols(
+                "realtime, openai-insecure-api-key.sk-abc, openai-beta.realtime-v1"
+            ),
+            Some("sk-abc")
+        );
+        assert_eq!(api_key_from_protocols("realtime"), None);
+        assert_eq!(api_key_from_protocols("openai-insecure-api-key."), None);
+    }
+
+    #[test]
+    fn test_relayable_close_code() {
+        assert_eq!(relayable_close_code(1000), 1000);
+        assert_eq!(relayable_close_code(1011), 1011);
+        assert_eq!(relayable_close_code(4001), 4001);
+        assert_eq!(relayable_close_code(1005), 1000);
+        assert_eq!(relayable_close_code(1006), 1000);
+        assert_eq!(relayable_close_code(1015), 1000);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::borrow::Cow;
+
+use serde_json::Value;
+use worker::Env;
+
+/// `full`, `redacted` (the default) or `off`: how much of request and response bodies is logged
+pub const LOG_BODIES_VAR: &str = "LOG_BODIES";
+
+/// Headers whose values are credentials
+const SENSITIVE_HEADERS: [&str; 8] = [
+    "api-key",
+    "authorization",
+    "proxy-authorization",
+    "x-api-key",
+    "x-goog-api-key",
+    "x-em-proxy-key",
+    "x-em-signature",
+    "cookie",
+];
+
+/// Query parameters whose values are credentials (Gemini's `key`, SAS `sig`, ...)
+const SENSITIVE_PARAMS: [&str; 6] = ["key", "api-key", "api_key", "sig", "token", "code"];
+
+/// Body fields holding prompt or completion text
+const CONTENT_FIELDS: [&str; 6] = [
+    "content",
+    "prompt",
+    "input",
+    "text",
+    "instructions",
+    "arguments",
+];
+
+/// How much of bodies goes to the logs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogBodies {
+    Full,
+    #[default]
+    Redacted,
+    Off,
+}
+
+impl LogBodies {
+    fn parse(value: Option<&str>) -> Self {
+        match value
+            .map(|value| value.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("full") => LogBodies::Full,
+            Some("off") => LogBodies::Off,
+            _ => LogBodies::Redacted,
+        }
+    }
+
+    pub fn from_env(env: &Env) -> Self {
+        let var = env.var(LOG_BODIES_VAR).ok().map(|var| var.to_string());
+        Self::parse(var.as_deref())
+    }
+
+    /// `body` as it may be logged; `None` when bodies aren't logged at all
+    pub fn body<'a>(&self, body: &'a str) -> Option<Cow<'a, str>> {
+        match self {
+            LogBodies::Full => Some(Cow::Borrowed(body)),
+            LogBodies::Redacted => Some(Cow::Owned(redact_body(body))),
+            LogBodies::Off => None,
+        }
+    }
+}
+
+/// Masks a credential down to its scheme and prefix plus the last 4 characters, `sk-***wxyz`
+pub fn mask_secret(value: &str) -> String {
+    let (scheme, secret) = match value.trim().split_once(' ') {
+        Some((scheme, secret)) => (format!("{scheme} "), secret.trim()),
+        None => (String::new(), value.trim()),
+    };
+
+    let chars = secret.chars().collect::<Vec<_>>();
+    // Too short to show any of it safely
+    if chars.len() < 12 {
+        return format!("{scheme}***");
+    }
+
+    let prefix = secret
+        .find(['-', '_'])
+        .filter(|end| *end < 6)
+        .map_or("", |end| &secret[..=end]);
+    let last4 = chars[chars.len() - 4..].iter().collect::<String>();
+    format!("{scheme}{prefix}***{last4}")
+}
+
+/// A header value as it may be logged
+pub fn header_value<'a>(name: &str, value: &'a str) -> Cow<'a, str> {
+    if SENSITIVE_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+        Cow::Owned(mask_secret(value))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// `name: value` lines for a set of headers, credentials masked
+pub fn headers(headers: &http::HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = value.to_str().unwrap_or("<binary>");
+            format!("{name}: {}", header_value(name.as_str(), value))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A URL as it may be logged, credentials in its query masked
+pub fn url(url: &str) -> Cow<'_, str> {
+    let Some((base, query)) = url.split_once('?') else {
+        return Cow::Borrowed(url);
+    };
+
+    let mut masked = false;
+    let pairs = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if SENSITIVE_PARAMS.contains(&key.to_ascii_lowercase().as_str()) => {
+                masked = true;
+                format!("{key}={}", mask_secret(value))
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    match masked {
+        true => Cow::Owned(format!("{base}?{}", pairs.join("&"))),
+        false => Cow::Borrowed(url),
+    }
+}
+
+/// Replaces prompt and completion text in a JSON value with its length
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(text) if CONTENT_FIELDS.contains(&key.as_str()) => {
+                        *value = Value::String(format!("[{} chars]", text.chars().count()));
+                    }
+                    _ => redact_value(value),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+/// Redacts one JSON document, or a line of one; anything else is reduced to its length
+fn redact_json(text: &str) -> String {
+    match serde_json::from_str::<Value>(text) {
+        Ok(mut value) => {
+            redact_value(&mut value);
+            value.to_string()
+        }
+        Err(_) if text.trim().is_empty() || text.trim() == "[DONE]" => text.to_string(),
+        Err(_) => format!("[{} chars]", text.chars().count()),
+    }
+}
+
+/// Redacts a JSON body or the `data:` lines of a server-sent event stream
+fn redact_body(body: &str) -> String {
+    if !body.trim_start().starts_with("data:") && !body.contains("\ndata:") {
+        return redact_json(body);
+    }
+
+    body.split('\n')
+        .map(|line| match line.strip_prefix("data:") {
+            Some(data) => format!("data: {}", redact_json(data.trim())),
+            None => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "sk-proj-4f8a9b2c7d1e6f3a5b8c9d0e1f2a3b4c";
+
+    #[test]
+    fn test_mask_secret() {
+        assert_eq!(mask_secret(KEY), "sk-***3b4c");
+        assert_eq!(mask_secret(&format!("Bearer {KEY}")), "Bearer sk-***3b4c");
+        assert_eq!(mask_secret("0123456789abcdef0123456789abcdef"), "***cdef");
+        assert_eq!(mask_secret("short"), "***");
+    }
+
+    #[test]
+    fn test_header_lines_never_contain_keys() {
+        let mut map = http::HeaderMap::new();
+        map.insert("api-key", KEY.parse().unwrap());
+        let bearer = format!("Bearer {KEY}");
+        map.insert("authorization", bearer.parse().unwrap());
+        map.insert(
+            "x-em-proxy-key",
+            "emk_9f86d081884c7d659a2feaa0c55ad015".parse().unwrap(),
+        );
+        map.insert("content-type", "application/json".parse().unwrap());
+        let lines = headers(&map);
+
+        assert!(!lines.contains(KEY), "{lines}");
+        assert!(
+            !lines.contains("emk_9f86d081884c7d659a2feaa0c55ad015"),
+            "{lines}"
+        );
+        assert_eq!(
+            lines,
+            "api-key: sk-***3b4c\nauthorization: Bearer sk-***3b4c\n\
+             x-em-proxy-key: emk_***d015\ncontent-type: application/json"
+        );
+    }
+
+    #[test]
+    fn test_url() {
+        let gemini = format!(
+            "https://generativelanguage.googleapis.com/v1/models/gemini:generateContent?key={KEY}&alt=sse"
+        );
+        let logged = url(&gemini);
+        assert!(!logged.contains(KEY), "{logged}");
+        assert!(logged.ends_with("?key=sk-***3b4c&alt=sse"), "{logged}");
+
+        let plain = "https://x.openai.azure.com/openai/deployments/gpt-4o?api-version=2024-10-21";
+        assert!(matches!(url(plain), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_log_bodies_mode() {
+        assert_eq!(LogBodies::parse(None), LogBodies::Redacted);
+        assert_eq!(LogBodies::parse(Some("FULL")), LogBodies::Full);
+        assert_eq!(LogBodies::parse(Some("off")), LogBodies::Off);
+        assert_eq!(LogBodies::parse(Some("verbose")), LogBodies::Redacted);
+
+        let body = r#"{"messages":[{"role":"user","content":"my card is 4111"}]}"#;
+        assert_eq!(LogBodies::Off.body(body), None);
+        assert_eq!(LogBodies::Full.body(body).as_deref(), Some(body));
+    }
+
+    #[test]
+    fn test_redacted_bodies() {
+        let body = r#"{"model":"gpt-4o","messages":[{"role":"user","content":"my card is 4111"}]}"#;
+        assert_eq!(
+            LogBodies::Redacted.body(body).unwrap(),
+            r#"{"model":"gpt-4o","messages":[{"role":"user","content":"[15 chars]"}]}"#
+        );
+
+        let stream = "data: {\"choices\":[{\"delta\":{\"content\":\"Hello there\"}}]}\n\n\
+                      data: {\"choices\":[],\"usage\":{\"total_tokens\":9}}\n\ndata: [DONE]\n\n";
+        assert_eq!(
+            LogBodies::Redacted.body(stream).unwrap(),
+            "data: {\"choices\":[{\"delta\":{\"content\":\"[11 chars]\"}}]}\n\n\
+             data: {\"choices\":[],\"usage\":{\"total_tokens\":9}}\n\ndata: [DONE]\n\n"
+        );
+
+        // A partial chunk that doesn't parse is reduced to its length
+        assert_eq!(
+            LogBodies::Redacted
+                .body(r#"{"choices":[{"delta":{"content":"Hel"#)
+                .unwrap(),
+            "[36 chars]"
+        );
+    }
+}
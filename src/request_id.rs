@@ -0,0 +1,95 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use crate::provider::Provider;
+
+/// Response header echoing the request's id (`reqId`, or the one generated for it) to the client
+pub const RESPONSE_HEADER: &str = "X-LangProxy-Request-Id";
+
+/// Response headers carrying the upstream's own request id, in the order they're looked for
+const UPSTREAM_ID_HEADERS: [&str; 2] = ["x-request-id", "apim-request-id"];
+
+/// The request header `provider` logs a caller's request id under
+pub fn upstream_header(provider: Provider) -> &'static str {
+    match provider {
+        Provider::Azure => "x-ms-client-request-id",
+        _ => "x-request-id",
+    }
+}
+
+/// The upstream's id of the request, read off its response headers with `header`
+pub fn upstream_id(header: impl Fn(&str) -> Option<String>) -> Option<String> {
+    UPSTREAM_ID_HEADERS
+        .iter()
+        .filter_map(|name| header(name))
+        .map(|id| id.trim().to_string())
+        .find(|id| !id.is_empty())
+}
+
+/// A random (version 4) UUID, for requests the client sent without an id and for names that
+/// must never collide
+pub fn random_uuid() -> std::result::Result<String, String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| e.to_string())?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_header() {
+        assert_eq!(upstream_header(Provider::Azure), "x-ms-client-request-id");
+        assert_eq!(upstream_header(Provider::OpenAi), "x-request-id");
+        assert_eq!(upstream_header(Provider::Anthropic), "x-request-id");
+    }
+
+    #[test]
+    fn test_upstream_id() {
+        let headers = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(
+            upstream_id(headers(&[
+                ("apim-request-id", "apim-1"),
+                ("x-request-id", "req-1")
+            ])),
+            Some("req-1".to_string())
+        );
+        assert_eq!(
+            upstream_id(headers(&[
+                ("apim-request-id", "apim-1"),
+                ("x-request-id", " ")
+            ])),
+            Some("apim-1".to_string())
+        );
+        assert_eq!(upstream_id(headers(&[("x-ms-region", "eastus")])), None);
+    }
+
+    #[test]
+    fn test_random_uuid() {
+        let id = random_uuid().unwrap();
+        let groups = id.split('-').map(str::len).collect::<Vec<_>>();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+        assert_eq!(&id[14..15], "4");
+        assert_ne!(id, random_uuid().unwrap());
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde::Deserialize;
+
+use crate::sse::SseEvent;
+use crate::usage_extractor::{Extracted, ExtractedUsage, UsageExtractor};
+
+/// Events ending a Responses API stream with the response's usage; `response.incomplete` is
+/// sent instead of `response.completed` when `max_output_tokens` cut the response short
+const FINAL_EVENTS: [&str; 2] = ["response.completed", "response.incomplete"];
+
+#[derive(Debug, Default, Deserialize)]
+struct OutputTokensDetails {
+    #[serde(default)]
+    reasoning_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+    #[serde(default)]
+    total_tokens: Option<u32>,
+    #[serde(default)]
+    output_tokens_details: Option<OutputTokensDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseObject {
+    /// Cut to `MAX_MODEL_LEN` bytes like a chat completion's
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    usage: Option<ResponseUsage>,
+    #[serde(default)]
+    service_tier: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FinalEvent {
+    response: ResponseObject,
+}
+
+/// Token usage mapped onto the OpenAI-style columns used by `UsageAnalytics`
+#[derive(Debug, PartialEq)]
+pub struct ResponsesUsage {
+    pub model: Option<String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    pub reasoning_tokens: u32,
+    pub service_tier: Option<String>,
+}
+
+/// Reads the usage of a Responses API stream off its final `response.completed` event.
+///
+/// Every other `response.*` event is passed over on its name alone, without parsing its data.
+#[derive(Debug, Default)]
+pub struct ResponsesUsageExtractor {
+    done: bool,
+}
+
+impl ResponsesUsageExtractor {
+    /// Feeds a whole event, returning the usage on the final one
+    pub fn push(&mut self, event: &SseEvent) -> Option<ResponsesUsage> {
+        if self.done || !is_final(event) {
+            return None;
+        }
+        let response = serde_json::from_str::<FinalEvent>(&event.data)
+            .ok()?
+            .response;
+        let usage = response.usage?;
+
+        self.done = true;
+        let total = usage.input_tokens.saturating_add(usage.output_tokens);
+        Some(ResponsesUsage {
+            model: response
+                .model
+                .map(crate::truncate_model)
+                .filter(|model| !model.is_empty()),
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.total_tokens.unwrap_or(total),
+            reasoning_tokens: usage
+                .output_tokens_details
+                .map_or(0, |details| details.reasoning_tokens),
+            service_tier: response.service_tier,
+        })
+    }
+}
+
+impl From<ResponsesUsage> for ExtractedUsage {
+    fn from(usage: ResponsesUsage) -> Self {
+        ExtractedUsage {
+            model: usage.model,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            reasoning_tokens: usage.reasoning_tokens,
+            service_tier: usage.service_tier,
+            ..ExtractedUsage::default()
+        }
+    }
+}
+
+impl UsageExtractor for ResponsesUsageExtractor {
+    fn feed_event(&mut self, event: &SseEvent) -> Option<Extracted> {
+        self.push(event).map(|usage| Ok(usage.into()))
+    }
+}
+
+/// Whether `event` may be a final one: by its name, or for streams relayed without `event:`
+/// lines, by a final event's name showing up in its data
+fn is_final(event: &SseEvent) -> bool {
+    match event.event.as_deref() {
+        Some(name) => FINAL_EVENTS.contains(&name),
+        None => FINAL_EVENTS.iter().any(|name| event.data.contains(name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse::SseParser;
+
+    fn extract(stream: &[u8]) -> Vec<ResponsesUsage> {
+        let mut extractor = ResponsesUsageExtractor::default();
+        SseParser::default()
+            .push(stream)
+            .iter()
+            .filter_map(|event| extractor.push(event))
+            .collect()
+    }
+
+    #[test]
+    fn test_completed_fixture() {
+        let stream = include_bytes!("../fixtures/responses_stream.txt");
+        assert_eq!(
+            extract(stream),
+            vec![ResponsesUsage {
+                model: Some("o4-mini-2025-04-16".to_string()),
+                prompt_tokens: 31,
+                completion_tokens: 210,
+                total_tokens: 241,
+                reasoning_tokens: 192,
+                service_tier: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_other_events_skipped_unparsed() {
+        // Malformed data under any other name is never parsed
+        let stream = concat!(
+            "event: response.output_text.delta\n",
+            "data: {\"type\":\"response.completed\",\"response\":\n\n",
+            "event: response.created\n",
+            "data: {\"type\":\"response.created\",\"response\":{\"usage\":null}}\n\n",
+        );
+        assert!(extract(stream.as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn test_final_event_shapes() {
+        let model = format!("ft:gpt-4.1-mini-2025-04-14:everymundo:{}", "x".repeat(60));
+        // Without `event:` lines, a long model, a service tier and no `total_tokens`
+        let stream = format!(
+            concat!(
+                "data: {{\"type\":\"response.incomplete\",\"response\":{{\"model\":\"{}\",",
+                "\"service_tier\":\"flex\",\"status\":\"incomplete\",",
+                "\"usage\":{{\"input_tokens\":5,\"output_tokens\":7}}}}}}\n\n",
+                "data: {{\"type\":\"response.completed\",\"response\":{{\"usage\":",
+                "{{\"input_tokens\":1,\"output_tokens\":1}}}}}}\n\n",
+            ),
+            model
+        );
+        assert_eq!(
+            extract(stream.as_bytes()),
+            vec![ResponsesUsage {
+                model: Some(model),
+                prompt_tokens: 5,
+                completion_tokens: 7,
+                total_tokens: 12,
+                reasoning_tokens: 0,
+                service_tier: Some("flex".to_string()),
+            }]
+        );
+
+        // A runaway model is cut like a chat completion's
+        let stream = format!(
+            concat!(
+                "event: response.completed\n",
+                "data: {{\"type\":\"response.completed\",\"response\":{{\"model\":\"{}\",",
+                "\"usage\":{{\"input_tokens\":1,\"output_tokens\":1}}}}}}\n\n",
+            ),
+            "m".repeat(crate::MAX_MODEL_LEN + 40)
+        );
+        let usage = extract(stream.as_bytes());
+        assert_eq!(usage[0].model, Some("m".repeat(crate::MAX_MODEL_LEN)));
+    }
+}
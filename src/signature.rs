@@ -0,0 +1,245 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use worker::*;
+
+use crate::kv_cache;
+
+/// Header carrying `t=<unix seconds>,v1=<hex HMAC-SHA256 of "{t}.{body}">`
+pub const SIGNATURE_HEADER: &str = "X-EM-Signature";
+/// KV namespace of per-app signing secrets, keyed by app
+pub const SIGNING_SECRETS_BINDING: &str = "SIGNING_SECRETS";
+/// How old, in seconds, a signature may be before it's refused as a replay
+pub const SIGNATURE_WINDOW_VAR: &str = "SIGNATURE_WINDOW_SECS";
+const DEFAULT_SIGNATURE_WINDOW_SECS: u64 = 5 * 60;
+
+/// Why a signed request was refused
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Missing,
+    Malformed,
+    Expired,
+    Mismatch,
+}
+
+impl SignatureError {
+    fn code(&self) -> &'static str {
+        match self {
+            SignatureError::Missing => "signature_missing",
+            SignatureError::Malformed => "signature_malformed",
+            SignatureError::Expired => "signature_expired",
+            SignatureError::Mismatch => "signature_mismatch",
+        }
+    }
+}
+
+/// The signing secret of `app`: Worker secret `SIGNING_SECRET__{app}`, else its `SIGNING_SECRETS`
+/// entry. Apps without one aren't verified.
+pub async fn signing_secret(env: &Env, app: &str) -> Option<String> {
+    if let Ok(secret) = env.secret(&format!("SIGNING_SECRET__{app}")) {
+        return Some(secret.to_string());
+    }
+    if env.kv(SIGNING_SECRETS_BINDING).is_err() {
+        return None;
+    }
+
+    match kv_cache::get_text(env, SIGNING_SECRETS_BINDING, app).await {
+        Ok(secret) => secret.filter(|secret| !secret.is_empty()),
+        Err(e) => {
+            console_error!("SIGNING_SECRETS lookup of {} failed: {}", app, e);
+            None
+        }
+    }
+}
+
+/// Splits the header into its timestamp, as sent and parsed, and decoded `v1` signature
+fn parse_header(header: &str) -> std::result::Result<(&str, u64, Vec<u8>), SignatureError> {
+    let mut timestamp = None;
+    let mut signature = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse().ok().map(|parsed| (value, parsed)),
+            Some(("v1", value)) => signature = hex::decode(value).ok(),
+            _ => {}
+        }
+    }
+
+    let ((sent, timestamp), signature) =
+        timestamp.zip(signature).ok_or(SignatureError::Malformed)?;
+    Ok((sent, timestamp, signature))
+}
+
+/// Checks `header` against the raw `body`, as received
+fn verify(
+    secret: &[u8],
+    header: Option<&str>,
+    body: &[u8],
+    now: u64,
+    window: u64,
+) -> std::result::Result<(), SignatureError> {
+    let (sent, timestamp, signature) = parse_header(header.ok_or(SignatureError::Missing)?)?;
+    // Clocks drift both ways
+    if now.abs_diff(timestamp) > window {
+        return Err(SignatureError::Expired);
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|_| SignatureError::Mismatch)?;
+    // The timestamp as signed, not as it parsed
+    mac.update(sent.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    // Constant-time comparison
+    mac.verify_slice(&signature)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+fn signature_window(var: Option<&str>) -> u64 {
+    var.and_then(|var| var.trim().parse().ok())
+        .filter(|window| *window > 0)
+        .unwrap_or(DEFAULT_SIGNATURE_WINDOW_SECS)
+}
+
+/// Refuses a request whose `X-EM-Signature` doesn't match `body` with a 401
+pub fn reject_unsigned(
+    req: &Request,
+    env: &Env,
+    secret: &str,
+    body: &[u8],
+) -> Option<Result<Response>> {
+    let header = req.headers().get(SIGNATURE_HEADER).ok().flatten();
+    let window = env
+        .var(SIGNATURE_WINDOW_VAR)
+        .ok()
+        .map(|var| var.to_string());
+    let now = Date::now().as_millis() / 1000;
+
+    let error = verify(
+        secret.as_bytes(),
+        header.as_deref(),
+        body,
+        now,
+        signature_window(window.as_deref()),
+    )
+    .err()?;
+    console_error!("Rejected request signature: {}", error.code());
+
+    Some(
+        Response::from_json(&json!({
+            "error": true,
+            "type": "Invalid Signature",
+            "code": error.code(),
+        }))
+        .map(|response| response.with_status(401)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"whsec_test";
+    const BODY: &[u8] = br#"{"messages":[{"role":"user","content":"hi"}]}"#;
+
+    fn sign(timestamp: impl std::fmt::Display, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        format!(
+            "t={timestamp},v1={}",
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn test_valid_signature() {
+        let header = sign(1_700_000_000, BODY);
+        assert_eq!(
+            verify(SECRET, Some(&header), BODY, 1_700_000_100, 300),
+            Ok(())
+        );
+        // Parts in either order, with spaces
+        let (t, v1) = header.split_once(',').unwrap();
+        let swapped = format!("{v1}, {t}");
+        assert_eq!(
+            verify(SECRET, Some(&swapped), BODY, 1_700_000_000, 300),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_timestamp_as_sent() {
+        // Signed over the header's own digits, which parse to the same time as others
+        let header = sign("01700000000", BODY);
+        assert_eq!(
+            verify(SECRET, Some(&header), BODY, 1_700_000_000, 300),
+            Ok(())
+        );
+        let (_, v1) = header.split_once(',').unwrap();
+        assert_eq!(
+            verify(
+                SECRET,
+                Some(&format!("t=1700000000,{v1}")),
+                BODY,
+                1_700_000_000,
+                300
+            ),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let header = sign(1_700_000_000, BODY);
+        let now = 1_700_000_000;
+
+        assert_eq!(
+            verify(SECRET, Some(&header), b"{}", now, 300),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify(b"other", Some(&header), BODY, now, 300),
+            Err(SignatureError::Mismatch)
+        );
+        // Whitespace the proxy would trim still counts
+        let padded = [BODY, b"\n"].concat();
+        assert_eq!(
+            verify(SECRET, Some(&header), &padded, now, 300),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify(SECRET, None, BODY, now, 300),
+            Err(SignatureError::Missing)
+        );
+        assert_eq!(
+            verify(SECRET, Some("t=1700000000"), BODY, now, 300),
+            Err(SignatureError::Malformed)
+        );
+        assert_eq!(
+            verify(SECRET, Some("t=soon,v1=00"), BODY, now, 300),
+            Err(SignatureError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let header = sign(1_700_000_000, BODY);
+        assert_eq!(
+            verify(SECRET, Some(&header), BODY, 1_700_000_301, 300),
+            Err(SignatureError::Expired)
+        );
+        assert_eq!(
+            verify(SECRET, Some(&header), BODY, 1_699_999_000, 300),
+            Err(SignatureError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_signature_window() {
+        assert_eq!(signature_window(None), DEFAULT_SIGNATURE_WINDOW_SECS);
+        assert_eq!(signature_window(Some("60")), 60);
+        assert_eq!(signature_window(Some("0")), DEFAULT_SIGNATURE_WINDOW_SECS);
+    }
+}
@@ -0,0 +1,545 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+/// Upper bound for a single buffered line or event; larger events are skipped
+const MAX_EVENT_LEN: usize = 1024 * 1024;
+
+/// `data` payload that ends an OpenAI-style stream
+const DONE: &str = "[DONE]";
+
+/// Event the proxy ends an OpenAI-style stream with when the upstream didn't
+pub const DONE_EVENT: &[u8] = b"data: [DONE]\n\n";
+
+/// Whether a response's `content-type` is `text/event-stream`, with or without parameters
+pub fn is_event_stream(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        essence.eq_ignore_ascii_case("text/event-stream")
+    })
+}
+
+/// A complete server-sent event
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SseEvent {
+    /// The `event:` field; `None` for the default `message` type
+    pub event: Option<String>,
+    /// The event's `data:` lines, joined with `\n`
+    pub data: String,
+    /// The `id:` field
+    pub id: Option<String>,
+}
+
+impl SseEvent {
+    /// Whether this is the `data: [DONE]` end-of-stream marker
+    pub fn is_done(&self) -> bool {
+        self.data.trim() == DONE
+    }
+}
+
+/// Incrementally decodes UTF-8 split across chunks.
+///
+/// The incomplete sequence a chunk ends with is held back until the next chunk completes it;
+/// invalid bytes become U+FFFD.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Decodes a chunk, returning the text of every character it completes
+    pub fn push(&mut self, chunk: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.pending);
+        bytes.extend_from_slice(chunk);
+
+        let mut text = String::with_capacity(bytes.len());
+        let mut rest = bytes.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    return text;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(&String::from_utf8_lossy(valid));
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // The chunk ends mid-character
+                        None => {
+                            self.pending = invalid.to_vec();
+                            return text;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Incrementally parses a byte stream of server-sent events.
+///
+/// Lines may end in `\n`, `\r\n` or `\r` and may be split anywhere across chunks, even inside
+/// a UTF-8 sequence; an event is complete at the blank line that follows it. Comments and
+/// fields other than `event`, `data` and `id` are ignored, and events without data aren't
+/// returned.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    decoder: Utf8Decoder,
+    line: String,
+    /// The last chunk ended in `\r`, so a leading `\n` belongs to that line ending
+    after_cr: bool,
+    event: Option<String>,
+    data: Vec<String>,
+    id: Option<String>,
+    /// Bytes buffered for the current event
+    len: usize,
+    overflow: bool,
+}
+
+impl SseParser {
+    /// Feeds a network chunk, returning every event completed by it
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+
+        for char in self.decoder.push(chunk).chars() {
+            let after_cr = std::mem::take(&mut self.after_cr);
+            match char {
+                '\n' if after_cr => {}
+                '\r' | '\n' => {
+                    self.after_cr = char == '\r';
+                    if let Some(event) = self.end_line() {
+                        events.push(event);
+                    }
+                }
+                _ if self.line.len() < MAX_EVENT_LEN => self.line.push(char),
+                _ => self.overflow = true,
+            }
+        }
+
+        events
+    }
+
+    /// Whether the bytes pushed so far end between events, rather than inside a line or event
+    pub fn is_between_events(&self) -> bool {
+        self.line.is_empty() && self.len == 0 && self.decoder.pending.is_empty()
+    }
+
+    /// Processes the buffered line, returning the event a blank line completes
+    fn end_line(&mut self) -> Option<SseEvent> {
+        let line = std::mem::take(&mut self.line);
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        self.len += line.len();
+        if self.len > MAX_EVENT_LEN {
+            self.overflow = true;
+        }
+        if self.overflow {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            // A single space after the colon isn't part of the value
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_str(), ""),
+        };
+        match field {
+            "data" => self.data.push(value.to_string()),
+            "event" => self.event = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            // Comments (`: keep-alive`), `retry` and unknown fields
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = std::mem::take(&mut self.event);
+        let data = std::mem::take(&mut self.data);
+        let id = std::mem::take(&mut self.id);
+        self.len = 0;
+
+        if std::mem::take(&mut self.overflow) || data.is_empty() {
+            return None;
+        }
+        Some(SseEvent {
+            event,
+            data: data.join("\n"),
+            id,
+        })
+    }
+}
+
+/// Splits a byte stream into the raw bytes of each event, so events can be relayed or dropped
+/// whole while everything relayed stays byte-identical.
+///
+/// A frame is an event with its blank line (or a run of comments and blank lines). Oversized
+/// events are passed through in pieces, since the parser skips them anyway.
+#[derive(Debug)]
+pub struct SseFramer {
+    frame: Vec<u8>,
+    line_empty: bool,
+    /// The frame ended in a `\r` blank line, whose `\n` may still follow
+    after_blank_cr: bool,
+    after_cr: bool,
+}
+
+impl Default for SseFramer {
+    fn default() -> Self {
+        Self {
+            frame: Vec::new(),
+            line_empty: true,
+            after_blank_cr: false,
+            after_cr: false,
+        }
+    }
+}
+
+impl SseFramer {
+    /// Feeds a network chunk, returning every frame completed by it
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        for &byte in chunk {
+            if std::mem::take(&mut self.after_blank_cr) {
+                if byte == b'\n' {
+                    self.frame.push(byte);
+                    self.after_cr = false;
+                    frames.push(std::mem::take(&mut self.frame));
+                    continue;
+                }
+                frames.push(std::mem::take(&mut self.frame));
+            }
+
+            self.frame.push(byte);
+            let after_cr = std::mem::take(&mut self.after_cr);
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    if self.line_empty {
+                        if byte == b'\r' {
+                            self.after_blank_cr = true;
+                        } else {
+                            frames.push(std::mem::take(&mut self.frame));
+                        }
+                    }
+                    self.line_empty = true;
+                    self.after_cr = byte == b'\r';
+                }
+                _ => self.line_empty = false,
+            }
+
+            if self.frame.len() > MAX_EVENT_LEN {
+                frames.push(std::mem::take(&mut self.frame));
+            }
+        }
+
+        frames
+    }
+
+    /// The bytes of an event the stream ended in the middle of
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.after_blank_cr = false;
+        std::mem::take(&mut self.frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chat completion streamed by Azure OpenAI with `stream_options.include_usage`
+    const AZURE_STREAM: &str = include_str!("../fixtures/azure_chat_stream.txt");
+
+    fn parse_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut parser = SseParser::default();
+        chunks.iter().flat_map(|chunk| parser.push(chunk)).collect()
+    }
+
+    fn data(data: &str) -> SseEvent {
+        SseEvent {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_utf8_split_mid_character() {
+        let text = "Hi 👋 你好，世界 ¡olé! 🇪🇸";
+        let bytes = text.as_bytes();
+
+        for split in 0..=bytes.len() {
+            let mut decoder = Utf8Decoder::default();
+            let mut decoded = decoder.push(&bytes[..split]);
+            decoded.push_str(&decoder.push(&bytes[split..]));
+            assert_eq!(decoded, text, "split at {split}");
+        }
+
+        // One byte at a time, nothing is emitted until a character is complete
+        let mut decoder = Utf8Decoder::default();
+        let emoji = "👋".as_bytes();
+        assert_eq!(decoder.push(&emoji[..1]), "");
+        assert_eq!(decoder.push(&emoji[1..3]), "");
+        assert_eq!(decoder.push(&emoji[3..]), "👋");
+        let decoded = bytes
+            .chunks(1)
+            .map(|byte| decoder.push(byte))
+            .collect::<String>();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn test_utf8_invalid_bytes_replaced() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(b"a\xffb\xe4\xbd"), "a\u{fffd}b");
+        // The held-back bytes don't start a valid character
+        assert_eq!(decoder.push(b"c"), "\u{fffd}c");
+    }
+
+    #[test]
+    fn test_cjk_data_split_mid_character() {
+        let input = "data: {\"content\":\"你好，世界\"}\n\n".as_bytes();
+        for split in 0..=input.len() {
+            assert_eq!(
+                parse_all(&[&input[..split], &input[split..]]),
+                vec![data("{\"content\":\"你好，世界\"}")],
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_fields_and_boundaries() {
+        let events = parse_all(&[
+            b": keep-alive\n\nevent: delta\nid: 7\ndata: {\"a\":1}\n\ndata:{\"b\":2}\nretry: 10\n\n",
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("delta".to_string()),
+                    data: "{\"a\":1}".to_string(),
+                    id: Some("7".to_string()),
+                },
+                data("{\"b\":2}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiline_data() {
+        let events = parse_all(&[b"data: line one\ndata:  line two\ndata\n\n"]);
+        assert_eq!(events, vec![data("line one\n line two\n")]);
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let input = b"data: a\r\n\r\ndata: b\r\rdata: c\n\n";
+        assert_eq!(parse_all(&[input]), vec![data("a"), data("b"), data("c")]);
+        // `\r\n` split between chunks is still one line ending
+        for split in 1..input.len() {
+            assert_eq!(
+                parse_all(&[&input[..split], &input[split..]]),
+                vec![data("a"), data("b"), data("c")],
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_mixed_line_endings_in_one_stream() {
+        // Every terminator style, within events as well as between them, and no `\r` left over
+        let input = b": hi\r\nevent: a\rdata: {\"x\":1}\r\n\ndata: 2\ndata: 3\r\r\ndata: 4\r\n\r\n";
+        let expected = vec![
+            SseEvent {
+                event: Some("a".to_string()),
+                data: "{\"x\":1}".to_string(),
+                id: None,
+            },
+            data("2\n3"),
+            data("4"),
+        ];
+        assert_eq!(parse_all(&[input]), expected);
+        assert_eq!(parse_all(&input.chunks(1).collect::<Vec<_>>()), expected);
+
+        let crlf = AZURE_STREAM.replace('\n', "\r\n");
+        let cr = AZURE_STREAM.replace('\n', "\r");
+        let expected = parse_all(&[AZURE_STREAM.as_bytes()]);
+        for stream in [crlf, cr] {
+            for split in 0..=stream.len() {
+                let input = stream.as_bytes();
+                assert_eq!(
+                    parse_all(&[&input[..split], &input[split..]]),
+                    expected,
+                    "split at {split}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_incomplete_event_not_returned() {
+        let mut parser = SseParser::default();
+        assert_eq!(parser.push(b"data: {\"a\":1}\n"), vec![]);
+        assert_eq!(parser.push(b"\n"), vec![data("{\"a\":1}")]);
+        // Events without data are dropped
+        assert_eq!(parser.push(b"event: ping\n\n"), vec![]);
+    }
+
+    #[test]
+    fn test_done() {
+        let events = parse_all(&[b"data: {}\n\ndata: [DONE]\n\n"]);
+        assert!(!events[0].is_done());
+        assert!(events[1].is_done());
+        assert!(parse_all(&[DONE_EVENT])[0].is_done());
+    }
+
+    #[test]
+    fn test_between_events() {
+        let between = |input: &[u8]| {
+            let mut parser = SseParser::default();
+            parser.push(input);
+            parser.is_between_events()
+        };
+
+        assert!(between(b""));
+        assert!(between(b"data: {}\n\n"));
+        assert!(between(b"data: {}\r\n\r\n"));
+        assert!(!between(b"data: {"));
+        assert!(!between(b"data: {}\n"));
+        assert!(!between(b": keep-alive\n"));
+        assert!(!between("data: 👋".as_bytes().split_last().unwrap().1));
+    }
+
+    #[test]
+    fn test_is_event_stream() {
+        assert!(is_event_stream(Some("text/event-stream")));
+        assert!(is_event_stream(Some("text/event-stream; charset=utf-8")));
+        assert!(is_event_stream(Some(" Text/Event-Stream")));
+        assert!(!is_event_stream(Some("application/json")));
+        assert!(!is_event_stream(Some("text/event-streams")));
+        assert!(!is_event_stream(None));
+    }
+
+    #[test]
+    fn test_oversized_event_skipped() {
+        let mut input = b"data: ".to_vec();
+        input.extend(std::iter::repeat_n(b'a', MAX_EVENT_LEN));
+        input.extend(b"\n\ndata: ok\n\n");
+        assert_eq!(parse_all(&[&input]), vec![data("ok")]);
+
+        // Many lines adding up past the limit
+        let line = format!("data: {}\n", "a".repeat(1000));
+        let mut input = line.repeat(MAX_EVENT_LEN / 1000 + 1).into_bytes();
+        input.extend(b"\ndata: ok\n\n");
+        assert_eq!(parse_all(&[&input]), vec![data("ok")]);
+    }
+
+    fn frames(chunks: &[&[u8]]) -> Vec<String> {
+        let mut framer = SseFramer::default();
+        let mut frames = chunks
+            .iter()
+            .flat_map(|chunk| framer.push(chunk))
+            .collect::<Vec<_>>();
+        frames.push(framer.finish());
+        frames
+            .into_iter()
+            .map(|frame| String::from_utf8(frame).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_framer_splits_events() {
+        let input = b": hi\n\nevent: a\ndata: 1\n\ndata: 2\r\n\r\n\ndata: 3\r\rdata: 4";
+        let expected = vec![
+            ": hi\n\n",
+            "event: a\ndata: 1\n\n",
+            "data: 2\r\n\r\n",
+            "\n",
+            "data: 3\r\r",
+            "data: 4",
+        ];
+        assert_eq!(frames(&[input]), expected);
+
+        for split in 0..=input.len() {
+            assert_eq!(
+                frames(&[&input[..split], &input[split..]]),
+                expected,
+                "split at {split}"
+            );
+        }
+        let bytes = input.chunks(1).collect::<Vec<_>>();
+        assert_eq!(frames(&bytes), expected);
+    }
+
+    #[test]
+    fn test_framer_matches_parser() {
+        let input = AZURE_STREAM.as_bytes();
+        let frames = frames(&input.chunks(7).collect::<Vec<_>>());
+        assert_eq!(frames.concat(), AZURE_STREAM);
+        assert_eq!(frames.last().map(String::as_str), Some(""));
+
+        // Each frame parses as exactly one event
+        let events = frames[..frames.len() - 1]
+            .iter()
+            .map(|frame| parse_all(&[frame.as_bytes()]))
+            .collect::<Vec<_>>();
+        assert!(events.iter().all(|events| events.len() == 1));
+        assert_eq!(events.concat(), parse_all(&[input]));
+    }
+
+    #[test]
+    fn test_framer_passes_oversized_events_through() {
+        let mut input = b"data: ".to_vec();
+        input.extend(std::iter::repeat_n(b'a', MAX_EVENT_LEN + 10));
+        input.extend(b"\n\ndata: ok\n\n");
+
+        let frames = frames(&[&input]);
+        assert_eq!(frames.concat().as_bytes(), input);
+        assert_eq!(frames[0].len(), MAX_EVENT_LEN + 1);
+        assert_eq!(frames[frames.len() - 2], "data: ok\n\n");
+    }
+
+    #[test]
+    fn test_azure_fixture() {
+        let events = parse_all(&[AZURE_STREAM.as_bytes()]);
+        assert_eq!(events.len(), 9);
+        assert!(events[..8].iter().all(|event| !event.is_done()));
+        assert!(events[8].is_done());
+
+        let usage = serde_json::from_str::<serde_json::Value>(&events[7].data).unwrap();
+        assert_eq!(usage["usage"]["total_tokens"], 37);
+        assert_eq!(usage["choices"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_azure_fixture_adversarial_splits() {
+        let input = AZURE_STREAM.as_bytes();
+        let expected = parse_all(&[input]);
+
+        // Every single split point, including inside multi-byte characters
+        for split in 0..=input.len() {
+            assert_eq!(
+                parse_all(&[&input[..split], &input[split..]]),
+                expected,
+                "split at {split}"
+            );
+        }
+
+        // Three chunks, with the JSON of an event spread over all of them
+        for first in (0..input.len()).step_by(11) {
+            for second in (first..=input.len()).step_by(17) {
+                let chunks = [&input[..first], &input[first..second], &input[second..]];
+                assert_eq!(parse_all(&chunks), expected, "split at {first}, {second}");
+            }
+        }
+
+        // One byte at a time
+        let bytes = input.chunks(1).collect::<Vec<_>>();
+        assert_eq!(parse_all(&bytes), expected);
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use worker::*;
+
+use crate::kv_cache;
+
+/// KV namespace of centrally managed system prompts, under `prompt:{tenant}:{app}` or `prompt:{app}`
+pub const SYSTEM_PROMPTS_BINDING: &str = "SYSTEM_PROMPTS";
+
+/// Whether the deployment has a `SYSTEM_PROMPTS` namespace bound
+pub fn enabled(env: &Env) -> bool {
+    env.kv(SYSTEM_PROMPTS_BINDING).is_ok()
+}
+
+/// KV keys tried for an app, most specific first
+fn lookup_keys(tenant: Option<&str>, app: &str) -> Vec<String> {
+    tenant
+        .map(|tenant| format!("prompt:{tenant}:{app}"))
+        .into_iter()
+        .chain(std::iter::once(format!("prompt:{app}")))
+        .collect()
+}
+
+/// The system prompt to prepend to `app`'s conversations, if one is configured
+pub async fn resolve(env: &Env, tenant: Option<&str>, app: &str) -> Option<String> {
+    if !enabled(env) {
+        return None;
+    }
+
+    for key in lookup_keys(tenant, app) {
+        match kv_cache::get_text(env, SYSTEM_PROMPTS_BINDING, &key).await {
+            Ok(Some(prompt)) if !prompt.trim().is_empty() => return Some(prompt),
+            Ok(_) => {}
+            Err(e) => {
+                console_error!("SYSTEM_PROMPTS lookup of {} failed: {}", key, e);
+                return None;
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_keys() {
+        assert_eq!(
+            lookup_keys(Some("acme"), "pricing"),
+            vec![
+                "prompt:acme:pricing".to_string(),
+                "prompt:pricing".to_string()
+            ]
+        );
+        assert_eq!(
+            lookup_keys(None, "pricing"),
+            vec!["prompt:pricing".to_string()]
+        );
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde::Deserialize;
+use serde_json::json;
+use worker::*;
+
+use crate::{jwt, provider::Provider, redact};
+
+/// KV namespace mapping target names to upstream records
+pub const TARGETS_BINDING: &str = "TARGETS";
+
+/// Header carrying the upstream credential
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+pub enum AuthHeader {
+    #[default]
+    #[serde(rename = "api-key")]
+    ApiKey,
+    #[serde(rename = "authorization")]
+    Authorization,
+    /// Anthropic and compatible gateways
+    #[serde(rename = "x-api-key")]
+    XApiKey,
+    /// Gemini's API keys; only taken from the caller for an upstream expecting them
+    #[serde(rename = "x-goog-api-key")]
+    XGoogApiKey,
+}
+
+impl AuthHeader {
+    /// Caller headers a credential is taken from, in order of precedence
+    pub const PRECEDENCE: [AuthHeader; 3] = [
+        AuthHeader::ApiKey,
+        AuthHeader::XApiKey,
+        AuthHeader::Authorization,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthHeader::ApiKey => "api-key",
+            AuthHeader::Authorization => "authorization",
+            AuthHeader::XApiKey => "x-api-key",
+            AuthHeader::XGoogApiKey => "x-goog-api-key",
+        }
+    }
+
+    /// Header value for a bare key
+    fn value(self, key: &str) -> String {
+        match self {
+            AuthHeader::ApiKey | AuthHeader::XApiKey | AuthHeader::XGoogApiKey => key.to_string(),
+            AuthHeader::Authorization => format!("Bearer {key}"),
+        }
+    }
+
+    /// The bare key of a value of this header
+    fn key(self, value: &str) -> &str {
+        match self {
+            AuthHeader::ApiKey | AuthHeader::XApiKey | AuthHeader::XGoogApiKey => value,
+            AuthHeader::Authorization => value.strip_prefix("Bearer ").unwrap_or(value),
+        }
+    }
+}
+
+/// The caller's credential: the first of `AuthHeader::PRECEDENCE` that `header` finds
+fn caller_credential(header: impl Fn(&str) -> Option<String>) -> Option<(AuthHeader, String)> {
+    AuthHeader::PRECEDENCE
+        .into_iter()
+        .find_map(|auth_header| Some((auth_header, header(auth_header.name())?)))
+}
+
+/// The header the upstream expects the credential in: the target's, else the provider's
+fn expected_header(target: Option<&Target>, provider: Provider) -> Option<AuthHeader> {
+    match target {
+        Some(target) => Some(target.auth_header),
+        None => provider.auth_header(),
+    }
+}
+
+/// The header a caller's own credential is forwarded in.
+///
+/// `api-key` keys and bearer tokens (possibly Entra ID tokens rather than keys) go out as sent,
+/// unless the upstream only takes `x-api-key`; `x-api-key` keys go where the upstream expects.
+fn forwarded_header(caller: AuthHeader, expected: Option<AuthHeader>) -> AuthHeader {
+    match (caller, expected) {
+        (AuthHeader::XApiKey, Some(expected)) => expected,
+        (_, Some(AuthHeader::XApiKey)) => AuthHeader::XApiKey,
+        (caller, _) => caller,
+    }
+}
+
+/// Upstream record stored in `TARGETS` under a short name such as `gpt4o-eastus`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Target {
+    pub url: String,
+    #[serde(default)]
+    pub auth_header: AuthHeader,
+    /// Worker secret holding the upstream key; the caller's credential is forwarded when absent
+    /// (unless `INJECT_UPSTREAM_KEY` is set)
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+/// Set to `1`/`true` to inject the tenant's upstream key (`UPSTREAM_KEY__{tenant}`) in place of
+/// the caller's credential, for the tenant the caller's proxy key or token is bound to
+pub const INJECT_UPSTREAM_KEY_VAR: &str = "INJECT_UPSTREAM_KEY";
+
+/// Why no upstream auth header could be built
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// The caller sent none of `api-key`, `x-api-key` and `authorization`
+    MissingCredential,
+    /// The Worker secret that should hold the upstream key isn't configured
+    KeyMissing(String),
+    /// The upstream key is the proxy's, but the caller has no validated proxy key or token
+    Unauthenticated,
+    /// The tenant's upstream key was asked for, but the caller's token or proxy key isn't bound
+    /// to a tenant
+    TenantUnverified,
+}
+
+impl AuthError {
+    pub fn to_response(&self) -> Result<Response> {
+        match self {
+            AuthError::MissingCredential => {
+                console_error!("Request Error: Missing authorization headers");
+                Response::error("Internal Server Error!!!", 500)
+            }
+            // Only the secret's name is logged; it's not returned to the caller
+            AuthError::KeyMissing(secret) => {
+                console_error!("Upstream key secret {} is not configured", secret);
+                Ok(Response::from_json(&json!({
+                    "error": true,
+                    "type": "Upstream Key Missing",
+                    "code": "upstream_key_missing",
+                    "message": "The upstream key for this request is not configured",
+                }))?
+                .with_status(500))
+            }
+            AuthError::Unauthenticated => {
+                console_error!("Refused to inject an upstream key for an unauthenticated caller");
+                Ok(Response::from_json(&json!({
+                    "error": true,
+                    "type": "Unauthorized",
+                    "code": "authentication_required",
+                    "message": "A proxy key or end-user token is required for this upstream",
+                }))?
+                .with_status(401))
+            }
+            AuthError::TenantUnverified => {
+                console_error!("Refused to inject a tenant's upstream key without a bound tenant");
+                Ok(Response::from_json(&json!({
+                    "error": true,
+                    "type": "Forbidden",
+                    "code": "tenant_unverified",
+                    "message": "The upstream key of a tenant needs a token or proxy key bound to it",
+                }))?
+                .with_status(403))
+            }
+        }
+    }
+}
+
+/// Worker secret holding the upstream key of a request; `None` forwards the caller's credential.
+///
+/// A secret is only ever used for an `authenticated` caller, since any value passes as the
+/// caller's credential, and a tenant's only for the `tenant` its token or proxy key is bound to,
+/// never the one the request names.
+fn key_secret(
+    target: Option<&Target>,
+    tenant: Option<&str>,
+    inject: bool,
+    authenticated: bool,
+) -> std::result::Result<Option<String>, AuthError> {
+    let target_secret = target.and_then(|target| target.secret.clone());
+    if target_secret.is_none() && !inject {
+        return Ok(None);
+    }
+    if !authenticated {
+        return Err(AuthError::Unauthenticated);
+    }
+    if let Some(secret) = target_secret {
+        return Ok(Some(secret));
+    }
+
+    match tenant {
+        Some(tenant) => Ok(Some(format!("UPSTREAM_KEY__{tenant}"))),
+        None => Err(AuthError::TenantUnverified),
+    }
+}
+
+/// The upstream auth header, logged with its value masked
+fn auth_headers(
+    caller: AuthHeader,
+    auth_header: AuthHeader,
+    value: &str,
+) -> std::result::Result<Headers, AuthError> {
+    console_log!(
+        "Upstream auth: caller sent {}, forwarding {}: {}",
+        caller.name(),
+        auth_header.name(),
+        redact::header_value(auth_header.name(), value)
+    );
+
+    let mut headers = Headers::new();
+    headers
+        .set(auth_header.name(), value)
+        .map_err(|_| AuthError::MissingCredential)?;
+    Ok(headers)
+}
+
+/// Builds the upstream auth header of a request to an upstream of `provider`.
+///
+/// The caller must present a credential even when the upstream key comes from a Worker secret
+/// (the target's, or the tenant's under `INJECT_UPSTREAM_KEY`), and the secret is only used once
+/// `guard_request` has `authenticated` the caller, so the proxy isn't open. `tenant` is the one
+/// `guard_request` verified, not the one the request names.
+pub fn upstream_auth(
+    req: &Request,
+    env: &Env,
+    target: Option<&Target>,
+    tenant: Option<&str>,
+    provider: Provider,
+    authenticated: bool,
+) -> std::result::Result<Headers, AuthError> {
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    let expected = expected_header(target, provider);
+    // Callers may instead send only the header the upstream expects, like Gemini's
+    let (caller, value) = caller_credential(header)
+        .or_else(|| expected.and_then(|expected| Some((expected, header(expected.name())?))))
+        .ok_or(AuthError::MissingCredential)?;
+
+    // With end-user tokens, `authorization` is the user's and never goes upstream
+    let inject = jwt::enabled(env)
+        || env
+            .var(INJECT_UPSTREAM_KEY_VAR)
+            .is_ok_and(|var| matches!(var.to_string().trim(), "1" | "true"));
+    let Some(secret) = key_secret(target, tenant, inject, authenticated)? else {
+        // Callers may still send the header the upstream expects alongside the other one
+        if let Some(expected) = expected {
+            if let Some(value) = header(expected.name()) {
+                return auth_headers(expected, expected, &value);
+            }
+        }
+        let forwarded = forwarded_header(caller, expected);
+        let value = match forwarded == caller {
+            true => value,
+            false => forwarded.value(caller.key(&value)),
+        };
+        return auth_headers(caller, forwarded, &value);
+    };
+
+    let key = env
+        .secret(&secret)
+        .map_err(|_| AuthError::KeyMissing(secret))?
+        .to_string();
+    // Without a target or a known provider, the key goes in the header the caller used
+    let auth_header = expected.unwrap_or(caller);
+    auth_headers(caller, auth_header, &auth_header.value(&key))
+}
+
+/// Looks a target up by name
+pub async fn resolve(env: &Env, name: &str) -> Result<Option<Target>> {
+    Ok(env.kv(TARGETS_BINDING)?.get(name).json::<Target>().await?)
+}
+
+pub fn unknown_target_response(name: &str) -> Result<Response> {
+    Ok(Response::from_json(&json!({
+        "error": true,
+        "type": "Unknown Target",
+        "message": format!("No upstream target named `{name}`"),
+        "target": name,
+    }))?
+    .with_status(404))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_record() {
+        let target: Target = serde_json::from_str(
+            r#"{
+                "url": "https://em-eastus.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21",
+                "auth_header": "api-key",
+                "secret": "AZURE_EASTUS_KEY"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(target.auth_header, AuthHeader::ApiKey);
+        assert_eq!(target.secret.as_deref(), Some("AZURE_EASTUS_KEY"));
+    }
+
+    #[test]
+    fn test_target_defaults() {
+        let target: Target =
+            serde_json::from_str(r#"{"url": "https://api.openai.com/v1/chat/completions"}"#)
+                .unwrap();
+        assert_eq!(target.auth_header, AuthHeader::ApiKey);
+        assert_eq!(target.secret, None);
+
+        assert!(
+            serde_json::from_str::<Target>(r#"{"url": "x", "auth_header": "cookie"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_auth_header_value() {
+        assert_eq!(AuthHeader::ApiKey.value("k1"), "k1");
+        assert_eq!(AuthHeader::XApiKey.value("k1"), "k1");
+        assert_eq!(AuthHeader::Authorization.value("k1"), "Bearer k1");
+        assert_eq!(AuthHeader::Authorization.key("Bearer k1"), "k1");
+        assert_eq!(AuthHeader::XApiKey.key("k1"), "k1");
+        assert_eq!(AuthHeader::XGoogApiKey.value("k1"), "k1");
+    }
+
+    #[test]
+    fn test_caller_credential_precedence() {
+        let credential = |sent: &[(&str, &str)]| {
+            let sent = sent
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<std::collections::HashMap<_, _>>();
+            caller_credential(|name| sent.get(name).cloned())
+        };
+
+        let all = [
+            ("authorization", "Bearer k3"),
+            ("x-api-key", "k2"),
+            ("api-key", "k1"),
+        ];
+        assert_eq!(
+            credential(&all),
+            Some((AuthHeader::ApiKey, "k1".to_string()))
+        );
+        assert_eq!(
+            credential(&all[..2]),
+            Some((AuthHeader::XApiKey, "k2".to_string()))
+        );
+        assert_eq!(
+            credential(&all[..1]),
+            Some((AuthHeader::Authorization, "Bearer k3".to_string()))
+        );
+        assert_eq!(credential(&[("cookie", "k4")]), None);
+    }
+
+    #[test]
+    fn test_expected_header() {
+        let target = Target {
+            url: "https://gateway.example.com/v1/messages".to_string(),
+            auth_header: AuthHeader::XApiKey,
+            secret: None,
+        };
+        assert_eq!(
+            expected_header(Some(&target), Provider::Azure),
+            Some(AuthHeader::XApiKey)
+        );
+        assert_eq!(
+            expected_header(None, Provider::Anthropic),
+            Some(AuthHeader::XApiKey)
+        );
+        assert_eq!(
+            expected_header(None, Provider::Azure),
+            Some(AuthHeader::ApiKey)
+        );
+        assert_eq!(expected_header(None, Provider::OpenAi), None);
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        use AuthHeader::*;
+
+        // x-api-key keys are renamed for the upstream
+        assert_eq!(forwarded_header(XApiKey, Some(ApiKey)), ApiKey);
+        assert_eq!(
+            forwarded_header(XApiKey, Some(Authorization)),
+            Authorization
+        );
+        assert_eq!(forwarded_header(XApiKey, None), XApiKey);
+        // Other credentials only for x-api-key upstreams
+        assert_eq!(forwarded_header(Authorization, Some(XApiKey)), XApiKey);
+        assert_eq!(forwarded_header(ApiKey, Some(XApiKey)), XApiKey);
+        assert_eq!(forwarded_header(Authorization, Some(ApiKey)), Authorization);
+        assert_eq!(forwarded_header(ApiKey, None), ApiKey);
+        assert_eq!(forwarded_header(XApiKey, Some(XGoogApiKey)), XGoogApiKey);
+        assert_eq!(
+            forwarded_header(Authorization, Some(XGoogApiKey)),
+            Authorization
+        );
+    }
+
+    #[test]
+    fn test_key_secret() {
+        let target = |secret: Option<&str>| Target {
+            url: "https://em-eastus.openai.azure.com".to_string(),
+            auth_header: AuthHeader::ApiKey,
+            secret: secret.map(str::to_string),
+        };
+        let with_secret = target(Some("AZURE_EASTUS_KEY"));
+        let without_secret = target(None);
+
+        // The target's secret wins, flag or not
+        assert_eq!(
+            key_secret(Some(&with_secret), Some("acme"), false, true),
+            Ok(Some("AZURE_EASTUS_KEY".to_string()))
+        );
+        assert_eq!(
+            key_secret(Some(&with_secret), Some("acme"), true, true),
+            Ok(Some("AZURE_EASTUS_KEY".to_string()))
+        );
+
+        assert_eq!(
+            key_secret(Some(&without_secret), Some("acme"), false, true),
+            Ok(None)
+        );
+        assert_eq!(key_secret(None, Some("acme"), false, true), Ok(None));
+        assert_eq!(
+            key_secret(Some(&without_secret), Some("acme"), true, true),
+            Ok(Some("UPSTREAM_KEY__acme".to_string()))
+        );
+        assert_eq!(
+            key_secret(None, Some("acme"), true, true),
+            Ok(Some("UPSTREAM_KEY__acme".to_string()))
+        );
+        // Injection can't fall back to the caller's key, nor pick a tenant the caller wasn't
+        // verified for
+        assert_eq!(
+            key_secret(None, None, true, true),
+            Err(AuthError::TenantUnverified)
+        );
+
+        // Nor can a caller that was never authenticated get a key of ours
+        assert_eq!(
+            key_secret(Some(&with_secret), Some("acme"), false, false),
+            Err(AuthError::Unauthenticated)
+        );
+        assert_eq!(
+            key_secret(None, Some("acme"), true, false),
+            Err(AuthError::Unauthenticated)
+        );
+        assert_eq!(
+            key_secret(Some(&without_secret), Some("acme"), false, false),
+            Ok(None)
+        );
+    }
+}
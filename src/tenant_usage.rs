@@ -0,0 +1,371 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use worker::wasm_bindgen::{self, JsValue};
+use worker::*;
+
+use crate::analytics::UsageAnalytics;
+use crate::usage::{self, DailyUsage};
+
+/// Binding of the `TenantUsage` Durable Object namespace, when configured
+pub const TENANT_USAGE_BINDING: &str = "TENANT_USAGE";
+
+/// Days of daily totals kept, enough for the longest range `GET /usage/:tenantId` serves
+const DAYS_KEPT: i64 = usage::MAX_RANGE_DAYS;
+/// Months of monthly totals kept: the current one and the twelve before it
+const MONTHS_KEPT: i64 = 13;
+/// Storage key of the object's `UsageTotals`
+const TOTALS_KEY: &str = "totals";
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// The tokens of one usage event, as `add` takes them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageIncrement {
+    pub timestamp: f64,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl From<&UsageAnalytics> for UsageIncrement {
+    fn from(event: &UsageAnalytics) -> Self {
+        UsageIncrement {
+            timestamp: event.timestamp,
+            prompt_tokens: event.prompt_tokens,
+            completion_tokens: event.completion_tokens,
+            total_tokens: event.total_tokens,
+        }
+    }
+}
+
+/// What `get` reads: a day (`yyyy-mm-dd`), a month (`yyyy-mm`) or an inclusive range of days
+/// (`yyyy-mm-dd..yyyy-mm-dd`)
+#[derive(Debug, PartialEq)]
+pub enum Period {
+    Day(i64),
+    Month(String),
+    Days(i64, i64),
+}
+
+impl Period {
+    pub fn parse(period: &str) -> Option<Self> {
+        if let Some((from, to)) = period.split_once("..") {
+            let (from, to) = (usage::parse_date(from)?, usage::parse_date(to)?);
+            return (from <= to).then_some(Period::Days(from, to));
+        }
+        if period.len() == 7 {
+            usage::parse_date(&format!("{period}-01"))?;
+            return Some(Period::Month(period.to_string()));
+        }
+        usage::parse_date(period).map(Period::Day)
+    }
+}
+
+/// A tenant's rolling daily and monthly totals, as the object stores them
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    #[serde(default)]
+    days: BTreeMap<String, DailyUsage>,
+    #[serde(default)]
+    months: BTreeMap<String, DailyUsage>,
+}
+
+impl UsageTotals {
+    /// Counts `usage` in the totals of its day and month
+    pub fn add(&mut self, usage: &UsageIncrement) {
+        let date = usage::format_date(usage::days_from_millis(usage.timestamp));
+        let month = date[..7].to_string();
+        for (totals, key) in [(&mut self.days, date), (&mut self.months, month)] {
+            totals
+                .entry(key.clone())
+                .or_insert_with(|| DailyUsage {
+                    date: key,
+                    ..DailyUsage::default()
+                })
+                .record(
+                    usage.prompt_tokens,
+                    usage.completion_tokens,
+                    usage.total_tokens,
+                );
+        }
+    }
+
+    /// The totals of `period`, a day at a time for days and as one total for a month; periods
+    /// without usage read as zero
+    pub fn get(&self, period: &Period) -> Vec<DailyUsage> {
+        let total = |totals: &BTreeMap<String, DailyUsage>, key: String| {
+            totals.get(&key).cloned().unwrap_or(DailyUsage {
+                date: key,
+                ..DailyUsage::default()
+            })
+        };
+        match period {
+            Period::Day(day) => vec![total(&self.days, usage::format_date(*day))],
+            Period::Days(from, to) => (*from.max(&(to - (DAYS_KEPT - 1)))..=*to)
+                .map(|day| total(&self.days, usage::format_date(day)))
+                .collect(),
+            Period::Month(month) => vec![total(&self.months, month.clone())],
+        }
+    }
+
+    /// Drops the days and months that have rolled out of what is kept as of `today`
+    pub fn roll_over(&mut self, today: i64) {
+        let first_day = usage::format_date(today - (DAYS_KEPT - 1));
+        self.days.retain(|date, _| *date >= first_day);
+
+        let month = &usage::format_date(today)[..7];
+        let (year, month) = (month[..4].parse::<i64>(), month[5..].parse::<i64>());
+        if let (Ok(year), Ok(month)) = (year, month) {
+            let first = year * 12 + (month - 1) - (MONTHS_KEPT - 1);
+            let first_month = format!("{:04}-{:02}", first.div_euclid(12), first % 12 + 1);
+            self.months.retain(|month, _| *month >= first_month);
+        }
+    }
+}
+
+/// Time from `now` (ms) to the next UTC midnight, when daily buckets roll over
+fn until_next_day(now: f64) -> Duration {
+    Duration::from_millis((MILLIS_PER_DAY - now.rem_euclid(MILLIS_PER_DAY)) as u64)
+}
+
+/// Exact usage of one tenant, counted one event at a time; unlike the KV aggregates it loses
+/// nothing to concurrent writers, for tenants on hard quotas
+#[durable_object]
+pub struct TenantUsage {
+    state: State,
+    /// Loaded on first use
+    totals: Option<UsageTotals>,
+}
+
+impl TenantUsage {
+    async fn totals(&mut self) -> Result<&mut UsageTotals> {
+        if self.totals.is_none() {
+            let stored = self
+                .state
+                .storage()
+                .get_multiple(vec![TOTALS_KEY])
+                .await?
+                .get(&JsValue::from_str(TOTALS_KEY))
+                .as_string();
+            let totals = match stored {
+                Some(json) => serde_json::from_str(&json)?,
+                None => UsageTotals::default(),
+            };
+            self.totals = Some(totals);
+        }
+        Ok(self.totals.get_or_insert_with(UsageTotals::default))
+    }
+
+    async fn persist(&mut self) -> Result<()> {
+        let json = serde_json::to_string(self.totals().await?)?;
+        self.state.storage().put_raw(TOTALS_KEY, json).await
+    }
+
+    async fn add(&mut self, usage: &UsageIncrement) -> Result<()> {
+        self.totals().await?.add(usage);
+        self.persist().await?;
+        if self.state.storage().get_alarm().await?.is_none() {
+            let now = Date::now().as_millis() as f64;
+            self.state.storage().set_alarm(until_next_day(now)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[durable_object]
+impl DurableObject for TenantUsage {
+    fn new(state: State, _env: Env) -> Self {
+        TenantUsage {
+            state,
+            totals: None,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        match (req.method(), req.path().as_str()) {
+            (Method::Post, "/add") => {
+                let usage = req.json::<UsageIncrement>().await?;
+                self.add(&usage).await?;
+                Ok(Response::empty()?.with_status(204))
+            }
+            (Method::Get, "/get") => {
+                let url = req.url()?;
+                let period = url
+                    .query_pairs()
+                    .find(|(key, _)| key == "period")
+                    .and_then(|(_, period)| Period::parse(&period));
+                match period {
+                    Some(period) => Response::from_json(&self.totals().await?.get(&period)),
+                    None => Response::error("`period` must be a day, a month or a range", 400),
+                }
+            }
+            _ => Response::error("Not Found", 404),
+        }
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        let now = Date::now().as_millis() as f64;
+        self.totals().await?.roll_over(usage::days_from_millis(now));
+        self.persist().await?;
+        self.state.storage().set_alarm(until_next_day(now)).await?;
+        Response::ok("")
+    }
+}
+
+/// The tenant's object, when the `TENANT_USAGE` binding exists
+fn stub(env: &Env, tenant: &str) -> Result<Option<Stub>> {
+    let Ok(namespace) = env.durable_object(TENANT_USAGE_BINDING) else {
+        return Ok(None);
+    };
+    namespace.id_from_name(tenant)?.get_stub().map(Some)
+}
+
+/// Counts `event` in its tenant's object; events without a tenant, or without the binding, are
+/// counted nowhere
+pub async fn add(env: &Env, event: &UsageAnalytics) -> Result<()> {
+    let Some(tenant) = event.tenant_id.as_deref() else {
+        return Ok(());
+    };
+    let Some(stub) = stub(env, tenant)? else {
+        return Ok(());
+    };
+
+    let body = serde_json::to_string(&UsageIncrement::from(event))?;
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(JsValue::from_str(&body)));
+    let response = stub
+        .fetch_with_request(Request::new_with_init("https://tenant-usage/add", &init)?)
+        .await?;
+    match response.status_code() {
+        200..=299 => Ok(()),
+        status => Err(Error::from(format!("TenantUsage add answered {status}"))),
+    }
+}
+
+/// The tenant's totals for `period` from its object; `None` without the binding
+pub async fn get(env: &Env, tenant: &str, period: &str) -> Result<Option<Vec<DailyUsage>>> {
+    let Some(stub) = stub(env, tenant)? else {
+        return Ok(None);
+    };
+    let url = format!("https://tenant-usage/get?period={period}");
+    let mut response = stub.fetch_with_str(&url).await?;
+    response.json().await.map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn increment(date: &str, tokens: u32) -> UsageIncrement {
+        UsageIncrement {
+            timestamp: usage::parse_date(date).unwrap() as f64 * MILLIS_PER_DAY + 3_600_000.0,
+            prompt_tokens: tokens,
+            completion_tokens: tokens / 2,
+            total_tokens: tokens + tokens / 2,
+        }
+    }
+
+    #[test]
+    fn test_period_parse() {
+        let day = |date| usage::parse_date(date).unwrap();
+        assert_eq!(
+            Period::parse("2025-06-30"),
+            Some(Period::Day(day("2025-06-30")))
+        );
+        assert_eq!(
+            Period::parse("2025-06"),
+            Some(Period::Month("2025-06".to_string()))
+        );
+        assert_eq!(
+            Period::parse("2025-06-01..2025-06-30"),
+            Some(Period::Days(day("2025-06-01"), day("2025-06-30")))
+        );
+        assert_eq!(Period::parse("2025-06-30..2025-06-01"), None);
+        assert_eq!(Period::parse("2025-13"), None);
+        assert_eq!(Period::parse("June"), None);
+    }
+
+    #[test]
+    fn test_totals_add_and_get() {
+        let mut totals = UsageTotals::default();
+        totals.add(&increment("2025-06-29", 10));
+        totals.add(&increment("2025-06-30", 100));
+        totals.add(&increment("2025-06-30", 20));
+        totals.add(&increment("2025-07-01", 40));
+
+        let day = totals.get(&Period::parse("2025-06-30").unwrap());
+        assert_eq!(
+            day,
+            [DailyUsage {
+                date: "2025-06-30".to_string(),
+                prompt_tokens: 120,
+                completion_tokens: 60,
+                total_tokens: 180,
+                requests: 2,
+            }]
+        );
+
+        let june = &totals.get(&Period::parse("2025-06").unwrap())[0];
+        assert_eq!((june.date.as_str(), june.total_tokens), ("2025-06", 195));
+        assert_eq!(june.requests, 3);
+
+        // Every day of a range is there, those without usage as zero
+        let days = totals.get(&Period::parse("2025-06-28..2025-07-01").unwrap());
+        let tokens = days
+            .iter()
+            .map(|day| (day.date.as_str(), day.total_tokens))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                ("2025-06-28", 0),
+                ("2025-06-29", 15),
+                ("2025-06-30", 180),
+                ("2025-07-01", 60)
+            ]
+        );
+
+        let days = totals.get(&Period::parse("2024-01-01..2025-07-01").unwrap());
+        assert_eq!(days.len() as i64, DAYS_KEPT);
+    }
+
+    #[test]
+    fn test_totals_roll_over() {
+        let mut totals = UsageTotals::default();
+        totals.add(&increment("2024-05-31", 1));
+        totals.add(&increment("2024-06-01", 2));
+        totals.add(&increment("2025-03-31", 3));
+        totals.add(&increment("2025-04-01", 4));
+        totals.add(&increment("2025-06-29", 5));
+
+        totals.roll_over(usage::parse_date("2025-06-29").unwrap());
+        assert_eq!(
+            totals.days.keys().collect::<Vec<_>>(),
+            ["2025-04-01", "2025-06-29"]
+        );
+        assert_eq!(
+            totals.months.keys().collect::<Vec<_>>(),
+            ["2024-06", "2025-03", "2025-04", "2025-06"]
+        );
+
+        // Rolling over is idempotent, so a retried alarm changes nothing
+        let stored = serde_json::to_string(&totals).unwrap();
+        totals.roll_over(usage::parse_date("2025-06-29").unwrap());
+        assert_eq!(serde_json::to_string(&totals).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_until_next_day() {
+        let midnight = 1640995200000.0;
+        assert_eq!(until_next_day(midnight), Duration::from_secs(86_400));
+        assert_eq!(
+            until_next_day(midnight + 23.0 * 3_600_000.0),
+            Duration::from_secs(3_600)
+        );
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use worker::*;
+
+use crate::kv_cache;
+
+/// KV namespace of per-tenant limits; the completion cap is stored under `max_tokens:{tenant}`
+pub const TENANT_LIMITS_BINDING: &str = "TENANT_LIMITS";
+/// Variable holding the completion cap of tenants without one of their own
+pub const MAX_TOKENS_CAP_VAR: &str = "MAX_TOKENS_CAP";
+
+/// Whether a completion cap may apply to any request of this deployment
+pub fn enabled(env: &Env) -> bool {
+    env.kv(TENANT_LIMITS_BINDING).is_ok() || env.var(MAX_TOKENS_CAP_VAR).is_ok()
+}
+
+fn parse_cap(value: &str) -> Option<u32> {
+    value.trim().parse().ok().filter(|cap| *cap > 0)
+}
+
+/// The completion cap of `tenant`: its own from `TENANT_LIMITS`, else `MAX_TOKENS_CAP`
+pub async fn resolve(env: &Env, tenant: Option<&str>) -> Option<u32> {
+    if let Some(tenant) = tenant.filter(|_| env.kv(TENANT_LIMITS_BINDING).is_ok()) {
+        let key = format!("max_tokens:{tenant}");
+        match kv_cache::get_text(env, TENANT_LIMITS_BINDING, &key).await {
+            Ok(Some(value)) => match parse_cap(&value) {
+                Some(cap) => return Some(cap),
+                None => console_warn!("Ignoring invalid {} value: {:?}", key, value),
+            },
+            Ok(None) => {}
+            Err(e) => console_error!("TENANT_LIMITS lookup of {} failed: {}", key, e),
+        }
+    }
+
+    let default = env.var(MAX_TOKENS_CAP_VAR).ok()?.to_string();
+    parse_cap(&default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cap() {
+        assert_eq!(parse_cap("4096"), Some(4096));
+        assert_eq!(parse_cap(" 512\n"), Some(512));
+        assert_eq!(parse_cap("0"), None);
+        assert_eq!(parse_cap("-1"), None);
+        assert_eq!(parse_cap("lots"), None);
+    }
+}
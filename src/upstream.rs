@@ -0,0 +1,250 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::fmt;
+
+use serde_json::json;
+use worker::*;
+
+/// Env var holding the comma-separated upstream host allowlist (e.g. `api.openai.com,*.openai.azure.com`)
+pub const ALLOWED_UPSTREAM_HOSTS_VAR: &str = "ALLOWED_UPSTREAM_HOSTS";
+
+/// Why an upstream URL was refused
+#[derive(Debug, PartialEq)]
+pub enum UpstreamUrlError {
+    Invalid(String),
+    InsecureScheme(String),
+    HostNotAllowed(String),
+}
+
+impl fmt::Display for UpstreamUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamUrlError::Invalid(e) => write!(f, "Invalid upstream URL: {e}"),
+            UpstreamUrlError::InsecureScheme(scheme) => {
+                write!(f, "Upstream URL must use https, got `{scheme}`")
+            }
+            UpstreamUrlError::HostNotAllowed(host) => {
+                write!(f, "Upstream host `{host}` is not allowed")
+            }
+        }
+    }
+}
+
+impl UpstreamUrlError {
+    /// What was refused, as recorded in analytics
+    pub fn rejected(&self) -> &str {
+        match self {
+            UpstreamUrlError::Invalid(_) => "invalid",
+            UpstreamUrlError::InsecureScheme(scheme) => scheme,
+            UpstreamUrlError::HostNotAllowed(host) => host,
+        }
+    }
+
+    pub fn to_response(&self) -> Result<Response> {
+        let status = match self {
+            UpstreamUrlError::Invalid(_) => 400,
+            _ => 403,
+        };
+
+        Ok(Response::from_json(&json!({
+            "error": true,
+            "type": "Upstream Not Allowed",
+            "message": self.to_string(),
+        }))?
+        .with_status(status))
+    }
+}
+
+/// Matches a host against one allowlist entry; `*.example.com` matches any subdomain
+pub fn host_matches(host: &str, pattern: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+/// Parses `upstream` and checks it against the allowlist.
+///
+/// Only `https` (or `wss` for WebSocket upstreams) is accepted.
+pub fn validate_upstream_url(
+    upstream: &str,
+    allowlist: &str,
+) -> std::result::Result<Url, UpstreamUrlError> {
+    let url = Url::parse(upstream).map_err(|e| UpstreamUrlError::Invalid(e.to_string()))?;
+
+    if !matches!(url.scheme(), "https" | "wss") {
+        return Err(UpstreamUrlError::InsecureScheme(url.scheme().to_string()));
+    }
+
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let allowed = allowlist
+        .split(',')
+        .map(|entry| entry.trim().to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| host_matches(&host, &entry));
+
+    if !allowed {
+        return Err(UpstreamUrlError::HostNotAllowed(host));
+    }
+
+    Ok(url)
+}
+
+/// Percent-encodes a query value, leaving RFC 3986 unreserved characters as they are
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Adds `api-version` to the upstream query string unless `upstream` already carries one.
+///
+/// Returns the URL together with the api-version the upstream will actually see.
+pub fn with_api_version(upstream: &str, api_version: Option<&str>) -> (String, Option<String>) {
+    let (base, fragment) = match upstream.find('#') {
+        Some(pos) => upstream.split_at(pos),
+        None => (upstream, ""),
+    };
+
+    let existing = base.split_once('?').and_then(|(_, query)| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "api-version")
+            .map(|(_, value)| value.to_string())
+    });
+    if existing.is_some() {
+        return (upstream.to_string(), existing);
+    }
+
+    let Some(api_version) = api_version else {
+        return (upstream.to_string(), None);
+    };
+
+    let separator = match base.find('?') {
+        None => "?",
+        Some(_) if base.ends_with('?') || base.ends_with('&') => "",
+        Some(_) => "&",
+    };
+    let url = format!(
+        "{base}{separator}api-version={}{fragment}",
+        encode_query_value(api_version)
+    );
+
+    (url, Some(api_version.to_string()))
+}
+
+thread_local! {
+    // Per isolate; workers run single-threaded
+    static HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+/// The isolate's client for outgoing requests, shared by every proxy route and the analytics
+/// webhook; clones share one client
+pub fn http_client() -> reqwest::Client {
+    HTTP_CLIENT.with(reqwest::Client::clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOWLIST: &str = "api.openai.com, *.openai.azure.com,api.anthropic.com";
+
+    #[test]
+    fn test_allowed_hosts() {
+        assert!(
+            validate_upstream_url("https://api.openai.com/v1/chat/completions", ALLOWLIST).is_ok()
+        );
+        assert!(validate_upstream_url(
+            "https://em-prod.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21",
+            ALLOWLIST
+        )
+        .is_ok());
+        assert!(validate_upstream_url("https://API.Anthropic.com/v1/messages", ALLOWLIST).is_ok());
+        assert!(validate_upstream_url("wss://api.openai.com/v1/realtime", ALLOWLIST).is_ok());
+    }
+
+    #[test]
+    fn test_rejected_hosts() {
+        assert_eq!(
+            validate_upstream_url("https://evil.example.com/collect", ALLOWLIST).unwrap_err(),
+            UpstreamUrlError::HostNotAllowed("evil.example.com".to_string())
+        );
+        // The wildcard needs a subdomain and a real label boundary
+        assert!(validate_upstream_url("https://openai.azure.com/", ALLOWLIST).is_err());
+        assert!(validate_upstream_url("https://evilopenai.azure.com/", ALLOWLIST).is_err());
+        assert!(validate_upstream_url("https://api.openai.com.evil.com/", ALLOWLIST).is_err());
+    }
+
+    #[test]
+    fn test_api_version_appended() {
+        assert_eq!(
+            with_api_version(
+                "https://em.openai.azure.com/openai/deployments/gpt-4o/chat/completions",
+                Some("2024-10-21")
+            ),
+            (
+                "https://em.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21".to_string(),
+                Some("2024-10-21".to_string())
+            )
+        );
+        assert_eq!(
+            with_api_version("https://example.com/chat?foo=bar", Some("2024-02-01")).0,
+            "https://example.com/chat?foo=bar&api-version=2024-02-01"
+        );
+        assert_eq!(
+            with_api_version("https://example.com/chat?", Some("2024-02-01")).0,
+            "https://example.com/chat?api-version=2024-02-01"
+        );
+        assert_eq!(
+            with_api_version("https://example.com/chat#top", Some("2024 preview")).0,
+            "https://example.com/chat?api-version=2024%20preview#top"
+        );
+    }
+
+    #[test]
+    fn test_api_version_not_duplicated() {
+        assert_eq!(
+            with_api_version(
+                "https://example.com/chat?api-version=2023-05-15&x=1",
+                Some("2024-10-21")
+            ),
+            (
+                "https://example.com/chat?api-version=2023-05-15&x=1".to_string(),
+                Some("2023-05-15".to_string())
+            )
+        );
+        // Only a real parameter counts, not a substring of another one
+        assert_eq!(
+            with_api_version("https://example.com/chat?old-api-version=1", Some("2")).0,
+            "https://example.com/chat?old-api-version=1&api-version=2"
+        );
+        assert_eq!(
+            with_api_version("https://example.com/chat", None),
+            ("https://example.com/chat".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_rejected_schemes() {
+        assert_eq!(
+            validate_upstream_url("http://api.openai.com/v1/chat/completions", ALLOWLIST)
+                .unwrap_err(),
+            UpstreamUrlError::InsecureScheme("http".to_string())
+        );
+        assert!(matches!(
+            validate_upstream_url("not a url", ALLOWLIST),
+            Err(UpstreamUrlError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use worker::*;
+
+use crate::analytics::KvAggregator;
+use crate::tenant_usage;
+
+/// KV namespace holding the daily per-tenant usage aggregates
+pub const USAGE_BINDING: &str = "USAGE";
+/// Longest range, in days, served by a single usage query
+pub const MAX_RANGE_DAYS: i64 = 90;
+/// Range served when the query doesn't specify `from`
+const DEFAULT_RANGE_DAYS: i64 = 30;
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// One day of token consumption for a tenant
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DailyUsage {
+    #[serde(default)]
+    pub date: String,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+    #[serde(default)]
+    pub requests: u64,
+}
+
+impl DailyUsage {
+    /// Adds one usage event to the aggregate
+    pub fn record(&mut self, prompt_tokens: u32, completion_tokens: u32, total_tokens: u32) {
+        self.prompt_tokens += prompt_tokens as u64;
+        self.completion_tokens += completion_tokens as u64;
+        self.total_tokens += total_tokens as u64;
+        self.requests += 1;
+    }
+}
+
+/// KV key of a tenant's aggregate for one day, as written before per-app aggregates
+pub fn usage_key(tenant: &str, date: &str) -> String {
+    format!("usage:{tenant}:{date}")
+}
+
+/// KV key prefix of a tenant's per-app daily aggregates
+fn aggregate_prefix(tenant: &str) -> String {
+    format!("agg:{tenant}:")
+}
+
+/// KV key of an app's aggregate for one day, kept by `KvAggregator`
+pub fn aggregate_key(tenant: &str, app: &str, date: &str) -> String {
+    format!("{}{app}:{date}", aggregate_prefix(tenant))
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Formats days since 1970-01-01 as `yyyy-mm-dd`
+pub fn format_date(days: i64) -> String {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Parses a `yyyy-mm-dd` date into days since 1970-01-01
+pub fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let month = parts.next()?.parse::<u32>().ok()?;
+    let day = parts.next()?.parse::<u32>().ok()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Reject dates such as 2025-02-30 that would silently roll over
+    let days = days_from_civil(year, month, day);
+    (format_date(days) == date).then_some(days)
+}
+
+/// Days since 1970-01-01 of a millisecond timestamp
+pub fn days_from_millis(millis: f64) -> i64 {
+    (millis / MILLIS_PER_DAY).floor() as i64
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct UsageQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// Resolves the inclusive day range of a query, capped at `MAX_RANGE_DAYS`
+fn query_range(query: &UsageQuery, today: i64) -> std::result::Result<(i64, i64), String> {
+    let parse = |name: &str, value: &Option<String>| -> std::result::Result<Option<i64>, String> {
+        value
+            .as_deref()
+            .map(|date| parse_date(date).ok_or(format!("`{name}` must be a yyyy-mm-dd date")))
+            .transpose()
+    };
+
+    let to = parse("to", &query.to)?.unwrap_or(today);
+    let from = parse("from", &query.from)?.unwrap_or(to - (DEFAULT_RANGE_DAYS - 1));
+
+    if from > to {
+        return Err("`from` must not be after `to`".to_string());
+    }
+
+    Ok((from.max(to - (MAX_RANGE_DAYS - 1)), to))
+}
+
+/// Returns a tenant's daily token consumption between `from` and `to` (inclusive), from its
+/// `TenantUsage` object when bound and the KV aggregates otherwise
+pub async fn handle_usage(req: Request, ctx: RouteContext<Rc<Context>>) -> Result<Response> {
+    let tenant = match ctx.param("tenantId") {
+        Some(tenant) => tenant.to_string(),
+        None => return Response::error("Bad Request", 400),
+    };
+
+    let query = req.query::<UsageQuery>().unwrap_or_default();
+    let today = days_from_millis(Date::now().as_millis() as f64);
+
+    let (from, to) = match query_range(&query, today) {
+        Ok(range) => range,
+        Err(message) => {
+            return Ok(Response::from_json(&json!({
+                "error": true,
+                "type": "Query String Error",
+                "message": message,
+            }))?
+            .with_status(400))
+        }
+    };
+
+    // The tenant's Durable Object counts exactly, where it is bound
+    let period = format!("{}..{}", format_date(from), format_date(to));
+    if let Some(usage) = tenant_usage::get(&ctx.env, &tenant, &period).await? {
+        return Response::from_json(&usage);
+    }
+
+    let kv = ctx.kv(USAGE_BINDING)?;
+    let dates = (from..=to).map(format_date).collect::<Vec<_>>();
+    let mut apps = app_aggregates(&kv, &tenant, &dates).await?;
+
+    let days = join_all(dates.iter().map(|date| {
+        let key = usage_key(&tenant, date);
+        let kv = &kv;
+        async move { kv.get(&key).json::<DailyUsage>().await }
+    }))
+    .await;
+
+    let mut usage = Vec::with_capacity(dates.len());
+    for (date, day) in dates.into_iter().zip(days) {
+        let apps = apps.remove(&date).unwrap_or_default();
+        let mut day = KvAggregator::merge(day?, &apps);
+        day.date = date;
+        usage.push(day);
+    }
+
+    Response::from_json(&usage)
+}
+
+/// The tenant's per-app aggregates summed by date, for the `dates` asked for; read off the
+/// metadata of the listed keys, without a read per key
+async fn app_aggregates(
+    kv: &kv::KvStore,
+    tenant: &str,
+    dates: &[String],
+) -> Result<BTreeMap<String, DailyUsage>> {
+    let mut days = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(aggregate_prefix(tenant));
+        if let Some(cursor) = cursor {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+
+        for key in page.keys {
+            let Some((_, date)) = key.name.rsplit_once(':') else {
+                continue;
+            };
+            if !dates.iter().any(|day| day == date) {
+                continue;
+            }
+            let usage = key
+                .metadata
+                .and_then(|metadata| serde_json::from_value::<DailyUsage>(metadata).ok());
+            if let Some(usage) = usage {
+                let day = days.remove(date);
+                days.insert(date.to_string(), KvAggregator::merge(day, &usage));
+            }
+        }
+
+        if page.list_complete || page.cursor.is_none() {
+            return Ok(days);
+        }
+        cursor = page.cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_round_trip() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(days_from_millis(1640995200000.0)), "2022-01-01");
+        assert_eq!(parse_date("2024-02-29"), Some(19782));
+        assert_eq!(format_date(19782), "2024-02-29");
+        for days in [-1, 59, 365, 11016, 20000, 30000] {
+            assert_eq!(parse_date(&format_date(days)), Some(days));
+        }
+    }
+
+    #[test]
+    fn test_parse_date_rejects_invalid() {
+        assert_eq!(parse_date("2025-02-30"), None);
+        assert_eq!(parse_date("2025-13-01"), None);
+        assert_eq!(parse_date("2025-1-01"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_query_range() {
+        let today = parse_date("2025-06-30").unwrap();
+
+        let (from, to) = query_range(&UsageQuery::default(), today).unwrap();
+        assert_eq!(
+            (format_date(from), format_date(to)),
+            ("2025-06-01".to_string(), "2025-06-30".to_string())
+        );
+
+        let query = UsageQuery {
+            from: Some("2024-01-01".to_string()),
+            to: Some("2025-06-30".to_string()),
+        };
+        let (from, to) = query_range(&query, today).unwrap();
+        assert_eq!(to - from + 1, MAX_RANGE_DAYS);
+
+        let query = UsageQuery {
+            from: Some("2025-07-01".to_string()),
+            to: Some("2025-06-30".to_string()),
+        };
+        assert!(query_range(&query, today).is_err());
+
+        let query = UsageQuery {
+            from: Some("June".to_string()),
+            to: None,
+        };
+        assert!(query_range(&query, today).is_err());
+    }
+
+    #[test]
+    fn test_daily_usage_record() {
+        let mut usage: DailyUsage =
+            serde_json::from_str(r#"{"date": "2025-06-30", "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15, "requests": 1}"#)
+                .unwrap();
+        usage.record(100, 50, 150);
+
+        assert_eq!(usage.prompt_tokens, 110);
+        assert_eq!(usage.completion_tokens, 55);
+        assert_eq!(usage.total_tokens, 165);
+        assert_eq!(usage.requests, 2);
+        assert_eq!(usage_key("acme", &usage.date), "usage:acme:2025-06-30");
+        assert_eq!(
+            aggregate_key("acme", "fares", &usage.date),
+            "agg:acme:fares:2025-06-30"
+        );
+    }
+}
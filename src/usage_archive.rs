@@ -0,0 +1,158 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::collections::{BTreeMap, HashMap};
+
+use worker::*;
+
+use crate::analytics::UsageAnalytics;
+use crate::analytics_sink::AnalyticsSink;
+use crate::request_id;
+use crate::usage;
+
+/// Binding of the R2 bucket raw usage events are archived to, when configured
+pub const USAGE_ARCHIVE_BINDING: &str = "USAGE_ARCHIVE";
+
+/// Version of the line format, kept in every object's metadata; bump it with breaking changes
+/// to the serialized `UsageAnalytics`
+pub const ARCHIVE_SCHEMA_VERSION: &str = "1";
+
+const ARCHIVE_CONTENT_TYPE: &str = "application/x-ndjson";
+
+const MILLIS_PER_HOUR: f64 = 3_600_000.0;
+
+/// Keeps every event, unsampled, as a line of NDJSON in R2, for retention beyond what
+/// Analytics Engine offers
+pub struct R2Sink(Bucket);
+
+impl R2Sink {
+    /// The sink of the `USAGE_ARCHIVE` binding; `None` without one
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.bucket(USAGE_ARCHIVE_BINDING).ok().map(R2Sink)
+    }
+}
+
+impl AnalyticsSink for R2Sink {
+    fn name(&self) -> &'static str {
+        "the usage archive"
+    }
+
+    async fn write(&self, events: &[UsageAnalytics]) -> std::result::Result<(), String> {
+        for (prefix, body) in archive_objects(events).map_err(|e| e.to_string())? {
+            // Named at random, so concurrent requests never overwrite each other's objects
+            let key = format!("{prefix}/{}.ndjson", request_id::random_uuid()?);
+            self.0
+                .put(key, body)
+                .http_metadata(HttpMetadata {
+                    content_type: Some(ARCHIVE_CONTENT_TYPE.to_string()),
+                    ..Default::default()
+                })
+                .custom_metadata(HashMap::from([(
+                    "schema-version".to_string(),
+                    ARCHIVE_SCHEMA_VERSION.to_string(),
+                )]))
+                .execute()
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Where objects holding `event` go: `usage/{tenId}/{yyyy}/{mm}/{dd}/{hh}`, in UTC
+fn archive_prefix(event: &UsageAnalytics) -> String {
+    let date = usage::format_date(usage::days_from_millis(event.timestamp));
+    let hour = (event.timestamp / MILLIS_PER_HOUR).floor().rem_euclid(24.0);
+    format!(
+        "usage/{}/{}/{hour:02}",
+        event.tenant_id.as_deref().unwrap_or("unknown"),
+        date.replace('-', "/"),
+    )
+}
+
+/// The events of one request as an NDJSON body per prefix, so each tenant and hour is written
+/// once
+fn archive_objects(events: &[UsageAnalytics]) -> serde_json::Result<BTreeMap<String, String>> {
+    let mut objects = BTreeMap::<String, String>::new();
+    for event in events {
+        let body = objects.entry(archive_prefix(event)).or_default();
+        body.push_str(&serde_json::to_string(event)?);
+        body.push('\n');
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(tenant_id: Option<&str>, timestamp: f64) -> UsageAnalytics {
+        UsageAnalytics::new_with_timestamp(
+            "app123".to_string(),
+            tenant_id.map(str::to_string),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "gpt-4o".to_string(),
+            10,
+            5,
+            15,
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn test_archive_prefix() {
+        // 2022-01-01T00:00:00Z
+        let midnight = 1640995200000.0;
+        assert_eq!(
+            archive_prefix(&event(Some("tenant123"), midnight)),
+            "usage/tenant123/2022/01/01/00"
+        );
+        assert_eq!(
+            archive_prefix(&event(None, midnight + 23.5 * MILLIS_PER_HOUR)),
+            "usage/unknown/2022/01/01/23"
+        );
+        assert_eq!(
+            archive_prefix(&event(Some("tenant123"), midnight + 24.0 * MILLIS_PER_HOUR)),
+            "usage/tenant123/2022/01/02/00"
+        );
+    }
+
+    #[test]
+    fn test_archive_objects() {
+        let midnight = 1640995200000.0;
+        let events = [
+            event(Some("tenant123"), midnight),
+            event(Some("tenant456"), midnight),
+            event(Some("tenant123"), midnight + 60_000.0),
+        ];
+        let objects = archive_objects(&events).unwrap();
+        assert_eq!(
+            objects.keys().collect::<Vec<_>>(),
+            [
+                "usage/tenant123/2022/01/01/00",
+                "usage/tenant456/2022/01/01/00"
+            ]
+        );
+
+        // One event per line, in the order they were recorded
+        let body = &objects["usage/tenant123/2022/01/01/00"];
+        assert!(body.ends_with('\n'));
+        let lines = body
+            .lines()
+            .map(|line| serde_json::from_str::<UsageAnalytics>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].timestamp, midnight + 60_000.0);
+        assert_eq!(lines[1].total_tokens, 15);
+
+        assert!(archive_objects(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use serde_json::{Map, Value};
+use worker::wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::analytics::UsageAnalytics;
+use crate::analytics_sink::AnalyticsSink;
+
+/// Binding of the D1 database usage events are also inserted into, when configured
+pub const USAGE_DB_BINDING: &str = "USAGE_DB";
+
+/// Created by `migrations/0001_create_usage_events.sql`
+const TABLE: &str = "usage_events";
+
+/// Inserts every event as a row of `usage_events`, for deployments that query usage in SQL
+pub struct D1Sink(D1Database);
+
+impl D1Sink {
+    /// The sink of the `USAGE_DB` binding; `None` without one
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.d1(USAGE_DB_BINDING).ok().map(D1Sink)
+    }
+}
+
+impl AnalyticsSink for D1Sink {
+    fn name(&self) -> &'static str {
+        "the usage database"
+    }
+
+    async fn write(&self, events: &[UsageAnalytics]) -> std::result::Result<(), String> {
+        let statements = events
+            .iter()
+            .map(|event| {
+                let (sql, params) = insert_statement(event).map_err(|e| e.to_string())?;
+                let params = params.iter().map(js_value).collect::<Vec<_>>();
+                self.0.prepare(sql).bind(&params).map_err(|e| e.to_string())
+            })
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        // One batch is one transaction, so a request's events are inserted together or not at all
+        self.0
+            .batch(statements)
+            .await
+            .map(drop)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The insert of `event` and its bound parameters: a column per field, so the table follows
+/// `UsageAnalytics` as fields are added (with a migration adding their column)
+fn insert_statement(event: &UsageAnalytics) -> serde_json::Result<(String, Vec<Value>)> {
+    let fields = match serde_json::to_value(event)? {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    };
+
+    let columns = fields.keys().cloned().collect::<Vec<_>>().join(", ");
+    let placeholders = vec!["?"; fields.len()].join(", ");
+    let sql = format!("INSERT INTO {TABLE} ({columns}) VALUES ({placeholders})");
+    Ok((sql, fields.into_values().map(sql_value).collect()))
+}
+
+/// A field as SQLite stores it: flags as 0 or 1, lists and maps as JSON text
+fn sql_value(value: Value) -> Value {
+    match value {
+        Value::Bool(flag) => Value::from(u8::from(flag)),
+        Value::Array(_) | Value::Object(_) => Value::String(value.to_string()),
+        value => value,
+    }
+}
+
+fn js_value(value: &Value) -> JsValue {
+    match value {
+        Value::Null => JsValue::NULL,
+        Value::Number(number) => JsValue::from_f64(number.as_f64().unwrap_or_default()),
+        Value::String(text) => JsValue::from_str(text),
+        value => JsValue::from_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> UsageAnalytics {
+        UsageAnalytics::new_with_timestamp(
+            "app123".to_string(),
+            Some("tenant123".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "gpt-4o".to_string(),
+            10,
+            5,
+            15,
+            1640995200000.0,
+        )
+    }
+
+    #[test]
+    fn test_insert_statement() {
+        let mut event = event();
+        event.status_code = 200;
+        event.ttft_ms = Some(420);
+        event.client_disconnected = true;
+        event.tool_names = vec!["search_fares".to_string()];
+        event.extra.insert("channel".to_string(), "web".to_string());
+
+        let (sql, params) = insert_statement(&event).unwrap();
+        let columns = sql
+            .strip_prefix("INSERT INTO usage_events (")
+            .and_then(|sql| sql.split_once(") VALUES ("))
+            .map(|(columns, _)| columns.split(", ").collect::<Vec<_>>())
+            .unwrap();
+        assert!(sql.ends_with(&format!("VALUES ({})", vec!["?"; params.len()].join(", "))));
+        assert_eq!(columns.len(), params.len());
+
+        let param = |column: &str| &params[columns.iter().position(|c| *c == column).unwrap()];
+        assert_eq!(param("app_id"), "app123");
+        assert_eq!(param("module_id"), &Value::Null);
+        assert_eq!(param("total_tokens"), 15);
+        assert_eq!(param("timestamp"), 1640995200000.0);
+        assert_eq!(param("status_code"), 200);
+        assert_eq!(param("ttft_ms"), 420);
+        assert_eq!(param("first_byte_latency_ms"), &Value::Null);
+        assert_eq!(param("client_disconnected"), 1);
+        assert_eq!(param("usage_captured"), 1);
+        assert_eq!(param("tool_names"), r#"["search_fares"]"#);
+        assert_eq!(param("extra"), r#"{"channel":"web"}"#);
+    }
+
+    #[test]
+    fn test_migration_has_every_column() {
+        let migration = include_str!("../migrations/0001_create_usage_events.sql");
+        let (sql, _) = insert_statement(&event()).unwrap();
+        let (_, columns) = sql.split_once('(').unwrap();
+        let (columns, _) = columns.split_once(')').unwrap();
+        for column in columns.split(", ") {
+            assert!(
+                migration.contains(&format!("\n    {column} ")),
+                "no `{column}` column in the migration"
+            );
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use crate::sse::SseEvent;
+
+/// A stream's usage as its provider reported it, mapped onto the OpenAI-style columns of
+/// `UsageAnalytics`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtractedUsage {
+    /// The model the provider named; the request's known model is recorded when it named none
+    pub model: Option<String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    pub finish_reason: Option<String>,
+    /// Prompt tokens read from the provider's prompt cache
+    pub cached_tokens: u32,
+    /// Completion tokens the model reasoned with
+    pub reasoning_tokens: u32,
+    /// The backend configuration and processing tier that served the stream, when reported
+    pub system_fingerprint: Option<String>,
+    pub service_tier: Option<String>,
+}
+
+/// A usage event that didn't deserialize into the usage it seemed to report
+#[derive(Debug)]
+pub struct UnparsedUsage {
+    pub data: String,
+    pub error: serde_json::Error,
+    /// Whether the event plainly reported usage; if not, it couldn't be read at all and may
+    /// well have carried none
+    pub reported_usage: bool,
+}
+
+/// What a usage event becomes: its usage, or why it couldn't be read
+pub type Extracted = Result<ExtractedUsage, UnparsedUsage>;
+
+/// What the content events of a stream showed, for formats whose content is read
+#[derive(Debug, Default)]
+pub struct ContentStats {
+    /// Events with choices; streams send about one completion token per event
+    pub chunks: u32,
+    /// The first model the events named
+    pub model: Option<String>,
+    /// Characters of content the first choice streamed, to size streams without usage
+    pub completion_chars: u32,
+    /// Why the first choice ended, once an event said so
+    pub finish_reason: Option<String>,
+    /// Tool calls the first choice made, and the names of the first ones
+    pub tool_call_count: u32,
+    pub tool_names: Vec<String>,
+    /// The first `system_fingerprint` and `service_tier` the events reported
+    pub system_fingerprint: Option<String>,
+    pub service_tier: Option<String>,
+}
+
+/// Reads a stream's usage the way its provider reports it.
+///
+/// `UsageScanner` feeds it the stream and builds the stream's one record from what it returns;
+/// extractors never touch the worker runtime, so every provider's parsing is tested natively.
+pub trait UsageExtractor {
+    /// Whether it reads the body's network chunks (`feed_chunk`) rather than its SSE events
+    /// (`feed_event`), for streams that may not be SSE at all
+    fn reads_chunks(&self) -> bool {
+        false
+    }
+
+    /// Feeds a network chunk, returning the usage of an event it completes
+    fn feed_chunk(&mut self, _chunk: &[u8]) -> Option<Extracted> {
+        None
+    }
+
+    /// Feeds a whole event but `data: [DONE]`, returning the usage it reports
+    fn feed_event(&mut self, _event: &SseEvent) -> Option<Extracted> {
+        None
+    }
+
+    /// The usage standing for the stream once it ended (or the client left it), when that's
+    /// not what the last event returned: usage only final at the end, or carried by content
+    fn finish(&mut self) -> Option<ExtractedUsage> {
+        None
+    }
+
+    /// What the stream's content showed, for formats whose content it reads
+    fn content(&self) -> Option<&ContentStats> {
+        None
+    }
+}
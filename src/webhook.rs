@@ -0,0 +1,168 @@
+// Copyright (c) 2025 PROS Inc.
+// All rights reserved.
+
+use std::time::Duration;
+
+use worker::*;
+
+use crate::analytics::UsageAnalytics;
+use crate::analytics_sink::AnalyticsSink;
+use crate::upstream;
+
+/// Env var holding the URL usage events are POSTed to; unset posts them nowhere
+pub const ANALYTICS_WEBHOOK_URL_VAR: &str = "ANALYTICS_WEBHOOK_URL";
+/// Worker secret holding the bearer token sent to the webhook, when it wants one
+pub const ANALYTICS_WEBHOOK_TOKEN_SECRET: &str = "ANALYTICS_WEBHOOK_TOKEN";
+
+/// How long one delivery may take before it counts as failed
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Deliveries tried per write: the first and one retry
+const WEBHOOK_ATTEMPTS: usize = 2;
+
+/// POSTs the events of a request as JSON to a collector outside Cloudflare
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl WebhookSink {
+    /// The sink of `ANALYTICS_WEBHOOK_URL`; `None` without one
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let url = env.var(ANALYTICS_WEBHOOK_URL_VAR).ok()?.to_string();
+        let token = env
+            .secret(ANALYTICS_WEBHOOK_TOKEN_SECRET)
+            .ok()
+            .map(|token| token.to_string());
+        Self::new(&url, token.as_deref())
+    }
+
+    fn new(url: &str, token: Option<&str>) -> Option<Self> {
+        let url = url.trim();
+        if url.is_empty() {
+            return None;
+        }
+        Some(WebhookSink {
+            client: upstream::http_client(),
+            url: url.to_string(),
+            token: token
+                .map(str::trim)
+                .filter(|token| !token.is_empty())
+                .map(str::to_string),
+        })
+    }
+
+    async fn post(&self, body: &str) -> std::result::Result<(), String> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(WEBHOOK_TIMEOUT)
+            .body(body.to_string());
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        status_error(response.status().as_u16()).map_or(Ok(()), Err)
+    }
+}
+
+impl AnalyticsSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "the analytics webhook"
+    }
+
+    async fn write(&self, events: &[UsageAnalytics]) -> std::result::Result<(), String> {
+        let Some(body) = webhook_body(events).map_err(|e| e.to_string())? else {
+            return Ok(());
+        };
+        let mut error = String::new();
+        for _ in 0..WEBHOOK_ATTEMPTS {
+            match self.post(&body).await {
+                Ok(()) => return Ok(()),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+/// The body posted for `events`: a lone event as an object, several as an array of them, and
+/// nothing to post for none
+fn webhook_body(events: &[UsageAnalytics]) -> serde_json::Result<Option<String>> {
+    match events {
+        [] => Ok(None),
+        [event] => serde_json::to_string(event).map(Some),
+        events => serde_json::to_string(events).map(Some),
+    }
+}
+
+/// Why a delivery answered with `status` failed; `None` when it was taken
+fn status_error(status: u16) -> Option<String> {
+    (!(200..300).contains(&status)).then(|| format!("webhook answered {status}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(app_id: &str) -> UsageAnalytics {
+        UsageAnalytics::new_with_timestamp(
+            app_id.to_string(),
+            Some("tenant123".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            "gpt-4o".to_string(),
+            10,
+            5,
+            15,
+            1640995200000.0,
+        )
+    }
+
+    #[test]
+    fn test_webhook_config() {
+        assert!(WebhookSink::new("", Some("secret")).is_none());
+        assert!(WebhookSink::new("  ", None).is_none());
+
+        let sink = WebhookSink::new(" https://collector.example.com/usage ", Some(" ")).unwrap();
+        assert_eq!(sink.url, "https://collector.example.com/usage");
+        assert_eq!(sink.token, None);
+
+        let sink = WebhookSink::new("https://collector.example.com/usage", Some("secret"));
+        assert_eq!(sink.unwrap().token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_webhook_body() {
+        assert_eq!(webhook_body(&[]).unwrap(), None);
+
+        let body = webhook_body(&[event("app123")]).unwrap().unwrap();
+        let posted: UsageAnalytics = serde_json::from_str(&body).unwrap();
+        assert_eq!(posted.app_id, "app123");
+        assert_eq!(posted.total_tokens, 15);
+
+        // Events of one request go out together
+        let body = webhook_body(&[event("app123"), event("app456")])
+            .unwrap()
+            .unwrap();
+        let posted: Vec<UsageAnalytics> = serde_json::from_str(&body).unwrap();
+        let apps = posted.iter().map(|event| event.app_id.as_str());
+        assert_eq!(apps.collect::<Vec<_>>(), ["app123", "app456"]);
+    }
+
+    #[test]
+    fn test_status_error() {
+        assert_eq!(status_error(200), None);
+        assert_eq!(status_error(204), None);
+        assert_eq!(status_error(302).as_deref(), Some("webhook answered 302"));
+        assert_eq!(status_error(503).as_deref(), Some("webhook answered 503"));
+    }
+}